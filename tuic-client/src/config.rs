@@ -18,7 +18,7 @@ use serde_json::Error as SerdeError;
 use thiserror::Error;
 use uuid::Uuid;
 
-use crate::utils::{CongestionControl, UdpRelayMode};
+use crate::utils::{Camouflage, CongestionControl, DnsProtocol, Ipv4Range, UdpRelayMode};
 
 const HELP_MSG: &str = r#"
 Usage tuic-client [arguments]
@@ -36,6 +36,9 @@ pub struct Config {
 
     pub local: Local,
 
+    #[serde(default)]
+    pub fake_dns: Option<FakeDns>,
+
     #[serde(default = "default::log_level")]
     pub log_level: LevelFilter,
 }
@@ -53,6 +56,8 @@ pub struct Relay {
 
     pub ip: Option<IpAddr>,
 
+    pub dns: Option<Dns>,
+
     #[serde(default = "default::relay::certificates")]
     pub certificates: Vec<PathBuf>,
 
@@ -74,6 +79,12 @@ pub struct Relay {
     )]
     pub alpn: Vec<Vec<u8>>,
 
+    #[serde(
+        default = "default::relay::camouflage",
+        deserialize_with = "deserialize_from_str"
+    )]
+    pub camouflage: Camouflage,
+
     #[serde(default = "default::relay::zero_rtt_handshake")]
     pub zero_rtt_handshake: bool,
 
@@ -101,6 +112,21 @@ pub struct Relay {
     #[serde(default = "default::relay::receive_window")]
     pub receive_window: u32,
 
+    #[serde(default)]
+    pub max_receive_window: Option<u64>,
+
+    #[serde(
+        default = "default::relay::max_idle_time",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub max_idle_time: Duration,
+
+    #[serde(
+        default = "default::relay::keep_alive_interval",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub keep_alive_interval: Duration,
+
     #[serde(default = "default::relay::initial_mtu")]
     pub initial_mtu: u16,
 
@@ -125,8 +151,47 @@ pub struct Relay {
     )]
     pub gc_lifetime: Duration,
 
-    #[serde(default = "default::relay::skip_cert_verify")]
-    pub skip_cert_verify: bool,
+    #[serde(default = "default::relay::insecure", alias = "skip_cert_verify")]
+    pub insecure: bool,
+
+    #[serde(default)]
+    pub pin_certificate: Option<PathBuf>,
+
+    #[serde(default)]
+    pub cover_traffic: Option<CoverTraffic>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct CoverTraffic {
+    #[serde(
+        default = "default::cover_traffic::min_interval",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub min_interval: Duration,
+
+    #[serde(
+        default = "default::cover_traffic::max_interval",
+        deserialize_with = "deserialize_duration"
+    )]
+    pub max_interval: Duration,
+
+    #[serde(default = "default::cover_traffic::max_padding")]
+    pub max_padding: usize,
+
+    #[serde(default = "default::cover_traffic::budget")]
+    pub budget: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Dns {
+    #[serde(deserialize_with = "deserialize_from_str")]
+    pub protocol: DnsProtocol,
+
+    pub servers: Vec<SocketAddr>,
+
+    pub name: String,
 }
 
 #[derive(Deserialize)]
@@ -146,6 +211,28 @@ pub struct Local {
     pub max_packet_size: usize,
 }
 
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FakeDns {
+    #[serde(default)]
+    pub server: Option<SocketAddr>,
+
+    #[serde(
+        default = "default::fake_dns::range",
+        deserialize_with = "deserialize_from_str"
+    )]
+    pub range: Ipv4Range,
+
+    #[serde(default = "default::fake_dns::ttl")]
+    pub ttl: u32,
+
+    #[serde(default = "default::fake_dns::hijack")]
+    pub hijack: bool,
+
+    #[serde(default)]
+    pub persist: Option<PathBuf>,
+}
+
 impl Config {
     pub fn parse(args: ArgsOs) -> Result<Self, ConfigError> {
         let mut parser = Parser::from_iter(args);
@@ -184,7 +271,7 @@ mod default {
     pub mod relay {
         use std::{path::PathBuf, time::Duration};
 
-        use crate::utils::{CongestionControl, UdpRelayMode};
+        use crate::utils::{Camouflage, CongestionControl, UdpRelayMode};
 
         pub fn certificates() -> Vec<PathBuf> {
             Vec::new()
@@ -202,6 +289,10 @@ mod default {
             Vec::new()
         }
 
+        pub fn camouflage() -> Camouflage {
+            Camouflage::None
+        }
+
         pub fn zero_rtt_handshake() -> bool {
             false
         }
@@ -230,6 +321,18 @@ mod default {
             8 * 1024 * 1024
         }
 
+        // struct.TransportConfig#method.max_idle_timeout
+        // zero -> max_idle_timeout(None)
+        pub fn max_idle_time() -> Duration {
+            Duration::ZERO
+        }
+
+        // struct.TransportConfig#method.keep_alive_interval
+        // zero -> keep_alive_interval(None)
+        pub fn keep_alive_interval() -> Duration {
+            Duration::ZERO
+        }
+
         // struct.TransportConfig#method.initial_mtu
         pub fn initial_mtu() -> u16 {
             1200
@@ -260,11 +363,47 @@ mod default {
             Duration::from_secs(15)
         }
 
-        pub fn skip_cert_verify() -> bool {
+        pub fn insecure() -> bool {
             false
         }
     }
 
+    pub mod cover_traffic {
+        use std::time::Duration;
+
+        pub fn min_interval() -> Duration {
+            Duration::from_secs(5)
+        }
+
+        pub fn max_interval() -> Duration {
+            Duration::from_secs(30)
+        }
+
+        pub fn max_padding() -> usize {
+            512
+        }
+
+        pub fn budget() -> u64 {
+            1024 * 1024
+        }
+    }
+
+    pub mod fake_dns {
+        use crate::utils::Ipv4Range;
+
+        pub fn range() -> Ipv4Range {
+            "198.18.0.0/15".parse().unwrap()
+        }
+
+        pub fn ttl() -> u32 {
+            1
+        }
+
+        pub fn hijack() -> bool {
+            true
+        }
+    }
+
     pub mod local {
         pub fn max_packet_size() -> usize {
             1500
@@ -57,6 +57,10 @@ impl Connection {
                 }
                 UdpRelayMode::Native => Err(Error::WrongPacketSource),
             },
+            Ok(Task::ExternalAddress(assoc_id, addr)) => {
+                Self::handle_external_address(assoc_id, addr);
+                Ok(())
+            }
             _ => unreachable!(), // already filtered in `tuic_quinn`
         };
 
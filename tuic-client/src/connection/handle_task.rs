@@ -8,7 +8,10 @@ use tuic::Address;
 use tuic_quinn::{Connect, Packet};
 
 use super::Connection;
-use crate::{error::Error, socks5::UDP_SESSIONS as SOCKS5_UDP_SESSIONS, utils::UdpRelayMode};
+use crate::{
+    config::CoverTraffic, error::Error, socks5::UDP_SESSIONS as SOCKS5_UDP_SESSIONS,
+    utils::UdpRelayMode,
+};
 
 impl Connection {
     pub async fn authenticate(self, zero_rtt_accepted: Option<ZeroRttAccepted>) {
@@ -49,7 +52,7 @@ impl Connection {
             UdpRelayMode::Native => {
                 log::info!("[relay] [packet] [{assoc_id:#06x}] [to-native] to {addr_display}");
                 match self.model.packet_native(pkt, addr, assoc_id) {
-                    Ok(()) => Ok(()),
+                    Ok(_) => Ok(()),
                     Err(err) => {
                         log::warn!(
                             "[relay] [packet] [{assoc_id:#06x}] [to-native] to {addr_display}: \
@@ -62,7 +65,7 @@ impl Connection {
             UdpRelayMode::Quic => {
                 log::info!("[relay] [packet] [{assoc_id:#06x}] [to-quic] {addr_display}");
                 match self.model.packet_quic(pkt, addr, assoc_id).await {
-                    Ok(()) => Ok(()),
+                    Ok(_) => Ok(()),
                     Err(err) => {
                         log::warn!(
                             "[relay] [packet] [{assoc_id:#06x}] [to-quic] to {addr_display}: {err}"
@@ -104,6 +107,59 @@ impl Connection {
         }
     }
 
+    /// Padded heartbeats at random intervals while no task is open, so an idle
+    /// connection doesn't stand out by its silence
+    pub async fn cover_traffic(self, cfg: CoverTraffic) {
+        let mut spent = 0;
+
+        loop {
+            let interval = fastrand::u64(
+                cfg.min_interval.as_millis() as u64..=cfg.max_interval.as_millis() as u64,
+            );
+            time::sleep(Duration::from_millis(interval)).await;
+
+            if self.is_closed() {
+                break;
+            }
+
+            if self.model.task_connect_count() + self.model.task_associate_count() != 0 {
+                continue;
+            }
+
+            // the heartbeat header takes 2 bytes
+            let Some(max_padding) = self
+                .conn
+                .max_datagram_size()
+                .map(|size| size.saturating_sub(2))
+            else {
+                break;
+            };
+            let len = fastrand::usize(..=cfg.max_padding.min(max_padding));
+            let size = len as u64 + 2;
+
+            if cfg.budget != 0 && spent + size > cfg.budget {
+                log::debug!("[relay] [cover] budget of {} bytes spent", cfg.budget);
+                break;
+            }
+
+            let padding: Vec<u8> = std::iter::repeat_with(|| fastrand::u8(..))
+                .take(len)
+                .collect();
+
+            match self.model.heartbeat_with_padding(&padding).await {
+                Ok(()) => {
+                    spent += size;
+                    log::debug!("[relay] [cover] {len} bytes of padding");
+                }
+                Err(err) => log::warn!("[relay] [cover] {err}"),
+            }
+        }
+    }
+
+    pub fn handle_external_address(assoc_id: u16, addr: Address) {
+        log::info!("[relay] [packet] [{assoc_id:#06x}] external address {addr}");
+    }
+
     pub async fn handle_packet(pkt: Packet) {
         let assoc_id = pkt.assoc_id();
         let pkt_id = pkt.pkt_id();
@@ -146,7 +202,7 @@ impl Connection {
                     .cloned();
 
                 if let Some(session) = session {
-                    if let Err(err) = session.send(pkt, addr).await {
+                    if let Err(err) = session.send(pkt, session.masquerade(addr)).await {
                         log::warn!(
                             "[relay] [packet] [{assoc_id:#06x}] [from-native] [{pkt_id:#06x}] \
                              failed sending packet to socks5 client: {err}",
@@ -0,0 +1,139 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use rustls::{
+    DigitallySignedStruct, Error as RustlsError, SignatureScheme,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{CryptoProvider, ring::cipher_suite::TLS13_AES_128_GCM_SHA256},
+    pki_types::{CertificateDer, ServerName, UnixTime},
+};
+
+/// Accepts any server certificate, but logs its fingerprint whenever it
+/// changes and, with a pin file, only trusts the first one it sees
+#[derive(Debug)]
+pub struct InsecureVerifier {
+    provider: Arc<CryptoProvider>,
+    server: String,
+    pin: Option<PathBuf>,
+    last_seen: Mutex<Option<String>>,
+}
+
+impl InsecureVerifier {
+    pub fn new(server: String, pin: Option<PathBuf>) -> Arc<Self> {
+        Arc::new(Self {
+            provider: Arc::new(rustls::crypto::ring::default_provider()),
+            server,
+            pin,
+            last_seen: Mutex::new(None),
+        })
+    }
+
+    /// Compare against the pinned fingerprint, pinning it on first use
+    fn check_pin(&self, fingerprint: &str) -> Result<(), RustlsError> {
+        let Some(path) = &self.pin else {
+            return Ok(());
+        };
+        match fs::read_to_string(path) {
+            Ok(pinned) if pinned.trim().eq_ignore_ascii_case(fingerprint) => Ok(()),
+            Ok(pinned) => Err(RustlsError::General(format!(
+                "certificate of {server} changed: pinned {pinned}, got {fingerprint}",
+                server = self.server,
+                pinned = pinned.trim(),
+            ))),
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                fs::write(path, format!("{fingerprint}\n")).map_err(|err| {
+                    RustlsError::General(format!(
+                        "failed to pin the certificate in {path}: {err}",
+                        path = path.display()
+                    ))
+                })?;
+                log::warn!(
+                    "[relay] pinned the certificate of {server} in {path}",
+                    server = self.server,
+                    path = path.display()
+                );
+                Ok(())
+            }
+            Err(err) => Err(RustlsError::General(format!(
+                "failed to read the pinned certificate {path}: {err}",
+                path = path.display()
+            ))),
+        }
+    }
+}
+
+impl ServerCertVerifier for InsecureVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, RustlsError> {
+        let fingerprint = fingerprint(end_entity);
+        self.check_pin(&fingerprint)?;
+
+        let mut last_seen = self.last_seen.lock().unwrap();
+        if last_seen.as_deref() != Some(fingerprint.as_str()) {
+            log::warn!(
+                "[relay] certificate verification of {server} is disabled, accepted a certificate \
+                 with SHA-256 fingerprint {fingerprint}",
+                server = self.server,
+            );
+            *last_seen = Some(fingerprint);
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, RustlsError> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, RustlsError> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// SHA-256 of the certificate, as colon-separated hex like OpenSSL prints it
+fn fingerprint(cert: &CertificateDer<'_>) -> String {
+    let suite = TLS13_AES_128_GCM_SHA256
+        .tls13()
+        .expect("TLS 1.3 cipher suite");
+    let hash = suite.common.hash_provider.hash(cert.as_ref());
+    hash.as_ref()
+        .iter()
+        .map(|b| format!("{b:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
@@ -9,15 +9,12 @@ use crossbeam_utils::atomic::AtomicCell;
 use once_cell::sync::OnceCell;
 use quinn::{
     ClientConfig, Connection as QuinnConnection, Endpoint as QuinnEndpoint, EndpointConfig,
-    TokioRuntime, TransportConfig, VarInt, ZeroRttAccepted,
+    IdleTimeout, TokioRuntime, TransportConfig, VarInt, ZeroRttAccepted,
     congestion::{BbrConfig, CubicConfig, NewRenoConfig},
     crypto::rustls::QuicClientConfig,
 };
 use register_count::Counter;
-use rustls::{
-    ClientConfig as RustlsClientConfig,
-    pki_types::{CertificateDer, ServerName, UnixTime},
-};
+use rustls::ClientConfig as RustlsClientConfig;
 use tokio::{
     sync::{OnceCell as AsyncOnceCell, RwLock as AsyncRwLock},
     time,
@@ -25,14 +22,16 @@ use tokio::{
 use tuic_quinn::{Connection as Model, side};
 use uuid::Uuid;
 
+use self::insecure::InsecureVerifier;
 use crate::{
-    config::Relay,
+    config::{CoverTraffic, Relay},
     error::Error,
-    utils::{self, CongestionControl, ServerAddr, UdpRelayMode},
+    utils::{self, Camouflage, CongestionControl, ServerAddr, UdpRelayMode},
 };
 
 mod handle_stream;
 mod handle_task;
+mod insecure;
 
 static ENDPOINT: OnceCell<AsyncRwLock<Endpoint>> = OnceCell::new();
 static CONNECTION: AsyncOnceCell<AsyncRwLock<Connection>> = AsyncOnceCell::const_new();
@@ -57,77 +56,41 @@ pub struct Connection {
 impl Connection {
     pub async fn set_config(cfg: Relay) -> Result<(), Error> {
         let certs = utils::load_certs(cfg.certificates, cfg.disable_native_certs)?;
-
-        let mut crypto = if cfg.skip_cert_verify {
-            #[derive(Debug)]
-            struct SkipServerVerification(Arc<rustls::crypto::CryptoProvider>);
-
-            impl SkipServerVerification {
-                fn new() -> Arc<Self> {
-                    Arc::new(Self(Arc::new(rustls::crypto::ring::default_provider())))
-                }
-            }
-
-            impl rustls::client::danger::ServerCertVerifier for SkipServerVerification {
-                fn verify_server_cert(
-                    &self,
-                    _end_entity: &CertificateDer<'_>,
-                    _intermediates: &[CertificateDer<'_>],
-                    _server_name: &ServerName<'_>,
-                    _ocsp: &[u8],
-                    _now: UnixTime,
-                ) -> Result<rustls::client::danger::ServerCertVerified, rustls::Error>
-                {
-                    Ok(rustls::client::danger::ServerCertVerified::assertion())
-                }
-
-                fn verify_tls12_signature(
-                    &self,
-                    message: &[u8],
-                    cert: &CertificateDer<'_>,
-                    dss: &rustls::DigitallySignedStruct,
-                ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error>
-                {
-                    rustls::crypto::verify_tls12_signature(
-                        message,
-                        cert,
-                        dss,
-                        &self.0.signature_verification_algorithms,
-                    )
-                }
-
-                fn verify_tls13_signature(
-                    &self,
-                    message: &[u8],
-                    cert: &CertificateDer<'_>,
-                    dss: &rustls::DigitallySignedStruct,
-                ) -> Result<rustls::client::danger::HandshakeSignatureValid, rustls::Error>
-                {
-                    rustls::crypto::verify_tls13_signature(
-                        message,
-                        cert,
-                        dss,
-                        &self.0.signature_verification_algorithms,
-                    )
-                }
-
-                fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
-                    self.0.signature_verification_algorithms.supported_schemes()
-                }
-            }
-            RustlsClientConfig::builder()
+        let resolver = cfg.dns.map(|dns| utils::dns_resolver(dns, certs.clone()));
+
+        let mut provider = RustlsClientConfig::builder()
+            .crypto_provider()
+            .as_ref()
+            .clone();
+        cfg.camouflage.apply(&mut provider);
+        let builder = RustlsClientConfig::builder_with_provider(Arc::new(provider));
+
+        let mut crypto = if cfg.insecure {
+            builder
+                .with_safe_default_protocol_versions()?
                 .dangerous()
-                .with_custom_certificate_verifier(SkipServerVerification::new())
+                .with_custom_certificate_verifier(InsecureVerifier::new(
+                    cfg.server.0.clone(),
+                    cfg.pin_certificate,
+                ))
                 .with_no_client_auth()
         } else {
-            RustlsClientConfig::builder_with_protocol_versions(&[&rustls::version::TLS13])
+            builder
+                .with_protocol_versions(&[&rustls::version::TLS13])?
                 .with_root_certificates(certs)
                 .with_no_client_auth()
         };
 
-        crypto.alpn_protocols = cfg.alpn;
+        crypto.alpn_protocols = if cfg.alpn.is_empty() {
+            cfg.camouflage.alpn()
+        } else {
+            cfg.alpn
+        };
         crypto.enable_early_data = true;
         crypto.enable_sni = !cfg.disable_sni;
+        if cfg.disable_sni && !matches!(cfg.camouflage, Camouflage::None) {
+            log::warn!("[relay] browsers always send SNI, `disable_sni` gives the camouflage away");
+        }
 
         let mut config = ClientConfig::new(Arc::new(
             QuicClientConfig::try_from(crypto).context("no initial cipher suite found")?,
@@ -139,10 +102,28 @@ impl Connection {
             .max_concurrent_uni_streams(VarInt::from(DEFAULT_CONCURRENT_STREAMS))
             .send_window(cfg.send_window)
             .stream_receive_window(VarInt::from_u32(cfg.receive_window))
-            .max_idle_timeout(None)
             .initial_mtu(cfg.initial_mtu)
             .min_mtu(cfg.min_mtu);
 
+        if cfg.max_idle_time.is_zero() {
+            tp_cfg.max_idle_timeout(None);
+        } else {
+            tp_cfg.max_idle_timeout(Some(
+                IdleTimeout::try_from(cfg.max_idle_time).map_err(|_| Error::InvalidMaxIdleTime)?,
+            ));
+        }
+        if cfg.cover_traffic.is_some_and(|cover| {
+            cover.min_interval.is_zero() || cover.max_interval < cover.min_interval
+        }) {
+            return Err(Error::InvalidCoverTrafficInterval);
+        }
+        if !cfg.keep_alive_interval.is_zero() {
+            tp_cfg.keep_alive_interval(Some(cfg.keep_alive_interval));
+        }
+        if let Some(window) = cfg.max_receive_window {
+            tp_cfg.receive_window(VarInt::try_from(window).context("invalid max receive window")?);
+        }
+
         if !cfg.gso {
             tp_cfg.enable_segmentation_offload(false);
         }
@@ -164,7 +145,7 @@ impl Connection {
 
         config.transport_config(Arc::new(tp_cfg));
 
-        let server = ServerAddr::new(cfg.server.0, cfg.server.1, cfg.ip);
+        let server = ServerAddr::new(cfg.server.0, cfg.server.1, cfg.ip, resolver);
         let server_ip: Option<IpAddr> = match server.resolve().await?.next() {
             Some(SocketAddr::V4(v4)) => Some(v4.ip().to_owned().into()),
             Some(SocketAddr::V6(v6)) => Some(v6.ip().to_owned().into()),
@@ -196,6 +177,7 @@ impl Connection {
             heartbeat: cfg.heartbeat,
             gc_interval: cfg.gc_interval,
             gc_lifetime: cfg.gc_lifetime,
+            cover_traffic: cfg.cover_traffic,
         };
 
         ENDPOINT
@@ -252,6 +234,7 @@ impl Connection {
         heartbeat: Duration,
         gc_interval: Duration,
         gc_lifetime: Duration,
+        cover_traffic: Option<CoverTraffic>,
     ) -> Self {
         let conn = Self {
             conn: conn.clone(),
@@ -265,10 +248,13 @@ impl Connection {
             max_concurrent_bi_streams: Arc::new(AtomicU32::new(DEFAULT_CONCURRENT_STREAMS)),
         };
 
-        tokio::spawn(
-            conn.clone()
-                .init(zero_rtt_accepted, heartbeat, gc_interval, gc_lifetime),
-        );
+        tokio::spawn(conn.clone().init(
+            zero_rtt_accepted,
+            heartbeat,
+            gc_interval,
+            gc_lifetime,
+            cover_traffic,
+        ));
 
         conn
     }
@@ -279,11 +265,15 @@ impl Connection {
         heartbeat: Duration,
         gc_interval: Duration,
         gc_lifetime: Duration,
+        cover_traffic: Option<CoverTraffic>,
     ) {
         log::info!("[relay] connection established");
 
         tokio::spawn(self.clone().authenticate(zero_rtt_accepted));
         tokio::spawn(self.clone().heartbeat(heartbeat));
+        if let Some(cover_traffic) = cover_traffic {
+            tokio::spawn(self.clone().cover_traffic(cover_traffic));
+        }
         tokio::spawn(self.clone().collect_garbage(gc_interval, gc_lifetime));
 
         let err = loop {
@@ -334,6 +324,7 @@ struct Endpoint {
     heartbeat: Duration,
     gc_interval: Duration,
     gc_lifetime: Duration,
+    cover_traffic: Option<CoverTraffic>,
 }
 
 impl Endpoint {
@@ -366,6 +357,7 @@ impl Endpoint {
                         self.heartbeat,
                         self.gc_interval,
                         self.gc_lifetime,
+                        self.cover_traffic,
                     ));
                 }
                 Err(err) => last_err = Some(err),
@@ -1,4 +1,4 @@
-use std::io::Error as IoError;
+use std::{io::Error as IoError, net::IpAddr};
 
 use quinn::{ConnectError, ConnectionError};
 use rustls::Error as RustlsError;
@@ -19,12 +19,18 @@ pub enum Error {
     Socket(&'static str, IoError),
     #[error("timeout establishing connection")]
     Timeout,
+    #[error("invalid max idle time")]
+    InvalidMaxIdleTime,
+    #[error("invalid cover traffic intervals")]
+    InvalidCoverTrafficInterval,
     #[error("cannot resolve the server name")]
     DnsResolve,
     #[error("received packet from an unexpected source")]
     WrongPacketSource,
     #[error("invalid socks5 authentication")]
     InvalidSocks5Auth,
+    #[error("fake IP {0} wasn't handed out by the fake DNS server, or was recycled since")]
+    UnknownFakeIp(IpAddr),
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
@@ -0,0 +1,449 @@
+//! Fake-IP DNS: `A` queries are answered with addresses of a reserved range,
+//! one per domain, which the socks5 server turns back into the domain before
+//! relaying. Behind a TUN to socks5 forwarder (e.g. tun2socks) capturing the
+//! traffic of every app, domains are then resolved by the TUIC server, and
+//! its domain-based rules apply to apps that only ever connect to IPs
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket as StdUdpSocket},
+    path::PathBuf,
+    sync::Mutex,
+    time::Duration,
+};
+
+use bytes::Bytes;
+use hickory_resolver::proto::{
+    op::{Message, MessageType, OpCode, ResponseCode},
+    rr::{
+        Name, RData, Record, RecordType,
+        rdata::{A, PTR},
+    },
+};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use socks5_proto::Address;
+use tokio::{net::UdpSocket, time};
+use tuic::Address as TuicAddress;
+
+use crate::{config::FakeDns as Config, error::Error, utils::Ipv4Range};
+
+static FAKE_DNS: OnceCell<FakeDns> = OnceCell::new();
+
+/// How often a changed mapping table is written to `persist`
+const PERSIST_INTERVAL: Duration = Duration::from_secs(10);
+
+pub struct FakeDns {
+    socket: Option<StdUdpSocket>,
+    range: Ipv4Range,
+    ttl: u32,
+    hijack: bool,
+    persist: Option<PathBuf>,
+    pool: Mutex<Pool>,
+}
+
+/// The mapping table, as persisted
+#[derive(Default, Serialize, Deserialize)]
+struct Pool {
+    /// The host index handed out next. Once the range is used up, addresses
+    /// are recycled in the order they were handed out
+    next: u32,
+    domains: HashMap<String, Ipv4Addr>,
+    #[serde(skip)]
+    ips: HashMap<Ipv4Addr, String>,
+    #[serde(skip)]
+    changed: bool,
+}
+
+impl Pool {
+    fn allocate(&mut self, range: Ipv4Range, domain: &str) -> Ipv4Addr {
+        if let Some(ip) = self.domains.get(domain) {
+            return *ip;
+        }
+        let ip = range.host(self.next);
+        self.next = (self.next + 1) % range.hosts();
+        if let Some(recycled) = self.ips.insert(ip, domain.to_owned()) {
+            self.domains.remove(&recycled);
+        }
+        self.domains.insert(domain.to_owned(), ip);
+        self.changed = true;
+        ip
+    }
+}
+
+impl FakeDns {
+    pub fn set_config(cfg: Option<Config>) -> Result<(), Error> {
+        let Some(cfg) = cfg else {
+            return Ok(());
+        };
+
+        let socket = cfg
+            .server
+            .map(|addr| {
+                let socket = StdUdpSocket::bind(addr)
+                    .map_err(|err| Error::Socket("failed to bind fake DNS server socket", err))?;
+                socket.set_nonblocking(true).map_err(|err| {
+                    Error::Socket("failed setting fake DNS server socket as non-blocking", err)
+                })?;
+                Ok::<_, Error>(socket)
+            })
+            .transpose()?;
+
+        let pool = cfg
+            .persist
+            .as_ref()
+            .map(|path| load(path, cfg.range))
+            .unwrap_or_default();
+
+        FAKE_DNS
+            .set(Self {
+                socket,
+                range: cfg.range,
+                ttl: cfg.ttl,
+                hijack: cfg.hijack,
+                persist: cfg.persist,
+                pool: Mutex::new(pool),
+            })
+            .map_err(|_| "failed initializing fake DNS server")
+            .unwrap();
+
+        Ok(())
+    }
+
+    pub async fn start() {
+        let Some(fake_dns) = FAKE_DNS.get() else {
+            return;
+        };
+
+        if let Some(path) = &fake_dns.persist {
+            tokio::spawn(persist(path));
+        }
+
+        let Some(socket) = &fake_dns.socket else {
+            return;
+        };
+        let socket = match socket.try_clone().and_then(UdpSocket::from_std) {
+            Ok(socket) => socket,
+            Err(err) => {
+                log::warn!("[fake-dns] failed to register the server socket: {err}");
+                return;
+            }
+        };
+
+        log::warn!(
+            "[fake-dns] server started, listening on {}",
+            socket.local_addr().unwrap()
+        );
+
+        let mut buf = vec![0; 4096];
+        loop {
+            let (len, addr) = match socket.recv_from(&mut buf).await {
+                Ok(res) => res,
+                Err(err) => {
+                    log::warn!("[fake-dns] failed to receive query: {err}");
+                    continue;
+                }
+            };
+            let Some(resp) = fake_dns.answer(&buf[..len]) else {
+                log::debug!("[fake-dns] [{addr}] invalid query");
+                continue;
+            };
+            if let Err(err) = socket.send_to(&resp, addr).await {
+                log::warn!("[fake-dns] [{addr}] failed to send answer: {err}");
+            }
+        }
+    }
+}
+
+/// Whether a UDP packet the socks5 client sent to `addr` is a DNS query to
+/// answer here rather than relay
+pub fn hijacks(addr: &Address) -> bool {
+    let port = match addr {
+        Address::SocketAddress(addr) => addr.port(),
+        Address::DomainAddress(_, port) => *port,
+    };
+    port == 53 && FAKE_DNS.get().is_some_and(|fake_dns| fake_dns.hijack)
+}
+
+/// Whether `ip` is in the fake IP range
+pub fn is_fake(ip: IpAddr) -> bool {
+    match (ip.to_canonical(), FAKE_DNS.get()) {
+        (IpAddr::V4(ip), Some(fake_dns)) => fake_dns.range.contains(ip),
+        _ => false,
+    }
+}
+
+/// The domain a fake IP was handed out for, addresses out of the fake IP
+/// range as they are
+pub fn restore(addr: TuicAddress) -> Result<TuicAddress, Error> {
+    let TuicAddress::SocketAddress(socket_addr) = addr else {
+        return Ok(addr);
+    };
+    let ip = socket_addr.ip().to_canonical();
+    let Some(fake_dns) = FAKE_DNS.get().filter(|_| is_fake(ip)) else {
+        return Ok(addr);
+    };
+    let IpAddr::V4(ip) = ip else {
+        return Ok(addr);
+    };
+    match fake_dns.pool.lock().unwrap().ips.get(&ip) {
+        Some(domain) => Ok(TuicAddress::DomainAddress(
+            domain.clone(),
+            socket_addr.port(),
+        )),
+        None => Err(Error::UnknownFakeIp(ip.into())),
+    }
+}
+
+/// The answer to the DNS query `pkt`, `None` if it can't be parsed or fake
+/// DNS is off
+pub fn answer(pkt: &[u8]) -> Option<Bytes> {
+    FAKE_DNS.get()?.answer(pkt)
+}
+
+impl FakeDns {
+    /// `A` queries get a fake IP, `PTR` queries of fake IPs their domain,
+    /// others an empty answer, so that apps fall back to IPv4
+    fn answer(&self, pkt: &[u8]) -> Option<Bytes> {
+        let req = Message::from_vec(pkt).ok()?;
+        if req.message_type() != MessageType::Query {
+            return None;
+        }
+
+        let mut resp = Message::error_msg(req.id(), req.op_code(), ResponseCode::NoError);
+        resp.set_recursion_desired(req.recursion_desired())
+            .set_recursion_available(true)
+            .add_queries(req.queries().to_vec());
+
+        match req.queries() {
+            [query] if req.op_code() == OpCode::Query => {
+                let name = query.name();
+                match query.query_type() {
+                    RecordType::A => {
+                        let domain = name.to_ascii().trim_end_matches('.').to_ascii_lowercase();
+                        let ip = self.pool.lock().unwrap().allocate(self.range, &domain);
+                        log::debug!("[fake-dns] {domain} -> {ip}");
+                        resp.add_answer(Record::from_rdata(
+                            name.clone(),
+                            self.ttl,
+                            RData::A(A(ip)),
+                        ));
+                    }
+                    RecordType::PTR => {
+                        let domain = match name.parse_arpa_name().map(|net| net.addr()) {
+                            Ok(IpAddr::V4(ip)) => self.pool.lock().unwrap().ips.get(&ip).cloned(),
+                            _ => None,
+                        };
+                        match domain.and_then(|domain| Name::from_str_relaxed(domain).ok()) {
+                            Some(domain) => {
+                                resp.add_answer(Record::from_rdata(
+                                    name.clone(),
+                                    self.ttl,
+                                    RData::PTR(PTR(domain)),
+                                ));
+                            }
+                            None => {
+                                resp.set_response_code(ResponseCode::NXDomain);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            [_] => {
+                resp.set_response_code(ResponseCode::NotImp);
+            }
+            _ => {
+                resp.set_response_code(ResponseCode::FormErr);
+            }
+        }
+
+        resp.to_vec().ok().map(Bytes::from)
+    }
+}
+
+/// The persisted mapping table, without the addresses out of `range`, which
+/// may have changed since
+fn load(path: &PathBuf, range: Ipv4Range) -> Pool {
+    let mut pool = match fs::read(path) {
+        Ok(data) => match serde_json::from_slice::<Pool>(&data) {
+            Ok(pool) => pool,
+            Err(err) => {
+                log::warn!(
+                    "[fake-dns] ignoring invalid mapping table {}: {err}",
+                    path.display()
+                );
+                return Pool::default();
+            }
+        },
+        Err(_) => return Pool::default(),
+    };
+    pool.domains.retain(|_, ip| range.contains(*ip));
+    pool.ips = pool
+        .domains
+        .iter()
+        .map(|(domain, ip)| (*ip, domain.clone()))
+        .collect();
+    pool.next %= range.hosts();
+    pool
+}
+
+/// Write the mapping table to `path` whenever it changed, through a
+/// temporary file so it's never left half written. The file is written on a
+/// blocking thread, off the runtime
+async fn persist(path: &'static PathBuf) {
+    let fake_dns = FAKE_DNS.get().unwrap();
+    let mut interval = time::interval(PERSIST_INTERVAL);
+    loop {
+        interval.tick().await;
+        let data = {
+            let mut pool = fake_dns.pool.lock().unwrap();
+            if !pool.changed {
+                continue;
+            }
+            pool.changed = false;
+            serde_json::to_vec(&*pool)
+        };
+        let tmp = path.with_extension("tmp");
+        let res = match data {
+            Ok(data) => tokio::task::spawn_blocking(move || {
+                fs::write(&tmp, data).and_then(|()| fs::rename(&tmp, path))
+            })
+            .await
+            .unwrap_or_else(|err| Err(io::Error::other(err))),
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = res {
+            log::warn!(
+                "[fake-dns] failed to write mapping table {}: {err}",
+                path.display()
+            );
+        }
+    }
+}
+
+/// The last socket address a socks5 UDP session sent to, as long as it's a
+/// fake IP and the only destination
+#[derive(Default)]
+pub enum FakePeer {
+    #[default]
+    None,
+    Fake(SocketAddr),
+    Mixed,
+}
+
+impl FakePeer {
+    pub fn note(&mut self, addr: &Address) {
+        *self = match (&*self, addr) {
+            (Self::None, Address::SocketAddress(addr)) if is_fake(addr.ip()) => Self::Fake(*addr),
+            (Self::Fake(fake), Address::SocketAddress(addr)) if fake == addr => Self::Fake(*addr),
+            _ => Self::Mixed,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_resolver::proto::op::Query;
+
+    use super::*;
+
+    fn range(range: &str) -> Ipv4Range {
+        range.parse().unwrap()
+    }
+
+    fn ip(ip: &str) -> Ipv4Addr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn allocate_recycles_the_oldest() {
+        // 2 hosts
+        let range = range("198.18.0.0/30");
+        let mut pool = Pool::default();
+        assert_eq!(pool.allocate(range, "a.example"), ip("198.18.0.1"));
+        assert_eq!(pool.allocate(range, "b.example"), ip("198.18.0.2"));
+        assert_eq!(pool.allocate(range, "a.example"), ip("198.18.0.1"));
+        assert_eq!(pool.allocate(range, "c.example"), ip("198.18.0.1"));
+        assert!(!pool.domains.contains_key("a.example"));
+        assert_eq!(pool.ips[&ip("198.18.0.1")], "c.example");
+        assert_eq!(pool.allocate(range, "a.example"), ip("198.18.0.2"));
+        assert!(!pool.domains.contains_key("b.example"));
+        assert!(pool.changed);
+    }
+
+    #[test]
+    fn load_keeps_the_range() {
+        let path = std::env::temp_dir().join(format!("fake-dns-{}.json", std::process::id()));
+        fs::write(
+            &path,
+            r#"{"next": 5, "domains": {"in.example": "198.18.0.2", "out.example": "10.0.0.1"}}"#,
+        )
+        .unwrap();
+        let pool = load(&path, range("198.18.0.0/30"));
+        assert_eq!(
+            pool.domains,
+            HashMap::from([("in.example".to_owned(), ip("198.18.0.2"))])
+        );
+        assert_eq!(
+            pool.ips,
+            HashMap::from([(ip("198.18.0.2"), "in.example".to_owned())])
+        );
+        assert_eq!(pool.next, 1);
+
+        fs::write(&path, "not json").unwrap();
+        assert!(load(&path, range("198.18.0.0/30")).domains.is_empty());
+        fs::remove_file(&path).unwrap();
+        assert!(load(&path, range("198.18.0.0/30")).domains.is_empty());
+    }
+
+    fn query(name: &str, ty: RecordType) -> Message {
+        let fake_dns = FakeDns {
+            socket: None,
+            range: range("198.18.0.0/15"),
+            ttl: 60,
+            hijack: false,
+            persist: None,
+            pool: Mutex::default(),
+        };
+        fake_dns
+            .pool
+            .lock()
+            .unwrap()
+            .allocate(fake_dns.range, "example.com");
+
+        let mut req = Message::new();
+        req.set_id(7)
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .add_query(Query::query(Name::from_ascii(name).unwrap(), ty));
+        let resp = fake_dns.answer(&req.to_vec().unwrap()).unwrap();
+        let resp = Message::from_vec(&resp).unwrap();
+        assert_eq!(resp.id(), 7);
+        assert_eq!(resp.message_type(), MessageType::Response);
+        resp
+    }
+
+    #[test]
+    fn answers() {
+        let resp = query("Example.COM.", RecordType::A);
+        assert_eq!(resp.response_code(), ResponseCode::NoError);
+        assert_eq!(resp.answers()[0].data(), &RData::A(A(ip("198.18.0.1"))));
+        let resp = query("new.example.", RecordType::A);
+        assert_eq!(resp.answers()[0].data(), &RData::A(A(ip("198.18.0.2"))));
+
+        let resp = query("1.0.18.198.in-addr.arpa.", RecordType::PTR);
+        assert_eq!(
+            resp.answers()[0].data(),
+            &RData::PTR(PTR(Name::from_ascii("example.com.").unwrap()))
+        );
+        let resp = query("9.0.18.198.in-addr.arpa.", RecordType::PTR);
+        assert_eq!(resp.response_code(), ResponseCode::NXDomain);
+        assert!(resp.answers().is_empty());
+
+        let resp = query("example.com.", RecordType::AAAA);
+        assert_eq!(resp.response_code(), ResponseCode::NoError);
+        assert!(resp.answers().is_empty());
+    }
+}
@@ -5,12 +5,14 @@ use env_logger::Builder as LoggerBuilder;
 use crate::{
     config::{Config, ConfigError},
     connection::Connection,
+    fake_dns::FakeDns,
     socks5::Server as Socks5Server,
 };
 
 mod config;
 mod connection;
 mod error;
+mod fake_dns;
 mod socks5;
 mod utils;
 
@@ -50,5 +52,14 @@ async fn main() {
         }
     }
 
+    match FakeDns::set_config(cfg.fake_dns) {
+        Ok(()) => {}
+        Err(err) => {
+            eprintln!("{err}");
+            process::exit(1);
+        }
+    }
+
+    tokio::spawn(FakeDns::start());
     Socks5Server::start().await;
 }
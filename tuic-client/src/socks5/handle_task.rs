@@ -8,19 +8,30 @@ use tokio_util::compat::FuturesAsyncReadCompatExt;
 use tuic::Address as TuicAddress;
 
 use super::{Server, UDP_SESSIONS, udp_session::UdpSession};
-use crate::connection::{Connection as TuicConnection, ERROR_CODE};
+use crate::{
+    connection::{Connection as TuicConnection, ERROR_CODE},
+    fake_dns,
+};
 
 impl Server {
     pub async fn handle_associate(
         assoc: Associate<associate::NeedReply>,
         assoc_id: u16,
+        client_addr: Address,
         dual_stack: Option<bool>,
         max_pkt_size: usize,
     ) {
         let peer_addr = assoc.peer_addr().unwrap();
         let local_ip = assoc.local_addr().unwrap().ip();
 
-        match UdpSession::new(assoc_id, peer_addr, local_ip, dual_stack, max_pkt_size) {
+        match UdpSession::new(
+            assoc_id,
+            peer_addr,
+            client_addr,
+            local_ip,
+            dual_stack,
+            max_pkt_size,
+        ) {
             Ok(session) => {
                 let local_addr = session.local_addr().unwrap();
                 log::debug!(
@@ -61,13 +72,24 @@ impl Server {
                             }
                         };
 
+                        if fake_dns::hijacks(&target_addr) {
+                            let session = session.clone();
+                            tokio::spawn(async move {
+                                if let Some(resp) = fake_dns::answer(&pkt) {
+                                    _ = session.send(resp, target_addr).await;
+                                }
+                            });
+                            continue;
+                        }
+                        session.note_destination(&target_addr);
+
                         let forward = async move {
-                            let target_addr = match target_addr {
+                            let target_addr = fake_dns::restore(match target_addr {
                                 Address::DomainAddress(domain, port) => {
                                     TuicAddress::DomainAddress(domain, port)
                                 }
                                 Address::SocketAddress(addr) => TuicAddress::SocketAddress(addr),
-                            };
+                            })?;
 
                             match TuicConnection::get_conn().await {
                                 Ok(conn) => conn.packet(pkt, target_addr, assoc_id).await,
@@ -173,8 +195,11 @@ impl Server {
             Address::SocketAddress(addr) => TuicAddress::SocketAddress(addr),
         };
 
-        let relay = match TuicConnection::get_conn().await {
-            Ok(conn) => conn.connect(target_addr.clone()).await,
+        let relay = match fake_dns::restore(target_addr.clone()) {
+            Ok(target_addr) => match TuicConnection::get_conn().await {
+                Ok(conn) => conn.connect(target_addr).await,
+                Err(err) => Err(err),
+            },
             Err(err) => Err(err),
         };
 
@@ -123,12 +123,13 @@ impl Server {
 
                     tokio::spawn(async move {
                         match conn.handshake().await {
-                            Ok(Connection::Associate(associate, _)) => {
+                            Ok(Connection::Associate(associate, client_addr)) => {
                                 let assoc_id = server.next_assoc_id.fetch_add(1, Ordering::Relaxed);
                                 log::info!("[socks5] [{addr}] [associate] [{assoc_id:#06x}]");
                                 Self::handle_associate(
                                     associate,
                                     assoc_id,
+                                    client_addr,
                                     server.dual_stack,
                                     server.max_pkt_size,
                                 )
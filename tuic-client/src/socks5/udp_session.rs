@@ -2,7 +2,7 @@ use std::{
     collections::HashMap,
     io::{Error as IoError, ErrorKind},
     net::{IpAddr, SocketAddr, UdpSocket as StdUdpSocket},
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
 
 use bytes::Bytes;
@@ -12,7 +12,7 @@ use socks5_proto::Address;
 use socks5_server::AssociatedUdpSocket;
 use tokio::{net::UdpSocket, sync::RwLock as AsyncRwLock};
 
-use crate::error::Error;
+use crate::{error::Error, fake_dns::FakePeer, utils};
 
 pub static UDP_SESSIONS: OnceCell<AsyncRwLock<HashMap<u16, UdpSession>>> = OnceCell::new();
 
@@ -21,12 +21,17 @@ pub struct UdpSession {
     socket: Arc<AssociatedUdpSocket>,
     assoc_id: u16,
     ctrl_addr: SocketAddr,
+    fake_peer: Arc<Mutex<FakePeer>>,
 }
 
 impl UdpSession {
+    /// `client_addr` is the address the socks5 client said it sends from in
+    /// the associate request. When it is fully specified, replies can be
+    /// delivered before the client's first packet arrives
     pub fn new(
         assoc_id: u16,
         ctrl_addr: SocketAddr,
+        client_addr: Address,
         local_ip: IpAddr,
         dual_stack: Option<bool>,
         max_pkt_size: usize,
@@ -62,6 +67,24 @@ impl UdpSession {
                 Error::Socket("failed to bind socks5 server UDP associate socket", err)
             })?;
 
+        utils::ignore_udp_resets(&socket).map_err(|err| {
+            Error::Socket(
+                "failed disabling socks5 server UDP associate socket reset errors",
+                err,
+            )
+        })?;
+
+        if let Some(client_addr) = expected_client_addr(&client_addr, local_ip) {
+            // e.g. an IPv4 client on an IPv6-only socket, fall back to the first
+            // packet received
+            if let Err(err) = socket.connect(&SockAddr::from(client_addr)) {
+                log::debug!(
+                    "[socks5] [{ctrl_addr}] [associate] [{assoc_id:#06x}] failed to connect to \
+                     {client_addr}: {err}"
+                );
+            }
+        }
+
         let socket = UdpSocket::from_std(StdUdpSocket::from(socket)).map_err(|err| {
             Error::Socket("failed to create socks5 server UDP associate socket", err)
         })?;
@@ -70,18 +93,30 @@ impl UdpSession {
             socket: Arc::new(AssociatedUdpSocket::from((socket, max_pkt_size))),
             assoc_id,
             ctrl_addr,
+            fake_peer: Arc::new(Mutex::new(FakePeer::None)),
         })
     }
 
+    /// Deliver a packet from any remote peer to the socks5 client, full-cone
+    /// style: the peer doesn't need to have been sent to before
     pub async fn send(&self, pkt: Bytes, src_addr: Address) -> Result<(), Error> {
         let src_addr_display = src_addr.to_string();
 
+        let Ok(dst_addr) = self.socket.peer_addr() else {
+            log::debug!(
+                "[socks5] [{ctrl_addr}] [associate] [{assoc_id:#06x}] drop packet from \
+                 {src_addr_display}: the socks5 client address is not known yet",
+                ctrl_addr = self.ctrl_addr,
+                assoc_id = self.assoc_id,
+            );
+            return Ok(());
+        };
+
         log::debug!(
             "[socks5] [{ctrl_addr}] [associate] [{assoc_id:#06x}] send packet from \
              {src_addr_display} to {dst_addr}",
             ctrl_addr = self.ctrl_addr,
             assoc_id = self.assoc_id,
-            dst_addr = self.socket.peer_addr().unwrap(),
         );
 
         if let Err(err) = self.socket.send(pkt, 0, src_addr).await {
@@ -90,7 +125,6 @@ impl UdpSession {
                  {src_addr_display} to {dst_addr} error: {err}",
                 ctrl_addr = self.ctrl_addr,
                 assoc_id = self.assoc_id,
-                dst_addr = self.socket.peer_addr().unwrap(),
             );
 
             return Err(Error::Io(err));
@@ -150,7 +184,43 @@ impl UdpSession {
         Ok((pkt, dst_addr))
     }
 
+    /// Note a destination the socks5 client sent to, before fake IPs are
+    /// turned back into domains
+    pub fn note_destination(&self, addr: &Address) {
+        self.fake_peer.lock().unwrap().note(addr);
+    }
+
+    /// The source to deliver a relayed packet from: the fake IP the socks5
+    /// client sent to, if it never sent anywhere else, as the packet comes
+    /// from the address the domain resolved to on the TUIC server. TUN to
+    /// socks5 forwarders associate once per flow, so replies reach the flow
+    pub fn masquerade(&self, src_addr: Address) -> Address {
+        match &*self.fake_peer.lock().unwrap() {
+            FakePeer::Fake(addr) => Address::SocketAddress(*addr),
+            _ => src_addr,
+        }
+    }
+
     pub fn local_addr(&self) -> Result<SocketAddr, IoError> {
         self.socket.local_addr()
     }
 }
+
+/// The address in the associate request, if it names a single endpoint the
+/// local socket can reach. Clients usually leave it unspecified, then the
+/// first packet received decides
+fn expected_client_addr(addr: &Address, local_ip: IpAddr) -> Option<SocketAddr> {
+    let Address::SocketAddress(addr) = addr else {
+        return None;
+    };
+    if addr.ip().is_unspecified() || addr.port() == 0 {
+        return None;
+    }
+    match (addr, local_ip) {
+        (SocketAddr::V4(v4), IpAddr::V6(_)) => {
+            Some(SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port()))
+        }
+        (SocketAddr::V6(_), IpAddr::V4(_)) => None,
+        _ => Some(*addr),
+    }
+}
@@ -1,15 +1,26 @@
 use std::{
     fs,
-    net::{IpAddr, SocketAddr},
+    io::Result as IoResult,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
     path::PathBuf,
     str::FromStr,
 };
 
 use anyhow::Context;
-use rustls::{RootCertStore, pki_types::CertificateDer};
+use hickory_resolver::{
+    TokioResolver,
+    config::{NameServerConfig, ResolverConfig, ResolverOpts},
+    name_server::TokioConnectionProvider,
+    proto::xfer::Protocol,
+};
+use rustls::{
+    CipherSuite, ClientConfig as RustlsClientConfig, NamedGroup, RootCertStore,
+    crypto::CryptoProvider, pki_types::CertificateDer,
+};
+use socket2::Socket;
 use tokio::net;
 
-use crate::error::Error;
+use crate::{config::Dns, error::Error};
 
 pub fn load_certs(paths: Vec<PathBuf>, disable_native: bool) -> Result<RootCertStore, Error> {
     let mut certs = RootCertStore::empty();
@@ -39,11 +50,22 @@ pub struct ServerAddr {
     domain: String,
     port: u16,
     ip: Option<IpAddr>,
+    resolver: Option<TokioResolver>,
 }
 
 impl ServerAddr {
-    pub fn new(domain: String, port: u16, ip: Option<IpAddr>) -> Self {
-        Self { domain, port, ip }
+    pub fn new(
+        domain: String,
+        port: u16,
+        ip: Option<IpAddr>,
+        resolver: Option<TokioResolver>,
+    ) -> Self {
+        Self {
+            domain,
+            port,
+            ip,
+            resolver,
+        }
     }
 
     pub fn server_name(&self) -> &str {
@@ -53,6 +75,19 @@ impl ServerAddr {
     pub async fn resolve(&self) -> Result<impl Iterator<Item = SocketAddr>, Error> {
         if let Some(ip) = self.ip {
             Ok(vec![SocketAddr::from((ip, self.port))].into_iter())
+        } else if let Some(resolver) = &self.resolver {
+            let lookup = resolver
+                .lookup_ip(self.domain.as_str())
+                .await
+                .map_err(|err| {
+                    log::warn!("[relay] failed to resolve {}: {err}", self.domain);
+                    Error::DnsResolve
+                })?;
+            Ok(lookup
+                .iter()
+                .map(|ip| SocketAddr::from((ip, self.port)))
+                .collect::<Vec<_>>()
+                .into_iter())
         } else {
             Ok(net::lookup_host((self.domain.as_str(), self.port))
                 .await?
@@ -62,6 +97,55 @@ impl ServerAddr {
     }
 }
 
+/// Resolve the server name over DoH/DoT instead of the system resolver
+pub fn dns_resolver(cfg: Dns, certs: RootCertStore) -> TokioResolver {
+    let protocol = match cfg.protocol {
+        DnsProtocol::Https => Protocol::Https,
+        DnsProtocol::Tls => Protocol::Tls,
+    };
+    let servers = cfg
+        .servers
+        .into_iter()
+        .map(|addr| {
+            let mut server = NameServerConfig::new(addr, protocol);
+            server.tls_dns_name = Some(cfg.name.clone());
+            server
+        })
+        .collect::<Vec<_>>();
+
+    let mut opts = ResolverOpts::default();
+    opts.tls_config = RustlsClientConfig::builder()
+        .with_root_certificates(certs)
+        .with_no_client_auth();
+
+    TokioResolver::builder_with_config(
+        ResolverConfig::from_parts(None, Vec::new(), servers),
+        TokioConnectionProvider::default(),
+    )
+    .with_options(opts)
+    .build()
+}
+
+#[derive(Clone, Copy)]
+pub enum DnsProtocol {
+    Https,
+    Tls,
+}
+
+impl FromStr for DnsProtocol {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("https") || s.eq_ignore_ascii_case("doh") {
+            Ok(Self::Https)
+        } else if s.eq_ignore_ascii_case("tls") || s.eq_ignore_ascii_case("dot") {
+            Ok(Self::Tls)
+        } else {
+            Err("invalid DNS protocol")
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 pub enum UdpRelayMode {
     Native,
@@ -103,3 +187,158 @@ impl FromStr for CongestionControl {
         }
     }
 }
+
+/// An IPv4 network, written as `"198.18.0.0/15"`
+#[derive(Clone, Copy)]
+pub struct Ipv4Range {
+    network: u32,
+    prefix: u8,
+}
+
+impl Ipv4Range {
+    /// Host addresses, the network and broadcast ones left out
+    pub fn hosts(self) -> u32 {
+        (1 << (32 - self.prefix)) - 2
+    }
+
+    /// The host address at `idx`, below `hosts()`
+    pub fn host(self, idx: u32) -> Ipv4Addr {
+        Ipv4Addr::from(self.network + 1 + idx)
+    }
+
+    pub fn contains(self, ip: Ipv4Addr) -> bool {
+        u32::from(ip) >> (32 - self.prefix) == self.network >> (32 - self.prefix)
+    }
+}
+
+impl FromStr for Ipv4Range {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ip, prefix) = s.split_once('/').ok_or("invalid IPv4 range")?;
+        let ip = ip.parse::<Ipv4Addr>().map_err(|_| "invalid IPv4 range")?;
+        let prefix = prefix.parse::<u8>().map_err(|_| "invalid IPv4 range")?;
+        if !(1..=30).contains(&prefix) {
+            return Err("IPv4 range prefix must be between 1 and 30");
+        }
+        let mask = u32::MAX << (32 - prefix);
+        Ok(Self {
+            network: u32::from(ip) & mask,
+            prefix,
+        })
+    }
+}
+
+/// A TLS ClientHello mimicking the HTTP/3 one of a browser, as far as rustls
+/// allows: the ALPN and the preference order of cipher suites and key
+/// exchange groups. Extension order and GREASE can't be controlled
+#[derive(Clone, Copy)]
+pub enum Camouflage {
+    None,
+    Chrome,
+    Firefox,
+    Safari,
+}
+
+impl Camouflage {
+    /// Offered when `alpn` isn't set
+    pub fn alpn(self) -> Vec<Vec<u8>> {
+        match self {
+            Self::None => Vec::new(),
+            Self::Chrome | Self::Firefox | Self::Safari => vec![b"h3".to_vec()],
+        }
+    }
+
+    /// Reorders the suites and groups of `provider` by the profile's
+    /// preference, those it doesn't know of last
+    pub fn apply(self, provider: &mut CryptoProvider) {
+        use CipherSuite::{
+            TLS13_AES_128_GCM_SHA256 as AES_128, TLS13_AES_256_GCM_SHA384 as AES_256,
+            TLS13_CHACHA20_POLY1305_SHA256 as CHACHA20,
+        };
+        use NamedGroup::{X25519, X25519MLKEM768, secp256r1, secp384r1};
+
+        let (suites, groups): (&[CipherSuite], &[NamedGroup]) = match self {
+            Self::None => return,
+            Self::Chrome => (
+                &[AES_128, AES_256, CHACHA20],
+                &[X25519MLKEM768, X25519, secp256r1, secp384r1],
+            ),
+            Self::Firefox => (
+                &[AES_128, CHACHA20, AES_256],
+                &[X25519MLKEM768, X25519, secp256r1, secp384r1],
+            ),
+            Self::Safari => (
+                &[AES_128, AES_256, CHACHA20],
+                &[X25519, secp256r1, secp384r1],
+            ),
+        };
+        fn rank<T: PartialEq>(list: &[T], item: T) -> usize {
+            list.iter().position(|x| *x == item).unwrap_or(list.len())
+        }
+        provider
+            .cipher_suites
+            .sort_by_key(|suite| rank(suites, suite.suite()));
+        provider
+            .kx_groups
+            .sort_by_key(|group| rank(groups, group.name()));
+    }
+}
+
+impl FromStr for Camouflage {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("none") {
+            Ok(Self::None)
+        } else if s.eq_ignore_ascii_case("chrome") {
+            Ok(Self::Chrome)
+        } else if s.eq_ignore_ascii_case("firefox") {
+            Ok(Self::Firefox)
+        } else if s.eq_ignore_ascii_case("safari") {
+            Ok(Self::Safari)
+        } else {
+            Err("invalid camouflage profile")
+        }
+    }
+}
+
+/// Keep the ICMP errors caused by earlier sends on a UDP socket from failing
+/// its next read, as Windows does even for unconnected sockets, with
+/// `WSAECONNRESET` for port unreachable and `WSAENETRESET` for TTL expired.
+/// Elsewhere only connected sockets get them
+#[cfg(windows)]
+pub fn ignore_udp_resets(socket: &Socket) -> IoResult<()> {
+    use std::{ffi::c_void, io::Error as IoError, mem, os::windows::io::AsRawSocket, ptr};
+
+    use windows_sys::Win32::Networking::WinSock::{
+        SIO_UDP_CONNRESET, SIO_UDP_NETRESET, SOCKET, SOCKET_ERROR, WSAIoctl,
+    };
+
+    for code in [SIO_UDP_CONNRESET, SIO_UDP_NETRESET] {
+        let enable: i32 = 0;
+        let mut returned = 0;
+        let res = unsafe {
+            WSAIoctl(
+                socket.as_raw_socket() as SOCKET,
+                code,
+                ptr::from_ref(&enable).cast::<c_void>(),
+                mem::size_of_val(&enable) as u32,
+                ptr::null_mut(),
+                0,
+                &mut returned,
+                ptr::null_mut(),
+                None,
+            )
+        };
+        if res == SOCKET_ERROR {
+            return Err(IoError::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+pub fn ignore_udp_resets(_socket: &Socket) -> IoResult<()> {
+    Ok(())
+}
@@ -58,46 +58,52 @@ pub struct Connection<Side> {
 }
 
 impl<Side> Connection<Side> {
-    /// Sends a `Packet` using UDP relay mode `native`.
+    /// Sends a `Packet` using UDP relay mode `native`, returning the bytes
+    /// sent including the headers.
     pub fn packet_native(
         &self,
         pkt: impl AsRef<[u8]>,
         addr: Address,
         assoc_id: u16,
-    ) -> Result<(), Error> {
+    ) -> Result<usize, Error> {
         let Some(max_pkt_size) = self.conn.max_datagram_size() else {
             return Err(Error::SendDatagram(SendDatagramError::Disabled));
         };
 
         let model = self.model.send_packet(assoc_id, addr, max_pkt_size);
+        let mut sent = 0;
 
         for (header, frag) in model.into_fragments(pkt) {
             let mut buf = BytesMut::with_capacity(header.len() + frag.len());
             header.write(&mut buf);
             buf.put_slice(frag);
+            sent += buf.len();
             self.conn.send_datagram(Bytes::from(buf))?;
         }
 
-        Ok(())
+        Ok(sent)
     }
 
-    /// Sends a `Packet` using UDP relay mode `quic`.
+    /// Sends a `Packet` using UDP relay mode `quic`, returning the bytes sent
+    /// including the headers.
     pub async fn packet_quic(
         &self,
         pkt: impl AsRef<[u8]>,
         addr: Address,
         assoc_id: u16,
-    ) -> Result<(), Error> {
+    ) -> Result<usize, Error> {
         let model = self.model.send_packet(assoc_id, addr, u16::MAX as usize);
+        let mut sent = 0;
 
         for (header, frag) in model.into_fragments(pkt) {
             let mut send = self.conn.open_uni().await?;
             header.async_marshal(&mut send).await?;
             AsyncWriteExt::write_all(&mut send, frag).await?;
             send.close().await?;
+            sent += header.len() + frag.len();
         }
 
-        Ok(())
+        Ok(sent)
     }
 
     /// Returns the number of `Connect` tasks
@@ -116,6 +122,12 @@ impl<Side> Connection<Side> {
         self.model.collect_garbage(timeout);
     }
 
+    /// Returns the number of packets waiting for fragments and the bytes of
+    /// the fragments they hold
+    pub fn fragment_cache_size(&self) -> (usize, usize) {
+        self.model.fragment_cache_size()
+    }
+
     fn keying_material_exporter(&self) -> KeyingMaterialExporter {
         KeyingMaterialExporter(self.conn.clone())
     }
@@ -169,6 +181,18 @@ impl Connection<side::Client> {
         Ok(())
     }
 
+    /// Sends a `Heartbeat` command followed by `padding` in the same datagram,
+    /// as cover traffic. The padding is ignored by servers, which only parse
+    /// the header.
+    pub async fn heartbeat_with_padding(&self, padding: &[u8]) -> Result<(), Error> {
+        let model = self.model.send_heartbeat();
+        let mut buf = Vec::with_capacity(model.header().len() + padding.len());
+        model.header().async_marshal(&mut buf).await.unwrap();
+        buf.extend_from_slice(padding);
+        self.conn.send_datagram(Bytes::from(buf))?;
+        Ok(())
+    }
+
     /// Try to parse a `quinn::RecvStream` as a TUIC command.
     ///
     /// The `quinn::RecvStream` should be accepted by
@@ -193,6 +217,13 @@ impl Connection<side::Client> {
             }
             Header::Dissociate(_) => Err(Error::BadCommandUniStream("dissociate", recv)),
             Header::Heartbeat(_) => Err(Error::BadCommandUniStream("heartbeat", recv)),
+            Header::ExternalAddress(ext) => {
+                let model = self.model.recv_external_address(ext);
+                Ok(Task::ExternalAddress(
+                    model.assoc_id(),
+                    model.addr().to_owned(),
+                ))
+            }
             _ => unreachable!(),
         }
     }
@@ -218,6 +249,9 @@ impl Connection<side::Client> {
             Header::Packet(_) => Err(Error::BadCommandBiStream("packet", send, recv)),
             Header::Dissociate(_) => Err(Error::BadCommandBiStream("dissociate", send, recv)),
             Header::Heartbeat(_) => Err(Error::BadCommandBiStream("heartbeat", send, recv)),
+            Header::ExternalAddress(_) => {
+                Err(Error::BadCommandBiStream("external_address", send, recv))
+            }
             _ => unreachable!(),
         }
     }
@@ -257,6 +291,10 @@ impl Connection<side::Client> {
             }
             Header::Dissociate(_) => Err(Error::BadCommandDatagram("dissociate", dg.into_inner())),
             Header::Heartbeat(_) => Err(Error::BadCommandDatagram("heartbeat", dg.into_inner())),
+            Header::ExternalAddress(_) => Err(Error::BadCommandDatagram(
+                "external_address",
+                dg.into_inner(),
+            )),
             _ => unreachable!(),
         }
     }
@@ -272,6 +310,16 @@ impl Connection<side::Server> {
         }
     }
 
+    /// Sends an `ExternalAddress` command, reporting the address a UDP
+    /// session is relayed from.
+    pub async fn external_address(&self, assoc_id: u16, addr: Address) -> Result<(), Error> {
+        let model = self.model.send_external_address(assoc_id, addr);
+        let mut send = self.conn.open_uni().await?;
+        model.header().async_marshal(&mut send).await?;
+        send.close().await?;
+        Ok(())
+    }
+
     /// Try to parse a `quinn::RecvStream` as a TUIC command.
     ///
     /// The `quinn::RecvStream` should be accepted by
@@ -300,6 +348,7 @@ impl Connection<side::Server> {
                 Ok(Task::Dissociate(model.assoc_id()))
             }
             Header::Heartbeat(_) => Err(Error::BadCommandUniStream("heartbeat", recv)),
+            Header::ExternalAddress(_) => Err(Error::BadCommandUniStream("external_address", recv)),
             _ => unreachable!(),
         }
     }
@@ -328,6 +377,9 @@ impl Connection<side::Server> {
             Header::Packet(_) => Err(Error::BadCommandBiStream("packet", send, recv)),
             Header::Dissociate(_) => Err(Error::BadCommandBiStream("dissociate", send, recv)),
             Header::Heartbeat(_) => Err(Error::BadCommandBiStream("heartbeat", send, recv)),
+            Header::ExternalAddress(_) => {
+                Err(Error::BadCommandBiStream("external_address", send, recv))
+            }
             _ => unreachable!(),
         }
     }
@@ -360,6 +412,10 @@ impl Connection<side::Server> {
                 let _ = self.model.recv_heartbeat(hb);
                 Ok(Task::Heartbeat)
             }
+            Header::ExternalAddress(_) => Err(Error::BadCommandDatagram(
+                "external_address",
+                dg.into_inner(),
+            )),
             _ => unreachable!(),
         }
     }
@@ -525,6 +581,17 @@ impl Packet {
         self.model.frag_total()
     }
 
+    /// Returns the size of the fragment payload
+    pub fn size(&self) -> u16 {
+        self.model.size()
+    }
+
+    /// Returns the address of the fragment, `Address::None` unless it is the
+    /// first one
+    pub fn addr(&self) -> &Address {
+        self.model.addr()
+    }
+
     /// Whether the packet is from UDP relay mode `quic`
     pub fn is_from_quic(&self) -> bool {
         matches!(self.src, PacketSource::Quic(_))
@@ -566,6 +633,7 @@ pub enum Task {
     Packet(Packet),
     Dissociate(u16),
     Heartbeat,
+    ExternalAddress(u16, Address),
 }
 
 #[derive(Debug)]
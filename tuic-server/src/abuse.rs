@@ -0,0 +1,267 @@
+//! Disabling users and banning source IPs that keep triggering ACL denials,
+//! blocklist hits or authentication failures, following the `abuse` rules.
+//! The bans of `fail2ban` and the RESTful `/bans` endpoint are kept here too
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::{Display, Formatter, Result as FmtResult},
+    net::IpAddr,
+    sync::{
+        Arc, LazyLock, Mutex, OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+use tokio::{
+    sync::watch,
+    time::{self, Instant, MissedTickBehavior},
+};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    AppContext,
+    config::{AbuseRule, Config},
+    error::Error,
+    hooks, security_log,
+    state::{self, PersistedBan},
+    users,
+    utils::{AbuseAction, AbuseEvent},
+};
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+type Strikes = HashMap<(usize, Offender), VecDeque<Instant>>;
+
+static RULES: OnceLock<Vec<AbuseRule>> = OnceLock::new();
+/// Whether anything may ban, so connections need to watch for it
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// When the recent events of each offender happened, per rule
+static STRIKES: LazyLock<Mutex<Strikes>> = LazyLock::new(Mutex::default);
+/// The bans in effect and when they expire
+static BANS: LazyLock<watch::Sender<HashMap<Offender, Instant>>> =
+    LazyLock::new(|| watch::Sender::new(HashMap::new()));
+
+/// Who an action is taken against
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Offender {
+    User(Uuid),
+    Ip(IpAddr),
+}
+
+impl Display for Offender {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::User(uuid) => write!(f, "{uuid}"),
+            Self::Ip(ip) => write!(f, "{ip}"),
+        }
+    }
+}
+
+pub fn init(cfg: &Config) -> eyre::Result<()> {
+    for rule in &cfg.abuse.rules {
+        if rule.count == 0 || rule.window.is_zero() || rule.duration.is_zero() {
+            eyre::bail!(
+                "abuse: `count`, `window` and `duration` of the {event} rule must be non-zero",
+                event = rule.event,
+            );
+        }
+    }
+    _ = RULES.set(cfg.abuse.rules.clone());
+    let enabled = !cfg.abuse.rules.is_empty() || cfg.fail2ban.enabled || cfg.restful.is_some();
+    ENABLED.store(enabled, Ordering::Relaxed);
+    if enabled {
+        restore(state::bans());
+    }
+    Ok(())
+}
+
+/// Put back the bans saved by the previous run that haven't expired yet
+fn restore(saved: Vec<PersistedBan>) {
+    let (now, unix_now) = (Instant::now(), state::now());
+    BANS.send_modify(|bans| {
+        for ban in saved {
+            let offender = match (ban.user, ban.ip) {
+                (Some(uuid), _) => Offender::User(uuid),
+                (None, Some(ip)) => Offender::Ip(ip),
+                (None, None) => continue,
+            };
+            if ban.expires_at > unix_now {
+                let remaining = Duration::from_secs(ban.expires_at - unix_now);
+                bans.insert(offender, now + remaining);
+            }
+        }
+    });
+}
+
+/// The bans in effect, to be saved
+pub fn bans_state() -> Vec<PersistedBan> {
+    let (now, unix_now) = (Instant::now(), state::now());
+    BANS.borrow()
+        .iter()
+        .filter(|(_, expiry)| **expiry > now)
+        .map(|(offender, expiry)| {
+            let (user, ip) = match offender {
+                Offender::User(uuid) => (Some(*uuid), None),
+                Offender::Ip(ip) => (None, Some(*ip)),
+            };
+            // rounded up so the ban doesn't end early
+            let remaining = (*expiry - now).as_secs() + 1;
+            PersistedBan {
+                user,
+                ip,
+                expires_at: unix_now + remaining,
+            }
+        })
+        .collect()
+}
+
+/// Whether bans are in use: `abuse` rules, `fail2ban` or the RESTful API
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Count `err` towards the rules of its event, if any. `user` is the
+/// authenticated user of the connection, authentication failures count
+/// towards the user they claimed
+pub fn observe(ctx: &Arc<AppContext>, err: &Error, user: Option<Uuid>, ip: IpAddr) {
+    let (event, user) = match err {
+        Error::AclDenied(_) => (AbuseEvent::AclDeny, user),
+        Error::Blocklisted(_) => (AbuseEvent::BlocklistHit, user),
+        Error::AuthFailed(uuid) => (AbuseEvent::AuthFailure, Some(*uuid)),
+        _ => return,
+    };
+    let Some(rules) = RULES.get() else {
+        return;
+    };
+    if event != AbuseEvent::AuthFailure && user.is_some_and(|uuid| users::is_priority(&uuid)) {
+        return;
+    }
+
+    let now = Instant::now();
+    let mut triggered = Vec::new();
+    {
+        let mut strikes = STRIKES.lock().unwrap();
+        for (idx, rule) in rules.iter().enumerate() {
+            if rule.event != event {
+                continue;
+            }
+            let offender = match (rule.action, user) {
+                // failures with the UUID of `priority_users` still count
+                // towards banning the IP, but never lock them out
+                (AbuseAction::DisableUser, Some(uuid)) if !users::is_priority(&uuid) => {
+                    Offender::User(uuid)
+                }
+                (AbuseAction::DisableUser, _) => continue,
+                (AbuseAction::BanIp, _) => Offender::Ip(ip.to_canonical()),
+            };
+            let times = strikes.entry((idx, offender)).or_default();
+            while times
+                .front()
+                .is_some_and(|time| now.duration_since(*time) > rule.window)
+            {
+                times.pop_front();
+            }
+            times.push_back(now);
+            if times.len() >= rule.count as usize {
+                strikes.remove(&(idx, offender));
+                triggered.push((rule, offender));
+            }
+        }
+    }
+
+    for (rule, offender) in triggered {
+        ban(ctx, rule, offender);
+    }
+}
+
+fn ban(ctx: &Arc<AppContext>, rule: &AbuseRule, offender: Offender) {
+    impose(offender, rule.duration);
+    warn!(
+        "[abuse] [{offender}] {count} {event} within {window}, {action} for {duration}",
+        count = rule.count,
+        event = rule.event,
+        window = humantime::format_duration(rule.window),
+        action = rule.action,
+        duration = humantime::format_duration(rule.duration),
+    );
+    hooks::exec_abuse(
+        ctx,
+        rule.event,
+        rule.action,
+        &offender.to_string(),
+        rule.duration,
+    );
+}
+
+/// Ban `offender` for `duration`, unless already banned for longer
+pub fn impose(offender: Offender, duration: Duration) {
+    if let Offender::Ip(ip) = offender {
+        security_log::ban(ip, duration);
+    }
+    let now = Instant::now();
+    let until = now + duration;
+    BANS.send_modify(|bans| {
+        bans.retain(|_, expiry| *expiry > now);
+        let expiry = bans.entry(offender).or_insert(until);
+        *expiry = (*expiry).max(until);
+    });
+}
+
+/// Lift the ban of `offender`, `false` if it isn't banned
+pub fn lift(offender: Offender) -> bool {
+    let now = Instant::now();
+    let mut lifted = false;
+    // nobody got banned, no need to wake up the connections
+    BANS.send_if_modified(|bans| {
+        lifted = bans.remove(&offender).is_some_and(|expiry| expiry > now);
+        false
+    });
+    lifted
+}
+
+pub fn is_banned(offender: Offender) -> bool {
+    BANS.borrow()
+        .get(&offender)
+        .is_some_and(|expiry| *expiry > Instant::now())
+}
+
+/// Wait until any of `offenders` gets banned
+pub async fn wait_banned(offenders: &[Offender]) {
+    let mut bans = BANS.subscribe();
+    loop {
+        {
+            let now = Instant::now();
+            let bans = bans.borrow_and_update();
+            let banned = |offender| bans.get(offender).is_some_and(|expiry| *expiry > now);
+            if offenders.iter().any(banned) {
+                return;
+            }
+        }
+        // the sender is static and never dropped
+        _ = bans.changed().await;
+    }
+}
+
+/// Forget the events that fell out of their window and the expired bans
+/// every minute
+pub async fn start() {
+    let rules = RULES.get().map(Vec::as_slice).unwrap_or_default();
+    let mut interval = time::interval(PRUNE_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+        STRIKES.lock().unwrap().retain(|(idx, _), times| {
+            times
+                .back()
+                .is_some_and(|time| now.duration_since(*time) <= rules[*idx].window)
+        });
+        // nobody got banned, no need to wake up the connections
+        BANS.send_if_modified(|bans| {
+            bans.retain(|_, expiry| *expiry > now);
+            false
+        });
+    }
+}
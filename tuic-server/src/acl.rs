@@ -0,0 +1,366 @@
+use std::{collections::HashMap, net::IpAddr, sync::Arc};
+
+use arc_swap::ArcSwapOption;
+use eyre::eyre;
+use uuid::Uuid;
+
+use crate::{
+    blocklist::{self, IpRange},
+    config::{Config, EgressRule},
+    geoip,
+    upstream::Proxy,
+    utils::{AclAction, EgressMode},
+};
+
+static ACL: ArcSwapOption<Acl> = ArcSwapOption::const_empty();
+/// `allowed_destinations` of `user_overrides`
+static USER_ALLOWLISTS: ArcSwapOption<HashMap<Uuid, Vec<Rule>>> = ArcSwapOption::const_empty();
+
+struct Acl {
+    /// With `egress_mode = "allowlist"`, destinations matching none of these
+    /// are denied before the rules are checked
+    allowlist: Option<Vec<Rule>>,
+    default: AclAction,
+    rules: Vec<Rule>,
+}
+
+/// Matches when every non-empty criterion matches
+struct Rule {
+    action: AclAction,
+    /// Lowercase, without leading or trailing dots
+    domains: Vec<String>,
+    ips: Vec<IpRange>,
+    ports: Vec<(u16, u16)>,
+    /// The `[[outbound.proxies]]` matching destinations are dialed through
+    proxy: Option<Arc<Proxy>>,
+}
+
+impl Rule {
+    fn parse(
+        action: AclAction,
+        domain: &[String],
+        ip: &[String],
+        port: &[String],
+    ) -> eyre::Result<Self> {
+        let ips = ip
+            .iter()
+            .map(|ip| {
+                blocklist::parse_cidr(ip).ok_or_else(|| eyre!("acl: invalid IP or CIDR {ip:?}"))
+            })
+            .collect::<Result<_, _>>()?;
+        let ports = port
+            .iter()
+            .map(|port| parse_ports(port).ok_or_else(|| eyre!("acl: invalid port range {port:?}")))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            action,
+            domains: domain
+                .iter()
+                .map(|domain| domain.trim_matches('.').to_ascii_lowercase())
+                .collect(),
+            ips,
+            ports,
+            proxy: None,
+        })
+    }
+
+    /// `None` if the rule has IP criteria but the address isn't resolved yet
+    fn matches(&self, domain: Option<&str>, ip: Option<IpAddr>, port: u16) -> Option<bool> {
+        if !self.domains.is_empty() {
+            let Some(domain) = domain else {
+                return Some(false);
+            };
+            let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+            let suffix_of = |rule: &String| {
+                domain == *rule
+                    || domain
+                        .strip_suffix(rule.as_str())
+                        .is_some_and(|sub| sub.ends_with('.'))
+            };
+            if !self.domains.iter().any(suffix_of) {
+                return Some(false);
+            }
+        }
+        if !self.ports.is_empty()
+            && !self
+                .ports
+                .iter()
+                .any(|(start, end)| (*start..=*end).contains(&port))
+        {
+            return Some(false);
+        }
+        if !self.ips.is_empty() {
+            let ip = ip?;
+            if !self.ips.iter().any(|range| range.contains(ip)) {
+                return Some(false);
+            }
+        }
+        Some(true)
+    }
+}
+
+/// Whether a destination matches any of `rules`, `None` if that depends on
+/// the address it resolves to
+fn listed(rules: &[Rule], domain: Option<&str>, ip: Option<IpAddr>, port: u16) -> Option<bool> {
+    let mut listed = Some(false);
+    for rule in rules {
+        match rule.matches(domain, ip, port) {
+            Some(true) => return Some(true),
+            Some(false) => {}
+            None => listed = None,
+        }
+    }
+    listed
+}
+
+/// `false` if `user` has `allowed_destinations` and the destination matches
+/// none of them
+fn allow_user(user: Option<Uuid>, domain: Option<&str>, ip: Option<IpAddr>, port: u16) -> bool {
+    let Some(uuid) = user else {
+        return true;
+    };
+    USER_ALLOWLISTS
+        .load()
+        .as_ref()
+        .and_then(|allowlists| allowlists.get(&uuid))
+        .is_none_or(|rules| listed(rules, domain, ip, port) != Some(false))
+}
+
+impl Acl {
+    fn decide(&self, domain: Option<&str>, ip: Option<IpAddr>, port: u16) -> Option<AclAction> {
+        if let Some(allowlist) = &self.allowlist
+            && !listed(allowlist, domain, ip, port)?
+        {
+            return Some(AclAction::Deny);
+        }
+        for rule in &self.rules {
+            if rule.matches(domain, ip, port)? {
+                return Some(rule.action);
+            }
+        }
+        Some(self.default)
+    }
+}
+
+/// Parse `80` or `6881-6889`
+fn parse_ports(port: &str) -> Option<(u16, u16)> {
+    match port.split_once('-') {
+        Some((start, end)) => {
+            let (start, end) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+            (start <= end).then_some((start, end))
+        }
+        None => port.trim().parse().ok().map(|port| (port, port)),
+    }
+}
+
+/// Compile the ACL rules with the `[[outbound.proxies]]` they name, the
+/// egress allowlist and the users' `allowed_destinations`, and open the
+/// `[acl.geoip]` database, replacing the current ones. Nothing is checked when
+/// there are no rules, the default is to allow and egress is open
+pub fn init(cfg: &Config) -> eyre::Result<()> {
+    let mut allowlists = HashMap::new();
+    for (uuid, overrides) in &cfg.user_overrides {
+        let Some(rules) = &overrides.allowed_destinations else {
+            continue;
+        };
+        let rules = rules
+            .iter()
+            .map(|EgressRule { domain, ip, port }| Rule::parse(AclAction::Allow, domain, ip, port))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| eyre!("user_overrides.{uuid}.allowed_destinations: {err}"))?;
+        allowlists.insert(*uuid, rules);
+    }
+
+    let acl = &cfg.acl;
+    let allowlist = match cfg.egress_mode {
+        EgressMode::Open => None,
+        EgressMode::Allowlist => Some(
+            cfg.egress_allowlist
+                .iter()
+                .map(|EgressRule { domain, ip, port }| {
+                    Rule::parse(AclAction::Allow, domain, ip, port)
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+        ),
+    };
+    geoip::init(acl.geoip.as_ref())?;
+    USER_ALLOWLISTS.store((!allowlists.is_empty()).then(|| Arc::new(allowlists)));
+    if allowlist.is_none() && acl.rules.is_empty() && acl.default == AclAction::Allow {
+        ACL.store(None);
+        return Ok(());
+    }
+    let proxies = cfg
+        .outbound
+        .proxies
+        .iter()
+        .map(|proxy| Ok((proxy.name.as_str(), Arc::new(Proxy::parse(proxy)?))))
+        .collect::<eyre::Result<HashMap<_, _>>>()?;
+    let rules = acl
+        .rules
+        .iter()
+        .map(|rule| {
+            let mut parsed = Rule::parse(rule.action, &rule.domain, &rule.ip, &rule.port)?;
+            if let Some(name) = &rule.proxy {
+                if rule.action != AclAction::Allow {
+                    eyre::bail!("acl: `proxy = {name:?}` on a rule that doesn't allow");
+                }
+                let proxy = proxies
+                    .get(name.as_str())
+                    .ok_or_else(|| eyre!("acl: no outbound.proxies entry named {name:?}"))?;
+                parsed.proxy = Some(proxy.clone());
+            }
+            Ok(parsed)
+        })
+        .collect::<eyre::Result<_>>()?;
+    ACL.store(Some(Arc::new(Acl {
+        allowlist,
+        default: acl.default,
+        rules,
+    })));
+    Ok(())
+}
+
+/// Whether a destination may be dialed for `user` before resolving it,
+/// `false` only if it is denied whatever it resolves to
+pub fn allow_target(user: Option<Uuid>, domain: Option<&str>, port: u16) -> bool {
+    if !allow_user(user, domain, None, port) {
+        return false;
+    }
+    ACL.load()
+        .as_ref()
+        .and_then(|acl| acl.decide(domain, None, port))
+        .is_none_or(|action| action == AclAction::Allow)
+}
+
+/// Whether a resolved destination may be dialed for `user`
+pub fn allow(user: Option<Uuid>, domain: Option<&str>, ip: IpAddr, port: u16) -> bool {
+    if !allow_user(user, domain, Some(ip), port) || !geoip::allow_destination(ip) {
+        return false;
+    }
+    ACL.load()
+        .as_ref()
+        .and_then(|acl| acl.decide(domain, Some(ip), port))
+        .is_none_or(|action| action == AclAction::Allow)
+}
+
+/// The outbound proxy of the first rule a destination matches, if any. Rules
+/// with IP criteria are passed over for unresolved domains, as proxied
+/// domains are resolved by the proxy
+pub fn proxy(domain: Option<&str>, ip: Option<IpAddr>, port: u16) -> Option<Arc<Proxy>> {
+    let acl = ACL.load();
+    let rule = acl
+        .as_ref()?
+        .rules
+        .iter()
+        .find(|rule| rule.matches(domain, ip, port) == Some(true))?;
+    rule.proxy.clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(action: AclAction, domain: &[&str], ip: &[&str], port: &[&str]) -> Rule {
+        let strings = |v: &[&str]| v.iter().map(|s| (*s).to_owned()).collect::<Vec<_>>();
+        Rule::parse(action, &strings(domain), &strings(ip), &strings(port)).unwrap()
+    }
+
+    fn acl(rules: Vec<Rule>) -> Acl {
+        Acl {
+            allowlist: None,
+            default: AclAction::Allow,
+            rules,
+        }
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let acl = acl(vec![
+            rule(AclAction::Allow, &["mail.example.com"], &[], &["25"]),
+            rule(AclAction::Deny, &[], &[], &["25"]),
+            rule(AclAction::Deny, &["example.com"], &[], &[]),
+        ]);
+        let decide = |domain, port| acl.decide(Some(domain), None, port);
+        assert_eq!(decide("mail.example.com", 25), Some(AclAction::Allow));
+        assert_eq!(decide("smtp.example.org", 25), Some(AclAction::Deny));
+        assert_eq!(decide("www.example.com", 443), Some(AclAction::Deny));
+        assert_eq!(decide("example.org", 443), Some(AclAction::Allow));
+    }
+
+    #[test]
+    fn cidr() {
+        let acl = acl(vec![rule(
+            AclAction::Deny,
+            &[],
+            &["10.0.0.0/8", "2001:db8::/32", "192.0.2.1"],
+            &[],
+        )]);
+        let decide = |addr| acl.decide(None, ip(addr), 443);
+        assert_eq!(decide("10.255.0.1"), Some(AclAction::Deny));
+        assert_eq!(decide("11.0.0.1"), Some(AclAction::Allow));
+        assert_eq!(decide("2001:db8:ffff::1"), Some(AclAction::Deny));
+        assert_eq!(decide("2001:db9::1"), Some(AclAction::Allow));
+        assert_eq!(decide("192.0.2.1"), Some(AclAction::Deny));
+        assert_eq!(decide("192.0.2.2"), Some(AclAction::Allow));
+        // depends on what the domain resolves to
+        assert_eq!(acl.decide(Some("example.com"), None, 443), None);
+    }
+
+    #[test]
+    fn ports() {
+        let rule = rule(AclAction::Deny, &[], &[], &["22", "6881-6889"]);
+        for (port, matches) in [
+            (22, true),
+            (23, false),
+            (6880, false),
+            (6881, true),
+            (6889, true),
+        ] {
+            assert_eq!(rule.matches(None, None, port), Some(matches), "{port}");
+        }
+        assert_eq!(parse_ports(" 80 "), Some((80, 80)));
+        assert_eq!(parse_ports("90-80"), None);
+        assert_eq!(parse_ports("1-65536"), None);
+        assert!(Rule::parse(AclAction::Deny, &[], &[], &["http".to_owned()]).is_err());
+        assert!(Rule::parse(AclAction::Deny, &[], &["10.0.0.256/8".to_owned()], &[]).is_err());
+    }
+
+    #[test]
+    fn domain_suffixes() {
+        let rule = rule(AclAction::Deny, &[".Example.COM."], &[], &[]);
+        for (domain, matches) in [
+            ("example.com", true),
+            ("EXAMPLE.com.", true),
+            ("www.example.com", true),
+            ("a.b.example.com", true),
+            ("badexample.com", false),
+            ("example.com.evil", false),
+            ("com", false),
+        ] {
+            assert_eq!(
+                rule.matches(Some(domain), None, 443),
+                Some(matches),
+                "{domain}"
+            );
+        }
+        // IP targets have no domain to match
+        assert_eq!(rule.matches(None, ip("192.0.2.1"), 443), Some(false));
+    }
+
+    #[test]
+    fn allowlist_comes_first() {
+        let acl = Acl {
+            allowlist: Some(vec![rule(AclAction::Allow, &["example.com"], &[], &[])]),
+            default: AclAction::Allow,
+            rules: vec![rule(AclAction::Deny, &["www.example.com"], &[], &[])],
+        };
+        let decide = |domain| acl.decide(Some(domain), None, 443);
+        assert_eq!(decide("api.example.com"), Some(AclAction::Allow));
+        assert_eq!(decide("www.example.com"), Some(AclAction::Deny));
+        assert_eq!(decide("example.org"), Some(AclAction::Deny));
+    }
+}
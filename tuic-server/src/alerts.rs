@@ -0,0 +1,40 @@
+//! The latest suspicious behaviors detected, listed by the RESTful `/alerts`
+
+use std::{
+    collections::VecDeque,
+    sync::{LazyLock, Mutex},
+};
+
+use chrono::{Local, SecondsFormat};
+use serde_json::{Map, Value, json};
+
+/// How many alerts are kept, the oldest are dropped first
+const CAPACITY: usize = 100;
+
+static ALERTS: LazyLock<Mutex<VecDeque<Value>>> = LazyLock::new(Mutex::default);
+
+/// Record an alert of `kind`, with `details` next to its time and kind
+pub fn push(kind: &str, details: Value) {
+    let mut alert = Map::new();
+    alert.insert(
+        "time".into(),
+        Local::now()
+            .to_rfc3339_opts(SecondsFormat::Secs, false)
+            .into(),
+    );
+    alert.insert("kind".into(), kind.into());
+    if let Value::Object(details) = details {
+        alert.extend(details);
+    }
+
+    let mut alerts = ALERTS.lock().unwrap();
+    if alerts.len() == CAPACITY {
+        alerts.pop_front();
+    }
+    alerts.push_back(Value::Object(alert));
+}
+
+/// The alerts kept, newest first
+pub fn list() -> Value {
+    json!(ALERTS.lock().unwrap().iter().rev().collect::<Vec<_>>())
+}
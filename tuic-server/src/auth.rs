@@ -0,0 +1,155 @@
+//! Users looked up from `[auth.http]` when they aren't in `users`, for panels
+//! rotating too many users to keep rewriting the config file. The token a
+//! client authenticates with is derived from the TLS session, so the backend
+//! answers with the passwords of the user and the token is checked here
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{LazyLock, Mutex, OnceLock},
+};
+
+use bytes::Bytes;
+use eyre::{Context, bail};
+use http_body_util::Full;
+use hyper::{
+    Request, StatusCode, Uri,
+    header::{AUTHORIZATION, CONTENT_TYPE},
+};
+use serde::{Deserialize, Serialize};
+use tokio::time::{self, Instant};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::{AppContext, config::HttpAuthConfig, http_client::Client, users, utils::UserPasswords};
+
+/// Users cached at most, expired answers are dropped to make room
+const MAX_CACHED: usize = 65536;
+
+static CLIENT: OnceLock<Client> = OnceLock::new();
+static CACHE: LazyLock<Mutex<HashMap<Uuid, Cached>>> = LazyLock::new(Mutex::default);
+
+struct Cached {
+    expires: Instant,
+    /// `None` for users unknown to the backend
+    passwords: Option<UserPasswords>,
+}
+
+#[derive(Serialize)]
+struct Lookup {
+    uuid: Uuid,
+    addr: SocketAddr,
+}
+
+#[derive(Deserialize)]
+struct Answer {
+    password: UserPasswords,
+}
+
+pub fn init(cfg: Option<&HttpAuthConfig>) -> eyre::Result<()> {
+    let Some(cfg) = cfg else {
+        return Ok(());
+    };
+    let uri: Uri = cfg
+        .url
+        .parse()
+        .with_context(|| format!("auth.http.url: invalid URL {:?}", cfg.url))?;
+    if !matches!(uri.scheme_str(), Some("http" | "https")) || uri.host().is_none() {
+        bail!("auth.http.url: expected an http:// or https:// URL");
+    }
+    let client = Client::new().context("auth.http")?;
+    _ = CLIENT.set(client);
+    Ok(())
+}
+
+/// Like [`users::verify`], asking `[auth.http]` about users missing from
+/// `users`, or about every user in `registration_mode`
+pub async fn verify(
+    ctx: &AppContext,
+    uuid: Uuid,
+    addr: SocketAddr,
+    validate: impl Fn(&str) -> bool,
+) -> Option<Option<String>> {
+    if !ctx.cfg.registration_mode
+        && let Some(label) = users::verify(&uuid, &validate)
+    {
+        return Some(label);
+    }
+    let cfg = ctx.cfg.auth.http.as_ref()?;
+    let passwords = lookup(cfg, uuid, addr).await?;
+    let (label, _) = passwords.iter().find(|(_, password)| validate(password))?;
+    Some(label.map(str::to_owned))
+}
+
+/// Whether there is no user to authenticate at all
+pub fn is_empty(ctx: &AppContext) -> bool {
+    users::is_empty() && ctx.cfg.auth.http.is_none()
+}
+
+async fn lookup(cfg: &HttpAuthConfig, uuid: Uuid, addr: SocketAddr) -> Option<UserPasswords> {
+    if let Some(cached) = CACHE.lock().unwrap().get(&uuid)
+        && cached.expires > Instant::now()
+    {
+        return cached.passwords.clone();
+    }
+
+    let passwords = match time::timeout(cfg.timeout, ask(cfg, uuid, addr)).await {
+        Ok(Ok(passwords)) => passwords,
+        Ok(Err(err)) => {
+            warn!("[auth] [{addr}] failed to look up {uuid}: {err:#}");
+            return None;
+        }
+        Err(_) => {
+            warn!("[auth] [{addr}] timed out looking up {uuid}");
+            return None;
+        }
+    };
+    debug!(
+        "[auth] [{addr}] looked up {uuid}: {}",
+        if passwords.is_some() {
+            "found"
+        } else {
+            "unknown"
+        }
+    );
+
+    let mut cache = CACHE.lock().unwrap();
+    let now = Instant::now();
+    if cache.len() >= MAX_CACHED {
+        cache.retain(|_, cached| cached.expires > now);
+    }
+    if cache.len() < MAX_CACHED {
+        cache.insert(
+            uuid,
+            Cached {
+                expires: now + cfg.cache_ttl,
+                passwords: passwords.clone(),
+            },
+        );
+    }
+    passwords
+}
+
+/// `None` if the backend doesn't know the user
+async fn ask(
+    cfg: &HttpAuthConfig,
+    uuid: Uuid,
+    addr: SocketAddr,
+) -> eyre::Result<Option<UserPasswords>> {
+    let client = CLIENT.get().ok_or_else(|| eyre::eyre!("not initialized"))?;
+    let body = serde_json::to_vec(&Lookup { uuid, addr })?;
+    let mut req = Request::post(cfg.url.as_str()).header(CONTENT_TYPE, "application/json");
+    if !cfg.secret.is_empty() {
+        req = req.header(AUTHORIZATION, format!("Bearer {}", cfg.secret));
+    }
+    let res = client.send(req.body(Full::new(Bytes::from(body)))?).await?;
+    match res.status() {
+        StatusCode::OK => {
+            let answer: Answer =
+                serde_json::from_slice(res.body()).context("malformed response")?;
+            Ok(Some(answer.password))
+        }
+        StatusCode::NOT_FOUND | StatusCode::FORBIDDEN => Ok(None),
+        status => bail!("unexpected status {status}"),
+    }
+}
@@ -0,0 +1,152 @@
+use std::{
+    net::IpAddr,
+    sync::{Arc, LazyLock},
+};
+
+use arc_swap::ArcSwap;
+use chashmap::CHashMap;
+use tokio::time::{self, MissedTickBehavior};
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{AppContext, config::BlocklistConfig};
+
+static BLOCKLIST: LazyLock<ArcSwap<Blocklist>> = LazyLock::new(ArcSwap::default);
+static HITS: LazyLock<CHashMap<Uuid, u64>> = LazyLock::new(CHashMap::new);
+
+/// Merged, sorted and non-overlapping inclusive address ranges
+#[derive(Default)]
+struct Blocklist {
+    v4: Vec<(u32, u32)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl Blocklist {
+    /// Parse feeds in the Spamhaus DROP / FireHOL netset format: one IP or
+    /// CIDR per line, comments starting with `;` or `#`
+    fn parse(text: &str, v4: &mut Vec<(u32, u32)>, v6: &mut Vec<(u128, u128)>) {
+        for line in text.lines() {
+            let line = line.split([';', '#']).next().unwrap_or_default();
+            let Some(entry) = line.split_whitespace().next() else {
+                continue;
+            };
+            match parse_cidr(entry) {
+                Some(IpRange::V4(start, end)) => v4.push((start, end)),
+                Some(IpRange::V6(start, end)) => v6.push((start, end)),
+                None => {}
+            }
+        }
+    }
+
+    fn merge<T: Ord + Copy>(mut ranges: Vec<(T, T)>) -> Vec<(T, T)> {
+        ranges.sort_unstable();
+        let mut merged: Vec<(T, T)> = Vec::with_capacity(ranges.len());
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+        merged
+    }
+
+    fn contains<T: Ord + Copy>(ranges: &[(T, T)], ip: T) -> bool {
+        let idx = ranges.partition_point(|(start, _)| *start <= ip);
+        idx > 0 && ip <= ranges[idx - 1].1
+    }
+}
+
+/// An inclusive address range
+#[derive(Clone, Copy)]
+pub enum IpRange {
+    V4(u32, u32),
+    V6(u128, u128),
+}
+
+impl IpRange {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self, ip.to_canonical()) {
+            (Self::V4(start, end), IpAddr::V4(ip)) => (*start..=*end).contains(&u32::from(ip)),
+            (Self::V6(start, end), IpAddr::V6(ip)) => (*start..=*end).contains(&u128::from(ip)),
+            _ => false,
+        }
+    }
+}
+
+/// Parse an IP or a CIDR like `10.0.0.0/8`. An out-of-range prefix length is
+/// clamped, an unparsable one ignored
+pub fn parse_cidr(entry: &str) -> Option<IpRange> {
+    let (ip, prefix) = match entry.split_once('/') {
+        Some((ip, prefix)) => (ip, prefix.parse::<u32>().ok()),
+        None => (entry, None),
+    };
+    match ip.parse::<IpAddr>().ok()? {
+        IpAddr::V4(ip) => {
+            let prefix = prefix.unwrap_or(32).min(32);
+            let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            let start = u32::from(ip) & mask;
+            Some(IpRange::V4(start, start | !mask))
+        }
+        IpAddr::V6(ip) => {
+            let prefix = prefix.unwrap_or(128).min(128);
+            let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            let start = u128::from(ip) & mask;
+            Some(IpRange::V6(start, start | !mask))
+        }
+    }
+}
+
+/// Load the blocklist feeds and keep reloading them every `refresh_interval`.
+/// A feed that fails to load keeps the previously loaded list in effect.
+pub async fn start(ctx: Arc<AppContext>) {
+    let Some(cfg) = ctx.cfg.blocklist.as_ref() else {
+        return;
+    };
+    let mut interval = time::interval(cfg.refresh_interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        match load(cfg).await {
+            Ok(list) => {
+                info!(
+                    "[blocklist] loaded {v4} IPv4 and {v6} IPv6 ranges",
+                    v4 = list.v4.len(),
+                    v6 = list.v6.len(),
+                );
+                BLOCKLIST.store(Arc::new(list));
+            }
+            Err(err) => warn!("[blocklist] failed to reload, keeping the previous list: {err}"),
+        }
+    }
+}
+
+async fn load(cfg: &BlocklistConfig) -> eyre::Result<Blocklist> {
+    let mut v4 = Vec::new();
+    let mut v6 = Vec::new();
+    for path in &cfg.files {
+        let text = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|err| eyre::eyre!("{path}: {err}", path = path.display()))?;
+        Blocklist::parse(&text, &mut v4, &mut v6);
+    }
+    Ok(Blocklist {
+        v4: Blocklist::merge(v4),
+        v6: Blocklist::merge(v6),
+    })
+}
+
+pub fn is_blocked(ip: IpAddr) -> bool {
+    let list = BLOCKLIST.load();
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => Blocklist::contains(&list.v4, u32::from(ip)),
+        IpAddr::V6(ip) => Blocklist::contains(&list.v6, u128::from(ip)),
+    }
+}
+
+pub async fn record_hit(uuid: Uuid) {
+    HITS.upsert(uuid, || 1, |hits| *hits += 1).await;
+}
+
+pub async fn hits() -> Vec<(Uuid, u64)> {
+    HITS.clone_locking().await.into_iter().collect()
+}
@@ -0,0 +1,104 @@
+//! Picking the certificate of each handshake by the server name (SNI) the
+//! client asks for, so one endpoint can serve several domains
+
+use std::{collections::HashMap, sync::Arc};
+
+use eyre::Context;
+use rustls::{
+    crypto::CryptoProvider,
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use tracing::debug;
+
+use crate::{
+    config::{SniCertificate, TlsConfig},
+    error::Error,
+    utils,
+};
+
+#[derive(Debug)]
+pub struct CertResolver {
+    /// `tls.certificates` by exact name, lowercase
+    names: HashMap<String, Arc<CertifiedKey>>,
+    /// `tls.certificates` for `*.example.com` by the parent domain,
+    /// `example.com`
+    wildcards: HashMap<String, Arc<CertifiedKey>>,
+    /// The self-signed certificate or `tls.certificate`, for clients sending no
+    /// server name or one without a certificate of its own
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    pub fn new(cfg: &TlsConfig, provider: &CryptoProvider) -> Result<Self, Error> {
+        let default = if cfg.self_sign {
+            let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+            let cert_der = CertificateDer::from(cert.cert);
+            let priv_key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
+            Some(CertifiedKey::from_der(
+                vec![cert_der],
+                PrivateKeyDer::Pkcs8(priv_key),
+                provider,
+            )?)
+        } else if cfg.certificates.is_empty() || !cfg.certificate.as_os_str().is_empty() {
+            let certs = utils::load_cert_chain(&cfg.certificate).map_err(Error::Tls)?;
+            let priv_key = utils::load_priv_key(&cfg.private_key).map_err(Error::Tls)?;
+            Some(CertifiedKey::from_der(certs, priv_key, provider)?)
+        } else {
+            None
+        };
+
+        let mut names = HashMap::new();
+        let mut wildcards = HashMap::new();
+        for entry in &cfg.certificates {
+            let sni = entry.sni.trim_end_matches('.').to_ascii_lowercase();
+            let key = load(entry, provider)
+                .with_context(|| format!("tls.certificates: {sni}"))
+                .map_err(Error::Tls)?;
+            let (table, name) = match sni.strip_prefix("*.") {
+                Some(parent) => (&mut wildcards, parent.to_owned()),
+                None => (&mut names, sni.clone()),
+            };
+            if name.is_empty() || table.insert(name, Arc::new(key)).is_some() {
+                return Err(Error::Tls(eyre::eyre!(
+                    "tls.certificates: `sni` {sni:?} is empty or listed more than once"
+                )));
+            }
+        }
+
+        Ok(Self {
+            names,
+            wildcards,
+            default: default.map(Arc::new),
+        })
+    }
+}
+
+fn load(entry: &SniCertificate, provider: &CryptoProvider) -> eyre::Result<CertifiedKey> {
+    let certs = utils::load_cert_chain(&entry.cert)?;
+    let priv_key = utils::load_priv_key(&entry.key)?;
+    Ok(CertifiedKey::from_der(certs, priv_key, provider)?)
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let Some(name) = client_hello.server_name() else {
+            return self.default.clone();
+        };
+        let name = name.to_ascii_lowercase();
+        if let Some(key) = self.names.get(&name) {
+            return Some(key.clone());
+        }
+        // a wildcard covers a single label
+        if let Some((_, parent)) = name.split_once('.')
+            && let Some(key) = self.wildcards.get(parent)
+        {
+            return Some(key.clone());
+        }
+        if self.default.is_none() {
+            debug!("[tls] no certificate for server name {name}, refusing the handshake");
+        }
+        self.default.clone()
+    }
+}
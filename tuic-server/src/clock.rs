@@ -0,0 +1,104 @@
+//! Noticing when the host was suspended, the VM paused or migrated, or the
+//! wall clock adjusted.
+//!
+//! Every timeout, garbage collection and interval of the server runs on the
+//! monotonic clock, so wall clock adjustments never move their deadlines, and
+//! intervals delay their missed ticks rather than firing them in a burst when
+//! the process resumes. The monotonic clock doesn't advance while the host is
+//! suspended though, so the time spent suspended isn't counted towards them:
+//! these events are logged to explain the state clients find afterwards
+
+use std::time::{Duration, SystemTime};
+
+use tokio::time::{self, Instant, MissedTickBehavior};
+use tracing::warn;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// How far the clocks may drift apart between two checks, scheduling delays
+/// of a busy runtime included, before it is considered a jump
+const TOLERANCE: Duration = Duration::from_secs(2);
+
+/// The clocks read at the same time
+struct Sample {
+    monotonic: Instant,
+    /// The monotonic clock including the time suspended, Linux only
+    boottime: Option<Duration>,
+    wall: SystemTime,
+}
+
+impl Sample {
+    fn now() -> Self {
+        Self {
+            monotonic: Instant::now(),
+            boottime: boottime(),
+            wall: SystemTime::now(),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn boottime() -> Option<Duration> {
+    let mut ts = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    let ret = unsafe { libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut ts) };
+    (ret == 0).then(|| Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn boottime() -> Option<Duration> {
+    None
+}
+
+/// Compare the clocks every 5 seconds, logging a resume when more time passed
+/// than expected and a jump when the wall clock disagrees with the others
+pub async fn start() {
+    let mut interval = time::interval(CHECK_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval.tick().await;
+    let mut last = Sample::now();
+
+    loop {
+        interval.tick().await;
+        let now = Sample::now();
+        let monotonic = now.monotonic - last.monotonic;
+        let elapsed = match (now.boottime, last.boottime) {
+            (Some(now), Some(last)) => now.saturating_sub(last),
+            _ => monotonic,
+        };
+
+        // the monotonic clock stops while suspended, but after a VM pause or
+        // migration it may not have, in which case the tick came late
+        if elapsed > CHECK_INTERVAL + TOLERANCE {
+            warn!(
+                "[clock] resume detected: the server was suspended or paused for at least \
+                 {paused}, connections may have timed out meanwhile",
+                paused = humantime::format_duration(round(elapsed - CHECK_INTERVAL)),
+            );
+        }
+
+        match now.wall.duration_since(last.wall) {
+            Ok(wall) if wall > elapsed + TOLERANCE => warn!(
+                "[clock] the system clock jumped forward by {jump}",
+                jump = humantime::format_duration(round(wall - elapsed)),
+            ),
+            Ok(wall) if wall + TOLERANCE < elapsed => warn!(
+                "[clock] the system clock jumped backward by {jump}",
+                jump = humantime::format_duration(round(elapsed - wall)),
+            ),
+            Ok(_) => {}
+            Err(err) => warn!(
+                "[clock] the system clock jumped backward by {jump}",
+                jump = humantime::format_duration(round(err.duration() + elapsed)),
+            ),
+        }
+
+        last = now;
+    }
+}
+
+/// Whole seconds, the precision of the checks
+fn round(duration: Duration) -> Duration {
+    Duration::from_secs(duration.as_secs())
+}
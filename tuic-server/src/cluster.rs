@@ -0,0 +1,153 @@
+//! Share load and health between TUIC servers, so clients or subscription
+//! generators can be steered to the least loaded node
+
+use std::{
+    net::SocketAddr,
+    sync::{Arc, LazyLock},
+    time::Instant,
+};
+
+use bytes::Bytes;
+use chashmap::CHashMap;
+use eyre::{Context, bail};
+use http_body_util::{BodyExt, Empty};
+use hyper::{
+    Request,
+    header::{AUTHORIZATION, HOST},
+};
+use hyper_util::rt::TokioIo;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::TcpStream,
+    time::{self, MissedTickBehavior},
+};
+use tracing::{debug, warn};
+
+use crate::{AppContext, config::ClusterConfig, restful};
+
+/// The last status received from each peer, keyed by its RESTful address
+static PEERS: LazyLock<CHashMap<SocketAddr, (NodeStatus, Instant)>> = LazyLock::new(CHashMap::new);
+
+/// A peer is considered down after missing this many polls in a row
+const MISSED_POLLS: u32 = 3;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NodeStatus {
+    pub name: String,
+    pub address: String,
+    pub weight: u32,
+    pub connections: u64,
+}
+
+impl NodeStatus {
+    /// Connections per unit of weight, the lower the better
+    fn load(&self) -> f64 {
+        self.connections as f64 / f64::from(self.weight.max(1))
+    }
+}
+
+#[derive(Serialize)]
+pub struct Node {
+    #[serde(flatten)]
+    pub status: NodeStatus,
+    pub healthy: bool,
+    /// Seconds since the node was last heard from, `None` for this node
+    pub last_seen: Option<u64>,
+}
+
+/// The status of this node
+pub fn local(cfg: &ClusterConfig) -> NodeStatus {
+    NodeStatus {
+        name: if cfg.name.is_empty() {
+            cfg.address.clone()
+        } else {
+            cfg.name.clone()
+        },
+        address: cfg.address.clone(),
+        weight: cfg.weight,
+        connections: restful::online_connections(),
+    }
+}
+
+/// This node followed by every peer that has answered at least once
+pub async fn nodes(cfg: &ClusterConfig) -> Vec<Node> {
+    let stale = cfg.interval * MISSED_POLLS;
+    let mut nodes = vec![Node {
+        status: local(cfg),
+        healthy: true,
+        last_seen: None,
+    }];
+    for (_, (status, seen)) in PEERS.clone_locking().await {
+        nodes.push(Node {
+            status,
+            healthy: seen.elapsed() <= stale,
+            last_seen: Some(seen.elapsed().as_secs()),
+        });
+    }
+    nodes
+}
+
+/// The healthy node with the lowest load, preferring this node on ties
+pub async fn recommended(cfg: &ClusterConfig) -> NodeStatus {
+    nodes(cfg)
+        .await
+        .into_iter()
+        .filter(|node| node.healthy)
+        .map(|node| node.status)
+        .reduce(|best, node| {
+            if node.load() < best.load() {
+                node
+            } else {
+                best
+            }
+        })
+        .unwrap_or_else(|| local(cfg))
+}
+
+/// Poll the status of every peer every `cluster.interval`
+pub async fn start(ctx: Arc<AppContext>) {
+    let cfg = ctx.cfg.cluster.as_ref().unwrap();
+    let mut interval = time::interval(cfg.interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        for &peer in &cfg.peers {
+            let ctx = ctx.clone();
+            tokio::spawn(async move {
+                let cfg = ctx.cfg.cluster.as_ref().unwrap();
+                match time::timeout(cfg.interval, fetch(peer, &cfg.secret)).await {
+                    Ok(Ok(status)) => {
+                        debug!("[cluster] [{peer}] {status:?}");
+                        PEERS.insert(peer, (status, Instant::now())).await;
+                    }
+                    Ok(Err(err)) => warn!("[cluster] [{peer}] failed to poll node status: {err:#}"),
+                    Err(_) => warn!("[cluster] [{peer}] polling node status timed out"),
+                }
+            });
+        }
+    }
+}
+
+async fn fetch(peer: SocketAddr, secret: &str) -> eyre::Result<NodeStatus> {
+    let stream = TcpStream::connect(peer).await?;
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(async move {
+        if let Err(err) = conn.await {
+            debug!("[cluster] [{peer}] connection error: {err}");
+        }
+    });
+
+    let mut req = Request::get("/cluster/node").header(HOST, peer.to_string());
+    if !secret.is_empty() {
+        req = req.header(AUTHORIZATION, format!("Bearer {secret}"));
+    }
+    let res = sender
+        .send_request(req.body(Empty::<Bytes>::new())?)
+        .await?;
+    if !res.status().is_success() {
+        bail!("unexpected status {}", res.status());
+    }
+    let body = res.into_body().collect().await?.to_bytes();
+    serde_json::from_slice(&body).context("malformed node status")
+}
@@ -1,18 +1,34 @@
-use std::{collections::HashMap, env::ArgsOs, net::SocketAddr, path::PathBuf, time::Duration};
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    fmt::{Display, Formatter, Result as FmtResult},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
 
 use educe::Educe;
 use figment::{
     Figment,
     providers::{Format, Serialized, Toml},
 };
-use lexopt::{Arg, Parser};
-use serde::{Deserialize, Serialize};
+use lexopt::{Arg, Parser, ValueExt};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error as DeError};
 use tracing::{level_filters::LevelFilter, warn};
 use uuid::Uuid;
 
 use crate::{
+    handshake,
     old_config::{ConfigError, OldConfig},
-    utils::CongestionController,
+    share,
+    update::{self, UpdateArgs},
+    utils::{
+        AbuseAction, AbuseEvent, AclAction, ByteSize, CongestionController, DnsProtocol,
+        DuplicateAuthPolicy, EgressMode, ListenAddrs, LogDestinations, LogOutput, PortRange,
+        ProxyProtocol, ScanAction, SyslogFacility, UserPasswords,
+    },
+    validate,
 };
 
 #[derive(Deserialize, Serialize, Educe)]
@@ -20,22 +36,68 @@ use crate::{
 #[serde(deny_unknown_fields)]
 pub struct Config {
     pub log_level: LogLevel,
-    #[educe(Default(expression = "[::]:443".parse().unwrap()))]
-    pub server: SocketAddr,
-    pub users: HashMap<Uuid, String>,
+    pub log_destinations: LogDestinations,
+    pub log_output: LogOutput,
+    pub syslog: SyslogConfig,
+    pub error_log: ErrorLogConfig,
+    #[educe(Default(expression = "[::]:443".parse::<SocketAddr>().unwrap().into()))]
+    pub server: ListenAddrs,
+    pub users: HashMap<Uuid, UserPasswords>,
+    pub priority_users: Vec<Uuid>,
+    pub user_overrides: HashMap<Uuid, UserOverrides>,
+    #[educe(Default = None)]
+    pub users_db: Option<PathBuf>,
     pub tls: TlsConfig,
 
     #[educe(Default = "./data.toml")]
     pub persistent_data: PathBuf,
 
+    #[serde(with = "humantime_serde")]
+    #[educe(Default(expression = Duration::ZERO))]
+    pub config_watch_interval: Duration,
+
+    /// The file this config was read from
+    #[serde(skip)]
+    pub config_path: PathBuf,
+
+    #[educe(Default = "./last_crash.json")]
+    pub crash_report: PathBuf,
+
     #[educe(Default = None)]
     pub restful: Option<RestfulConfig>,
 
     pub quic: QuicConfig,
 
+    pub masque: MasqueConfig,
+
+    #[educe(Default = None)]
+    pub fallback: Option<FallbackConfig>,
+
+    #[educe(Default = None)]
+    pub fallback_h3_upstream: Option<String>,
+
     #[educe(Default = true)]
     pub udp_relay_ipv6: bool,
 
+    #[educe(Default = false)]
+    pub registration_mode: bool,
+
+    #[educe(Default = false)]
+    pub udp_relay_dual_stack: bool,
+
+    #[educe(Default = None)]
+    pub udp_relay_bind_ipv4: Option<Ipv4Addr>,
+
+    #[educe(Default = None)]
+    pub udp_relay_bind_ipv6: Option<Ipv6Addr>,
+
+    #[educe(Default = None)]
+    pub udp_relay_socket_buffer: Option<ByteSize>,
+
+    /// Alias of `outbound.port_range`, moved into `parse_config`
+    #[educe(Default = None)]
+    pub outbound_port_range: Option<PortRange>,
+
     #[educe(Default = false)]
     pub zero_rtt_handshake: bool,
 
@@ -46,20 +108,106 @@ pub struct Config {
     #[educe(Default(expression = Duration::from_millis(3000)))]
     pub auth_timeout: Duration,
 
+    pub duplicate_auth: DuplicateAuthPolicy,
+
+    #[educe(Default = 64)]
+    pub max_pre_auth_tasks: usize,
+
+    #[educe(Default = true)]
+    pub disconnect_on_password_change: bool,
+
     #[serde(with = "humantime_serde")]
     #[educe(Default(expression = Duration::from_millis(3000)))]
     pub task_negotiation_timeout: Duration,
 
+    #[serde(with = "humantime_serde")]
+    #[educe(Default(expression = Duration::ZERO))]
+    pub stream_timeout: Duration,
+
+    #[serde(with = "humantime_serde")]
+    #[educe(Default(expression = Duration::ZERO))]
+    pub first_byte_timeout: Duration,
+
+    #[educe(Default = 65536)]
+    pub tcp_relay_buffer_size: usize,
+
+    #[serde(with = "humantime_serde")]
+    #[educe(Default(expression = Duration::ZERO))]
+    pub max_connection_lifetime: Duration,
+
+    #[educe(Default = 0)]
+    pub per_connection_traffic_quota: u64,
+
+    #[educe(Default = 0)]
+    pub max_udp_sessions_per_connection: usize,
+
+    #[educe(Default = 0)]
+    pub max_connections_per_ip: usize,
+
+    #[educe(Default = 0)]
+    pub max_concurrent_streams_per_user: usize,
+
     #[serde(with = "humantime_serde")]
     #[educe(Default(expression = Duration::from_millis(3000)))]
     pub gc_interval: Duration,
 
+    #[educe(Default = 1048576)]
+    pub gc_cache_threshold: usize,
+
     #[serde(alias = "gc_lifetime", with = "humantime_serde")]
     #[educe(Default(expression = Duration::from_millis(15000)))]
     pub gc_lifetime: Duration,
 
     #[educe(Default = 1500)]
     pub max_external_packet_size: usize,
+
+    #[educe(Default = false)]
+    pub udp_address_report: bool,
+
+    pub on_connect_exec: Vec<String>,
+
+    pub on_disconnect_exec: Vec<String>,
+
+    #[serde(with = "humantime_serde")]
+    #[educe(Default(expression = Duration::from_millis(5000)))]
+    pub exec_timeout: Duration,
+
+    #[educe(Default = None)]
+    pub blocklist: Option<BlocklistConfig>,
+
+    pub outbound: OutboundConfig,
+
+    pub dns: DnsConfig,
+
+    #[educe(Default = None)]
+    pub routing_script: Option<ScriptConfig>,
+
+    pub sniff: SniffConfig,
+
+    pub acl: AclConfig,
+
+    pub egress_mode: EgressMode,
+
+    pub egress_allowlist: Vec<EgressRule>,
+
+    pub abuse: AbuseConfig,
+
+    pub port_scan: PortScanConfig,
+
+    pub fail2ban: Fail2banConfig,
+
+    #[educe(Default = None)]
+    pub security_log: Option<SecurityLogConfig>,
+
+    pub accounting: AccountingConfig,
+
+    #[educe(Default = None)]
+    pub cluster: Option<ClusterConfig>,
+
+    #[educe(Default = None)]
+    pub subscription: Option<SubscriptionConfig>,
+
+    pub auth: AuthConfig,
 }
 
 #[derive(Deserialize, Serialize, Educe)]
@@ -71,6 +219,23 @@ pub struct TlsConfig {
     pub private_key: PathBuf,
     #[educe(Default(expression = Vec::new()))]
     pub alpn: Vec<String>,
+    #[educe(Default = 2)]
+    pub session_tickets: usize,
+    #[serde(with = "humantime_serde")]
+    #[educe(Default = None)]
+    pub ticket_lifetime: Option<Duration>,
+    #[educe(Default(expression = Vec::new()))]
+    pub certificates: Vec<SniCertificate>,
+}
+
+/// A certificate served to clients asking for `sni`, e.g. `"example.com"` or
+/// `"*.example.com"`
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SniCertificate {
+    pub sni: String,
+    pub cert: PathBuf,
+    pub key: PathBuf,
 }
 
 #[derive(Deserialize, Serialize, Educe)]
@@ -97,9 +262,45 @@ pub struct QuicConfig {
     #[educe(Default = 8388608)]
     pub receive_window: u32,
 
+    #[educe(Default = false)]
+    pub auto_tune_window: bool,
+
+    #[educe(Default = 67108864)]
+    pub max_receive_window: u64,
+
+    #[educe(Default = 67108864)]
+    pub max_send_window: u64,
+
     #[serde(with = "humantime_serde")]
     #[educe(Default(expression = Duration::from_millis(10000)))]
     pub max_idle_time: Duration,
+
+    #[educe(Default = None)]
+    pub ack_frequency: Option<AckFrequencyConfig>,
+
+    #[educe(Default(expression = handshake::SUPPORTED_VERSIONS.to_vec()))]
+    pub versions: Vec<u32>,
+
+    #[educe(Default = true)]
+    pub grease_quic_bit: bool,
+
+    #[educe(Default = false)]
+    pub log_transport_parameters: bool,
+}
+
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AckFrequencyConfig {
+    #[educe(Default = 1)]
+    pub ack_eliciting_threshold: u32,
+
+    #[serde(with = "humantime_serde")]
+    #[educe(Default = None)]
+    pub max_ack_delay: Option<Duration>,
+
+    #[educe(Default = 2)]
+    pub reordering_threshold: u32,
 }
 #[derive(Deserialize, Serialize, Educe)]
 #[educe(Default)]
@@ -110,24 +311,506 @@ pub struct CongestionControlConfig {
     pub initial_window: u64,
 }
 
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct MasqueConfig {
+    #[educe(Default = false)]
+    pub enabled: bool,
+}
+
+/// Exactly one of `root` and `upstream`
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct FallbackConfig {
+    /// Directory of the static site
+    pub root: Option<PathBuf>,
+    /// `host:port` of the web server the decrypted connections are handed to
+    pub upstream: Option<String>,
+}
+
+/// The level errors are logged at, by `error::ErrorClass`
 #[derive(Deserialize, Serialize, Educe, Clone)]
 #[educe(Default)]
-#[serde(deny_unknown_fields)]
+#[serde(default, deny_unknown_fields)]
+pub struct ErrorLogConfig {
+    #[educe(Default(expression = LogLevel::Debug))]
+    pub closed: LogLevel,
+    #[educe(Default(expression = LogLevel::Warn))]
+    pub network: LogLevel,
+    #[educe(Default(expression = LogLevel::Warn))]
+    pub peer: LogLevel,
+    #[educe(Default(expression = LogLevel::Warn))]
+    pub policy: LogLevel,
+    #[educe(Default(expression = LogLevel::Warn))]
+    pub local: LogLevel,
+}
+
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SyslogConfig {
+    /// Remote syslog server, the local socket at `path` is used if unset
+    #[educe(Default = None)]
+    pub server: Option<SocketAddr>,
+    #[educe(Default = "/dev/log")]
+    pub path: PathBuf,
+    pub facility: SyslogFacility,
+    #[educe(Default = "tuic-server")]
+    pub tag: String,
+}
+
+#[derive(Deserialize, Serialize, Educe, Clone)]
+#[educe(Default)]
+#[serde(default, deny_unknown_fields)]
 pub struct RestfulConfig {
     #[educe(Default(expression = "127.0.0.1:8443".parse().unwrap()))]
-    pub addr: SocketAddr,
+    pub addr: RestfulAddr,
+    #[educe(Default = 0o600)]
+    pub unix_socket_mode: u32,
+    #[educe(Default = None)]
+    pub tls_cert: Option<PathBuf>,
+    #[educe(Default = None)]
+    pub tls_key: Option<PathBuf>,
     #[educe(Default = "YOUR_SECRET_HERE")]
     pub secret: String,
     #[educe(Default = 0)]
     pub maximum_clients_per_user: u64,
+    #[educe(Default = 0)]
+    pub rate_limit: u32,
+    #[educe(Default = None)]
+    pub audit_log: Option<PathBuf>,
+    pub trusted_proxies: Vec<String>,
+    #[serde(with = "humantime_serde")]
+    #[educe(Default(expression = Duration::from_secs(60)))]
+    pub persist_interval: Duration,
+    #[educe(Default = false)]
+    pub traffic_by_destination: bool,
+    #[educe(Default = None)]
+    pub webhook: Option<WebhookConfig>,
+}
+
+#[derive(Deserialize, Serialize, Educe, Clone)]
+#[educe(Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+    pub secret: String,
+    #[educe(Default = 3)]
+    pub retries: u32,
+    #[serde(with = "humantime_serde")]
+    #[educe(Default(expression = Duration::from_secs(5)))]
+    pub timeout: Duration,
+}
+
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(deny_unknown_fields)]
+pub struct OutboundConfig {
+    #[educe(Default = 0)]
+    pub max_attempts: u32,
+
+    #[serde(with = "humantime_serde")]
+    #[educe(Default = None)]
+    pub connect_timeout: Option<Duration>,
+
+    #[educe(Default = 0)]
+    pub retries: u32,
+
+    #[serde(with = "humantime_serde")]
+    #[educe(Default(expression = Duration::from_millis(200)))]
+    pub retry_backoff: Duration,
+
+    #[educe(Default = 0)]
+    pub max_concurrent_dials: usize,
+
+    #[educe(Default = 0)]
+    pub max_concurrent_dials_per_user: usize,
+
+    #[serde(with = "humantime_serde")]
+    #[educe(Default(expression = Duration::from_millis(250)))]
+    pub happy_eyeballs_delay: Duration,
+
+    #[educe(Default = false)]
+    pub tcp_fast_open: bool,
+
+    /// How long to wait for the client's first bytes before a Fast Open
+    /// connect, which sends nothing on the SYN without them
+    #[serde(with = "humantime_serde")]
+    #[educe(Default(expression = Duration::ZERO))]
+    pub tcp_fast_open_wait: Duration,
+
+    #[educe(Default = None)]
+    pub bind_ipv4: Option<Ipv4Addr>,
+
+    #[educe(Default = None)]
+    pub bind_ipv6: Option<Ipv6Addr>,
+
+    #[educe(Default = None)]
+    pub bind_device: Option<String>,
+
+    #[educe(Default = None)]
+    pub port_range: Option<PortRange>,
+
+    #[educe(Default(expression = Vec::new()))]
+    pub proxies: Vec<OutboundProxy>,
+}
+
+/// An upstream proxy `[[acl.rules]]` can send destinations through, by its
+/// `name`
+#[derive(Deserialize, Serialize, Educe, Clone)]
+#[educe(Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutboundProxy {
+    pub name: String,
+    pub protocol: ProxyProtocol,
+    /// `host:port`
+    pub addr: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(deny_unknown_fields)]
+pub struct DnsConfig {
+    pub protocol: DnsProtocol,
+
+    pub servers: Vec<SocketAddr>,
+
+    #[educe(Default = None)]
+    pub tls_name: Option<String>,
+
+    #[serde(with = "humantime_serde")]
+    #[educe(Default(expression = Duration::from_secs(5)))]
+    pub timeout: Duration,
+
+    #[educe(Default = 1024)]
+    pub cache_size: usize,
+
+    #[serde(with = "humantime_serde")]
+    #[educe(Default = None)]
+    pub min_ttl: Option<Duration>,
+
+    #[serde(with = "humantime_serde")]
+    #[educe(Default = None)]
+    pub max_ttl: Option<Duration>,
+}
+
+#[derive(Deserialize, Serialize, Educe, Clone)]
+#[educe(Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlocklistConfig {
+    pub files: Vec<PathBuf>,
+    #[serde(with = "humantime_serde")]
+    #[educe(Default(expression = Duration::from_secs(3600)))]
+    pub refresh_interval: Duration,
+}
+
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SniffConfig {
+    #[educe(Default = false)]
+    pub tcp: bool,
+    #[educe(Default = false)]
+    pub quic: bool,
+    #[serde(with = "humantime_serde")]
+    #[educe(Default(expression = Duration::from_millis(300)))]
+    pub timeout: Duration,
+}
+
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AclConfig {
+    pub default: AclAction,
+    pub rules: Vec<AclRule>,
+    pub geoip: Option<GeoIpConfig>,
+}
+
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AclRule {
+    pub action: AclAction,
+    pub domain: Vec<String>,
+    pub ip: Vec<String>,
+    pub port: Vec<String>,
+    /// The `name` of the `[[outbound.proxies]]` allowed destinations are
+    /// dialed through
+    pub proxy: Option<String>,
+}
+
+/// Clients and destinations allowed or denied by country, ISO 3166-1 alpha-2
+/// codes looked up in a MaxMind database
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct GeoIpConfig {
+    pub database: PathBuf,
+    pub allow_clients: Vec<String>,
+    pub deny_clients: Vec<String>,
+    pub allow_destinations: Vec<String>,
+    pub deny_destinations: Vec<String>,
+}
+
+/// A destination relayed with `egress_mode = "allowlist"`, matching when
+/// every non-empty criterion matches
+#[derive(Deserialize, Serialize, Educe, Clone)]
+#[educe(Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct EgressRule {
+    pub domain: Vec<String>,
+    pub ip: Vec<String>,
+    pub port: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AbuseConfig {
+    pub rules: Vec<AbuseRule>,
+    pub exec: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Educe, Clone)]
+#[educe(Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AbuseRule {
+    pub event: AbuseEvent,
+    #[educe(Default = 10)]
+    pub count: u32,
+    #[serde(with = "humantime_serde")]
+    #[educe(Default(expression = Duration::from_secs(60)))]
+    pub window: Duration,
+    pub action: AbuseAction,
+    #[serde(with = "humantime_serde")]
+    #[educe(Default(expression = Duration::from_secs(3600)))]
+    pub duration: Duration,
+}
+
+#[derive(Deserialize, Serialize, Educe, Clone)]
+#[educe(Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct PortScanConfig {
+    #[educe(Default = false)]
+    pub enabled: bool,
+    #[serde(with = "humantime_serde")]
+    #[educe(Default(expression = Duration::from_secs(60)))]
+    pub window: Duration,
+    #[educe(Default = 30)]
+    pub min_targets: usize,
+    #[educe(Default = 0.7)]
+    pub min_failure_ratio: f64,
+    pub action: ScanAction,
+    #[serde(with = "humantime_serde")]
+    #[educe(Default(expression = Duration::from_secs(600)))]
+    pub throttle_duration: Duration,
+    #[serde(with = "humantime_serde")]
+    #[educe(Default(expression = Duration::from_secs(1)))]
+    pub throttle_delay: Duration,
+    pub exec: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Educe, Clone)]
+#[educe(Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Fail2banConfig {
+    #[educe(Default = false)]
+    pub enabled: bool,
+    #[educe(Default = 5)]
+    pub max_failures: usize,
+    #[serde(with = "humantime_serde")]
+    #[educe(Default(expression = Duration::from_secs(600)))]
+    pub window: Duration,
+    #[serde(with = "humantime_serde")]
+    #[educe(Default(expression = Duration::from_secs(3600)))]
+    pub ban_duration: Duration,
+}
+
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecurityLogConfig {
+    /// Appended to
+    pub path: Option<PathBuf>,
+    /// A Unix datagram socket, one line per datagram
+    pub socket: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountingConfig {
+    #[educe(Default = false)]
+    pub count_overhead: bool,
+    #[educe(Default = false)]
+    pub count_fragments: bool,
+}
+
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScriptConfig {
+    pub path: PathBuf,
+    #[serde(with = "humantime_serde")]
+    #[educe(Default(expression = Duration::from_millis(10)))]
+    pub timeout: Duration,
+    #[educe(Default = 100000)]
+    pub max_operations: u64,
+    #[educe(Default = 4096)]
+    pub max_data_size: usize,
+}
+
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClusterConfig {
+    /// Defaults to `address`
+    pub name: String,
+    /// The address clients use to connect to this node
+    pub address: String,
+    #[educe(Default = 1)]
+    pub weight: u32,
+    /// RESTful addresses of the other nodes
+    pub peers: Vec<SocketAddr>,
+    /// RESTful secret of the other nodes
+    pub secret: String,
+    #[serde(with = "humantime_serde")]
+    #[educe(Default(expression = Duration::from_secs(5)))]
+    pub interval: Duration,
+}
+
+#[derive(Clone, Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SubscriptionConfig {
+    /// The address clients connect to, the port defaults to the listening one
+    pub address: String,
+    /// Defaults to the host of `address`
+    pub sni: String,
+    #[educe(Default = "tuic")]
+    pub name: String,
+}
+
+/// Settings of one user replacing the global ones, any subset of them
+#[derive(Deserialize, Serialize, Educe, Clone)]
+#[educe(Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserOverrides {
+    /// Replaces being listed in `priority_users`
+    pub priority: Option<bool>,
+    #[serde(with = "humantime_serde")]
+    pub max_connection_lifetime: Option<Duration>,
+    pub per_connection_traffic_quota: Option<u64>,
+    pub max_udp_sessions_per_connection: Option<usize>,
+    /// Replaces `max_concurrent_streams_per_user`
+    pub max_concurrent_streams: Option<usize>,
+    /// Replaces `outbound.max_concurrent_dials_per_user`
+    pub max_concurrent_dials: Option<usize>,
+    /// Whether the user may relay UDP at all
+    pub udp_relay: Option<bool>,
+    /// Bytes the user may relay both ways, across connections, until
+    /// `/reset_traffic`
+    pub quota: Option<ByteSize>,
+    /// Only these destinations are relayed for the user, on top of `[acl]`
+    /// and `egress_mode`
+    pub allowed_destinations: Option<Vec<EgressRule>>,
+}
+
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Asked for the users missing from `users`
+    #[educe(Default = None)]
+    pub http: Option<HttpAuthConfig>,
+}
+
+#[derive(Deserialize, Serialize, Educe)]
+#[educe(Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpAuthConfig {
+    pub url: String,
+    pub secret: String,
+    /// How long answers are cached, unknown users included
+    #[serde(with = "humantime_serde")]
+    #[educe(Default(expression = Duration::from_secs(60)))]
+    pub cache_ttl: Duration,
+    #[serde(with = "humantime_serde")]
+    #[educe(Default(expression = Duration::from_secs(5)))]
+    pub timeout: Duration,
+}
+
+/// Either a TCP socket address, or a Unix domain socket path prefixed with
+/// `unix:`
+#[derive(Clone, Debug)]
+pub enum RestfulAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for RestfulAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("empty Unix domain socket path".into());
+            }
+            Ok(Self::Unix(PathBuf::from(path)))
+        } else {
+            s.parse()
+                .map(Self::Tcp)
+                .map_err(|err| format!("invalid RESTful address {s}: {err}"))
+        }
+    }
+}
+
+impl Display for RestfulAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl Serialize for RestfulAddr {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for RestfulAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(DeError::custom)
+    }
 }
 
 impl Config {
+    /// The local addresses of UDP relay sockets, `udp_relay_bind_ipv4` and
+    /// `udp_relay_bind_ipv6` falling back to the ones of `[outbound]`
+    pub fn udp_relay_bind(&self) -> (Ipv4Addr, Ipv6Addr) {
+        (
+            self.udp_relay_bind_ipv4
+                .or(self.outbound.bind_ipv4)
+                .unwrap_or(Ipv4Addr::UNSPECIFIED),
+            self.udp_relay_bind_ipv6
+                .or(self.outbound.bind_ipv6)
+                .unwrap_or(Ipv6Addr::UNSPECIFIED),
+        )
+    }
+
     pub fn full_example() -> Self {
         Self {
             users: {
                 let mut users = HashMap::new();
-                users.insert(Uuid::new_v4(), "YOUR_USER_PASSWD_HERE".into());
+                users.insert(Uuid::new_v4(), String::from("YOUR_USER_PASSWD_HERE").into());
                 users
             },
             restful: Some(RestfulConfig::default()),
@@ -140,13 +823,18 @@ impl Config {
 impl From<OldConfig> for Config {
     fn from(value: OldConfig) -> Self {
         Self {
-            server: value.server,
-            users: value.users,
+            server: value.server.into(),
+            users: value
+                .users
+                .into_iter()
+                .map(|(uuid, password)| (uuid, password.into()))
+                .collect(),
             tls: TlsConfig {
                 self_sign: value.self_sign,
                 certificate: value.certificate,
                 private_key: value.private_key,
                 alpn: value.alpn,
+                ..Default::default()
             },
             udp_relay_ipv6: value.udp_relay_ipv6,
             zero_rtt_handshake: value.zero_rtt_handshake,
@@ -158,7 +846,7 @@ impl From<OldConfig> for Config {
             max_external_packet_size: value.max_external_packet_size,
             restful: if value.restful_server.is_some() {
                 Some(RestfulConfig {
-                    addr: value.restful_server.unwrap(),
+                    addr: RestfulAddr::Tcp(value.restful_server.unwrap()),
                     ..Default::default()
                 })
             } else {
@@ -177,6 +865,7 @@ impl From<OldConfig> for Config {
                 send_window: value.send_window,
                 receive_window: value.receive_window,
                 max_idle_time: value.max_idle_time,
+                ..Default::default()
             },
             ..Default::default()
         }
@@ -196,6 +885,18 @@ pub enum LogLevel {
     Error,
     Off,
 }
+impl Display for LogLevel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.write_str(match self {
+            LogLevel::Trace => "trace",
+            LogLevel::Debug => "debug",
+            LogLevel::Info => "info",
+            LogLevel::Warn => "warn",
+            LogLevel::Error => "error",
+            LogLevel::Off => "off",
+        })
+    }
+}
 impl From<LogLevel> for LevelFilter {
     fn from(value: LogLevel) -> Self {
         match value {
@@ -209,9 +910,187 @@ impl From<LogLevel> for LevelFilter {
     }
 }
 
-pub async fn parse_config(args: ArgsOs) -> Result<Config, ConfigError> {
+/// Keys whose string values are credentials, wherever they appear
+const SECRET_KEYS: &[&str] = &["secret", "password"];
+
+/// Replace the passwords of `users` and every non-empty `secret` and
+/// `password` of a serialized config, so it can be shown
+pub fn redact(value: &mut serde_json::Value) {
+    use serde_json::Value;
+
+    const REDACTED: &str = "[redacted]";
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                match value {
+                    Value::String(secret)
+                        if SECRET_KEYS.contains(&key.as_str()) && !secret.is_empty() =>
+                    {
+                        *secret = REDACTED.to_owned();
+                    }
+                    Value::Object(users) if key == "users" => {
+                        for passwords in users.values_mut() {
+                            match passwords {
+                                Value::Object(labelled) => labelled
+                                    .values_mut()
+                                    .for_each(|password| *password = REDACTED.into()),
+                                password => *password = REDACTED.into(),
+                            }
+                        }
+                    }
+                    value => redact(value),
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+/// Tables whose keys are user data rather than options
+const DYNAMIC_TABLES: &[&str] = &["users", "user_overrides"];
+
+/// A config with every optional section present and one element in every
+/// array of tables, so that serializing it yields every key the server
+/// understands
+fn schema() -> toml::Table {
+    let mut cfg = Config::full_example();
+    cfg.tls.certificates = vec![SniCertificate {
+        sni: String::new(),
+        cert: PathBuf::new(),
+        key: PathBuf::new(),
+    }];
+    cfg.tls.ticket_lifetime = Some(Duration::ZERO);
+    cfg.syslog.server = Some(SocketAddr::from(([0, 0, 0, 0], 0)));
+    cfg.quic.ack_frequency = Some(AckFrequencyConfig {
+        max_ack_delay: Some(Duration::ZERO),
+        ..Default::default()
+    });
+    if let Some(restful) = &mut cfg.restful {
+        restful.audit_log = Some(PathBuf::new());
+        restful.tls_cert = Some(PathBuf::new());
+        restful.tls_key = Some(PathBuf::new());
+        restful.webhook = Some(WebhookConfig::default());
+    }
+    cfg.blocklist = Some(BlocklistConfig::default());
+    cfg.outbound.connect_timeout = Some(Duration::ZERO);
+    cfg.outbound.bind_ipv4 = Some(Ipv4Addr::UNSPECIFIED);
+    cfg.outbound.bind_ipv6 = Some(Ipv6Addr::UNSPECIFIED);
+    cfg.outbound.bind_device = Some(String::new());
+    cfg.outbound.port_range = Some(PortRange { start: 1, end: 1 });
+    cfg.outbound.proxies = vec![OutboundProxy {
+        username: Some(String::new()),
+        password: Some(String::new()),
+        ..Default::default()
+    }];
+    cfg.dns.tls_name = Some(String::new());
+    cfg.dns.min_ttl = Some(Duration::ZERO);
+    cfg.dns.max_ttl = Some(Duration::ZERO);
+    cfg.udp_relay_bind_ipv4 = Some(Ipv4Addr::UNSPECIFIED);
+    cfg.udp_relay_bind_ipv6 = Some(Ipv6Addr::UNSPECIFIED);
+    cfg.udp_relay_socket_buffer = Some(ByteSize(0));
+    cfg.outbound_port_range = Some(PortRange { start: 1, end: 1 });
+    cfg.routing_script = Some(ScriptConfig::default());
+    cfg.cluster = Some(ClusterConfig::default());
+    cfg.subscription = Some(SubscriptionConfig::default());
+    cfg.auth.http = Some(HttpAuthConfig::default());
+    cfg.users_db = Some(PathBuf::new());
+    cfg.acl.geoip = Some(GeoIpConfig::default());
+    cfg.acl.rules = vec![AclRule {
+        proxy: Some(String::new()),
+        ..Default::default()
+    }];
+    cfg.egress_allowlist = vec![EgressRule::default()];
+    cfg.abuse.rules = vec![AbuseRule::default()];
+    cfg.fallback_h3_upstream = Some(String::new());
+    cfg.fallback = Some(FallbackConfig {
+        root: Some(PathBuf::new()),
+        upstream: Some(String::new()),
+    });
+    cfg.security_log = Some(SecurityLogConfig {
+        path: Some(PathBuf::new()),
+        socket: Some(PathBuf::new()),
+    });
+    toml::Table::try_from(cfg).expect("config must serialize")
+}
+
+/// Remove the keys of `table` that aren't in `schema`, collecting a warning
+/// with the closest known key as suggestion for each of them
+fn strip_unknown_keys(
+    table: &mut toml::Table,
+    schema: &toml::Table,
+    prefix: &str,
+    warnings: &mut Vec<String>,
+) {
+    table.retain(|key, value| {
+        let path = format!("{prefix}{key}");
+        let Some(known) = schema.get(key) else {
+            let suggestion = schema
+                .keys()
+                .map(|known| (edit_distance(key, known), known))
+                .filter(|(dist, known)| *dist <= known.len() / 3 + 1)
+                .min_by_key(|(dist, _)| *dist);
+            warnings.push(match suggestion {
+                Some((_, known)) => {
+                    format!("ignoring unknown config key `{path}`, did you mean `{prefix}{known}`?")
+                }
+                None => format!("ignoring unknown config key `{path}`"),
+            });
+            return false;
+        };
+        match (value, known) {
+            (toml::Value::Table(table), toml::Value::Table(known))
+                if !DYNAMIC_TABLES.contains(&path.as_str()) =>
+            {
+                strip_unknown_keys(table, known, &format!("{path}."), warnings);
+            }
+            // arrays of tables, like `[[acl.rules]]`, against their one element
+            // in the schema
+            (toml::Value::Array(values), toml::Value::Array(known)) => {
+                if let Some(toml::Value::Table(known)) = known.first() {
+                    for (i, value) in values.iter_mut().enumerate() {
+                        if let toml::Value::Table(table) = value {
+                            strip_unknown_keys(table, known, &format!("{path}[{i}]."), warnings);
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+        true
+    });
+}
+
+/// Levenshtein distance between two keys
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.as_bytes();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.bytes().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cur = row[j + 1];
+            row[j + 1] = (prev + usize::from(ca != *cb)).min(row[j] + 1).min(cur + 1);
+            prev = cur;
+        }
+    }
+    row[b.len()]
+}
+
+/// Returns the config along with the warnings that should be logged once
+/// logging is set up
+pub async fn parse_config(
+    args: impl IntoIterator<Item = OsString> + 'static,
+) -> Result<(Config, Vec<String>), ConfigError> {
     let mut parser = Parser::from_iter(args);
     let mut path = None;
+    let mut lenient = false;
+    let mut fix = false;
+    let mut export = false;
+    let mut export_args = ExportArgs::default();
+    let mut update = false;
+    let mut update_args = UpdateArgs::default();
+    let mut warnings = Vec::new();
 
     while let Some(arg) = parser.next()? {
         match arg {
@@ -232,26 +1111,335 @@ pub async fn parse_config(args: ArgsOs) -> Result<Config, ConfigError> {
                 warn!("Generating a example configuration to config.toml......");
                 let example = Config::full_example();
                 let example = toml::to_string_pretty(&example).unwrap();
-                tokio::fs::write("config.toml", example).await?;
+                std::fs::write("config.toml", example)?;
                 return Err(ConfigError::Help("Done")); // TODO refactor
             }
+            Arg::Long("lenient-config") => lenient = true,
+            Arg::Long("fix") => fix = true,
+            Arg::Value(cmd) if cmd == "export-client" && !export => export = true,
+            Arg::Long("uuid") if export => export_args.uuid = Some(parser.value()?.parse()?),
+            Arg::Long("format") if export => export_args.format = Some(parser.value()?.parse()?),
+            Arg::Long("address") if export => export_args.address = Some(parser.value()?.string()?),
+            Arg::Long("label") if export => export_args.label = Some(parser.value()?.string()?),
+            Arg::Value(cmd) if cmd == "self-update" && !update => update = true,
+            Arg::Long("check-only") if update => update_args.check_only = true,
+            Arg::Long("public-key") if update => {
+                update_args.public_key = Some(parser.value()?.string()?)
+            }
+            Arg::Long("feed") if update => update_args.feed = Some(parser.value()?.string()?),
             _ => return Err(ConfigError::Argument(arg.unexpected())),
         }
     }
 
+    // the parser isn't `Send`, keep it from being held across awaits
+    drop(parser);
+
+    // needs no config, so that a broken one can be fixed by updating
+    if update {
+        return Err(match update::run(update_args).await {
+            Ok(msg) => ConfigError::Updated(msg),
+            Err(err) => ConfigError::Update(err),
+        });
+    }
     if path.is_none() {
         return Err(ConfigError::NoConfig);
     }
     let path = path.unwrap().to_string_lossy().to_string();
-    let config = if path.ends_with(".toml") || std::env::var("TUIC_FORCE_TOML").is_ok() {
-        Figment::from(Serialized::defaults(Config::default()))
-            .merge(Toml::file(path))
-            .extract()
-            .unwrap()
+    let is_toml = path.ends_with(".toml") || std::env::var("TUIC_FORCE_TOML").is_ok();
+    let mut config: Config = if is_toml {
+        let figment = Figment::from(Serialized::defaults(Config::default()));
+        if lenient {
+            let mut table: toml::Table = toml::from_str(&tokio::fs::read_to_string(&path).await?)?;
+            strip_unknown_keys(&mut table, &schema(), "", &mut warnings);
+            figment.merge(Serialized::defaults(table))
+        } else {
+            figment.merge(Toml::file(&path))
+        }
+        .extract()?
     } else {
         let config_text = tokio::fs::read(&path).await?;
         let config: OldConfig = serde_json::from_slice(&config_text)?;
         config.into()
     };
-    Ok(config)
+    config.config_path = PathBuf::from(&path);
+    if let Some(range) = config.outbound_port_range.take() {
+        match config.outbound.port_range {
+            Some(nested) if nested != range => warnings.push(format!(
+                "ignoring `outbound_port_range = \"{range}\"`, `outbound.port_range = \
+                 \"{nested}\"` takes precedence"
+            )),
+            _ => config.outbound.port_range = Some(range),
+        }
+    }
+    if export {
+        return Err(ConfigError::Export(export_args.render(&config)?));
+    }
+    if fix {
+        if !is_toml {
+            return Err(ConfigError::Argument(
+                "--fix: only TOML config files can be fixed".into(),
+            ));
+        }
+        return Err(ConfigError::Fixed(
+            validate::fix(Path::new(&path), &config).await?,
+        ));
+    }
+    if config.registration_mode {
+        if config.auth.http.is_none() {
+            return Err(ConfigError::RegistrationWithoutBackend);
+        }
+        if !config.users.is_empty() || config.users_db.is_some() {
+            warnings.push(
+                "registration mode: `[users]` and `users_db` are ignored, every user is \
+                 authenticated against `[auth.http]`"
+                    .into(),
+            );
+        }
+    } else if config.users.is_empty() && config.auth.http.is_none() && config.users_db.is_none() {
+        return Err(ConfigError::NoUsers);
+    }
+    warnings.extend(validate::check(&config));
+    Ok((config, warnings))
+}
+
+#[derive(Default)]
+struct ExportArgs {
+    uuid: Option<Uuid>,
+    format: Option<share::Format>,
+    address: Option<String>,
+    label: Option<String>,
+}
+
+impl ExportArgs {
+    /// The client configuration of a user, from `export-client`
+    fn render(self, cfg: &Config) -> Result<String, lexopt::Error> {
+        let uuid = self.uuid.ok_or("export-client: missing --uuid")?;
+        let format = self.format.ok_or("export-client: missing --format")?;
+        let passwords = cfg
+            .users
+            .get(&uuid)
+            .ok_or_else(|| format!("export-client: no user {uuid} in the config"))?;
+        let password = match &self.label {
+            Some(label) => passwords
+                .get(Some(label))
+                .ok_or_else(|| format!("export-client: user {uuid} has no password {label}"))?,
+            None => passwords
+                .primary()
+                .ok_or_else(|| format!("export-client: user {uuid} has no password"))?,
+        };
+        let mut sub = cfg.subscription.clone().unwrap_or_default();
+        if let Some(address) = self.address {
+            sub.address = address;
+        }
+        if sub.address.is_empty() {
+            return Err(
+                "export-client: missing --address, and no `subscription.address` in the config"
+                    .into(),
+            );
+        }
+
+        let share = share::Share::new(cfg, &sub, uuid, password);
+        Ok(match format {
+            // a single link is more useful than a subscription body here
+            share::Format::V2rayN => share.link(),
+            format => share.render(format),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::de::{Visitor, value::Error as ValueError};
+
+    use super::*;
+
+    /// The fields `T` deserializes, as `deserialize_struct` is told them
+    fn fields<'de, T: Deserialize<'de>>() -> &'static [&'static str] {
+        struct Fields<'a>(&'a mut &'static [&'static str]);
+
+        impl<'de> Deserializer<'de> for Fields<'_> {
+            type Error = ValueError;
+
+            serde::forward_to_deserialize_any! {
+                bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes
+                byte_buf option unit unit_struct newtype_struct seq tuple tuple_struct map enum
+                identifier ignored_any
+            }
+
+            fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+                Err(ValueError::custom("not a struct"))
+            }
+
+            fn deserialize_struct<V: Visitor<'de>>(
+                self,
+                _: &'static str,
+                fields: &'static [&'static str],
+                _: V,
+            ) -> Result<V::Value, Self::Error> {
+                *self.0 = fields;
+                Err(ValueError::custom("only the fields are needed"))
+            }
+        }
+
+        let mut fields: &[&str] = &[];
+        _ = T::deserialize(Fields(&mut fields));
+        fields
+    }
+
+    #[test]
+    fn schema_has_every_key() {
+        let schema = schema();
+        let sections = [
+            ("", fields::<Config>()),
+            ("syslog", fields::<SyslogConfig>()),
+            ("error_log", fields::<ErrorLogConfig>()),
+            ("tls", fields::<TlsConfig>()),
+            ("restful", fields::<RestfulConfig>()),
+            ("restful.webhook", fields::<WebhookConfig>()),
+            ("quic", fields::<QuicConfig>()),
+            (
+                "quic.congestion_control",
+                fields::<CongestionControlConfig>(),
+            ),
+            ("quic.ack_frequency", fields::<AckFrequencyConfig>()),
+            ("masque", fields::<MasqueConfig>()),
+            ("fallback", fields::<FallbackConfig>()),
+            ("blocklist", fields::<BlocklistConfig>()),
+            ("outbound", fields::<OutboundConfig>()),
+            ("dns", fields::<DnsConfig>()),
+            ("routing_script", fields::<ScriptConfig>()),
+            ("sniff", fields::<SniffConfig>()),
+            ("acl", fields::<AclConfig>()),
+            ("acl.geoip", fields::<GeoIpConfig>()),
+            ("abuse", fields::<AbuseConfig>()),
+            ("port_scan", fields::<PortScanConfig>()),
+            ("fail2ban", fields::<Fail2banConfig>()),
+            ("security_log", fields::<SecurityLogConfig>()),
+            ("accounting", fields::<AccountingConfig>()),
+            ("cluster", fields::<ClusterConfig>()),
+            ("subscription", fields::<SubscriptionConfig>()),
+            ("auth", fields::<AuthConfig>()),
+            ("auth.http", fields::<HttpAuthConfig>()),
+            ("tls.certificates[]", fields::<SniCertificate>()),
+            ("outbound.proxies[]", fields::<OutboundProxy>()),
+            ("acl.rules[]", fields::<AclRule>()),
+            ("egress_allowlist[]", fields::<EgressRule>()),
+            ("abuse.rules[]", fields::<AbuseRule>()),
+        ];
+        for (section, fields) in sections {
+            assert!(!fields.is_empty(), "no fields found for `{section}`");
+            let table =
+                section
+                    .split('.')
+                    .filter(|key| !key.is_empty())
+                    .fold(&schema, |table, key| {
+                        // `key[]` is the element of an array of tables
+                        let value = match key.strip_suffix("[]") {
+                            Some(key) => match table.get(key) {
+                                Some(toml::Value::Array(values)) => values.first(),
+                                _ => None,
+                            },
+                            None => table.get(key),
+                        };
+                        match value {
+                            Some(toml::Value::Table(table)) => table,
+                            _ => panic!("`{section}` is missing from the schema"),
+                        }
+                    });
+            for field in fields {
+                assert!(
+                    table.contains_key(*field),
+                    "`{section}.{field}` is missing from the schema"
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn outbound_port_range_alias() {
+        let path =
+            std::env::temp_dir().join(format!("tuic-port-range-{}.toml", std::process::id()));
+        let parse = |extra: &str| {
+            std::fs::write(
+                &path,
+                format!(
+                    "outbound_port_range = \
+                     \"40000-50000\"\n{extra}\n[users]\n00000000-0000-0000-0000-000000000760 = \
+                     \"password\"\n"
+                ),
+            )
+            .unwrap();
+            let args = ["tuic-server", "-c", path.to_str().unwrap()].map(OsString::from);
+            parse_config(args)
+        };
+
+        let (cfg, _) = parse("").await.unwrap();
+        assert_eq!(cfg.outbound.port_range, "40000-50000".parse().ok());
+        assert_eq!(cfg.outbound_port_range, None);
+
+        let (cfg, warnings) = parse("[outbound]\nport_range = \"1000-2000\"")
+            .await
+            .unwrap();
+        assert_eq!(cfg.outbound.port_range, "1000-2000".parse().ok());
+        assert!(warnings.iter().any(|w| w.contains("outbound_port_range")));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn registration_mode_needs_http_auth() {
+        let path =
+            std::env::temp_dir().join(format!("tuic-registration-{}.toml", std::process::id()));
+        let parse = |toml: &str| {
+            std::fs::write(&path, format!("registration_mode = true\n{toml}")).unwrap();
+            let args = ["tuic-server", "-c", path.to_str().unwrap()].map(OsString::from);
+            parse_config(args)
+        };
+
+        assert!(matches!(
+            parse("").await,
+            Err(ConfigError::RegistrationWithoutBackend)
+        ));
+
+        let (_, warnings) = parse("[auth.http]\nurl = \"http://127.0.0.1:8080/auth\"")
+            .await
+            .unwrap();
+        assert!(!warnings.iter().any(|w| w.contains("registration mode")));
+
+        let (_, warnings) = parse(
+            "[auth.http]\nurl = \"http://127.0.0.1:8080/auth\"\n[users]\n\
+             00000000-0000-0000-0000-000000000762 = \"password\"",
+        )
+        .await
+        .unwrap();
+        assert!(warnings.iter().any(|w| w.contains("registration mode")));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unknown_keys_are_stripped_from_arrays() {
+        let mut table: toml::Table = toml::from_str(
+            r#"
+            [[acl.rules]]
+            action = "deny"
+            port = ["25"]
+
+            [[acl.rules]]
+            action = "allow"
+            domian = ["example.com"]
+            "#,
+        )
+        .unwrap();
+        let mut warnings = Vec::new();
+        strip_unknown_keys(&mut table, &schema(), "", &mut warnings);
+        assert_eq!(
+            warnings,
+            [
+                "ignoring unknown config key `acl.rules[1].domian`, did you mean \
+                 `acl.rules[1].domain`?"
+            ]
+        );
+        let rules = table["acl"]["rules"].as_array().unwrap();
+        assert!(rules[0].get("port").is_some());
+        assert!(rules[1].get("domian").is_none());
+    }
 }
@@ -0,0 +1,105 @@
+use tuic::Address;
+
+use crate::config::AccountingConfig;
+
+/// VER, TYPE, ASSOC_ID, PKT_ID, FRAG_TOTAL, FRAG_ID and SIZE of a `Packet`
+/// header, followed by ADDR
+const HEADER_LEN: usize = 1 + 1 + 2 + 2 + 1 + 1 + 2;
+
+/// Bytes to account for a fragment from the client as soon as it arrives,
+/// whether or not its packet is ever reassembled
+pub fn fragment(cfg: &AccountingConfig, addr: &Address, payload: usize) -> u64 {
+    if !cfg.count_fragments {
+        return 0;
+    }
+    with_overhead(cfg, payload, HEADER_LEN + addr.len())
+}
+
+/// Bytes to account for a packet from the client once reassembled from
+/// `frag_total` fragments, of which only the first carries `addr`
+pub fn reassembled(cfg: &AccountingConfig, addr: &Address, frag_total: u8, payload: usize) -> u64 {
+    if cfg.count_fragments {
+        return 0;
+    }
+    let frag_total = usize::from(frag_total.max(1));
+    let headers = HEADER_LEN * frag_total + addr.len() + Address::None.len() * (frag_total - 1);
+    with_overhead(cfg, payload, headers)
+}
+
+/// Bytes to account for a packet relayed to the client, `sent` being what
+/// was written to the connection including the headers of every fragment
+pub fn sent(cfg: &AccountingConfig, payload: usize, sent: usize) -> u64 {
+    with_overhead(cfg, payload, sent.saturating_sub(payload))
+}
+
+fn with_overhead(cfg: &AccountingConfig, payload: usize, overhead: usize) -> u64 {
+    if cfg.count_overhead {
+        (payload + overhead) as u64
+    } else {
+        payload as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use super::*;
+
+    fn cfg(count_overhead: bool, count_fragments: bool) -> AccountingConfig {
+        AccountingConfig {
+            count_overhead,
+            count_fragments,
+        }
+    }
+
+    fn addr() -> Address {
+        Address::SocketAddress(SocketAddr::from((Ipv4Addr::LOCALHOST, 53)))
+    }
+
+    /// A 3000-byte packet sent as fragments of 1200, 1200 and 600 bytes
+    fn fragments(cfg: &AccountingConfig) -> u64 {
+        fragment(cfg, &addr(), 1200)
+            + fragment(cfg, &Address::None, 1200)
+            + fragment(cfg, &Address::None, 600)
+    }
+
+    #[test]
+    fn payload_is_counted_once_per_packet() {
+        let cfg = cfg(false, false);
+        assert_eq!(fragments(&cfg), 0);
+        assert_eq!(reassembled(&cfg, &addr(), 3, 3000), 3000);
+    }
+
+    #[test]
+    fn payload_is_counted_once_per_fragment() {
+        let cfg = cfg(false, true);
+        assert_eq!(fragments(&cfg), 3000);
+        assert_eq!(reassembled(&cfg, &addr(), 3, 3000), 0);
+    }
+
+    #[test]
+    fn overhead_matches_between_fragments_and_packets() {
+        let per_fragment = fragments(&cfg(true, true));
+        let per_packet = reassembled(&cfg(true, false), &addr(), 3, 3000);
+        assert_eq!(per_fragment, per_packet);
+        // 3 headers, an IPv4 address in the first and `Address::None` in the others
+        assert_eq!(per_packet, 3000 + 3 * HEADER_LEN as u64 + 7 + 2);
+    }
+
+    #[test]
+    fn unfragmented_packet() {
+        assert_eq!(reassembled(&cfg(false, false), &addr(), 1, 100), 100);
+        assert_eq!(
+            reassembled(&cfg(true, false), &addr(), 1, 100),
+            100 + HEADER_LEN as u64 + 7
+        );
+    }
+
+    #[test]
+    fn sent_overhead() {
+        assert_eq!(sent(&cfg(false, false), 3000, 3039), 3000);
+        assert_eq!(sent(&cfg(false, true), 3000, 3039), 3000);
+        assert_eq!(sent(&cfg(true, false), 3000, 3039), 3039);
+    }
+}
@@ -0,0 +1,149 @@
+use std::{
+    io::Result as IoResult,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time,
+};
+
+use super::stats::ConnectionStats;
+
+/// When either side of a relayed stream last made progress
+pub struct Activity {
+    started_at: Instant,
+    /// Milliseconds since `started_at`
+    last: AtomicU64,
+    /// Whether any byte was relayed yet
+    relayed: AtomicBool,
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Self {
+            started_at: Instant::now(),
+            last: AtomicU64::new(0),
+            relayed: AtomicBool::new(false),
+        }
+    }
+
+    fn touch(&self) {
+        let now = self.started_at.elapsed().as_millis() as u64;
+        self.last.store(now, Ordering::Relaxed);
+        self.relayed.store(true, Ordering::Relaxed);
+    }
+
+    fn idle(&self) -> Duration {
+        self.started_at
+            .elapsed()
+            .saturating_sub(Duration::from_millis(self.last.load(Ordering::Relaxed)))
+    }
+
+    /// Resolves once no progress has been made for `timeout`, never if it is
+    /// zero
+    pub async fn idle_for(&self, timeout: Duration) {
+        if timeout.is_zero() {
+            return std::future::pending().await;
+        }
+        loop {
+            let idle = self.idle();
+            if idle >= timeout {
+                return;
+            }
+            time::sleep(timeout - idle).await;
+        }
+    }
+
+    /// Resolves if no byte was relayed within `timeout` of the start, never if
+    /// one was or `timeout` is zero
+    pub async fn silent_for(&self, timeout: Duration) {
+        if !timeout.is_zero() {
+            time::sleep(timeout.saturating_sub(self.started_at.elapsed())).await;
+            if !self.relayed.load(Ordering::Relaxed) {
+                return;
+            }
+        }
+        std::future::pending().await
+    }
+}
+
+/// Wraps one side of a relayed stream, recording its progress in an
+/// [`Activity`] and counting the bytes read from it
+pub struct Tracked<'a, S> {
+    inner: S,
+    activity: &'a Activity,
+    read: &'a AtomicU64,
+    /// Where to count the time writes spend blocked, with when the current
+    /// blocked write started
+    blocked: Option<(&'a ConnectionStats, Option<Instant>)>,
+}
+
+impl<'a, S> Tracked<'a, S> {
+    pub fn new(inner: S, activity: &'a Activity, read: &'a AtomicU64) -> Self {
+        Self {
+            inner,
+            activity,
+            read,
+            blocked: None,
+        }
+    }
+
+    /// Count the time writes spend blocked, for the client's side
+    pub fn count_blocked(mut self, stats: &'a ConnectionStats) -> Self {
+        self.blocked = Some((stats, None));
+        self
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Tracked<'_, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<IoResult<()>> {
+        let filled = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = buf.filled().len() - filled;
+        if read != 0 {
+            self.read.fetch_add(read as u64, Ordering::Relaxed);
+            self.activity.touch();
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Tracked<'_, S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<IoResult<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if matches!(res, Poll::Ready(Ok(n)) if n != 0) {
+            self.activity.touch();
+        }
+        if let Some((stats, since)) = &mut self.blocked {
+            match (&res, *since) {
+                (Poll::Pending, None) => *since = Some(Instant::now()),
+                (Poll::Ready(_), Some(start)) => {
+                    stats.add_send_blocked(start.elapsed());
+                    *since = None;
+                }
+                _ => {}
+            }
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
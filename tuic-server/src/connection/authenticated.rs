@@ -4,7 +4,7 @@ use std::{
     sync::Arc,
 };
 
-use arc_swap::ArcSwap;
+use arc_swap::{ArcSwap, ArcSwapOption};
 use tokio::sync::{RwLock as AsyncRwLock, broadcast::Sender};
 use uuid::Uuid;
 
@@ -14,6 +14,8 @@ pub struct Authenticated(Arc<AuthenticatedInner>);
 struct AuthenticatedInner {
     /// uuid that waiting for auth
     uuid: ArcSwap<Option<Uuid>>,
+    /// label of the password it authenticated with, if the user has several
+    label: ArcSwapOption<String>,
     tx: AsyncRwLock<Option<Sender<()>>>,
 }
 
@@ -24,12 +26,14 @@ impl Authenticated {
 
         Self(Arc::new(AuthenticatedInner {
             uuid: ArcSwap::new(None.into()),
+            label: ArcSwapOption::empty(),
             tx: AsyncRwLock::new(Some(tx)),
         }))
     }
 
     /// invoking 'set' means auth success
-    pub async fn set(&self, uuid: Uuid) {
+    pub async fn set(&self, uuid: Uuid, label: Option<String>) {
+        self.0.label.store(label.map(Arc::new));
         self.0.uuid.store(Some(uuid).into());
         if let Some(tx) = self.0.tx.read().await.deref() {
             // It will fail if there is no active receiver
@@ -45,6 +49,10 @@ impl Authenticated {
         **self.0.uuid.load()
     }
 
+    pub fn label(&self) -> Option<Arc<String>> {
+        self.0.label.load_full()
+    }
+
     /// waiting for auth success
     pub async fn wait(&self) {
         let guard = self.0.tx.read().await;
@@ -62,7 +70,11 @@ impl Authenticated {
 impl Display for Authenticated {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
         if let Some(uuid) = self.get() {
-            write!(f, "{uuid}")
+            write!(f, "{uuid}")?;
+            if let Some(label) = self.label() {
+                write!(f, " ({label})")?;
+            }
+            Ok(())
         } else {
             write!(f, "unauthenticated")
         }
@@ -0,0 +1,30 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+/// Lowercase base32, without the letters easily mistaken for digits
+const ALPHABET: &[u8; 32] = b"0123456789abcdefghjkmnpqrstvwxyz";
+
+/// A short random ID given to every connection, in its logs, RESTful
+/// outputs and hook environment. Unlike the QUIC stable ID, it isn't reused
+/// after a restart or by other nodes, so one session can be followed across
+/// systems
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CorrelationId(u64);
+
+impl CorrelationId {
+    /// Characters of the textual form, 40 bits of randomness
+    const LEN: usize = 8;
+
+    pub fn random() -> Self {
+        Self(fastrand::u64(..1 << (Self::LEN * 5)))
+    }
+}
+
+impl Display for CorrelationId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let mut buf = [0; Self::LEN];
+        for (i, c) in buf.iter_mut().enumerate() {
+            *c = ALPHABET[(self.0 >> ((Self::LEN - 1 - i) * 5)) as usize & 31];
+        }
+        f.write_str(std::str::from_utf8(&buf).unwrap())
+    }
+}
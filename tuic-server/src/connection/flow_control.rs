@@ -0,0 +1,123 @@
+//! Telling apart connections whose throughput is capped by a flow control
+//! window from the ones limited by congestion control
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use serde_json::{Value, json};
+use tokio::time;
+use tracing::info;
+
+use super::Connection;
+
+const INTERVAL: Duration = Duration::from_secs(5);
+/// Consecutive limited intervals before logging a hint
+const PERSISTENT_INTERVALS: u32 = 3;
+
+/// Seconds connections spent limited by a window, across all of them
+static DOWNLOAD_LIMITED: AtomicU64 = AtomicU64::new(0);
+static UPLOAD_LIMITED: AtomicU64 = AtomicU64::new(0);
+static HINTS: AtomicU64 = AtomicU64::new(0);
+
+/// One direction of a connection
+#[derive(Default)]
+struct Streak {
+    intervals: u32,
+}
+
+impl Streak {
+    /// Whether the limit just became persistent
+    fn update(&mut self, limited: bool) -> bool {
+        if !limited {
+            self.intervals = 0;
+            return false;
+        }
+        self.intervals += 1;
+        self.intervals == PERSISTENT_INTERVALS
+    }
+}
+
+impl Connection {
+    /// Sample the connection every few seconds, logging a hint once either
+    /// direction has been limited by a window for a while.
+    ///
+    /// Downloads are limited when writing to the client's streams keeps
+    /// blocking although the congestion window isn't used up, by the client's
+    /// receive window or `quic.send_window`. Uploads are limited when the
+    /// client reports being blocked with `DATA_BLOCKED` or
+    /// `STREAM_DATA_BLOCKED` frames, which not all clients send
+    pub(super) async fn watch_flow_control(self) {
+        let mut last = self.inner.stats();
+        let mut last_blocked = self.stats.send_blocked();
+        let (mut download, mut upload) = (Streak::default(), Streak::default());
+
+        loop {
+            time::sleep(INTERVAL).await;
+            if self.is_closed() {
+                break;
+            }
+
+            let stats = self.inner.stats();
+            let blocked = self.stats.send_blocked().saturating_sub(last_blocked);
+            let sent = stats.udp_tx.bytes.saturating_sub(last.udp_tx.bytes);
+            let in_flight = sent as f64 / INTERVAL.as_secs_f64() * stats.path.rtt.as_secs_f64();
+            let download_limited =
+                blocked >= INTERVAL / 2 && in_flight < stats.path.cwnd as f64 / 2.0;
+            let upload_limited = stats.frame_rx.data_blocked + stats.frame_rx.stream_data_blocked
+                > last.frame_rx.data_blocked + last.frame_rx.stream_data_blocked;
+            last = stats;
+            last_blocked += blocked;
+
+            if download_limited {
+                self.stats.add_download_limited(INTERVAL);
+                DOWNLOAD_LIMITED.fetch_add(INTERVAL.as_secs(), Ordering::Relaxed);
+            }
+            if upload_limited {
+                self.stats.add_upload_limited(INTERVAL);
+                UPLOAD_LIMITED.fetch_add(INTERVAL.as_secs(), Ordering::Relaxed);
+            }
+
+            if download.update(download_limited) {
+                HINTS.fetch_add(1, Ordering::Relaxed);
+                info!(
+                    "[{id:#010x}] [{cid}] [{addr}] [{user}] downloads limited by flow control for \
+                     {secs}s, not by congestion (cwnd {cwnd} bytes, rtt {rtt:?}): raise the \
+                     client's receive window or `quic.send_window`, or enable \
+                     `quic.auto_tune_window` and raise `quic.max_send_window`",
+                    id = self.id(),
+                    cid = self.cid(),
+                    addr = self.inner.remote_address(),
+                    user = self.auth,
+                    secs = (INTERVAL * PERSISTENT_INTERVALS).as_secs(),
+                    cwnd = last.path.cwnd,
+                    rtt = last.path.rtt,
+                );
+            }
+            if upload.update(upload_limited) {
+                HINTS.fetch_add(1, Ordering::Relaxed);
+                info!(
+                    "[{id:#010x}] [{cid}] [{addr}] [{user}] uploads blocked by flow control for \
+                     {secs}s (rtt {rtt:?}): raise `quic.receive_window`, or enable \
+                     `quic.auto_tune_window` and raise `quic.max_receive_window`",
+                    id = self.id(),
+                    cid = self.cid(),
+                    addr = self.inner.remote_address(),
+                    user = self.auth,
+                    secs = (INTERVAL * PERSISTENT_INTERVALS).as_secs(),
+                    rtt = last.path.rtt,
+                );
+            }
+        }
+    }
+}
+
+/// Counters across all connections
+pub fn snapshot() -> Value {
+    json!({
+        "download_limited_secs": DOWNLOAD_LIMITED.load(Ordering::Relaxed),
+        "upload_limited_secs": UPLOAD_LIMITED.load(Ordering::Relaxed),
+        "hints": HINTS.load(Ordering::Relaxed),
+    })
+}
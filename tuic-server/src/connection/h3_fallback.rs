@@ -0,0 +1,155 @@
+//! Answering like a plain HTTP/3 server, so probes negotiating `h3` instead of
+//! the ALPN of TUIC clients find a web site rather than a closed connection.
+//! Requests are handed over HTTP/1.1 to `fallback_h3_upstream`, the web
+//! server the site is actually served by
+
+use std::{net::SocketAddr, time::Duration};
+
+use bytes::{Buf, Bytes, BytesMut};
+use eyre::{Context, OptionExt};
+use http::{
+    HeaderName, HeaderValue, Request, Response,
+    header::{CONNECTION, HOST, TRANSFER_ENCODING, UPGRADE},
+};
+use http_body_util::{BodyExt, Full};
+use hyper_util::rt::TokioIo;
+use quinn::Connection as QuinnConnection;
+use tokio::{net::TcpStream, time};
+use tracing::debug;
+
+use super::{CorrelationId, masque::Stream};
+use crate::{
+    AppContext,
+    error::{Error, log_error},
+};
+
+const UPSTREAM_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Larger request bodies are refused, probes send little if anything
+const MAX_REQUEST_BODY: usize = 1024 * 1024;
+
+/// Headers of a single HTTP/1.1 connection, never forwarded
+const HOP_BY_HOP: [HeaderName; 4] = [
+    CONNECTION,
+    TRANSFER_ENCODING,
+    UPGRADE,
+    HeaderName::from_static("keep-alive"),
+];
+
+/// Serve the HTTP/3 connection `conn` from `fallback_h3_upstream`
+pub async fn handle(ctx: &AppContext, conn: QuinnConnection, cid: CorrelationId) {
+    let Some(upstream) = ctx.cfg.fallback_h3_upstream.clone() else {
+        return;
+    };
+    let id = conn.stable_id() as u32;
+    let addr = conn.remote_address();
+    debug!("[{id:#010x}] [{cid}] [{addr}] HTTP/3 fallback connection");
+
+    let serve = async {
+        let mut h3 = h3::server::builder()
+            .build::<_, Bytes>(h3_quinn::Connection::new(conn.clone()))
+            .await?;
+        while let Some(resolver) = h3.accept().await? {
+            let upstream = upstream.clone();
+            tokio::spawn(async move {
+                match resolver.resolve_request().await {
+                    Ok((req, mut stream)) => proxy(&upstream, addr, req, &mut stream).await,
+                    Err(err) => debug!("[{id:#010x}] [{cid}] [{addr}] HTTP/3 fallback: {err}"),
+                }
+            });
+        }
+        Ok::<_, Error>(())
+    };
+    if let Err(err) = serve.await {
+        log_error!(
+            err,
+            "[{id:#010x}] [{cid}] [{addr}] HTTP/3 fallback connection error: {err}"
+        );
+    }
+}
+
+/// Answer `req` with the response of `upstream`, `502 Bad Gateway` if it
+/// can't be reached
+pub async fn proxy(upstream: &str, peer: SocketAddr, req: Request<()>, stream: &mut Stream) {
+    let uri = req.uri().to_string();
+    if let Err(err) = forward(upstream, peer, req, stream).await {
+        debug!("[{peer}] HTTP/3 fallback {uri}: {err:#}");
+        let mut resp = Response::new(());
+        *resp.status_mut() = http::StatusCode::BAD_GATEWAY;
+        _ = stream.send_response(resp).await;
+    }
+    _ = stream.finish().await;
+}
+
+async fn forward(
+    upstream: &str,
+    peer: SocketAddr,
+    req: Request<()>,
+    stream: &mut Stream,
+) -> eyre::Result<()> {
+    let mut body = BytesMut::new();
+    while let Some(mut chunk) = stream.recv_data().await? {
+        if body.len() + chunk.remaining() > MAX_REQUEST_BODY {
+            eyre::bail!("request body over {MAX_REQUEST_BODY} bytes");
+        }
+        while chunk.has_remaining() {
+            let bytes = chunk.chunk();
+            body.extend_from_slice(bytes);
+            let len = bytes.len();
+            chunk.advance(len);
+        }
+    }
+
+    let (mut parts, ()) = req.into_parts();
+    let authority = parts
+        .uri
+        .authority()
+        .ok_or_eyre("request without authority")?
+        .clone();
+    parts.uri = parts
+        .uri
+        .path_and_query()
+        .map_or("/", |path| path.as_str())
+        .parse()?;
+    parts.version = http::Version::HTTP_11;
+    for name in HOP_BY_HOP {
+        parts.headers.remove(name);
+    }
+    parts
+        .headers
+        .insert(HOST, HeaderValue::from_str(authority.as_str())?);
+    parts.headers.append(
+        HeaderName::from_static("x-forwarded-for"),
+        HeaderValue::from_str(&peer.ip().to_canonical().to_string())?,
+    );
+    parts.headers.insert(
+        HeaderName::from_static("x-forwarded-proto"),
+        HeaderValue::from_static("https"),
+    );
+    let req = Request::from_parts(parts, Full::new(body.freeze()));
+
+    let conn = time::timeout(UPSTREAM_CONNECT_TIMEOUT, TcpStream::connect(upstream))
+        .await
+        .map_err(|_| eyre::eyre!("connecting to {upstream} timed out"))?
+        .with_context(|| format!("failed to connect to {upstream}"))?;
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(conn)).await?;
+    tokio::spawn(async move {
+        if let Err(err) = conn.await {
+            debug!("[http] connection error: {err}");
+        }
+    });
+    let (mut parts, mut body) = sender.send_request(req).await?.into_parts();
+
+    for name in HOP_BY_HOP {
+        parts.headers.remove(name);
+    }
+    parts.version = http::Version::HTTP_3;
+    stream
+        .send_response(Response::from_parts(parts, ()))
+        .await?;
+    while let Some(frame) = body.frame().await {
+        if let Ok(data) = frame?.into_data() {
+            stream.send_data(data).await?;
+        }
+    }
+    Ok(())
+}
@@ -4,21 +4,27 @@ use bytes::Bytes;
 use quinn::{RecvStream, SendStream, VarInt};
 use register_count::Register;
 use tokio::time;
-use tracing::{debug, warn};
+use tracing::debug;
 use tuic_quinn::Task;
 
-use super::Connection;
-use crate::{error::Error, utils::UdpRelayMode};
+use super::{Connection, USER_QUOTA_EXCEEDED};
+use crate::{
+    error::{Error, log_error},
+    utils::{DuplicateAuthPolicy, UdpRelayMode},
+};
 
 impl Connection {
     pub async fn handle_uni_stream(self, recv: RecvStream, _reg: Register) {
         debug!(
-            "[{id:#010x}] [{addr}] [{user}] incoming unidirectional stream",
+            "[{id:#010x}] [{cid}] [{addr}] [{user}] incoming unidirectional stream",
             id = self.id(),
+            cid = self.cid(),
             addr = self.inner.remote_address(),
             user = self.auth,
         );
 
+        self.stats.stream_opened();
+
         let max = self.max_concurrent_uni_streams.load(Ordering::Relaxed);
 
         if self.remote_uni_stream_cnt.count() as u32 == max {
@@ -41,10 +47,7 @@ impl Connection {
                 self.authenticate(auth).await?;
             }
 
-            tokio::select! {
-                () = self.auth.wait() => {}
-                err = self.inner.closed() => return Err(Error::from(err)),
-            };
+            self.wait_auth().await?;
 
             let same_pkt_src = matches!(task, Task::Packet(_))
                 && matches!(**self.udp_relay_mode.load(), Some(UdpRelayMode::Native));
@@ -52,35 +55,64 @@ impl Connection {
                 return Err(Error::UnexpectedPacketSource);
             }
 
-            Ok(task)
+            let slot = match task {
+                Task::Packet(_) => self.stream_slot()?,
+                _ => None,
+            };
+
+            Ok((task, slot))
         };
 
         match pre_process.await {
-            Ok(Task::Authenticate(auth)) => self.handle_authenticate(auth).await,
-            Ok(Task::Packet(pkt)) => self.handle_packet(pkt, UdpRelayMode::Quic).await,
-            Ok(Task::Dissociate(assoc_id)) => self.handle_dissociate(assoc_id).await,
+            Ok((Task::Authenticate(auth), _)) => self.handle_authenticate(auth).await,
+            Ok((Task::Packet(pkt), _slot)) => self.handle_packet(pkt, UdpRelayMode::Quic).await,
+            Ok((Task::Dissociate(assoc_id), _)) => self.handle_dissociate(assoc_id).await,
             Ok(_) => unreachable!(), // already filtered in `tuic_quinn`
+            Err(Error::DuplicatedAuth)
+                if self.ctx.cfg.duplicate_auth == DuplicateAuthPolicy::Ignore =>
+            {
+                debug!(
+                    "[{id:#010x}] [{cid}] [{addr}] [{user}] ignored duplicated authentication",
+                    id = self.id(),
+                    cid = self.cid(),
+                    addr = self.inner.remote_address(),
+                    user = self.auth,
+                );
+            }
             Err(err) => {
-                warn!(
-                    "[{id:#010x}] [{addr}] [{user}] handling incoming unidirectional stream \
-                     error: {err}",
+                self.observe_abuse(&err);
+                log_error!(
+                    err,
+                    "[{id:#010x}] [{cid}] [{addr}] [{user}] handling incoming unidirectional \
+                     stream error: {err}",
                     id = self.id(),
+                    cid = self.cid(),
                     addr = self.inner.remote_address(),
                     user = self.auth,
                 );
-                self.close();
+                match err {
+                    // only the stream is rejected
+                    Error::TooManyStreams(_) => {}
+                    Error::QuotaExceeded(_) => self
+                        .inner
+                        .close(USER_QUOTA_EXCEEDED, b"User traffic quota exceeded"),
+                    _ => self.close(),
+                }
             }
         }
     }
 
     pub async fn handle_bi_stream(self, (send, recv): (SendStream, RecvStream), _reg: Register) {
         debug!(
-            "[{id:#010x}] [{addr}] [{user}] incoming bidirectional stream",
+            "[{id:#010x}] [{cid}] [{addr}] [{user}] incoming bidirectional stream",
             id = self.id(),
+            cid = self.cid(),
             addr = self.inner.remote_address(),
             user = self.auth,
         );
 
+        self.stats.stream_opened();
+
         let max = self.max_concurrent_bi_streams.load(Ordering::Relaxed);
 
         if self.remote_bi_stream_cnt.count() as u32 == max {
@@ -99,34 +131,58 @@ impl Connection {
             .await
             .map_err(|_| Error::TaskNegotiationTimeout)??;
 
-            tokio::select! {
-                () = self.auth.wait() => {}
-                err = self.inner.closed() => return Err(Error::from(err)),
-            };
+            self.wait_auth().await?;
+            let slot = self.stream_slot()?;
 
-            Ok(task)
+            Ok::<_, Error>((task, slot))
         };
 
         match pre_process.await {
-            Ok(Task::Connect(conn)) => self.handle_connect(conn).await,
+            Ok((Task::Connect(conn), _slot)) => self.handle_connect(conn).await,
             Ok(_) => unreachable!(), // already filtered in `tuic_quinn`
             Err(err) => {
-                warn!(
-                    "[{id:#010x}] [{addr}] [{user}] handling incoming bidirectional stream error: \
-                     {err}",
+                self.observe_abuse(&err);
+                log_error!(
+                    err,
+                    "[{id:#010x}] [{cid}] [{addr}] [{user}] handling incoming bidirectional \
+                     stream error: {err}",
                     id = self.id(),
+                    cid = self.cid(),
                     addr = self.inner.remote_address(),
                     user = self.auth,
                 );
-                self.close();
+                if !matches!(err, Error::TooManyStreams(_)) {
+                    self.close();
+                }
             }
         }
     }
 
+    /// Wait for the connection to be authenticated, refusing more than
+    /// `max_pre_auth_tasks` tasks waiting at once
+    async fn wait_auth(&self) -> Result<(), Error> {
+        if self.auth.get().is_some() {
+            return Ok(());
+        }
+        let max = self.ctx.cfg.max_pre_auth_tasks;
+        let waiting = self.pre_auth_tasks.fetch_add(1, Ordering::Relaxed) + 1;
+        let res = if max != 0 && waiting > max {
+            Err(Error::TooManyPreAuthTasks(max))
+        } else {
+            tokio::select! {
+                () = self.auth.wait() => Ok(()),
+                err = self.inner.closed() => Err(Error::from(err)),
+            }
+        };
+        self.pre_auth_tasks.fetch_sub(1, Ordering::Relaxed);
+        res
+    }
+
     pub async fn handle_datagram(self, dg: Bytes) {
         debug!(
-            "[{id:#010x}] [{addr}] [{user}] incoming datagram",
+            "[{id:#010x}] [{cid}] [{addr}] [{user}] incoming datagram",
             id = self.id(),
+            cid = self.cid(),
             addr = self.inner.remote_address(),
             user = self.auth,
         );
@@ -134,10 +190,7 @@ impl Connection {
         let pre_process = async {
             let task = self.model.accept_datagram(dg)?;
 
-            tokio::select! {
-                () = self.auth.wait() => {}
-                err = self.inner.closed() => return Err(Error::from(err)),
-            };
+            self.wait_auth().await?;
 
             let same_pkt_src = matches!(task, Task::Packet(_))
                 && matches!(**self.udp_relay_mode.load(), Some(UdpRelayMode::Quic));
@@ -153,9 +206,13 @@ impl Connection {
             Ok(Task::Heartbeat) => self.handle_heartbeat().await,
             Ok(_) => unreachable!(),
             Err(err) => {
-                warn!(
-                    "[{id:#010x}] [{addr}] [{user}] handling incoming datagram error: {err}",
+                self.observe_abuse(&err);
+                log_error!(
+                    err,
+                    "[{id:#010x}] [{cid}] [{addr}] [{user}] handling incoming datagram error: \
+                     {err}",
                     id = self.id(),
+                    cid = self.cid(),
                     addr = self.inner.remote_address(),
                     user = self.auth,
                 );
@@ -1,92 +1,373 @@
 use std::{
-    collections::hash_map::Entry,
+    collections::{VecDeque, hash_map::Entry},
     io::{Error as IoError, ErrorKind},
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        Arc,
+        atomic::{AtomicU32, Ordering},
+    },
+    time::{Duration, Instant},
 };
 
 use bytes::Bytes;
 use eyre::{OptionExt, eyre};
 use tokio::{
-    io::{self, AsyncWriteExt},
-    net::{self, TcpStream},
+    io::{self, AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    task::JoinSet,
+    time,
 };
 use tokio_util::compat::FuturesAsyncReadCompatExt;
-use tracing::{info, warn};
+use tracing::{debug, info};
 use tuic::Address;
 use tuic_quinn::{Authenticate, Connect, Packet};
 
-use super::{Connection, ERROR_CODE, UdpSession};
-use crate::{error::Error, restful, utils::UdpRelayMode};
+use super::{
+    Connection, ERROR_CODE, UdpSession, accounting,
+    activity::{Activity, Tracked},
+};
+use crate::{
+    acl, blocklist, destinations, dial, dns,
+    error::{Error, log_error},
+    hooks::{self, HookEvent},
+    latency::{self, FirstByte, Metric},
+    privacy, restful, scan, script,
+    sniff::{self, Sniffed},
+    upstream::Proxy,
+    utils::UdpRelayMode,
+};
 
 impl Connection {
     pub async fn handle_authenticate(&self, auth: Authenticate) {
         info!(
-            "[{id:#010x}] [{addr}] [{user}] [AUTH] {auth_uuid}",
+            "[{id:#010x}] [{cid}] [{addr}] [{user}] [AUTH] {auth_uuid}",
             id = self.id(),
+            cid = self.cid(),
             addr = self.inner.remote_address(),
             user = self.auth,
             auth_uuid = auth.uuid(),
         );
+
+        hooks::exec(
+            &self.ctx,
+            HookEvent::Connect,
+            self.id(),
+            self.cid(),
+            auth.uuid(),
+            self.inner.remote_address(),
+            self.stats.duration(),
+        );
     }
 
     pub async fn handle_connect(&self, conn: Connect) {
         let target_addr = conn.addr().to_string();
 
         info!(
-            "[{id:#010x}] [{addr}] [{user}] [TCP] {target_addr}",
+            "[{id:#010x}] [{cid}] [{addr}] [{user}] [TCP] {target_addr}",
             id = self.id(),
+            cid = self.cid(),
             addr = self.inner.remote_address(),
             user = self.auth,
+            target_addr = privacy::text(&target_addr),
         );
 
         let process = async {
-            let mut stream = None;
-            let mut last_err = None;
-
-            match resolve_dns(conn.addr()).await {
-                Ok(addrs) => {
-                    for addr in addrs {
-                        match TcpStream::connect(addr).await {
-                            Ok(s) => {
-                                s.set_nodelay(true)?;
-                                stream = Some(s);
-                                break;
+            let uuid = self.auth.get().unwrap();
+            let target = conn.addr().clone();
+            let mut conn = conn.compat();
+
+            if !script::allow(uuid, &target_addr, "tcp") {
+                _ = conn.shutdown().await;
+                return Err(Error::Denied(target_addr.clone()));
+            }
+            if !acl::allow_target(Some(uuid), domain_of(&target), port(&target)) {
+                _ = conn.shutdown().await;
+                return Err(Error::AclDenied(target_addr.clone()));
+            }
+
+            // bytes read from the client while sniffing, to be sent first
+            let mut head = Vec::new();
+            if self.ctx.cfg.sniff.tcp
+                && let Some(domain) =
+                    sniff(&mut conn, &mut head, self.ctx.cfg.sniff.timeout).await?
+                && domain.parse::<IpAddr>().is_err()
+                && !matches!(&target, Address::DomainAddress(host, _) if *host == domain)
+            {
+                info!(
+                    "[{id:#010x}] [{cid}] [{addr}] [{user}] [TCP] {target_addr} sniffed {domain}",
+                    id = self.id(),
+                    cid = self.cid(),
+                    addr = self.inner.remote_address(),
+                    user = self.auth,
+                    target_addr = privacy::text(&target_addr),
+                    domain = privacy::domain(&domain),
+                );
+                let dest = Address::DomainAddress(domain, port(&target));
+                if !acl::allow_target(Some(uuid), domain_of(&dest), port(&dest)) {
+                    _ = conn.shutdown().await;
+                    return Err(Error::AclDenied(dest.to_string()));
+                }
+                let dest = dest.to_string();
+                if !script::allow(uuid, &dest, "tcp") {
+                    _ = conn.shutdown().await;
+                    return Err(Error::Denied(dest));
+                }
+            }
+
+            // take what the client already sent, waiting up to
+            // `tcp_fast_open_wait` for it, to put it on the SYN. Without any,
+            // the target is dialed right away without Fast Open
+            if dial::fast_open() && head.is_empty() {
+                read_head(
+                    &mut conn,
+                    &mut head,
+                    self.ctx.cfg.outbound.tcp_fast_open_wait,
+                )
+                .await?;
+            }
+            let fast_open = dial::fast_open() && !head.is_empty();
+
+            let start = Instant::now();
+            let throttle = scan::throttle(uuid).await;
+            let policy = self.policy();
+            let dial = dial::start(
+                self.auth.get(),
+                policy.priority,
+                policy.max_concurrent_dials,
+            )
+            .await;
+            latency::record(Metric::DialQueue, port(&target), start.elapsed());
+            destinations::connection(&target);
+            let start = Instant::now();
+            let stream = match acl::proxy(domain_of(&target), ip_of(&target), port(&target)) {
+                Some(proxy) => self.connect_proxied(&proxy, &target).await,
+                None => match resolve_dns(&target).await {
+                    Ok(addrs) => self.connect_target(&target, addrs, fast_open).await,
+                    Err(err) => Err(err.into()),
+                },
+            };
+            drop(dial);
+            drop(throttle);
+            scan::observe(&self.ctx, uuid, &target_addr, stream.is_err());
+
+            match stream {
+                Ok(stream) => {
+                    latency::record(Metric::Connect, port(&target), start.elapsed());
+                    let mut stream = FirstByte::new(stream, port(&target));
+                    let entry = self.streams.open(target_addr.clone());
+                    let (tx, rx) = (&entry.stream.tx, &entry.stream.rx);
+                    tx.store(head.len() as u64, Ordering::Relaxed);
+                    let activity = Activity::new();
+                    let mut client =
+                        Tracked::new(&mut conn, &activity, tx).count_blocked(&self.stats);
+                    let mut remote = Tracked::new(&mut stream, &activity, rx);
+                    let timeout = self.ctx.cfg.stream_timeout;
+                    let first_byte_timeout = self.ctx.cfg.first_byte_timeout;
+                    // copied through userspace, splice(2) needs kernel sockets at both ends
+                    let size = self.ctx.cfg.tcp_relay_buffer_size;
+                    let res = match remote.write_all(&head).await {
+                        Ok(()) => tokio::select! {
+                            res = io::copy_bidirectional_with_sizes(
+                                &mut client,
+                                &mut remote,
+                                size,
+                                size,
+                            ) => {
+                                res.map(|_| ()).map_err(Error::from)
                             }
-                            Err(err) => last_err = Some(err),
-                        }
-                    }
+                            () = activity.idle_for(timeout) => Err(Error::StreamIdle(timeout)),
+                            () = activity.silent_for(first_byte_timeout) => {
+                                latency::first_byte_timeout(port(&target));
+                                Err(Error::FirstByteTimeout(first_byte_timeout))
+                            }
+                        },
+                        Err(err) => Err(err.into()),
+                    };
+                    // a -> b tx
+                    // a <- b rx
+                    let (tx, rx) = (tx.load(Ordering::Relaxed), rx.load(Ordering::Relaxed));
+                    _ = conn.get_mut().reset(ERROR_CODE);
+                    _ = stream.shutdown().await;
+                    self.stats.add_tx(tx);
+                    self.stats.add_rx(rx);
+                    restful::traffic_tx(&uuid, tx);
+                    restful::traffic_rx(&uuid, rx);
+                    destinations::traffic(uuid, &target, tx, rx);
+                    res
+                }
+                Err(err) => {
+                    let _ = conn.shutdown().await;
+                    Err(err)
                 }
-                Err(err) => last_err = Some(err),
-            }
-
-            if let Some(mut stream) = stream {
-                let mut conn = conn.compat();
-                let res = io::copy_bidirectional(&mut conn, &mut stream).await;
-                _ = conn.get_mut().reset(ERROR_CODE);
-                _ = stream.shutdown().await;
-                // a -> b tx
-                // a <- b rx
-                let (tx, rx) = res?;
-                let uuid = self.auth.get().unwrap();
-                restful::traffic_tx(&self.ctx, &uuid, tx);
-                restful::traffic_rx(&self.ctx, &uuid, rx);
-                Ok::<_, Error>(())
-            } else {
-                let _ = conn.compat().shutdown().await;
-                Err(last_err
-                    .unwrap_or_else(|| IoError::new(ErrorKind::NotFound, "no address resolved")))?
             }
         };
 
-        match process.await {
-            Ok(()) => {}
-            Err(err) => warn!(
-                "[{id:#010x}] [{addr}] [{user}] [TCP] {target_addr}: {err}",
+        if let Err(err) = process.await {
+            self.observe_abuse(&err);
+            log_error!(
+                err,
+                "[{id:#010x}] [{cid}] [{addr}] [{user}] [TCP] {target_addr}: {err}",
                 id = self.id(),
+                cid = self.cid(),
                 addr = self.inner.remote_address(),
                 user = self.auth,
-            ),
+                target_addr = privacy::text(&target_addr),
+            );
+        }
+    }
+
+    /// Dial the resolved target addresses, following the `outbound` retry
+    /// policy. Transient errors are retried on the same address with
+    /// exponential backoff, others move on to the next address right away.
+    ///
+    /// With `outbound.happy_eyeballs_delay`, addresses alternate between
+    /// families and the next one is dialed whenever the previous attempt
+    /// failed or is still pending after the delay, the first connection
+    /// established winning (RFC 8305). Otherwise they are dialed in turn
+    async fn connect_target(
+        &self,
+        target: &Address,
+        addrs: impl Iterator<Item = SocketAddr>,
+        fast_open: bool,
+    ) -> Result<TcpStream, Error> {
+        let delay = self.ctx.cfg.outbound.happy_eyeballs_delay;
+        let attempts = Arc::new(AtomicU32::new(0));
+        let mut last_err = None;
+
+        if delay.is_zero() {
+            for addr in addrs {
+                match self.dial_addr(target, addr, fast_open, &attempts).await {
+                    Ok(stream) => return Ok(stream),
+                    Err(Some(err)) => last_err = Some(err),
+                    Err(None) => break,
+                }
+            }
+        } else {
+            let mut addrs = interleave_families(addrs).into_iter().peekable();
+            let mut dials = JoinSet::new();
+            let mut dial_next = true;
+            loop {
+                if dial_next && let Some(addr) = addrs.next() {
+                    let (conn, target, attempts) = (self.clone(), target.clone(), attempts.clone());
+                    dials.spawn(async move {
+                        conn.dial_addr(&target, addr, fast_open, &attempts).await
+                    });
+                }
+                if dials.is_empty() {
+                    break;
+                }
+                tokio::select! {
+                    Some(res) = dials.join_next() => match res {
+                        Ok(Ok(stream)) => return Ok(stream),
+                        Ok(Err(Some(err))) => {
+                            last_err = Some(err);
+                            dial_next = true;
+                        }
+                        // out of attempts, let the pending ones finish
+                        Ok(Err(None)) => dial_next = false,
+                        Err(err) => return Err(IoError::other(err).into()),
+                    },
+                    () = time::sleep(delay), if addrs.peek().is_some() => dial_next = true,
+                }
+            }
+        }
+
+        Err(last_err
+            .unwrap_or_else(|| IoError::new(ErrorKind::NotFound, "no address resolved").into()))
+    }
+
+    /// Connect to the target through an `[[outbound.proxies]]` entry, which
+    /// resolves domains itself. IP addresses are checked like dialed ones
+    async fn connect_proxied(&self, proxy: &Proxy, target: &Address) -> Result<TcpStream, Error> {
+        if let Address::SocketAddress(addr) = target {
+            self.check_proxied(*addr).await?;
+        }
+        debug!(
+            "[{id:#010x}] [{cid}] [{peer}] [{user}] [TCP] {target} via outbound proxy {proxy}",
+            id = self.id(),
+            cid = self.cid(),
+            peer = self.inner.remote_address(),
+            user = self.auth,
+            target = privacy::addr(target),
+            proxy = proxy.name,
+        );
+        proxy.connect(target, &self.ctx.cfg.outbound).await
+    }
+
+    /// Whether an IP destination sent through an outbound proxy is neither
+    /// blocklisted nor denied
+    async fn check_proxied(&self, addr: SocketAddr) -> Result<(), Error> {
+        if self.ctx.cfg.blocklist.is_some() && blocklist::is_blocked(addr.ip()) {
+            blocklist::record_hit(self.auth.get().unwrap()).await;
+            return Err(Error::Blocklisted(addr));
+        }
+        if !acl::allow(self.auth.get(), None, addr.ip(), addr.port()) {
+            return Err(Error::AclDenied(addr.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Dial a single address, retrying transient errors. Fails with `None`
+    /// when `outbound.max_attempts` was reached before any attempt
+    async fn dial_addr(
+        &self,
+        target: &Address,
+        addr: SocketAddr,
+        fast_open: bool,
+        attempts: &AtomicU32,
+    ) -> Result<TcpStream, Option<Error>> {
+        let cfg = &self.ctx.cfg.outbound;
+        if self.ctx.cfg.blocklist.is_some() && blocklist::is_blocked(addr.ip()) {
+            blocklist::record_hit(self.auth.get().unwrap()).await;
+            return Err(Some(Error::Blocklisted(addr)));
+        }
+        if !acl::allow(self.auth.get(), domain_of(target), addr.ip(), addr.port()) {
+            return Err(Some(Error::AclDenied(addr.to_string())));
+        }
+
+        let mut backoff = cfg.retry_backoff;
+        let mut last_err = None;
+        for retry in 0..=cfg.retries {
+            let attempt = attempts.fetch_add(1, Ordering::Relaxed) + 1;
+            if cfg.max_attempts != 0 && attempt > cfg.max_attempts {
+                break;
+            }
+
+            let res = match cfg.connect_timeout {
+                Some(timeout) => time::timeout(timeout, dial::connect(addr, cfg, fast_open))
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(IoError::new(ErrorKind::TimedOut, "connect timed out"))
+                    }),
+                None => dial::connect(addr, cfg, fast_open).await,
+            };
+
+            match res {
+                Ok(stream) => {
+                    stream.set_nodelay(true).map_err(|err| Some(err.into()))?;
+                    return Ok(stream);
+                }
+                Err(err) => {
+                    let transient = is_transient(&err);
+                    debug!(
+                        "[{id:#010x}] [{cid}] [{peer}] [{user}] [TCP] attempt {attempt} to {addr} \
+                         failed: {err}",
+                        id = self.id(),
+                        cid = self.cid(),
+                        peer = self.inner.remote_address(),
+                        user = self.auth,
+                        addr = privacy::socket(&addr),
+                    );
+                    last_err = Some(err.into());
+                    if !transient || retry == cfg.retries {
+                        break;
+                    }
+                    time::sleep(backoff).await;
+                    backoff = backoff.saturating_mul(2);
+                }
+            }
         }
+        Err(last_err)
     }
 
     pub async fn handle_packet(&self, pkt: Packet, mode: UdpRelayMode) {
@@ -96,9 +377,10 @@ impl Connection {
         let frag_total = pkt.frag_total();
 
         info!(
-            "[{id:#010x}] [{addr}] [{user}] [UDP-OUT] [{assoc_id:#06x}] [from-{mode}] \
+            "[{id:#010x}] [{cid}] [{addr}] [{user}] [UDP-OUT] [{assoc_id:#06x}] [from-{mode}] \
              [{pkt_id:#06x}] fragment {frag_id}/{frag_total}",
             id = self.id(),
+            cid = self.cid(),
             addr = self.inner.remote_address(),
             user = self.auth,
             frag_id = frag_id + 1,
@@ -106,14 +388,27 @@ impl Connection {
 
         self.udp_relay_mode.store(Some(mode).into());
 
+        let size = pkt.size();
+        let uuid = self.auth.get().unwrap();
+        let frag_size = accounting::fragment(&self.ctx.cfg.accounting, pkt.addr(), size.into());
+        if frag_size != 0 {
+            self.stats.add_tx(frag_size);
+            restful::traffic_tx(&uuid, frag_size);
+        }
+
         let (pkt, addr, assoc_id) = match pkt.accept().await {
-            Ok(None) => return,
+            Ok(None) => {
+                self.fragment_cached(size.into());
+                return;
+            }
             Ok(Some(res)) => res,
             Err(err) => {
-                warn!(
-                    "[{id:#010x}] [{addr}] [{user}] [UDP-OUT] [{assoc_id:#06x}] [from-{mode}] \
-                     [{pkt_id:#06x}] fragment {frag_id}/{frag_total}: {err}",
+                log_error!(
+                    err,
+                    "[{id:#010x}] [{cid}] [{addr}] [{user}] [UDP-OUT] [{assoc_id:#06x}] \
+                     [from-{mode}] [{pkt_id:#06x}] fragment {frag_id}/{frag_total}: {err}",
                     id = self.id(),
+                    cid = self.cid(),
                     addr = self.inner.remote_address(),
                     user = self.auth,
                     frag_id = frag_id + 1,
@@ -124,59 +419,151 @@ impl Connection {
 
         let process = async {
             info!(
-                "[{id:#010x}] [{addr}] [{user}] [UDP-OUT] [{assoc_id:#06x}] [from-{mode}] \
+                "[{id:#010x}] [{cid}] [{addr}] [{user}] [UDP-OUT] [{assoc_id:#06x}] [from-{mode}] \
                  [{pkt_id:#06x}] to {src_addr}",
                 id = self.id(),
+                cid = self.cid(),
                 addr = self.inner.remote_address(),
                 user = self.auth,
-                src_addr = addr,
+                src_addr = privacy::addr(&addr),
             );
 
+            let policy = self.policy();
+            if !policy.udp_relay {
+                return Err(Error::UdpRelayDisabled(self.auth.get().unwrap()));
+            }
+
             let guard = self.udp_sessions.read().await;
             let session = guard.get(&assoc_id).map(|v| v.to_owned());
             drop(guard);
-            let session = match session {
-                Some(v) => v,
-                None => match self.udp_sessions.write().await.entry(assoc_id) {
-                    Entry::Occupied(entry) => entry.get().clone(),
-                    Entry::Vacant(entry) => {
-                        let session = UdpSession::new(self.ctx.clone(), self.clone(), assoc_id)?;
-                        entry.insert(session.clone());
-                        session
+            let (session, created) = match session {
+                Some(v) => (v, false),
+                None => {
+                    let mut sessions = self.udp_sessions.write().await;
+                    let max = policy.max_udp_sessions_per_connection;
+                    let full = max != 0 && sessions.len() >= max && !policy.priority;
+                    match sessions.entry(assoc_id) {
+                        Entry::Occupied(entry) => (entry.get().clone(), false),
+                        Entry::Vacant(_) if full => return Err(Error::TooManyUdpSessions(max)),
+                        Entry::Vacant(entry) => {
+                            let session =
+                                UdpSession::new(self.ctx.clone(), self.clone(), assoc_id)?;
+                            self.stats.udp_session_opened();
+                            entry.insert(session.clone());
+                            (session, true)
+                        }
                     }
-                },
+                }
             };
 
-            let Some(socket_addr) = resolve_dns(&addr).await?.next() else {
+            let Some(session) = session.upgrade() else {
+                return Err(eyre!("UdpSession dropped already").into());
+            };
+
+            if created && self.ctx.cfg.udp_address_report {
+                let session = session.clone();
+                tokio::spawn(async move { session.report_external_address().await });
+            }
+
+            if !script::allow(self.auth.get().unwrap(), &addr.to_string(), "udp") {
+                return Err(Error::Denied(addr.to_string()));
+            }
+            if !acl::allow_target(self.auth.get(), domain_of(&addr), port(&addr)) {
+                return Err(Error::AclDenied(addr.to_string()));
+            }
+
+            if self.ctx.cfg.sniff.quic
+                && let Some(domain) = sniff::sniff_quic(&pkt)
+                && domain.parse::<IpAddr>().is_err()
+                && !matches!(&addr, Address::DomainAddress(host, _) if *host == domain)
+            {
+                info!(
+                    "[{id:#010x}] [{cid}] [{peer}] [{user}] [UDP-OUT] [{assoc_id:#06x}] \
+                     [from-{mode}] [{pkt_id:#06x}] to {addr} sniffed {domain}",
+                    id = self.id(),
+                    cid = self.cid(),
+                    peer = self.inner.remote_address(),
+                    user = self.auth,
+                    addr = privacy::addr(&addr),
+                    domain = privacy::domain(&domain),
+                );
+                let dest = Address::DomainAddress(domain, port(&addr));
+                if !acl::allow_target(self.auth.get(), domain_of(&dest), port(&dest)) {
+                    return Err(Error::AclDenied(dest.to_string()));
+                }
+                let dest = dest.to_string();
+                if !script::allow(self.auth.get().unwrap(), &dest, "udp") {
+                    return Err(Error::Denied(dest));
+                }
+            }
+
+            if let Some(proxy) = acl::proxy(domain_of(&addr), ip_of(&addr), port(&addr)) {
+                if let Address::SocketAddress(socket_addr) = &addr {
+                    self.check_proxied(*socket_addr).await?;
+                }
+                let size =
+                    accounting::reassembled(&self.ctx.cfg.accounting, &addr, frag_total, pkt.len());
+                if size != 0 {
+                    self.stats.add_tx(size);
+                    restful::traffic_tx(&uuid, size);
+                }
+                destinations::traffic(uuid, &addr, pkt.len() as u64, 0);
+                return session.send_proxied(&proxy, pkt, &addr).await;
+            }
+
+            let addrs = resolve_dns(&addr).await?.collect::<Vec<_>>();
+            let socket_addr = match &addr {
+                Address::DomainAddress(domain, _) => session.select_addr(domain, &addrs).await,
+                _ => addrs.first().copied(),
+            };
+            let Some(socket_addr) = socket_addr else {
                 return Err(Error::from(IoError::new(
                     ErrorKind::NotFound,
                     "no address resolved",
                 )));
             };
-            restful::traffic_tx(&self.ctx, &self.auth.get().unwrap(), pkt.len() as u64);
-            if let Some(session) = session.upgrade() {
-                session.send(pkt, socket_addr).await
-            } else {
-                Err(eyre!("UdpSession dropped already").into())
+            if self.ctx.cfg.blocklist.is_some() && blocklist::is_blocked(socket_addr.ip()) {
+                blocklist::record_hit(self.auth.get().unwrap()).await;
+                return Err(Error::Blocklisted(socket_addr));
             }
+            if !acl::allow(
+                self.auth.get(),
+                domain_of(&addr),
+                socket_addr.ip(),
+                socket_addr.port(),
+            ) {
+                return Err(Error::AclDenied(socket_addr.to_string()));
+            }
+            let size =
+                accounting::reassembled(&self.ctx.cfg.accounting, &addr, frag_total, pkt.len());
+            if size != 0 {
+                self.stats.add_tx(size);
+                restful::traffic_tx(&uuid, size);
+            }
+            destinations::traffic(uuid, &addr, pkt.len() as u64, 0);
+            session.send(pkt, socket_addr).await
         };
 
         if let Err(err) = process.await {
-            warn!(
-                "[{id:#010x}] [{addr}] [{user}] [UDP-OUT] [{assoc_id:#06x}] [from-{mode}] \
+            self.observe_abuse(&err);
+            log_error!(
+                err,
+                "[{id:#010x}] [{cid}] [{addr}] [{user}] [UDP-OUT] [{assoc_id:#06x}] [from-{mode}] \
                  [{pkt_id:#06x}] to {src_addr}: {err}",
                 id = self.id(),
+                cid = self.cid(),
                 addr = self.inner.remote_address(),
                 user = self.auth,
-                src_addr = addr,
+                src_addr = privacy::addr(&addr),
             );
         }
     }
 
     pub async fn handle_dissociate(&self, assoc_id: u16) {
         info!(
-            "[{id:#010x}] [{addr}] [{user}] [UDP-DROP] [{assoc_id:#06x}]",
+            "[{id:#010x}] [{cid}] [{addr}] [{user}] [UDP-DROP] [{assoc_id:#06x}]",
             id = self.id(),
+            cid = self.cid(),
             addr = self.inner.remote_address(),
             user = self.auth,
         );
@@ -190,41 +577,51 @@ impl Connection {
 
     pub async fn handle_heartbeat(&self) {
         info!(
-            "[{id:#010x}] [{addr}] [{user}] [HB]",
+            "[{id:#010x}] [{cid}] [{addr}] [{user}] [HB]",
             id = self.id(),
+            cid = self.cid(),
             addr = self.inner.remote_address(),
             user = self.auth,
         );
     }
 
     pub async fn relay_packet(self, pkt: Bytes, addr: Address, assoc_id: u16) -> eyre::Result<()> {
-        let addr_display = addr.to_string();
+        let addr_display = privacy::addr(&addr).to_string();
 
         info!(
-            "[{id:#010x}] [{addr}] [{user}] [UDP-IN] [{assoc_id:#06x}] [to-{mode}] from {src_addr}",
+            "[{id:#010x}] [{cid}] [{addr}] [{user}] [UDP-IN] [{assoc_id:#06x}] [to-{mode}] from \
+             {src_addr}",
             id = self.id(),
+            cid = self.cid(),
             addr = self.inner.remote_address(),
             user = self.auth,
             mode = self.udp_relay_mode.load().unwrap(),
             src_addr = addr_display,
         );
 
-        restful::traffic_rx(
-            &self.ctx,
-            &self.auth.get().ok_or_eyre("Unreachable")?,
-            pkt.len() as u64,
-        );
+        let uuid = self.auth.get().ok_or_eyre("Unreachable")?;
+        let payload = pkt.len();
+        destinations::traffic(uuid, &addr, 0, payload as u64);
 
         let res = match self.udp_relay_mode.load().unwrap() {
             UdpRelayMode::Native => self.model.packet_native(pkt, addr, assoc_id),
             UdpRelayMode::Quic => self.model.packet_quic(pkt, addr, assoc_id).await,
         };
 
+        // The payload is counted even if sending failed half way, the overhead
+        // only when known
+        let sent = *res.as_ref().unwrap_or(&payload);
+        let size = accounting::sent(&self.ctx.cfg.accounting, payload, sent);
+        self.stats.add_rx(size);
+        restful::traffic_rx(&uuid, size);
+
         if let Err(err) = res {
-            warn!(
-                "[{id:#010x}] [{addr}] [{user}] [UDP-IN] [{assoc_id:#06x}] [to-{mode}] from \
-                 {src_addr}: {err}",
+            log_error!(
+                err,
+                "[{id:#010x}] [{cid}] [{addr}] [{user}] [UDP-IN] [{assoc_id:#06x}] [to-{mode}] \
+                 from {src_addr}: {err}",
                 id = self.id(),
+                cid = self.cid(),
                 addr = self.inner.remote_address(),
                 user = self.auth,
                 mode = self.udp_relay_mode.load().unwrap(),
@@ -235,13 +632,109 @@ impl Connection {
     }
 }
 
-async fn resolve_dns(addr: &Address) -> Result<impl Iterator<Item = SocketAddr>, IoError> {
+/// Whether retrying the same address may succeed, as opposed to errors like
+/// `EHOSTUNREACH` where the next address is a better bet
+pub(super) fn is_transient(err: &IoError) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::TimedOut
+    )
+}
+
+/// Buffer the start of `conn` into `head` until a domain can be sniffed from
+/// it, the protocol turns out unknown, or `timeout` elapses
+async fn sniff(
+    conn: &mut (impl AsyncRead + Unpin),
+    head: &mut Vec<u8>,
+    timeout: Duration,
+) -> Result<Option<String>, IoError> {
+    let deadline = time::Instant::now() + timeout;
+    loop {
+        match sniff::sniff_stream(head) {
+            Sniffed::Domain(domain) => return Ok(Some(domain)),
+            Sniffed::Incomplete if head.len() < sniff::MAX_SNIFF_LEN => {}
+            Sniffed::Incomplete | Sniffed::Unknown => return Ok(None),
+        }
+
+        let len = head.len();
+        head.resize(sniff::MAX_SNIFF_LEN, 0);
+        let res = time::timeout_at(deadline, conn.read(&mut head[len..])).await;
+        let n = match res {
+            Ok(Ok(n)) => n,
+            Ok(Err(err)) => return Err(err),
+            Err(_) => 0,
+        };
+        head.truncate(len + n);
+        if n == 0 {
+            return Ok(None);
+        }
+    }
+}
+
+/// Read what the client sends within `timeout` into the empty `head`
+async fn read_head(
+    conn: &mut (impl AsyncRead + Unpin),
+    head: &mut Vec<u8>,
+    timeout: Duration,
+) -> Result<(), IoError> {
+    head.resize(sniff::MAX_SNIFF_LEN, 0);
+    let n = time::timeout(timeout, conn.read(head))
+        .await
+        .unwrap_or(Ok(0))?;
+    head.truncate(n);
+    Ok(())
+}
+
+/// Alternate between the address families, starting with the one of the
+/// first address, as the resolver sorts them by preference
+fn interleave_families(addrs: impl Iterator<Item = SocketAddr>) -> Vec<SocketAddr> {
+    let addrs = addrs.collect::<Vec<_>>();
+    let Some(first) = addrs.first() else {
+        return addrs;
+    };
+    let (mut preferred, mut other): (VecDeque<_>, VecDeque<_>) = addrs
+        .iter()
+        .partition(|addr| addr.is_ipv4() == first.is_ipv4());
+    let mut out = Vec::with_capacity(addrs.len());
+    while let Some(addr) = preferred.pop_front() {
+        out.push(addr);
+        out.extend(other.pop_front());
+    }
+    out.extend(other);
+    out
+}
+
+pub(super) fn domain_of(addr: &Address) -> Option<&str> {
+    match addr {
+        Address::DomainAddress(domain, _) => Some(domain),
+        _ => None,
+    }
+}
+
+pub(super) fn ip_of(addr: &Address) -> Option<IpAddr> {
+    match addr {
+        Address::SocketAddress(addr) => Some(addr.ip()),
+        _ => None,
+    }
+}
+
+pub(super) fn port(addr: &Address) -> u16 {
+    match addr {
+        Address::None => 0,
+        Address::DomainAddress(_, port) => *port,
+        Address::SocketAddress(addr) => addr.port(),
+    }
+}
+
+pub(super) async fn resolve_dns(
+    addr: &Address,
+) -> Result<impl Iterator<Item = SocketAddr>, IoError> {
     match addr {
         Address::None => Err(IoError::new(ErrorKind::InvalidInput, "empty address")),
-        Address::DomainAddress(domain, port) => Ok(net::lookup_host((domain.as_str(), *port))
-            .await?
-            .collect::<Vec<_>>()
-            .into_iter()),
+        Address::DomainAddress(domain, port) => Ok(dns::lookup(domain, *port).await?.into_iter()),
         Address::SocketAddress(addr) => Ok(vec![*addr].into_iter()),
     }
 }
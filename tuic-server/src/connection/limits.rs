@@ -0,0 +1,178 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use quinn::VarInt;
+use tokio::time::{self, Instant, MissedTickBehavior};
+use tracing::info;
+use uuid::Uuid;
+
+use super::Connection;
+use crate::{
+    error::Error,
+    rejections::{self, Reason},
+    webhook::{self, WebhookEvent},
+};
+
+/// How often the traffic of a connection is checked against its quota
+const QUOTA_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+const LIFETIME_EXCEEDED: VarInt = VarInt::from_u32(6004);
+const TRAFFIC_QUOTA_EXCEEDED: VarInt = VarInt::from_u32(6005);
+/// Closing connections over `max_connections_per_ip`
+pub(super) const TOO_MANY_CONNECTIONS: VarInt = VarInt::from_u32(6008);
+
+/// Streams open by each user across their connections, with
+/// `max_concurrent_streams_per_user`
+static USER_STREAMS: LazyLock<Mutex<HashMap<Uuid, usize>>> = LazyLock::new(Mutex::default);
+
+/// A stream counted towards `max_concurrent_streams_per_user` until dropped
+pub(super) struct StreamSlot(Uuid);
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        let mut streams = USER_STREAMS.lock().unwrap();
+        if let Some(count) = streams.get_mut(&self.0) {
+            *count -= 1;
+            if *count == 0 {
+                streams.remove(&self.0);
+            }
+        }
+    }
+}
+
+/// Connections open from each source IP, with `max_connections_per_ip`
+static IP_CONNECTIONS: LazyLock<Mutex<HashMap<IpAddr, usize>>> = LazyLock::new(Mutex::default);
+
+/// A connection counted towards `max_connections_per_ip` until dropped
+pub(super) struct IpSlot(IpAddr);
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        let mut connections = IP_CONNECTIONS.lock().unwrap();
+        if let Some(count) = connections.get_mut(&self.0) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.0);
+            }
+        }
+    }
+}
+
+/// Count a connection from `ip` towards `max_connections_per_ip`, failing once
+/// it has that many open. `None` without a limit
+pub(super) fn ip_slot(max: usize, ip: IpAddr) -> Result<Option<IpSlot>, Error> {
+    if max == 0 {
+        return Ok(None);
+    }
+    let ip = ip.to_canonical();
+    let mut connections = IP_CONNECTIONS.lock().unwrap();
+    let count = connections.entry(ip).or_default();
+    if *count >= max {
+        return Err(Error::TooManyConnections(max));
+    }
+    *count += 1;
+    Ok(Some(IpSlot(ip)))
+}
+
+impl Connection {
+    /// Close the connection once it has been open for
+    /// `max_connection_lifetime` or relayed `per_connection_traffic_quota`
+    /// bytes, each with its own error code. The user's overrides apply once
+    /// authenticated
+    pub(super) async fn enforce_limits(self) {
+        let opened = Instant::now();
+        tokio::select! {
+            () = self.auth.wait() => {}
+            _ = self.inner.closed() => return,
+        };
+        let policy = self.policy();
+        let lifetime = policy.max_connection_lifetime;
+        let quota = policy.per_connection_traffic_quota;
+        if lifetime.is_zero() && quota == 0 {
+            return;
+        }
+        let deadline = (!lifetime.is_zero()).then(|| opened + lifetime);
+        let mut interval = time::interval(QUOTA_CHECK_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _ = self.inner.closed() => return,
+                () = time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    info!(
+                        "[{id:#010x}] [{cid}] [{addr}] [{user}] connection open for {lifetime}, closing it",
+                        id = self.id(),
+                        cid = self.cid(),
+                        addr = self.inner.remote_address(),
+                        user = self.auth,
+                        lifetime = humantime::format_duration(lifetime),
+                    );
+                    self.inner.close(LIFETIME_EXCEEDED, b"Connection lifetime exceeded");
+                    self.notify_limit("max_connection_lifetime");
+                    return;
+                }
+                _ = interval.tick(), if quota != 0 => {
+                    let relayed = self.relayed();
+                    if relayed >= quota {
+                        info!(
+                            "[{id:#010x}] [{cid}] [{addr}] [{user}] connection relayed {relayed} bytes, \
+                             over its quota of {quota}, closing it",
+                            id = self.id(),
+                            cid = self.cid(),
+                            addr = self.inner.remote_address(),
+                            user = self.auth,
+                        );
+                        rejections::record(
+                            Reason::Quota,
+                            self.auth.get(),
+                            self.inner.remote_address().ip(),
+                        );
+                        self.inner.close(TRAFFIC_QUOTA_EXCEEDED, b"Traffic quota exceeded");
+                        self.notify_limit("per_connection_traffic_quota");
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    fn notify_limit(&self, reason: &str) {
+        if let Some(uuid) = self.auth.get() {
+            webhook::notify(
+                &self.ctx,
+                WebhookEvent::Limit,
+                uuid,
+                self.inner.remote_address(),
+                self.cid(),
+                Some(reason.to_owned()),
+            );
+        }
+    }
+
+    /// Count a relaying stream towards the authenticated user's
+    /// `max_concurrent_streams_per_user`, failing once they have that many.
+    /// `None` without a limit, or for `priority_users`
+    pub(super) fn stream_slot(&self) -> Result<Option<StreamSlot>, Error> {
+        let policy = self.policy();
+        let max = policy.max_concurrent_streams;
+        let Some(uuid) = self.auth.get().filter(|_| max != 0 && !policy.priority) else {
+            return Ok(None);
+        };
+        let mut streams = USER_STREAMS.lock().unwrap();
+        let count = streams.entry(uuid).or_default();
+        if *count >= max {
+            return Err(Error::TooManyStreams(max));
+        }
+        *count += 1;
+        Ok(Some(StreamSlot(uuid)))
+    }
+
+    /// Bytes relayed both ways, by finished and open streams and UDP
+    fn relayed(&self) -> u64 {
+        self.stats.tx() + self.stats.rx() + self.streams.relayed()
+    }
+}
@@ -0,0 +1,800 @@
+//! Serving standard MASQUE clients on the TUIC endpoint, over HTTP/3 on the
+//! connections negotiating the `h3` ALPN: CONNECT (RFC 9114) for TCP and
+//! CONNECT-UDP (RFC 9298) for UDP.
+//!
+//! Requests authenticate with `Proxy-Authorization: Basic` and the UUID and
+//! password of a user, then go through the same routing script, ACL,
+//! blocklist and dial limits as TUIC's own
+
+use std::{
+    collections::HashMap,
+    io::{Error as IoError, ErrorKind},
+    net::{IpAddr, SocketAddr, UdpSocket as StdUdpSocket},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use h3::{
+    ext::Protocol,
+    server::{RequestResolver, RequestStream},
+};
+use http::{
+    HeaderValue, Method, Request, Response, StatusCode,
+    header::{PROXY_AUTHENTICATE, PROXY_AUTHORIZATION},
+};
+use quinn::{Connection as QuinnConnection, SendDatagramError, VarInt};
+use quinn_proto::coding::Codec;
+use socket2::{Domain, Protocol as SocketProtocol, SockAddr, Socket, Type};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    sync::OnceCell,
+    time,
+};
+use tracing::info;
+use tuic::Address;
+use uuid::Uuid;
+
+use super::{
+    CorrelationId,
+    authenticated::Authenticated,
+    h3_fallback,
+    handle_task::{domain_of, ip_of, port, resolve_dns},
+    udp_session::{bind_device, tune_socket},
+};
+use crate::{
+    AppContext,
+    abuse::{self, Offender},
+    acl, auth, blocklist, destinations, dial,
+    error::{Error, log_error},
+    events, fail2ban, handshake, privacy, rejections, restful, scan, script, security_log,
+    upstream::Proxy,
+    users,
+};
+
+/// A CONNECT-UDP socket and the target it's connected to
+type UdpTarget = (Arc<UdpSocket>, Arc<Address>);
+
+/// The ALPN protocol of HTTP/3, telling MASQUE clients from TUIC ones
+pub const ALPN: &[u8] = b"h3";
+
+/// Where CONNECT-UDP requests ask for their target, the default URI template
+/// of RFC 9298: `/.well-known/masque/udp/{target_host}/{target_port}/`
+const UDP_PATH: &str = "/.well-known/masque/udp/";
+
+pub(super) type Stream = RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>;
+
+struct Masque {
+    ctx: Arc<AppContext>,
+    conn: QuinnConnection,
+    cid: CorrelationId,
+    /// The user of the first authenticated request, for the logs
+    auth: Authenticated,
+    /// The same user, set once the connection was counted in the RESTful
+    /// `/online`. The following requests must be this user
+    user: OnceCell<Uuid>,
+    /// The sockets of the CONNECT-UDP requests open and their targets, by
+    /// quarter stream ID
+    udp: Mutex<HashMap<u64, UdpTarget>>,
+}
+
+/// Whether `conn` negotiated HTTP/3 rather than TUIC
+pub fn is_masque(conn: &QuinnConnection) -> bool {
+    handshake::negotiated(conn).is_some_and(|n| n.protocol.as_deref() == Some(ALPN))
+}
+
+pub async fn handle(ctx: Arc<AppContext>, conn: QuinnConnection, cid: CorrelationId) {
+    let masque = Arc::new(Masque {
+        ctx: ctx.clone(),
+        conn: conn.clone(),
+        cid,
+        auth: Authenticated::new(),
+        user: OnceCell::new(),
+        udp: Mutex::default(),
+    });
+    info!(
+        "[{id:#010x}] [{cid}] [{addr}] [{user}] MASQUE connection established (QUIC {version})",
+        id = masque.id(),
+        cid = masque.cid(),
+        addr = conn.remote_address(),
+        user = masque.auth,
+        version = handshake::negotiated(&conn).map_or_else(
+            || "unknown".to_owned(),
+            |n| handshake::version_name(n.version)
+        ),
+    );
+
+    if let Err(err) = masque.clone().serve().await {
+        log_error!(
+            err,
+            "[{id:#010x}] [{cid}] [{addr}] [{user}] MASQUE connection error: {err}",
+            id = masque.id(),
+            cid = masque.cid(),
+            addr = conn.remote_address(),
+            user = masque.auth,
+        );
+    }
+
+    if let Some(uuid) = masque.user.get() {
+        restful::client_disconnect(&ctx, uuid, conn, masque.cid()).await;
+    }
+}
+
+impl Masque {
+    fn id(&self) -> u32 {
+        self.conn.stable_id() as u32
+    }
+
+    fn cid(&self) -> CorrelationId {
+        self.cid
+    }
+
+    async fn serve(self: Arc<Self>) -> Result<(), Error> {
+        let mut h3 = h3::server::builder()
+            .enable_extended_connect(true)
+            .enable_datagram(true)
+            .build::<_, Bytes>(h3_quinn::Connection::new(self.conn.clone()))
+            .await?;
+        tokio::spawn(self.clone().recv_datagrams());
+
+        while let Some(resolver) = h3.accept().await? {
+            tokio::spawn(self.clone().handle_request(resolver));
+        }
+        Ok(())
+    }
+
+    async fn handle_request(
+        self: Arc<Self>,
+        resolver: RequestResolver<h3_quinn::Connection, Bytes>,
+    ) {
+        let (req, mut stream) = match resolver.resolve_request().await {
+            Ok(res) => res,
+            Err(err) => {
+                let err = Error::from(err);
+                log_error!(
+                    err,
+                    "[{id:#010x}] [{cid}] [{addr}] [{user}] MASQUE request error: {err}",
+                    id = self.id(),
+                    cid = self.cid(),
+                    addr = self.conn.remote_address(),
+                    user = self.auth,
+                );
+                return;
+            }
+        };
+        // what a web server would answer, rather than a proxy's 400
+        if req.method() != Method::CONNECT
+            && let Some(upstream) = &self.ctx.cfg.fallback_h3_upstream
+        {
+            h3_fallback::proxy(upstream, self.conn.remote_address(), req, &mut stream).await;
+            return;
+        }
+        let udp = req.extensions().get::<Protocol>() == Some(&Protocol::CONNECT_UDP);
+        let kind = if udp { "CONNECT-UDP" } else { "CONNECT" };
+
+        let (uuid, target) = match self.accept(&req, udp).await {
+            Ok(res) => res,
+            Err(err) => {
+                reject(&mut stream, &err).await;
+                self.observe_abuse(&err);
+                log_error!(
+                    err,
+                    "[{id:#010x}] [{cid}] [{addr}] [{user}] [{kind}] {method} {uri}: {err}",
+                    id = self.id(),
+                    cid = self.cid(),
+                    addr = self.conn.remote_address(),
+                    user = self.auth,
+                    method = req.method(),
+                    uri = privacy::text(&req.uri().to_string()),
+                );
+                return;
+            }
+        };
+        let target_addr = target.to_string();
+        info!(
+            "[{id:#010x}] [{cid}] [{addr}] [{user}] [{kind}] {target_addr}",
+            id = self.id(),
+            cid = self.cid(),
+            addr = self.conn.remote_address(),
+            user = self.auth,
+            target_addr = privacy::text(&target_addr),
+        );
+
+        let res = if udp {
+            self.connect_udp(stream, uuid, target).await
+        } else {
+            self.connect_tcp(stream, uuid, target).await
+        };
+        if let Err(err) = res {
+            self.observe_abuse(&err);
+            log_error!(
+                err,
+                "[{id:#010x}] [{cid}] [{addr}] [{user}] [{kind}] {target_addr}: {err}",
+                id = self.id(),
+                cid = self.cid(),
+                addr = self.conn.remote_address(),
+                user = self.auth,
+                target_addr = privacy::text(&target_addr),
+            );
+        }
+    }
+
+    /// Authenticate a request and check the target it asks for, as TUIC
+    /// checks a connect or packet
+    async fn accept(&self, req: &Request<()>, udp: bool) -> Result<(Uuid, Address), Error> {
+        if req.method() != Method::CONNECT {
+            return Err(Error::MasqueRequest("only CONNECT requests are served"));
+        }
+        let uuid = self.authenticate(req).await?;
+        let target = if udp {
+            udp_target(req)
+        } else {
+            connect_target(req)
+        }
+        .ok_or(Error::MasqueRequest("no valid target host and port"))?;
+
+        let target_addr = target.to_string();
+        if !script::allow(uuid, &target_addr, if udp { "udp" } else { "tcp" }) {
+            return Err(Error::Denied(target_addr));
+        }
+        if !acl::allow_target(Some(uuid), domain_of(&target), port(&target)) {
+            return Err(Error::AclDenied(target_addr));
+        }
+        Ok((uuid, target))
+    }
+
+    /// Check the `Proxy-Authorization: Basic` credentials of a request, the
+    /// UUID as user name
+    async fn authenticate(&self, req: &Request<()>) -> Result<Uuid, Error> {
+        let credentials = req
+            .headers()
+            .get(PROXY_AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Basic "))
+            .and_then(|value| STANDARD.decode(value.trim()).ok())
+            .and_then(|value| String::from_utf8(value).ok());
+        let Some((uuid, password)) = credentials.as_deref().and_then(|c| c.split_once(':')) else {
+            return Err(Error::ProxyAuthRequired);
+        };
+        let uuid = Uuid::parse_str(uuid).map_err(|_| Error::AuthFailed(Uuid::nil()))?;
+
+        if self.user.get().is_some_and(|user| *user != uuid) {
+            return Err(Error::AuthFailed(uuid));
+        }
+        if abuse::is_banned(Offender::User(uuid)) {
+            return Err(Error::UserDisabled(uuid));
+        }
+
+        // The first request to pass picks the user of the connection. The ones
+        // arriving meanwhile wait for it, then must be the same user
+        let verified = AtomicBool::new(false);
+        let user = self
+            .user
+            .get_or_try_init(|| async {
+                let label = self.verify(uuid, password).await?;
+                verified.store(true, Ordering::Relaxed);
+                self.auth.set(uuid, label).await;
+                events::auth(uuid, self.conn.remote_address(), self.cid());
+                restful::client_connect(&self.ctx, &uuid, self.conn.clone(), self.cid()).await;
+                Ok::<_, Error>(uuid)
+            })
+            .await?;
+        if *user != uuid {
+            return Err(Error::AuthFailed(uuid));
+        }
+        if !verified.load(Ordering::Relaxed) {
+            self.verify(uuid, password).await?;
+        }
+        Ok(uuid)
+    }
+
+    /// Check the password of `uuid`, and the label it matched
+    async fn verify(&self, uuid: Uuid, password: &str) -> Result<Option<String>, Error> {
+        let addr = self.conn.remote_address();
+        match auth::verify(&self.ctx, uuid, addr, |expected| expected == password).await {
+            Some(_) if restful::over_quota(&self.ctx, &uuid) => Err(Error::QuotaExceeded(uuid)),
+            Some(label) => Ok(label),
+            None if auth::is_empty(&self.ctx) => Err(Error::NoUsers(uuid)),
+            None => Err(Error::AuthFailed(uuid)),
+        }
+    }
+
+    async fn connect_tcp(
+        &self,
+        mut stream: Stream,
+        uuid: Uuid,
+        target: Address,
+    ) -> Result<(), Error> {
+        let target_addr = target.to_string();
+        let throttle = scan::throttle(uuid).await;
+        let policy = users::policy(&self.ctx.cfg, Some(uuid));
+        let dial = dial::start(Some(uuid), policy.priority, policy.max_concurrent_dials).await;
+        destinations::connection(&target);
+        let tcp = match acl::proxy(domain_of(&target), ip_of(&target), port(&target)) {
+            Some(proxy) => self.dial_proxied(uuid, &proxy, &target).await,
+            None => match resolve_dns(&target).await {
+                Ok(addrs) => self.dial(uuid, &target, addrs).await,
+                Err(err) => Err(err.into()),
+            },
+        };
+        drop(dial);
+        drop(throttle);
+        scan::observe(&self.ctx, uuid, &target_addr, tcp.is_err());
+
+        let tcp = match tcp {
+            Ok(tcp) => tcp,
+            Err(err) => {
+                reject(&mut stream, &err).await;
+                return Err(err);
+            }
+        };
+        stream.send_response(Response::new(())).await?;
+
+        let (mut send, mut recv) = stream.split();
+        let (mut read, mut write) = tcp.into_split();
+        // a -> b tx
+        // a <- b rx
+        let (mut tx, mut rx) = (0, 0);
+        let uplink = async {
+            while let Some(mut data) = recv.recv_data().await? {
+                while data.has_remaining() {
+                    let len = data.chunk().len();
+                    write.write_all(data.chunk()).await?;
+                    data.advance(len);
+                    tx += len as u64;
+                }
+            }
+            write.shutdown().await?;
+            Ok::<_, Error>(())
+        };
+        let downlink = async {
+            let mut buf = vec![0; self.ctx.cfg.tcp_relay_buffer_size];
+            loop {
+                let n = read.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                send.send_data(Bytes::copy_from_slice(&buf[..n])).await?;
+                rx += n as u64;
+            }
+            send.finish().await?;
+            Ok::<_, Error>(())
+        };
+        let res = tokio::try_join!(uplink, downlink).map(|_| ());
+
+        restful::traffic_tx(&uuid, tx);
+        restful::traffic_rx(&uuid, rx);
+        destinations::traffic(uuid, &target, tx, rx);
+        res
+    }
+
+    /// Dial the resolved target addresses in turn
+    async fn dial(
+        &self,
+        uuid: Uuid,
+        target: &Address,
+        addrs: impl Iterator<Item = SocketAddr>,
+    ) -> Result<TcpStream, Error> {
+        let cfg = &self.ctx.cfg.outbound;
+        let mut last_err = None;
+        for addr in addrs {
+            if self.ctx.cfg.blocklist.is_some() && blocklist::is_blocked(addr.ip()) {
+                blocklist::record_hit(uuid).await;
+                last_err = Some(Error::Blocklisted(addr));
+                continue;
+            }
+            if !acl::allow(Some(uuid), domain_of(target), addr.ip(), addr.port()) {
+                last_err = Some(Error::AclDenied(addr.to_string()));
+                continue;
+            }
+
+            let res = match cfg.connect_timeout {
+                Some(timeout) => time::timeout(timeout, dial::connect(addr, cfg, false))
+                    .await
+                    .unwrap_or_else(|_| {
+                        Err(IoError::new(ErrorKind::TimedOut, "connect timed out"))
+                    }),
+                None => dial::connect(addr, cfg, false).await,
+            };
+            match res {
+                Ok(stream) => {
+                    stream.set_nodelay(true)?;
+                    return Ok(stream);
+                }
+                Err(err) => last_err = Some(err.into()),
+            }
+        }
+        Err(last_err
+            .unwrap_or_else(|| IoError::new(ErrorKind::NotFound, "no address resolved").into()))
+    }
+
+    /// Connect to the target through an `[[outbound.proxies]]` entry, IP
+    /// addresses checked like dialed ones
+    async fn dial_proxied(
+        &self,
+        uuid: Uuid,
+        proxy: &Proxy,
+        target: &Address,
+    ) -> Result<TcpStream, Error> {
+        if let Address::SocketAddress(addr) = target {
+            if self.ctx.cfg.blocklist.is_some() && blocklist::is_blocked(addr.ip()) {
+                blocklist::record_hit(uuid).await;
+                return Err(Error::Blocklisted(*addr));
+            }
+            if !acl::allow(Some(uuid), None, addr.ip(), addr.port()) {
+                return Err(Error::AclDenied(addr.to_string()));
+            }
+        }
+        proxy.connect(target, &self.ctx.cfg.outbound).await
+    }
+
+    async fn connect_udp(
+        &self,
+        mut stream: Stream,
+        uuid: Uuid,
+        target: Address,
+    ) -> Result<(), Error> {
+        let socket = match self.bind_udp(uuid, &target).await {
+            Ok(socket) => Arc::new(socket),
+            Err(err) => {
+                reject(&mut stream, &err).await;
+                return Err(err);
+            }
+        };
+        let quarter_id = stream.id().into_inner() / 4;
+        let target = Arc::new(target);
+        self.udp
+            .lock()
+            .unwrap()
+            .insert(quarter_id, (socket.clone(), target.clone()));
+
+        let mut resp = Response::new(());
+        resp.headers_mut()
+            .insert("capsule-protocol", HeaderValue::from_static("?1"));
+        let res = match stream.send_response(resp).await {
+            Ok(()) => {
+                let downlink = async {
+                    let mut buf = vec![0; u16::MAX as usize];
+                    loop {
+                        let n = match socket.recv(&mut buf).await {
+                            Ok(n) => n,
+                            // an ICMP error for an earlier datagram, reported
+                            // as the socket is connected
+                            Err(err)
+                                if matches!(
+                                    err.kind(),
+                                    ErrorKind::ConnectionRefused | ErrorKind::ConnectionReset
+                                ) =>
+                            {
+                                continue;
+                            }
+                            Err(err) => return Err(err.into()),
+                        };
+                        let mut datagram = BytesMut::with_capacity(n + 9);
+                        VarInt::from_u64(quarter_id).unwrap().encode(&mut datagram);
+                        VarInt::from_u32(0).encode(&mut datagram);
+                        datagram.put_slice(&buf[..n]);
+                        match self.conn.send_datagram(datagram.freeze()) {
+                            Ok(()) => {
+                                restful::traffic_rx(&uuid, n as u64);
+                                destinations::traffic(uuid, &target, 0, n as u64);
+                            }
+                            Err(SendDatagramError::ConnectionLost(err)) => return Err(err.into()),
+                            // too large for the path, dropped as a router would
+                            Err(_) => {}
+                        }
+                    }
+                };
+                // the capsules the client may send are of no use, the request
+                // ends with the stream
+                let closed = async {
+                    while stream.recv_data().await?.is_some() {}
+                    Ok::<_, Error>(())
+                };
+                tokio::select! {
+                    res = downlink => res,
+                    res = closed => res,
+                }
+            }
+            Err(err) => Err(err.into()),
+        };
+        self.udp.lock().unwrap().remove(&quarter_id);
+        _ = stream.finish().await;
+        res
+    }
+
+    /// Resolve the target of a CONNECT-UDP request, then bind a socket
+    /// connected to it from the `outbound` source address
+    async fn bind_udp(&self, uuid: Uuid, target: &Address) -> Result<UdpSocket, Error> {
+        if self.conn.max_datagram_size().is_none() {
+            return Err(Error::MasqueRequest(
+                "QUIC datagrams are disabled by the client",
+            ));
+        }
+        if !users::policy(&self.ctx.cfg, Some(uuid)).udp_relay {
+            return Err(Error::UdpRelayDisabled(uuid));
+        }
+        if let Some(proxy) = acl::proxy(domain_of(target), ip_of(target), port(target)) {
+            return Err(Error::UpstreamProxy(
+                proxy.name.clone(),
+                "CONNECT-UDP isn't relayed through outbound proxies",
+            ));
+        }
+        let addrs = resolve_dns(target).await?.collect::<Vec<_>>();
+        let addr = addrs
+            .iter()
+            .find(|addr| addr.is_ipv4() || self.ctx.cfg.udp_relay_ipv6)
+            .copied();
+        let Some(addr) = addr else {
+            return Err(match addrs.first() {
+                Some(addr) => Error::UdpRelayIpv6Disabled(*addr),
+                None => IoError::new(ErrorKind::NotFound, "no address resolved").into(),
+            });
+        };
+        if self.ctx.cfg.blocklist.is_some() && blocklist::is_blocked(addr.ip()) {
+            blocklist::record_hit(uuid).await;
+            return Err(Error::Blocklisted(addr));
+        }
+        if !acl::allow(Some(uuid), domain_of(target), addr.ip(), addr.port()) {
+            return Err(Error::AclDenied(addr.to_string()));
+        }
+
+        let (bind_ipv4, bind_ipv6) = self.ctx.cfg.udp_relay_bind();
+        let (domain, ip) = match addr {
+            SocketAddr::V4(_) => (Domain::IPV4, IpAddr::V4(bind_ipv4)),
+            SocketAddr::V6(_) => (Domain::IPV6, IpAddr::V6(bind_ipv6)),
+        };
+        let socket = Socket::new(domain, Type::DGRAM, Some(SocketProtocol::UDP))
+            .map_err(|err| Error::Socket("failed to create CONNECT-UDP socket", err))?;
+        socket.set_nonblocking(true).map_err(|err| {
+            Error::Socket("failed setting CONNECT-UDP socket as non-blocking", err)
+        })?;
+        dial::bind_port(ip, self.ctx.cfg.outbound.port_range, |addr| {
+            socket.bind(&SockAddr::from(addr))
+        })
+        .map_err(|err| Error::Socket("failed to bind CONNECT-UDP socket", err))?;
+        bind_device(&socket, &self.ctx)?;
+        tune_socket(&socket, &self.ctx)?;
+
+        let socket = UdpSocket::from_std(StdUdpSocket::from(socket))?;
+        socket.connect(addr).await?;
+        Ok(socket)
+    }
+
+    /// Relay the UDP payloads of the CONNECT-UDP requests, sent in HTTP
+    /// datagrams prefixed with the quarter stream ID of the request and the
+    /// context ID 0
+    async fn recv_datagrams(self: Arc<Self>) {
+        while let Ok(mut datagram) = self.conn.read_datagram().await {
+            let (Ok(quarter_id), Ok(context_id)) =
+                (VarInt::decode(&mut datagram), VarInt::decode(&mut datagram))
+            else {
+                continue;
+            };
+            if context_id.into_inner() != 0 {
+                continue;
+            }
+            let socket = self
+                .udp
+                .lock()
+                .unwrap()
+                .get(&quarter_id.into_inner())
+                .cloned();
+            if let (Some((socket, target)), Some(uuid)) = (socket, self.auth.get())
+                && socket.send(&datagram).await.is_ok()
+            {
+                restful::traffic_tx(&uuid, datagram.len() as u64);
+                destinations::traffic(uuid, &target, datagram.len() as u64, 0);
+            }
+        }
+    }
+
+    fn observe_abuse(&self, err: &Error) {
+        let ip = self.conn.remote_address().ip();
+        abuse::observe(&self.ctx, err, self.auth.get(), ip);
+        fail2ban::observe(err, ip);
+        security_log::observe(err, self.conn.remote_address());
+        rejections::observe(err, self.auth.get(), ip);
+        events::observe(err, self.conn.remote_address(), self.cid());
+    }
+}
+
+/// Answer a request that can't be served with the status matching `err`
+async fn reject(stream: &mut Stream, err: &Error) {
+    let status = status(err);
+    let mut resp = Response::new(());
+    *resp.status_mut() = status;
+    if status == StatusCode::PROXY_AUTHENTICATION_REQUIRED {
+        resp.headers_mut().insert(
+            PROXY_AUTHENTICATE,
+            HeaderValue::from_static("Basic realm=\"tuic\""),
+        );
+    }
+    _ = stream.send_response(resp).await;
+    _ = stream.finish().await;
+}
+
+fn status(err: &Error) -> StatusCode {
+    match err {
+        Error::ProxyAuthRequired | Error::AuthFailed(_) | Error::NoUsers(_) => {
+            StatusCode::PROXY_AUTHENTICATION_REQUIRED
+        }
+        Error::UserDisabled(_)
+        | Error::QuotaExceeded(_)
+        | Error::Denied(_)
+        | Error::AclDenied(_)
+        | Error::Blocklisted(_)
+        | Error::UdpRelayIpv6Disabled(_)
+        | Error::UdpRelayDisabled(_) => StatusCode::FORBIDDEN,
+        Error::MasqueRequest(_) => StatusCode::BAD_REQUEST,
+        _ => StatusCode::BAD_GATEWAY,
+    }
+}
+
+/// The target of a CONNECT request, from its authority
+fn connect_target(req: &Request<()>) -> Option<Address> {
+    let authority = req.uri().authority()?;
+    Some(address(authority.host(), authority.port_u16()?))
+}
+
+/// The target of a CONNECT-UDP request, from its path
+fn udp_target(req: &Request<()>) -> Option<Address> {
+    let rest = req.uri().path().strip_prefix(UDP_PATH)?;
+    let (host, port) = rest.trim_end_matches('/').split_once('/')?;
+    Some(address(&percent_decode(host)?, port.parse().ok()?))
+}
+
+fn address(host: &str, port: u16) -> Address {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    match host.parse::<IpAddr>() {
+        Ok(ip) => Address::SocketAddress(SocketAddr::new(ip, port)),
+        Err(_) => Address::DomainAddress(host.to_owned(), port),
+    }
+}
+
+/// Decode the `%XX` escapes of a URI path segment, like the colons of an IPv6
+/// address
+fn percent_decode(segment: &str) -> Option<String> {
+    let mut out = Vec::with_capacity(segment.len());
+    let mut bytes = segment.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            out.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            out.push(byte);
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use super::*;
+
+    fn connect(uri: &str) -> Request<()> {
+        Request::builder()
+            .method(Method::CONNECT)
+            .uri(uri)
+            .body(())
+            .unwrap()
+    }
+
+    fn socket(ip: impl Into<IpAddr>, port: u16) -> Address {
+        Address::SocketAddress(SocketAddr::new(ip.into(), port))
+    }
+
+    #[test]
+    fn udp_targets() {
+        let cases = [
+            (
+                "https://proxy/.well-known/masque/udp/192.0.2.6/443/",
+                Some(socket(Ipv4Addr::new(192, 0, 2, 6), 443)),
+            ),
+            (
+                "https://proxy/.well-known/masque/udp/2001%3Adb8%3A%3A1/53/",
+                Some(socket("2001:db8::1".parse::<Ipv6Addr>().unwrap(), 53)),
+            ),
+            (
+                "https://proxy/.well-known/masque/udp/example.com/443",
+                Some(Address::DomainAddress("example.com".to_owned(), 443)),
+            ),
+            ("https://proxy/.well-known/masque/udp/example.com/", None),
+            (
+                "https://proxy/.well-known/masque/udp/example.com/http/",
+                None,
+            ),
+            (
+                "https://proxy/.well-known/masque/udp/example.com/70000/",
+                None,
+            ),
+            ("https://proxy/.well-known/masque/udp/bad%zz/443/", None),
+            ("https://proxy/masque/udp/example.com/443/", None),
+        ];
+        for (uri, target) in cases {
+            assert_eq!(udp_target(&connect(uri)), target, "{uri}");
+        }
+    }
+
+    #[test]
+    fn connect_targets() {
+        let cases = [
+            (
+                "example.com:443",
+                Some(Address::DomainAddress("example.com".to_owned(), 443)),
+            ),
+            (
+                "192.0.2.6:80",
+                Some(socket(Ipv4Addr::new(192, 0, 2, 6), 80)),
+            ),
+            (
+                "[2001:db8::1]:443",
+                Some(socket("2001:db8::1".parse::<Ipv6Addr>().unwrap(), 443)),
+            ),
+            ("example.com", None),
+        ];
+        for (uri, target) in cases {
+            assert_eq!(connect_target(&connect(uri)), target, "{uri}");
+        }
+    }
+
+    #[test]
+    fn addresses() {
+        assert_eq!(address("[::1]", 53), socket(Ipv6Addr::LOCALHOST, 53));
+        assert_eq!(address("::1", 53), socket(Ipv6Addr::LOCALHOST, 53));
+        assert_eq!(
+            address("localhost", 53),
+            Address::DomainAddress("localhost".to_owned(), 53)
+        );
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("a%3Ab%3ac").as_deref(), Some("a:b:c"));
+        assert_eq!(percent_decode("plain").as_deref(), Some("plain"));
+        assert_eq!(percent_decode("%"), None);
+        assert_eq!(percent_decode("%4"), None);
+        assert_eq!(percent_decode("%zz"), None);
+        // not UTF-8
+        assert_eq!(percent_decode("%ff"), None);
+    }
+
+    #[test]
+    fn rejection_status() {
+        let uuid = Uuid::nil();
+        let cases = [
+            (
+                Error::ProxyAuthRequired,
+                StatusCode::PROXY_AUTHENTICATION_REQUIRED,
+            ),
+            (
+                Error::AuthFailed(uuid),
+                StatusCode::PROXY_AUTHENTICATION_REQUIRED,
+            ),
+            (
+                Error::NoUsers(uuid),
+                StatusCode::PROXY_AUTHENTICATION_REQUIRED,
+            ),
+            (Error::UserDisabled(uuid), StatusCode::FORBIDDEN),
+            (Error::QuotaExceeded(uuid), StatusCode::FORBIDDEN),
+            (
+                Error::AclDenied("192.0.2.6:25".to_owned()),
+                StatusCode::FORBIDDEN,
+            ),
+            (
+                Error::MasqueRequest("no valid target host and port"),
+                StatusCode::BAD_REQUEST,
+            ),
+            (Error::TimedOut, StatusCode::BAD_GATEWAY),
+        ];
+        for (err, expected) in cases {
+            assert_eq!(status(&err), expected, "{err}");
+        }
+    }
+}
@@ -1,31 +1,74 @@
 use std::{
     collections::HashMap,
-    sync::{Arc, Weak, atomic::AtomicU32},
+    sync::{
+        Arc, Weak,
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+    },
     time::Duration,
 };
 
 use arc_swap::ArcSwap;
 use quinn::{Connecting, Connection as QuinnConnection, VarInt};
 use register_count::Counter;
-use tokio::{sync::RwLock as AsyncRwLock, time};
+use tokio::{
+    sync::{Notify, RwLock as AsyncRwLock},
+    time,
+};
 use tracing::{debug, info, warn};
 use tuic_quinn::{Authenticate, Connection as Model, side};
+use uuid::Uuid;
 
-use self::{authenticated::Authenticated, udp_session::UdpSession};
-use crate::{AppContext, error::Error, restful, utils::UdpRelayMode};
+pub use self::correlation::CorrelationId;
+use self::{
+    authenticated::Authenticated, stats::ConnectionStats, streams::StreamRegistry,
+    udp_session::UdpSession,
+};
+use crate::{
+    AppContext,
+    abuse::{self, Offender},
+    auth,
+    error::{Error, log_error},
+    events, fail2ban, geoip,
+    handshake::{self, Negotiated},
+    hooks::{self, HookEvent},
+    rejections::{self, Reason},
+    restful, security_log,
+    users::{self, UserPolicy},
+    utils::{DuplicateAuthPolicy, UdpRelayMode, UserPasswords},
+};
 
+mod accounting;
+mod activity;
 mod authenticated;
+mod correlation;
+pub mod flow_control;
+mod h3_fallback;
 mod handle_stream;
 mod handle_task;
+mod limits;
+pub mod masque;
+pub mod registry;
+mod stats;
+pub mod streams;
+mod udp_batch;
 mod udp_session;
 
 pub const ERROR_CODE: VarInt = VarInt::from_u32(0);
+/// Closing the connections of users over their `quota`
+pub const USER_QUOTA_EXCEEDED: VarInt = VarInt::from_u32(6007);
+/// Closing connections from countries `acl.geoip` denies
+const COUNTRY_DENIED: VarInt = VarInt::from_u32(6009);
 pub const INIT_CONCURRENT_STREAMS: u32 = 32;
+/// What connections of `priority_users` start with once authenticated
+const PRIORITY_CONCURRENT_STREAMS: u32 = INIT_CONCURRENT_STREAMS * 4;
+const WINDOW_TUNING_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Clone)]
 pub struct Connection {
     ctx: Arc<AppContext>,
     inner: QuinnConnection,
+    /// Logged and reported along with the stable ID of `inner`
+    cid: CorrelationId,
     model: Model<side::Server>,
     auth: Authenticated,
     udp_sessions: Arc<AsyncRwLock<HashMap<u16, Weak<UdpSession>>>>,
@@ -34,12 +77,20 @@ pub struct Connection {
     remote_bi_stream_cnt: Counter,
     max_concurrent_uni_streams: Arc<AtomicU32>,
     max_concurrent_bi_streams: Arc<AtomicU32>,
+    stats: ConnectionStats,
+    /// Estimated bytes of packet fragments waiting for reassembly
+    fragment_bytes: Arc<AtomicUsize>,
+    gc_notify: Arc<Notify>,
+    /// Streams and datagrams waiting for the connection to be authenticated
+    pre_auth_tasks: Arc<AtomicUsize>,
+    streams: Arc<StreamRegistry>,
 }
 
 #[allow(clippy::too_many_arguments)]
 impl Connection {
     pub async fn handle(ctx: Arc<AppContext>, conn: Connecting) {
         let addr = conn.remote_address();
+        let cid = CorrelationId::random();
 
         let init = async {
             let conn = if ctx.cfg.zero_rtt_handshake {
@@ -51,18 +102,89 @@ impl Connection {
                 conn.await?
             };
 
-            Ok::<_, Error>(Self::new(ctx.clone(), conn))
+            Ok::<_, Error>(conn)
+        };
+
+        if !geoip::allow_client(addr.ip()) {
+            if let Ok(conn) = init.await {
+                info!(
+                    "[{id:#010x}] [{cid}] [{addr}] [unauthenticated] refusing connection: country \
+                     denied by acl.geoip",
+                    id = conn.stable_id() as u32,
+                );
+                rejections::record(Reason::Acl, None, addr.ip());
+                conn.close(COUNTRY_DENIED, b"Country denied");
+            }
+            return;
+        }
+
+        // held until the connection is closed
+        let _ip_slot = match limits::ip_slot(ctx.cfg.max_connections_per_ip, addr.ip()) {
+            Ok(slot) => slot,
+            Err(err) => {
+                if let Ok(conn) = init.await {
+                    info!(
+                        "[{id:#010x}] [{cid}] [{addr}] [unauthenticated] refusing connection: \
+                         {err}",
+                        id = conn.stable_id() as u32,
+                    );
+                    rejections::observe(&err, None, addr.ip());
+                    conn.close(limits::TOO_MANY_CONNECTIONS, b"Too many connections");
+                }
+                return;
+            }
         };
 
         match init.await {
+            Ok(conn) if ctx.cfg.masque.enabled && masque::is_masque(&conn) => {
+                masque::handle(ctx, conn, cid).await;
+            }
+            Ok(conn) if masque::is_masque(&conn) => h3_fallback::handle(&ctx, conn, cid).await,
             Ok(conn) => {
+                let conn = Self::new(ctx.clone(), conn, cid);
+                let negotiated = conn.negotiated();
                 info!(
-                    "[{id:#010x}] [{addr}] [{user}] connection established",
+                    "[{id:#010x}] [{cid}] [{addr}] [{user}] connection established (QUIC \
+                     {version})",
                     id = conn.id(),
+                    cid = conn.cid(),
                     user = conn.auth,
+                    version = negotiated.as_ref().map_or_else(
+                        || "unknown".to_owned(),
+                        |n| handshake::version_name(n.version)
+                    ),
                 );
+                if ctx.cfg.quic.log_transport_parameters
+                    && let Some(params) = negotiated.and_then(|n| n.transport_parameters)
+                {
+                    info!(
+                        "[{id:#010x}] [{cid}] [{addr}] [{user}] client transport parameters: \
+                         {params}",
+                        id = conn.id(),
+                        cid = conn.cid(),
+                        user = conn.auth,
+                    );
+                }
+                streams::register(conn.id(), conn.streams.clone()).await;
+                registry::register(&conn).await;
                 tokio::spawn(conn.clone().timeout_authenticate(ctx.cfg.auth_timeout));
                 tokio::spawn(conn.clone().collect_garbage());
+                tokio::spawn(conn.clone().watch_flow_control());
+                if ctx.cfg.quic.auto_tune_window {
+                    tokio::spawn(conn.clone().tune_windows());
+                }
+                if ctx.cfg.disconnect_on_password_change {
+                    tokio::spawn(conn.clone().watch_credentials());
+                }
+                if abuse::enabled() {
+                    tokio::spawn(conn.clone().watch_abuse());
+                }
+                if !ctx.cfg.max_connection_lifetime.is_zero()
+                    || ctx.cfg.per_connection_traffic_quota != 0
+                    || users::has_overrides()
+                {
+                    tokio::spawn(conn.clone().enforce_limits());
+                }
 
                 loop {
                     if conn.is_closed() {
@@ -84,40 +206,46 @@ impl Connection {
 
                     match handle_incoming.await {
                         Ok(()) => {}
-                        Err(err) if err.is_trivial() => {
-                            debug!(
-                                "[{id:#010x}] [{addr}] [{user}] {err}",
-                                id = conn.id(),
-                                user = conn.auth,
-                            );
-                        }
-                        Err(err) => warn!(
-                            "[{id:#010x}] [{addr}] [{user}] connection error: {err}",
+                        Err(err) => log_error!(
+                            err,
+                            "[{id:#010x}] [{cid}] [{addr}] [{user}] connection error: {err}",
                             id = conn.id(),
+                            cid = conn.cid(),
                             user = conn.auth,
                         ),
                     }
                 }
-            }
-            Err(err) if err.is_trivial() => {
-                debug!(
-                    "[{id:#010x}] [{addr}] [unauthenticated] {err}",
-                    id = u32::MAX,
-                );
+
+                streams::unregister(conn.id()).await;
+                registry::unregister(&conn).await;
+                conn.log_summary();
+                if let Some(uuid) = conn.auth.get() {
+                    hooks::exec(
+                        &ctx,
+                        HookEvent::Disconnect,
+                        conn.id(),
+                        conn.cid(),
+                        uuid,
+                        addr,
+                        conn.stats.duration(),
+                    );
+                }
             }
             Err(err) => {
-                warn!(
-                    "[{id:#010x}] [{addr}] [unauthenticated] {err}",
+                log_error!(
+                    err,
+                    "[{id:#010x}] [{cid}] [{addr}] [unauthenticated] {err}",
                     id = u32::MAX,
                 )
             }
         }
     }
 
-    fn new(ctx: Arc<AppContext>, conn: QuinnConnection) -> Self {
+    fn new(ctx: Arc<AppContext>, conn: QuinnConnection, cid: CorrelationId) -> Self {
         Self {
             ctx,
             inner: conn.clone(),
+            cid,
             model: Model::<side::Server>::new(conn),
             auth: Authenticated::new(),
             udp_sessions: Arc::new(AsyncRwLock::new(HashMap::new())),
@@ -126,37 +254,186 @@ impl Connection {
             remote_bi_stream_cnt: Counter::new(),
             max_concurrent_uni_streams: Arc::new(AtomicU32::new(INIT_CONCURRENT_STREAMS)),
             max_concurrent_bi_streams: Arc::new(AtomicU32::new(INIT_CONCURRENT_STREAMS)),
+            stats: ConnectionStats::new(),
+            fragment_bytes: Arc::new(AtomicUsize::new(0)),
+            gc_notify: Arc::new(Notify::new()),
+            pre_auth_tasks: Arc::new(AtomicUsize::new(0)),
+            streams: Arc::default(),
         }
     }
 
     async fn authenticate(&self, auth: &Authenticate) -> Result<(), Error> {
-        if self.auth.get().is_some() {
-            Err(Error::DuplicatedAuth)
-        } else if self
-            .ctx
-            .cfg
-            .users
-            .get(&auth.uuid())
-            .is_some_and(|password| auth.validate(password))
+        if let Some(uuid) = self.auth.get() {
+            restful::record_duplicate_auth(uuid).await;
+            let same = auth.uuid() == uuid
+                && auth::verify(&self.ctx, uuid, self.inner.remote_address(), |password| {
+                    auth.validate(password)
+                })
+                .await
+                .is_some();
+            if self.ctx.cfg.duplicate_auth == DuplicateAuthPolicy::Ignore && !same {
+                Err(Error::AuthFailed(auth.uuid()))
+            } else {
+                Err(Error::DuplicatedAuth)
+            }
+        } else if abuse::is_banned(Offender::User(auth.uuid())) {
+            Err(Error::UserDisabled(auth.uuid()))
+        } else if let Some(label) = auth::verify(
+            &self.ctx,
+            auth.uuid(),
+            self.inner.remote_address(),
+            |password| auth.validate(password),
+        )
+        .await
         {
-            self.auth.set(auth.uuid()).await;
+            if restful::over_quota(&self.ctx, &auth.uuid()) {
+                return Err(Error::QuotaExceeded(auth.uuid()));
+            }
+            self.auth.set(auth.uuid(), label).await;
+            events::auth(auth.uuid(), self.inner.remote_address(), self.cid);
+            if users::is_priority(&auth.uuid()) {
+                self.raise_stream_limits(PRIORITY_CONCURRENT_STREAMS);
+            }
             Ok(())
+        } else if auth::is_empty(&self.ctx) {
+            Err(Error::NoUsers(auth.uuid()))
         } else {
             Err(Error::AuthFailed(auth.uuid()))
         }
     }
 
+    /// Close the connection once the password it authenticated with changes or
+    /// the user gets removed, as it was authenticated with the old credentials.
+    /// The other passwords of the user may change freely
+    async fn watch_credentials(self) {
+        let mut users = users::subscribe();
+        tokio::select! {
+            () = self.auth.wait() => {}
+            _ = self.inner.closed() => return,
+        };
+        let Some(uuid) = self.auth.get() else {
+            return;
+        };
+        let label = self.auth.label();
+        let label = label.as_deref().map(String::as_str);
+        let password = |users: &HashMap<Uuid, UserPasswords>| {
+            users
+                .get(&uuid)
+                .and_then(|passwords| passwords.get(label))
+                .map(str::to_owned)
+        };
+        let old = password(&users.borrow_and_update());
+
+        loop {
+            tokio::select! {
+                res = users.changed() => if res.is_err() {
+                    return;
+                },
+                _ = self.inner.closed() => return,
+            };
+            if password(&users.borrow_and_update()) != old {
+                warn!(
+                    "[{id:#010x}] [{cid}] [{addr}] [{user}] credentials changed, closing \
+                     connection",
+                    id = self.id(),
+                    cid = self.cid(),
+                    addr = self.inner.remote_address(),
+                    user = self.auth,
+                );
+                self.inner
+                    .close(VarInt::from_u32(6003), b"Credentials changed");
+                return;
+            }
+        }
+    }
+
+    /// The limits of the authenticated user, the global ones until then
+    fn policy(&self) -> UserPolicy {
+        users::policy(&self.ctx.cfg, self.auth.get())
+    }
+
+    /// Let the client open at least `max` streams of each kind at once
+    fn raise_stream_limits(&self, max: u32) {
+        if self
+            .max_concurrent_bi_streams
+            .fetch_max(max, Ordering::Relaxed)
+            < max
+        {
+            self.inner.set_max_concurrent_bi_streams(VarInt::from(max));
+        }
+        if self
+            .max_concurrent_uni_streams
+            .fetch_max(max, Ordering::Relaxed)
+            < max
+        {
+            self.inner.set_max_concurrent_uni_streams(VarInt::from(max));
+        }
+    }
+
+    /// Close the connection once its source IP gets banned or its user
+    /// disabled by an `abuse` rule
+    async fn watch_abuse(self) {
+        tokio::select! {
+            () = self.auth.wait() => {}
+            _ = self.inner.closed() => return,
+        };
+        let Some(uuid) = self.auth.get() else {
+            return;
+        };
+        let mut offenders = vec![Offender::User(uuid)];
+        // so that operators aren't locked out by others behind the same IP
+        if !users::is_priority(&uuid) {
+            offenders.push(Offender::Ip(
+                self.inner.remote_address().ip().to_canonical(),
+            ));
+        }
+        tokio::select! {
+            () = abuse::wait_banned(&offenders) => {}
+            _ = self.inner.closed() => return,
+        };
+        warn!(
+            "[{id:#010x}] [{cid}] [{addr}] [{user}] banned for abuse, closing connection",
+            id = self.id(),
+            cid = self.cid(),
+            addr = self.inner.remote_address(),
+            user = self.auth,
+        );
+        self.inner
+            .close(VarInt::from_u32(6006), b"Banned for abuse");
+    }
+
+    /// Count an error towards the `abuse` rules, `fail2ban` and the rejection
+    /// statistics, logging it to `security_log`
+    fn observe_abuse(&self, err: &Error) {
+        let ip = self.inner.remote_address().ip();
+        abuse::observe(&self.ctx, err, self.auth.get(), ip);
+        fail2ban::observe(err, ip);
+        security_log::observe(err, self.inner.remote_address());
+        rejections::observe(err, self.auth.get(), ip);
+        events::observe(err, self.inner.remote_address(), self.cid);
+    }
+
+    /// Close the connection unless it authenticates within `timeout`. Once it
+    /// does, it's counted online right away until closed
     async fn timeout_authenticate(self, timeout: Duration) {
-        time::sleep(timeout).await;
+        tokio::select! {
+            biased;
+            () = self.auth.wait() => {}
+            () = time::sleep(timeout) => {}
+            _ = self.inner.closed() => return,
+        }
 
         match self.auth.get() {
             Some(uuid) => {
-                restful::client_connect(&self.ctx, &uuid, self.inner).await;
+                restful::client_connect(&self.ctx, &uuid, self.inner.clone(), self.cid).await;
+                self.inner.closed().await;
+                restful::client_disconnect(&self.ctx, &uuid, self.inner, self.cid).await;
             }
             None => {
                 warn!(
-                    "[{id:#010x}] [{addr}] [unauthenticated] [authenticate] timeout",
+                    "[{id:#010x}] [{cid}] [{addr}] [unauthenticated] [authenticate] timeout",
                     id = self.id(),
+                    cid = self.cid(),
                     addr = self.inner.remote_address(),
                 );
                 self.close();
@@ -164,31 +441,148 @@ impl Connection {
         }
     }
 
+    /// Collect packet fragments that can't be reassembled anymore, every
+    /// `gc_interval` while any are cached, or right away once they exceed
+    /// `gc_cache_threshold`
     async fn collect_garbage(self) {
+        let mut cached = (0, 0);
         loop {
-            time::sleep(self.ctx.cfg.gc_interval).await;
+            // start counting before measuring, so fragments cached meanwhile
+            // wake us up
+            self.fragment_bytes.store(0, Ordering::Relaxed);
+            let measured = self.model.fragment_cache_size();
+            self.fragment_bytes.fetch_add(measured.1, Ordering::Relaxed);
+            restful::record_fragment_cache(cached, measured);
+            cached = measured;
 
-            if self.is_closed() {
-                if let Some(uuid) = self.auth.get() {
-                    restful::client_disconnect(&self.ctx, &uuid, self.inner).await;
-                }
-                break;
+            tokio::select! {
+                _ = self.inner.closed() => break,
+                () = self.gc_notify.notified() => {}
+                () = time::sleep(self.ctx.cfg.gc_interval), if cached.0 != 0 => {}
             }
 
             debug!(
-                "[{id:#010x}] [{addr}] [{user}] packet fragment garbage collecting event",
+                "[{id:#010x}] [{cid}] [{addr}] [{user}] packet fragment garbage collecting event",
                 id = self.id(),
+                cid = self.cid(),
                 addr = self.inner.remote_address(),
                 user = self.auth,
             );
             self.model.collect_garbage(self.ctx.cfg.gc_lifetime);
+            restful::record_gc_run();
+        }
+
+        restful::record_fragment_cache(cached, (0, 0));
+    }
+
+    /// Account for a fragment waiting for reassembly, waking up the garbage
+    /// collector for the first one or when crossing `gc_cache_threshold`
+    fn fragment_cached(&self, size: usize) {
+        let prev = self.fragment_bytes.fetch_add(size, Ordering::Relaxed);
+        let threshold = self.ctx.cfg.gc_cache_threshold;
+        if prev == 0 || (threshold != 0 && prev < threshold && prev + size >= threshold) {
+            self.gc_notify.notify_one();
         }
     }
 
+    /// Keep the receive and send windows at twice the bandwidth-delay product
+    /// observed in each direction, bounded by `quic.receive_window` and
+    /// `quic.max_receive_window`, `quic.send_window` and
+    /// `quic.max_send_window`. The receive window is the connection-level
+    /// one, which bounds the streams as well, see `Server::init`
+    async fn tune_windows(self) {
+        let quic = &self.ctx.cfg.quic;
+        let min_rx = u64::from(quic.receive_window);
+        let max_rx = quic.max_receive_window.max(min_rx);
+        let min_tx = quic.send_window;
+        let max_tx = quic.max_send_window.max(min_tx);
+        let (mut current_rx, mut current_tx) = (min_rx, min_tx);
+        let stats = self.inner.stats();
+        let (mut last_rx, mut last_tx) = (stats.udp_rx.bytes, stats.udp_tx.bytes);
+
+        loop {
+            time::sleep(WINDOW_TUNING_INTERVAL).await;
+
+            if self.is_closed() {
+                break;
+            }
+
+            let stats = self.inner.stats();
+            let secs = WINDOW_TUNING_INTERVAL.as_secs_f64();
+            let rx_throughput = (stats.udp_rx.bytes - last_rx) as f64 / secs;
+            let tx_throughput = (stats.udp_tx.bytes - last_tx) as f64 / secs;
+            (last_rx, last_tx) = (stats.udp_rx.bytes, stats.udp_tx.bytes);
+            let rtt = self.inner.rtt();
+
+            // Skip changes under 1/8 to avoid flooding the peer with MAX_DATA updates
+            let rx_window = tuned_window(rx_throughput, rtt, min_rx, max_rx);
+            if current_rx.abs_diff(rx_window) >= current_rx / 8
+                && let Ok(varint) = VarInt::from_u64(rx_window)
+            {
+                self.log_window("receive", rx_window, rx_throughput);
+                self.inner.set_receive_window(varint);
+                current_rx = rx_window;
+            }
+
+            let tx_window = tuned_window(tx_throughput, rtt, min_tx, max_tx);
+            if current_tx.abs_diff(tx_window) >= current_tx / 8 {
+                self.log_window("send", tx_window, tx_throughput);
+                self.inner.set_send_window(tx_window);
+                current_tx = tx_window;
+            }
+        }
+    }
+
+    fn log_window(&self, direction: &str, window: u64, throughput: f64) {
+        debug!(
+            "[{id:#010x}] [{cid}] [{addr}] [{user}] {direction} window tuned to {window} bytes \
+             (rtt {rtt:?}, {throughput:.0} B/s)",
+            id = self.id(),
+            cid = self.cid(),
+            addr = self.inner.remote_address(),
+            user = self.auth,
+            rtt = self.inner.rtt(),
+        );
+    }
+
+    fn log_summary(&self) {
+        let reason = self
+            .inner
+            .close_reason()
+            .map_or_else(|| "unknown".to_owned(), |err| err.to_string());
+
+        info!(
+            "[{id:#010x}] [{cid}] [{addr}] [{user}] connection closed: duration={duration} \
+             streams={streams} udp_sessions={udp_sessions} tx={tx} rx={rx} \
+             download_window_limited={down} upload_window_limited={up} reason=\"{reason}\"",
+            id = self.id(),
+            cid = self.cid(),
+            addr = self.inner.remote_address(),
+            user = self.auth,
+            duration =
+                humantime::format_duration(Duration::from_secs(self.stats.duration().as_secs())),
+            streams = self.stats.streams(),
+            udp_sessions = self.stats.udp_sessions(),
+            tx = self.stats.tx(),
+            rx = self.stats.rx(),
+            down = humantime::format_duration(self.stats.download_limited()),
+            up = humantime::format_duration(self.stats.upload_limited()),
+        );
+    }
+
     fn id(&self) -> u32 {
         self.inner.stable_id() as u32
     }
 
+    fn cid(&self) -> CorrelationId {
+        self.cid
+    }
+
+    /// What the handshake negotiated, `None` until it completed
+    fn negotiated(&self) -> Option<Box<Negotiated>> {
+        handshake::negotiated(&self.inner)
+    }
+
     fn is_closed(&self) -> bool {
         self.inner.close_reason().is_some()
     }
@@ -197,3 +591,36 @@ impl Connection {
         self.inner.close(ERROR_CODE, &[]);
     }
 }
+
+/// Twice the bandwidth-delay product of `throughput` bytes per second over
+/// `rtt`, bounded by `min` and `max`
+fn tuned_window(throughput: f64, rtt: Duration, min: u64, max: u64) -> u64 {
+    let bdp = (throughput * rtt.as_secs_f64()) as u64;
+    bdp.saturating_mul(2).clamp(min, max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    #[test]
+    fn window_follows_bdp() {
+        // 100 MiB/s over 100ms
+        let window = tuned_window(
+            (100 * MIB) as f64,
+            Duration::from_millis(100),
+            8 * MIB,
+            64 * MIB,
+        );
+        assert_eq!(window, 20 * MIB);
+    }
+
+    #[test]
+    fn window_is_bounded() {
+        let rtt = Duration::from_millis(50);
+        assert_eq!(tuned_window(0.0, rtt, 8 * MIB, 64 * MIB), 8 * MIB);
+        assert_eq!(tuned_window(f64::MAX, rtt, 8 * MIB, 64 * MIB), 64 * MIB);
+    }
+}
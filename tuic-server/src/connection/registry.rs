@@ -0,0 +1,87 @@
+//! Every open connection, listed by the RESTful `/connections`
+
+use std::{net::SocketAddr, sync::LazyLock};
+
+use chashmap::CHashMap;
+use quinn::VarInt;
+use serde_json::{Value, json};
+use uuid::Uuid;
+
+use super::{Connection, CorrelationId};
+use crate::{handshake, peaks};
+
+static CONNECTIONS: LazyLock<CHashMap<u32, Connection>> = LazyLock::new(CHashMap::new);
+
+pub(super) async fn register(conn: &Connection) {
+    if CONNECTIONS.insert(conn.id(), conn.clone()).await.is_none() {
+        peaks::connection_opened();
+    }
+}
+
+pub(super) async fn unregister(conn: &Connection) {
+    if CONNECTIONS.remove(&conn.id()).await.is_some() {
+        peaks::connection_closed();
+    }
+}
+
+/// The UDP bytes each open connection sent and received so far, QUIC
+/// overhead included
+pub async fn wire_bytes() -> Vec<(u32, u64)> {
+    CONNECTIONS
+        .clone_locking()
+        .await
+        .into_iter()
+        .map(|(id, conn)| {
+            let stats = conn.inner.stats();
+            (id, stats.udp_tx.bytes + stats.udp_rx.bytes)
+        })
+        .collect()
+}
+
+/// The open connections and their stats, oldest first. Traffic includes the
+/// bytes relayed so far by open streams
+pub async fn list() -> Vec<Value> {
+    let mut conns: Vec<_> = CONNECTIONS
+        .clone_locking()
+        .await
+        .into_iter()
+        .map(|(_, conn)| conn)
+        .collect();
+    conns.sort_unstable_by_key(|conn| std::cmp::Reverse(conn.stats.duration()));
+
+    let mut list = Vec::with_capacity(conns.len());
+    for conn in conns {
+        let (open_tx, open_rx) = conn.streams.traffic();
+        let udp_relay_mode = (**conn.udp_relay_mode.load()).map(|mode| mode.to_string());
+        let negotiated = conn.negotiated();
+        list.push(json!({
+            "id": format!("{:#010x}", conn.id()),
+            "correlation_id": conn.cid().to_string(),
+            "user": conn.auth.get(),
+            "label": conn.auth.label().as_deref(),
+            "addr": conn.inner.remote_address(),
+            "uptime_secs": conn.stats.duration().as_secs(),
+            "quic_version": negotiated.as_ref().map(|n| handshake::version_name(n.version)),
+            "server_name": negotiated.and_then(|n| n.server_name),
+            "rtt_ms": conn.inner.rtt().as_micros() as f64 / 1000.0,
+            "tx": conn.stats.tx() + open_tx,
+            "rx": conn.stats.rx() + open_rx,
+            "open_streams": conn.remote_bi_stream_cnt.count() + conn.remote_uni_stream_cnt.count(),
+            "udp_sessions": conn.udp_sessions.read().await.len(),
+            "udp_relay_mode": udp_relay_mode,
+        }));
+    }
+    list
+}
+
+/// Close a connection with `code`, returning its user, remote address and
+/// correlation ID, or `None` if it isn't open
+pub async fn close(
+    id: u32,
+    code: VarInt,
+    reason: &[u8],
+) -> Option<(Option<Uuid>, SocketAddr, CorrelationId)> {
+    let conn = CONNECTIONS.get(&id).await?;
+    conn.inner.close(code, reason);
+    Some((conn.auth.get(), conn.inner.remote_address(), conn.cid()))
+}
@@ -0,0 +1,107 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+/// Per-connection counters, summarized when the connection closes
+#[derive(Clone)]
+pub struct ConnectionStats(Arc<ConnectionStatsInner>);
+
+struct ConnectionStatsInner {
+    started_at: Instant,
+    streams: AtomicU64,
+    udp_sessions: AtomicU64,
+    tx: AtomicU64,
+    rx: AtomicU64,
+    /// Microseconds writes to the client's streams spent blocked
+    send_blocked: AtomicU64,
+    /// Seconds limited by a flow control window
+    download_limited: AtomicU64,
+    upload_limited: AtomicU64,
+}
+
+impl ConnectionStats {
+    pub fn new() -> Self {
+        Self(Arc::new(ConnectionStatsInner {
+            started_at: Instant::now(),
+            streams: AtomicU64::new(0),
+            udp_sessions: AtomicU64::new(0),
+            tx: AtomicU64::new(0),
+            rx: AtomicU64::new(0),
+            send_blocked: AtomicU64::new(0),
+            download_limited: AtomicU64::new(0),
+            upload_limited: AtomicU64::new(0),
+        }))
+    }
+
+    pub fn stream_opened(&self) {
+        self.0.streams.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn udp_session_opened(&self) {
+        self.0.udp_sessions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// client -> target
+    pub fn add_tx(&self, size: u64) {
+        self.0.tx.fetch_add(size, Ordering::Relaxed);
+    }
+
+    /// target -> client
+    pub fn add_rx(&self, size: u64) {
+        self.0.rx.fetch_add(size, Ordering::Relaxed);
+    }
+
+    pub fn add_send_blocked(&self, blocked: Duration) {
+        self.0
+            .send_blocked
+            .fetch_add(blocked.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_download_limited(&self, limited: Duration) {
+        self.0
+            .download_limited
+            .fetch_add(limited.as_secs(), Ordering::Relaxed);
+    }
+
+    pub fn add_upload_limited(&self, limited: Duration) {
+        self.0
+            .upload_limited
+            .fetch_add(limited.as_secs(), Ordering::Relaxed);
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.0.started_at.elapsed()
+    }
+
+    pub fn streams(&self) -> u64 {
+        self.0.streams.load(Ordering::Relaxed)
+    }
+
+    pub fn udp_sessions(&self) -> u64 {
+        self.0.udp_sessions.load(Ordering::Relaxed)
+    }
+
+    pub fn tx(&self) -> u64 {
+        self.0.tx.load(Ordering::Relaxed)
+    }
+
+    pub fn rx(&self) -> u64 {
+        self.0.rx.load(Ordering::Relaxed)
+    }
+
+    pub fn send_blocked(&self) -> Duration {
+        Duration::from_micros(self.0.send_blocked.load(Ordering::Relaxed))
+    }
+
+    pub fn download_limited(&self) -> Duration {
+        Duration::from_secs(self.0.download_limited.load(Ordering::Relaxed))
+    }
+
+    pub fn upload_limited(&self) -> Duration {
+        Duration::from_secs(self.0.upload_limited.load(Ordering::Relaxed))
+    }
+}
@@ -0,0 +1,113 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc, LazyLock, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use chashmap::CHashMap;
+use chrono::{DateTime, Local};
+use serde_json::{Value, json};
+
+/// The stream registry of every open connection, by connection ID
+static CONNECTIONS: LazyLock<CHashMap<u32, Arc<StreamRegistry>>> = LazyLock::new(CHashMap::new);
+
+/// The TCP streams a connection is currently relaying
+#[derive(Default)]
+pub struct StreamRegistry {
+    next_id: AtomicU64,
+    streams: Mutex<HashMap<u64, Arc<RelayedStream>>>,
+}
+
+pub struct RelayedStream {
+    target: String,
+    started_at: DateTime<Local>,
+    /// client -> target
+    pub tx: AtomicU64,
+    /// client <- target
+    pub rx: AtomicU64,
+}
+
+/// Keeps a stream listed until dropped
+pub struct StreamEntry {
+    registry: Arc<StreamRegistry>,
+    id: u64,
+    pub stream: Arc<RelayedStream>,
+}
+
+/// Make the streams of a connection listable
+pub async fn register(conn_id: u32, registry: Arc<StreamRegistry>) {
+    CONNECTIONS.insert(conn_id, registry).await;
+}
+
+pub async fn unregister(conn_id: u32) {
+    CONNECTIONS.remove(&conn_id).await;
+}
+
+impl StreamRegistry {
+    pub fn open(self: &Arc<Self>, target: String) -> StreamEntry {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let stream = Arc::new(RelayedStream {
+            target,
+            started_at: Local::now(),
+            tx: AtomicU64::new(0),
+            rx: AtomicU64::new(0),
+        });
+        self.streams.lock().unwrap().insert(id, stream.clone());
+        StreamEntry {
+            registry: self.clone(),
+            id,
+            stream,
+        }
+    }
+}
+
+impl StreamRegistry {
+    /// Bytes relayed so far by the open streams, both ways
+    pub fn relayed(&self) -> u64 {
+        let (tx, rx) = self.traffic();
+        tx + rx
+    }
+
+    /// Bytes relayed so far by the open streams, `(tx, rx)`
+    pub fn traffic(&self) -> (u64, u64) {
+        self.streams
+            .lock()
+            .unwrap()
+            .values()
+            .fold((0, 0), |(tx, rx), stream| {
+                (
+                    tx + stream.tx.load(Ordering::Relaxed),
+                    rx + stream.rx.load(Ordering::Relaxed),
+                )
+            })
+    }
+}
+
+impl Drop for StreamEntry {
+    fn drop(&mut self) {
+        self.registry.streams.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// The streams relayed by a connection, `None` if it isn't open
+pub async fn list(conn_id: u32) -> Option<Vec<Value>> {
+    let registry = CONNECTIONS.get(&conn_id).await?.clone();
+    let streams = registry.streams.lock().unwrap();
+    let mut list: Vec<_> = streams.iter().collect();
+    list.sort_unstable_by_key(|(id, _)| **id);
+    Some(
+        list.into_iter()
+            .map(|(id, stream)| {
+                json!({
+                    "id": id,
+                    "target": stream.target,
+                    "started_at": stream.started_at.to_rfc3339(),
+                    "tx": stream.tx.load(Ordering::Relaxed),
+                    "rx": stream.rx.load(Ordering::Relaxed),
+                })
+            })
+            .collect(),
+    )
+}
@@ -0,0 +1,191 @@
+//! Moving several datagrams per syscall on the relay sockets of UDP sessions,
+//! with `recvmmsg(2)` and `sendmmsg(2)` on Linux, one at a time elsewhere
+
+use std::{io::Result as IoResult, net::SocketAddr};
+
+use bytes::Bytes;
+use tokio::net::UdpSocket;
+
+/// Most datagrams moved by a syscall
+pub const BATCH_SIZE: usize = 32;
+
+/// Wait for datagrams of at most `size` bytes on `socket`, returning those
+/// received, at least one
+pub async fn recv(socket: &UdpSocket, size: usize) -> IoResult<Vec<(Bytes, SocketAddr)>> {
+    #[cfg(target_os = "linux")]
+    {
+        use tokio::io::Interest;
+
+        socket
+            .async_io(Interest::READABLE, || recvmmsg(socket, size))
+            .await
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let mut buf = vec![0; size];
+        let (n, addr) = socket.recv_from(&mut buf).await?;
+        buf.truncate(n);
+        Ok(vec![(Bytes::from(buf), addr)])
+    }
+}
+
+/// Send `pkts` to their addresses, returning how many were sent before an
+/// error, if any
+pub async fn send(socket: &UdpSocket, pkts: &[(Bytes, SocketAddr)]) -> (usize, IoResult<()>) {
+    let mut sent = 0;
+    while sent < pkts.len() {
+        #[cfg(target_os = "linux")]
+        let res = {
+            use tokio::io::Interest;
+
+            socket
+                .async_io(Interest::WRITABLE, || sendmmsg(socket, &pkts[sent..]))
+                .await
+        };
+        #[cfg(not(target_os = "linux"))]
+        let res = {
+            let (pkt, addr) = &pkts[sent];
+            socket.send_to(pkt, *addr).await.map(|_| 1)
+        };
+        match res {
+            Ok(n) => sent += n,
+            Err(err) => return (sent, Err(err)),
+        }
+    }
+    (sent, Ok(()))
+}
+
+#[cfg(target_os = "linux")]
+fn recvmmsg(socket: &UdpSocket, size: usize) -> IoResult<Vec<(Bytes, SocketAddr)>> {
+    use std::{cell::RefCell, io::Error as IoError, mem, os::fd::AsRawFd, ptr};
+
+    use socket2::SockAddr;
+
+    thread_local! {
+        /// Shared by the sessions on this thread, as datagrams are copied out
+        /// before returning
+        static BUF: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+    }
+
+    BUF.with_borrow_mut(|buf| {
+        buf.resize(size * BATCH_SIZE, 0);
+        let mut addrs: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { mem::zeroed() };
+        let mut iovs: [libc::iovec; BATCH_SIZE] = unsafe { mem::zeroed() };
+        for (iov, slot) in iovs.iter_mut().zip(buf.chunks_exact_mut(size)) {
+            iov.iov_base = slot.as_mut_ptr().cast();
+            iov.iov_len = slot.len();
+        }
+        let mut msgs: [libc::mmsghdr; BATCH_SIZE] = unsafe { mem::zeroed() };
+        for ((msg, iov), addr) in msgs.iter_mut().zip(&mut iovs).zip(&mut addrs) {
+            msg.msg_hdr.msg_name = ptr::from_mut(addr).cast();
+            msg.msg_hdr.msg_namelen = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            msg.msg_hdr.msg_iov = iov;
+            msg.msg_hdr.msg_iovlen = 1;
+        }
+
+        let ret = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                BATCH_SIZE as libc::c_uint,
+                libc::MSG_DONTWAIT,
+                ptr::null_mut(),
+            )
+        };
+        if ret < 0 {
+            return Err(IoError::last_os_error());
+        }
+
+        let mut pkts = Vec::with_capacity(ret as usize);
+        for (i, msg) in msgs.iter().take(ret as usize).enumerate() {
+            // SAFETY: the kernel wrote a socket address of `msg_namelen` bytes
+            let addr = unsafe { SockAddr::new(addrs[i], msg.msg_hdr.msg_namelen) };
+            let Some(addr) = addr.as_socket() else {
+                continue;
+            };
+            let start = i * size;
+            pkts.push((
+                Bytes::copy_from_slice(&buf[start..start + msg.msg_len as usize]),
+                addr,
+            ));
+        }
+        Ok(pkts)
+    })
+}
+
+/// Returns how many of `pkts` were sent, at least one
+#[cfg(target_os = "linux")]
+fn sendmmsg(socket: &UdpSocket, pkts: &[(Bytes, SocketAddr)]) -> IoResult<usize> {
+    use std::{io::Error as IoError, mem, os::fd::AsRawFd};
+
+    use socket2::SockAddr;
+
+    let pkts = &pkts[..pkts.len().min(BATCH_SIZE)];
+    let addrs = pkts
+        .iter()
+        .map(|(_, addr)| SockAddr::from(*addr))
+        .collect::<Vec<_>>();
+    let mut iovs = pkts
+        .iter()
+        .map(|(pkt, _)| libc::iovec {
+            iov_base: pkt.as_ptr().cast_mut().cast(),
+            iov_len: pkt.len(),
+        })
+        .collect::<Vec<_>>();
+    let mut msgs = iovs
+        .iter_mut()
+        .zip(&addrs)
+        .map(|(iov, addr)| {
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            msg.msg_hdr.msg_name = addr.as_ptr().cast_mut().cast();
+            msg.msg_hdr.msg_namelen = addr.len();
+            msg.msg_hdr.msg_iov = iov;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg
+        })
+        .collect::<Vec<_>>();
+
+    let ret = unsafe {
+        libc::sendmmsg(
+            socket.as_raw_fd(),
+            msgs.as_mut_ptr(),
+            msgs.len() as libc::c_uint,
+            libc::MSG_DONTWAIT,
+        )
+    };
+    if ret < 0 {
+        return Err(IoError::last_os_error());
+    }
+    Ok(ret as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn batches_round_trip() {
+        let a = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let b = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let to = b.local_addr().unwrap();
+        let pkts = (0..BATCH_SIZE + 8)
+            .map(|i| (Bytes::from(vec![i as u8; i + 1]), to))
+            .collect::<Vec<_>>();
+
+        let (sent, res) = send(&a, &pkts).await;
+        res.unwrap();
+        assert_eq!(sent, pkts.len());
+
+        let mut received = Vec::new();
+        while received.len() < pkts.len() {
+            received.extend(recv(&b, 1500).await.unwrap());
+        }
+        let from = a.local_addr().unwrap();
+        for ((pkt, addr), (expected, _)) in received.iter().zip(&pkts) {
+            assert_eq!(pkt, expected);
+            assert_eq!(*addr, from);
+        }
+    }
+}
@@ -1,34 +1,98 @@
 use std::{
+    collections::HashMap,
     io::Error as IoError,
-    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket as StdUdpSocket},
-    sync::{Arc, Weak},
+    net::{IpAddr, SocketAddr, UdpSocket as StdUdpSocket},
+    sync::{
+        Arc, LazyLock, Weak,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
 };
 
 use bytes::Bytes;
+use chashmap::CHashMap;
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 use tokio::{
-    net::UdpSocket,
-    sync::{RwLock as AsyncRwLock, oneshot},
+    io::AsyncReadExt,
+    net::{TcpStream, UdpSocket},
+    sync::{Mutex as AsyncMutex, Notify, RwLock as AsyncRwLock, mpsc, oneshot},
 };
-use tracing::warn;
+use tracing::{info, warn};
 use tuic::Address;
 
-use super::Connection;
-use crate::{AppContext, error::Error, utils::FutResultExt};
+use super::{
+    Connection,
+    udp_batch::{self, BATCH_SIZE},
+};
+use crate::{
+    AppContext, dial,
+    error::Error,
+    privacy,
+    upstream::{self, Proxy},
+    utils::{self, FutResultExt},
+};
+
+/// The address family (`true` for IPv6) each dual-stack domain last answered
+/// from, so following packets avoid a family broken on the egress
+static FAMILY_PREFERENCE: LazyLock<CHashMap<String, (bool, Instant)>> =
+    LazyLock::new(CHashMap::new);
+
+/// How long a learned family preference is trusted before probing again
+const FAMILY_PREFERENCE_TTL: Duration = Duration::from_secs(600);
+
+/// Domains whose family preference is kept at most. Reaching it sweeps the
+/// expired ones, or forgets them all if over half are still trusted, as they
+/// only spare a probe
+const MAX_FAMILY_PREFERENCES: usize = 65536;
+
+/// Outbound packets waiting to be sent in batches, beyond which senders wait
+const SEND_QUEUE_LEN: usize = BATCH_SIZE * 4;
+
+/// The buffers of UDP relay sockets on Windows when `udp_relay_socket_buffer`
+/// is unset, the default of 64 KiB dropping the bursts of fast downloads
+const WINDOWS_SOCKET_BUFFER: usize = 1 << 20;
+
+/// A packet to send, its destination and whether it goes out of `socket_v6`
+type Outbound = (Bytes, SocketAddr, bool);
+
+/// A SOCKS5 UDP association with an `[[outbound.proxies]]` entry
+struct ProxiedUdp {
+    socket: Arc<UdpSocket>,
+    /// Set once the proxy ended the association or the session closed
+    closed: Arc<AtomicBool>,
+}
 
 pub struct UdpSession {
     ctx: Arc<AppContext>,
     assoc_id: u16,
     conn: Connection,
-    socket_v4: UdpSocket,
+    /// `None` with `udp_relay_dual_stack`, IPv4 is then sent from the IPv6
+    /// socket through v4-mapped addresses
+    socket_v4: Option<UdpSocket>,
     socket_v6: Option<UdpSocket>,
     close: AsyncRwLock<Option<oneshot::Sender<()>>>,
+    /// Addresses probed on behalf of dual-stack domains, waiting for an answer
+    probing: AsyncRwLock<HashMap<IpAddr, String>>,
+    probe_cnt: AtomicUsize,
+    /// Drained by the listening task, so a session only sending keeps its
+    /// ports, which peers may have learned
+    send_queue: mpsc::Sender<Outbound>,
+    /// Associations with the outbound proxies packets were sent through, by
+    /// proxy name
+    proxied: AsyncMutex<HashMap<String, ProxiedUdp>>,
+    /// Packets received through them, relayed by the listening task
+    proxied_recv: mpsc::Sender<(Bytes, Address)>,
+    /// Notified on sending through a proxy, keeping the session alive
+    proxied_sent: Notify,
 }
 
 impl UdpSession {
     // spawn a task which actually owns itself, then return its wake reference.
     pub fn new(ctx: Arc<AppContext>, conn: Connection, assoc_id: u16) -> Result<Weak<Self>, Error> {
-        let socket_v4 = {
+        let dual_stack = ctx.cfg.udp_relay_ipv6 && ctx.cfg.udp_relay_dual_stack;
+        let (bind_ipv4, bind_ipv6) = ctx.cfg.udp_relay_bind();
+
+        let socket_v4 = if !dual_stack {
             let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))
                 .map_err(|err| Error::Socket("failed to create UDP associate IPv4 socket", err))?;
 
@@ -39,14 +103,16 @@ impl UdpSession {
                 )
             })?;
 
-            socket
-                .bind(&SockAddr::from(SocketAddr::from((
-                    Ipv4Addr::UNSPECIFIED,
-                    0,
-                ))))
-                .map_err(|err| Error::Socket("failed to bind UDP associate IPv4 socket", err))?;
+            dial::bind_port(bind_ipv4.into(), ctx.cfg.outbound.port_range, |addr| {
+                socket.bind(&SockAddr::from(addr))
+            })
+            .map_err(|err| Error::Socket("failed to bind UDP associate IPv4 socket", err))?;
+            bind_device(&socket, &ctx)?;
+            tune_socket(&socket, &ctx)?;
 
-            UdpSocket::from_std(StdUdpSocket::from(socket))?
+            Some(UdpSocket::from_std(StdUdpSocket::from(socket))?)
+        } else {
+            None
         };
 
         let socket_v6 = if ctx.cfg.udp_relay_ipv6 {
@@ -60,16 +126,19 @@ impl UdpSession {
                 )
             })?;
 
-            socket.set_only_v6(true).map_err(|err| {
-                Error::Socket("failed setting UDP associate IPv6 socket as IPv6-only", err)
+            socket.set_only_v6(!dual_stack).map_err(|err| {
+                Error::Socket(
+                    "failed setting UDP associate IPv6 socket dual-stack mode",
+                    err,
+                )
             })?;
 
-            socket
-                .bind(&SockAddr::from(SocketAddr::from((
-                    Ipv6Addr::UNSPECIFIED,
-                    0,
-                ))))
-                .map_err(|err| Error::Socket("failed to bind UDP associate IPv6 socket", err))?;
+            dial::bind_port(bind_ipv6.into(), ctx.cfg.outbound.port_range, |addr| {
+                socket.bind(&SockAddr::from(addr))
+            })
+            .map_err(|err| Error::Socket("failed to bind UDP associate IPv6 socket", err))?;
+            bind_device(&socket, &ctx)?;
+            tune_socket(&socket, &ctx)?;
 
             Some(UdpSocket::from_std(StdUdpSocket::from(socket))?)
         } else {
@@ -77,6 +146,8 @@ impl UdpSession {
         };
 
         let (tx, rx) = oneshot::channel();
+        let (send_queue, mut send_queue_rx) = mpsc::channel(SEND_QUEUE_LEN);
+        let (proxied_recv, mut proxied_recv_rx) = mpsc::channel(SEND_QUEUE_LEN);
 
         let session = Arc::new(Self {
             ctx: ctx.clone(),
@@ -85,6 +156,12 @@ impl UdpSession {
             socket_v4,
             socket_v6,
             close: AsyncRwLock::new(Some(tx)),
+            probing: AsyncRwLock::new(HashMap::new()),
+            probe_cnt: AtomicUsize::new(0),
+            send_queue,
+            proxied: AsyncMutex::new(HashMap::new()),
+            proxied_recv,
+            proxied_sent: Notify::new(),
         });
 
         let session_listening = session.clone();
@@ -93,17 +170,39 @@ impl UdpSession {
             let mut rx = rx;
             let mut timeout = tokio::time::interval(ctx.cfg.gc_lifetime);
             timeout.reset();
+            let mut outbound = Vec::with_capacity(BATCH_SIZE);
 
             loop {
                 let next;
                 tokio::select! {
                     recv = session_listening.recv() => next = recv,
+                    _ = send_queue_rx.recv_many(&mut outbound, BATCH_SIZE) => {
+                        session_listening.flush(&mut outbound).await;
+                        timeout.reset();
+                        continue;
+                    },
+                    Some((pkt, addr)) = proxied_recv_rx.recv() => {
+                        tokio::spawn(
+                            session_listening
+                                .conn
+                                .clone()
+                                .relay_packet(pkt, addr, session_listening.assoc_id)
+                                .log_err(),
+                        );
+                        timeout.reset();
+                        continue;
+                    },
+                    () = session_listening.proxied_sent.notified() => {
+                        timeout.reset();
+                        continue;
+                    },
                     // Avoid client didn't send `UDP-DROP` properly
                     _ = timeout.tick() => {
                         session_listening.close().await;
                         warn!(
-                            "[{id:#010x}] [{addr}] [{user}] [packet] [{assoc_id:#06x}] UDP session timeout",
+                            "[{id:#010x}] [{cid}] [{addr}] [{user}] [packet] [{assoc_id:#06x}] UDP session timeout",
                             id = session_listening.conn.id(),
+                            cid = session_listening.conn.cid(),
                             addr = session_listening.conn.inner.remote_address(),
                             user = session_listening.conn.auth,
                         );
@@ -113,13 +212,14 @@ impl UdpSession {
                     _ = &mut rx => break
                 }
                 timeout.reset();
-                let (pkt, addr) = match next {
-                    Ok(v) => v,
+                let pkts = match next {
+                    Ok(pkts) => pkts,
                     Err(err) => {
                         warn!(
-                            "[{id:#010x}] [{addr}] [{user}] [packet] [{assoc_id:#06x}] outbound \
-                             listening error: {err}",
+                            "[{id:#010x}] [{cid}] [{addr}] [{user}] [packet] [{assoc_id:#06x}] \
+                             outbound listening error: {err}",
                             id = session_listening.conn.id(),
+                            cid = session_listening.conn.cid(),
                             addr = session_listening.conn.inner.remote_address(),
                             user = session_listening.conn.auth,
                         );
@@ -127,17 +227,20 @@ impl UdpSession {
                     }
                 };
 
-                tokio::spawn(
-                    session_listening
-                        .conn
-                        .clone()
-                        .relay_packet(
-                            pkt,
-                            Address::SocketAddress(addr),
-                            session_listening.assoc_id,
-                        )
-                        .log_err(),
-                );
+                for (pkt, addr) in pkts {
+                    session_listening.learn_family(addr.ip()).await;
+                    tokio::spawn(
+                        session_listening
+                            .conn
+                            .clone()
+                            .relay_packet(
+                                pkt,
+                                Address::SocketAddress(addr),
+                                session_listening.assoc_id,
+                            )
+                            .log_err(),
+                    );
+                }
             }
             session_listening
                 .conn
@@ -151,34 +254,224 @@ impl UdpSession {
         Ok(Arc::downgrade(&session))
     }
 
+    /// Pick the address to send to among those `domain` resolved to.
+    ///
+    /// For dual-stack domains the family that answered before is preferred.
+    /// Without such knowledge, packets alternate between families until one
+    /// of them answers.
+    pub async fn select_addr(&self, domain: &str, addrs: &[SocketAddr]) -> Option<SocketAddr> {
+        let first = addrs
+            .iter()
+            .find(|addr| addr.is_ipv4() || self.socket_v6.is_some())
+            .copied()?;
+        let Some(second) = addrs
+            .iter()
+            .find(|addr| addr.is_ipv4() != first.is_ipv4())
+            .filter(|addr| addr.is_ipv4() || self.socket_v6.is_some())
+            .copied()
+        else {
+            return Some(first);
+        };
+
+        if let Some(pref) = FAMILY_PREFERENCE.get(domain).await
+            && pref.1.elapsed() < FAMILY_PREFERENCE_TTL
+        {
+            return Some(if pref.0 == first.is_ipv6() {
+                first
+            } else {
+                second
+            });
+        }
+
+        let addr = if self.probe_cnt.fetch_add(1, Ordering::Relaxed) % 2 == 0 {
+            first
+        } else {
+            second
+        };
+        self.probing
+            .write()
+            .await
+            .insert(addr.ip(), domain.to_owned());
+        Some(addr)
+    }
+
+    async fn learn_family(&self, ip: IpAddr) {
+        if self.probing.read().await.is_empty() {
+            return;
+        }
+        if let Some(domain) = self.probing.write().await.remove(&ip) {
+            if FAMILY_PREFERENCE.len() >= MAX_FAMILY_PREFERENCES {
+                FAMILY_PREFERENCE
+                    .retain(|_, (_, learned)| learned.elapsed() < FAMILY_PREFERENCE_TTL)
+                    .await;
+                if FAMILY_PREFERENCE.len() > MAX_FAMILY_PREFERENCES / 2 {
+                    FAMILY_PREFERENCE.clear().await;
+                }
+            }
+            FAMILY_PREFERENCE
+                .insert(domain, (ip.is_ipv6(), Instant::now()))
+                .await;
+        }
+    }
+
+    /// Report the addresses this session is relayed from back to the client,
+    /// so it can hand them to a peer for UDP hole punching. The IP is the one
+    /// the client reached the server on when the family matches, as the
+    /// relay sockets themselves are bound to the unspecified address.
+    pub async fn report_external_address(&self) {
+        let local_ip = self.conn.inner.local_ip().map(|ip| ip.to_canonical());
+        let sockets = self.socket_v4.iter().chain(self.socket_v6.as_ref());
+        let dual_stack = self.socket_v4.is_none();
+
+        for socket in sockets {
+            let Ok(bound) = socket.local_addr() else {
+                continue;
+            };
+            let ip = match local_ip {
+                Some(ip) if dual_stack || ip.is_ipv4() == bound.is_ipv4() => ip,
+                _ => bound.ip(),
+            };
+            let addr = SocketAddr::new(ip, bound.port());
+
+            info!(
+                "[{id:#010x}] [{cid}] [{peer}] [{user}] [packet] [{assoc_id:#06x}] external \
+                 address {addr}",
+                id = self.conn.id(),
+                cid = self.conn.cid(),
+                peer = self.conn.inner.remote_address(),
+                user = self.conn.auth,
+                assoc_id = self.assoc_id,
+            );
+
+            if let Err(err) = self
+                .conn
+                .model
+                .external_address(self.assoc_id, Address::SocketAddress(addr))
+                .await
+            {
+                warn!(
+                    "[{id:#010x}] [{cid}] [{peer}] [{user}] [packet] [{assoc_id:#06x}] failed to \
+                     report external address {addr}: {err}",
+                    id = self.conn.id(),
+                    cid = self.conn.cid(),
+                    peer = self.conn.inner.remote_address(),
+                    user = self.conn.auth,
+                    assoc_id = self.assoc_id,
+                );
+                return;
+            }
+        }
+    }
+
+    /// Queue `pkt` to be sent to `addr`, along with the packets queued in
+    /// the meantime
     pub async fn send(&self, pkt: Bytes, addr: SocketAddr) -> Result<(), Error> {
-        let socket = match addr {
-            SocketAddr::V4(_) => &self.socket_v4,
-            SocketAddr::V6(_) => self
-                .socket_v6
-                .as_ref()
-                .ok_or_else(|| Error::UdpRelayIpv6Disabled(addr))?,
+        let pkt = match (addr, &self.socket_v4) {
+            (SocketAddr::V4(_), Some(_)) => (pkt, addr, false),
+            // the dual-stack socket, as `socket_v6` exists without `socket_v4`
+            (SocketAddr::V4(v4), None) => (
+                pkt,
+                SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port()),
+                true,
+            ),
+            (SocketAddr::V6(_), _) if self.socket_v6.is_some() => (pkt, addr, true),
+            (SocketAddr::V6(_), _) => return Err(Error::UdpRelayIpv6Disabled(addr)),
         };
 
-        socket.send_to(&pkt, addr).await?;
+        // only fails once the session is closed, like sending on its sockets
+        // would then
+        _ = self.send_queue.send(pkt).await;
         Ok(())
     }
 
-    async fn recv(&self) -> Result<(Bytes, SocketAddr), IoError> {
-        let recv = async |socket: &UdpSocket| -> Result<(Bytes, SocketAddr), IoError> {
-            let mut buf = vec![0u8; self.ctx.cfg.max_external_packet_size];
-            let (n, addr) = socket.recv_from(&mut buf).await?;
-            buf.truncate(n);
-            Ok((Bytes::from(buf), addr))
+    /// Send `pkt` to `addr` through `proxy`, associating with it first if
+    /// this session hasn't yet. `addr` is handed over unresolved
+    pub async fn send_proxied(
+        &self,
+        proxy: &Proxy,
+        pkt: Bytes,
+        addr: &Address,
+    ) -> Result<(), Error> {
+        let socket = {
+            let mut proxied = self.proxied.lock().await;
+            match proxied.get(&proxy.name) {
+                Some(udp) if !udp.closed.load(Ordering::Relaxed) => udp.socket.clone(),
+                _ => {
+                    let (control, socket) = proxy.associate(&self.ctx.cfg.outbound).await?;
+                    let udp = ProxiedUdp {
+                        socket: Arc::new(socket),
+                        closed: Arc::new(AtomicBool::new(false)),
+                    };
+                    tokio::spawn(recv_proxied(
+                        control,
+                        udp.socket.clone(),
+                        udp.closed.clone(),
+                        self.proxied_recv.clone(),
+                        self.ctx.cfg.max_external_packet_size,
+                    ));
+                    let socket = udp.socket.clone();
+                    proxied.insert(proxy.name.clone(), udp);
+                    socket
+                }
+            }
         };
+        socket.send(&upstream::encapsulate(&pkt, addr)).await?;
+        self.proxied_sent.notify_one();
+        Ok(())
+    }
 
-        if let Some(socket_v6) = &self.socket_v6 {
-            tokio::select! {
-                res = recv(&self.socket_v4) => res,
-                res = recv(socket_v6) => res,
+    /// Send the queued packets, as few syscalls as possible per socket. A
+    /// packet failing is dropped and logged, the following ones are still sent
+    async fn flush(&self, outbound: &mut Vec<Outbound>) {
+        let mut pkts = Vec::with_capacity(outbound.len());
+        let mut drain = outbound.drain(..).peekable();
+        while let Some((pkt, addr, v6)) = drain.next() {
+            pkts.push((pkt, addr));
+            if drain.peek().is_some_and(|next| next.2 == v6) {
+                continue;
             }
-        } else {
-            recv(&self.socket_v4).await
+
+            let socket = if v6 { &self.socket_v6 } else { &self.socket_v4 };
+            let socket = socket.as_ref().unwrap();
+            let mut sent = 0;
+            while sent < pkts.len() {
+                let (n, res) = udp_batch::send(socket, &pkts[sent..]).await;
+                sent += n;
+                if let Err(err) = res {
+                    warn!(
+                        "[{id:#010x}] [{cid}] [{addr}] [{user}] [packet] [{assoc_id:#06x}] \
+                         outbound sending to {target} error: {err}",
+                        id = self.conn.id(),
+                        cid = self.conn.cid(),
+                        addr = self.conn.inner.remote_address(),
+                        user = self.conn.auth,
+                        assoc_id = self.assoc_id,
+                        target = privacy::text(&pkts[sent].1.to_string()),
+                    );
+                    sent += 1;
+                }
+            }
+            pkts.clear();
+        }
+    }
+
+    async fn recv(&self) -> Result<Vec<(Bytes, SocketAddr)>, IoError> {
+        let recv = async |socket: &UdpSocket| -> Result<Vec<(Bytes, SocketAddr)>, IoError> {
+            let mut pkts = udp_batch::recv(socket, self.ctx.cfg.max_external_packet_size).await?;
+            for (_, addr) in &mut pkts {
+                // v4-mapped on the dual-stack socket
+                *addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+            }
+            Ok(pkts)
+        };
+
+        match (&self.socket_v4, &self.socket_v6) {
+            (Some(socket_v4), Some(socket_v6)) => tokio::select! {
+                res = recv(socket_v4) => res,
+                res = recv(socket_v6) => res,
+            },
+            (Some(socket), None) | (None, Some(socket)) => recv(socket).await,
+            (None, None) => unreachable!(),
         }
     }
 
@@ -188,3 +481,68 @@ impl UdpSession {
         }
     }
 }
+
+/// Hand the packets received on a proxy association over to the listening
+/// task, until the proxy closes the control connection, which ends the
+/// association, or the session closes
+async fn recv_proxied(
+    mut control: TcpStream,
+    socket: Arc<UdpSocket>,
+    closed: Arc<AtomicBool>,
+    tx: mpsc::Sender<(Bytes, Address)>,
+    max_packet_size: usize,
+) {
+    // room for the SOCKS5 UDP header, with a domain address at most
+    let mut buf = vec![0; max_packet_size + 262];
+    loop {
+        tokio::select! {
+            _ = control.read_u8() => break,
+            res = socket.recv(&mut buf) => {
+                let Ok(len) = res else {
+                    break;
+                };
+                let Some(pkt) = upstream::decapsulate(Bytes::copy_from_slice(&buf[..len])) else {
+                    continue;
+                };
+                if tx.send(pkt).await.is_err() {
+                    break;
+                }
+            },
+            () = tx.closed() => break,
+        }
+    }
+    closed.store(true, Ordering::Relaxed);
+}
+
+/// Send from `outbound.bind_device`, if set
+pub(super) fn bind_device(socket: &Socket, ctx: &AppContext) -> Result<(), Error> {
+    #[cfg(target_os = "linux")]
+    if let Some(device) = &ctx.cfg.outbound.bind_device {
+        socket
+            .bind_device(Some(device.as_bytes()))
+            .map_err(|err| Error::Socket("failed to bind UDP associate socket to device", err))?;
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (socket, ctx);
+    Ok(())
+}
+
+/// Size the buffers as `udp_relay_socket_buffer` is set, and keep ICMP errors
+/// from failing reads on Windows
+pub(super) fn tune_socket(socket: &Socket, ctx: &AppContext) -> Result<(), Error> {
+    let size = match ctx.cfg.udp_relay_socket_buffer {
+        Some(size) => size.0 as usize,
+        None if cfg!(windows) => WINDOWS_SOCKET_BUFFER,
+        None => 0,
+    };
+    if size != 0 {
+        socket.set_recv_buffer_size(size).map_err(|err| {
+            Error::Socket("failed setting UDP relay socket receive buffer size", err)
+        })?;
+        socket.set_send_buffer_size(size).map_err(|err| {
+            Error::Socket("failed setting UDP relay socket send buffer size", err)
+        })?;
+    }
+    utils::ignore_udp_resets(socket)
+        .map_err(|err| Error::Socket("failed disabling UDP relay socket reset errors", err))
+}
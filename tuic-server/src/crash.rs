@@ -0,0 +1,97 @@
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    panic::{self, Location},
+    path::{Path, PathBuf},
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::json;
+
+use crate::error::Error;
+
+/// Process exit codes, one per class of failure, so that supervisors and
+/// scripts can react without parsing stderr
+#[derive(Clone, Copy)]
+pub enum ExitCode {
+    Runtime = 1,
+    Config  = 2,
+    Bind    = 3,
+    Tls     = 4,
+    Panic   = 5,
+}
+
+impl Display for ExitCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Runtime => write!(f, "runtime"),
+            Self::Config => write!(f, "config"),
+            Self::Bind => write!(f, "bind"),
+            Self::Tls => write!(f, "tls"),
+            Self::Panic => write!(f, "panic"),
+        }
+    }
+}
+
+impl From<&Error> for ExitCode {
+    fn from(err: &Error) -> Self {
+        match err {
+            Error::Rustls(_) | Error::Tls(_) => Self::Tls,
+            Error::Bind(..) | Error::Socket(..) => Self::Bind,
+            _ => Self::Runtime,
+        }
+    }
+}
+
+/// Print `msg`, record it in the crash report at `path` and exit with `code`
+pub fn exit(code: ExitCode, path: &Path, msg: impl Display) -> ! {
+    eprintln!("{msg}");
+    report(path, code, &msg.to_string(), None);
+    process::exit(code as i32)
+}
+
+/// Record every panic in the crash report at `path`, in addition to the
+/// default panic message, and exit with `ExitCode::Panic`. Release builds
+/// abort on panic, which would otherwise end the process before any exit code
+/// is set, so debug builds exit the same way rather than unwinding.
+pub fn set_panic_hook(path: PathBuf) {
+    let default = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default(info);
+        let payload = info.payload();
+        let msg = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("Box<dyn Any>");
+        report(&path, ExitCode::Panic, msg, info.location());
+        process::exit(ExitCode::Panic as i32);
+    }));
+}
+
+/// Overwrite the crash report with the given failure. An empty `path` turns
+/// crash reports off.
+fn report(path: &Path, code: ExitCode, msg: &str, location: Option<&Location<'_>>) {
+    if path.as_os_str().is_empty() {
+        return;
+    }
+    let report = json!({
+        "time": SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        "version": env!("CARGO_PKG_VERSION"),
+        "pid": process::id(),
+        "kind": code.to_string(),
+        "exit_code": code as i32,
+        "message": msg,
+        "location": location.map(ToString::to_string),
+        "args": std::env::args().collect::<Vec<_>>(),
+    });
+    if let Err(err) = std::fs::write(path, format!("{report:#}\n")) {
+        eprintln!(
+            "failed to write crash report to {path}: {err}",
+            path = path.display()
+        );
+    }
+}
@@ -0,0 +1,338 @@
+//! Connections and bytes relayed by destination over the last hour, for the
+//! RESTful `/top_destinations` to show spam targets, scanned hosts and popular
+//! services without post-processing flow logs. With
+//! `restful.traffic_by_destination`, bytes are also totaled per destination and
+//! user until `/reset_traffic`, for `/traffic/destinations`
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::{
+        LazyLock, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tuic::Address;
+use uuid::Uuid;
+
+use crate::privacy;
+
+/// Traffic is aggregated in slots of this long
+pub const SLOT: Duration = Duration::from_secs(60);
+/// Slots kept, the longest window reported
+pub const SLOTS: usize = 60;
+/// Destinations tracked in each slot, later ones are only counted in `other`
+const MAX_PER_SLOT: usize = 4096;
+/// Destinations totaled until `/reset_traffic`, later ones are only counted in
+/// `other`
+const MAX_TOTALS: usize = 65536;
+
+static START: LazyLock<Instant> = LazyLock::new(Instant::now);
+static WINDOW: LazyLock<Mutex<VecDeque<Slot>>> = LazyLock::new(Mutex::default);
+static TOTALS_ENABLED: AtomicBool = AtomicBool::new(false);
+static TOTALS: LazyLock<Mutex<Totals>> = LazyLock::new(Mutex::default);
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Destination {
+    Domain(String),
+    Ip(IpAddr),
+}
+
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct Stat {
+    /// TCP connections, UDP is only counted in bytes
+    pub connections: u64,
+    pub tx: u64,
+    pub rx: u64,
+}
+
+impl Stat {
+    fn add(&mut self, other: &Self) {
+        self.connections += other.connections;
+        self.tx += other.tx;
+        self.rx += other.rx;
+    }
+}
+
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortBy {
+    Connections,
+    #[default]
+    Bytes,
+}
+
+#[derive(Serialize)]
+pub struct TopDestination {
+    pub destination: String,
+    #[serde(flatten)]
+    pub stat: Stat,
+}
+
+#[derive(Serialize)]
+pub struct Report {
+    pub destinations: Vec<TopDestination>,
+    /// Everything else, destinations past the top ones and untracked ones
+    pub other: Stat,
+}
+
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct Traffic {
+    pub tx: u64,
+    pub rx: u64,
+}
+
+impl Traffic {
+    fn add(&mut self, other: &Self) {
+        self.tx += other.tx;
+        self.rx += other.rx;
+    }
+}
+
+#[derive(Serialize)]
+pub struct DestinationTraffic {
+    pub destination: String,
+    #[serde(flatten)]
+    pub bytes: Traffic,
+    /// The users who relayed bytes to and from the destination, most first
+    pub users: Vec<UserTraffic>,
+}
+
+#[derive(Serialize)]
+pub struct UserTraffic {
+    pub uuid: Uuid,
+    #[serde(flatten)]
+    pub bytes: Traffic,
+}
+
+#[derive(Serialize)]
+pub struct TrafficReport {
+    pub destinations: Vec<DestinationTraffic>,
+    /// Everything else, destinations past the top ones and untracked ones
+    pub other: Traffic,
+}
+
+#[derive(Default)]
+struct Totals {
+    destinations: HashMap<Destination, HashMap<Uuid, Traffic>>,
+    untracked: Traffic,
+}
+
+struct Slot {
+    index: u64,
+    destinations: HashMap<Destination, Stat>,
+    untracked: Stat,
+}
+
+impl Destination {
+    fn of(addr: &Address) -> Option<Self> {
+        match addr {
+            Address::DomainAddress(domain, _) => Some(Self::Domain(domain.to_ascii_lowercase())),
+            Address::SocketAddress(addr) => Some(Self::Ip(addr.ip().to_canonical())),
+            Address::None => None,
+        }
+    }
+
+    /// As `log_destinations` allows
+    fn display(&self) -> String {
+        match self {
+            Self::Domain(domain) => privacy::domain(domain).to_string(),
+            Self::Ip(ip) => privacy::ip(ip).to_string(),
+        }
+    }
+}
+
+/// A TCP connection to `addr`
+pub fn connection(addr: &Address) {
+    record(
+        addr,
+        Stat {
+            connections: 1,
+            ..Default::default()
+        },
+    );
+}
+
+/// Whether `/traffic/destinations` totals are kept, see
+/// `restful.traffic_by_destination`
+pub fn init(totals: bool) {
+    TOTALS_ENABLED.store(totals, Ordering::Relaxed);
+}
+
+pub fn totals_enabled() -> bool {
+    TOTALS_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Traffic `uuid` relayed to (`tx`) and from (`rx`) `addr`
+pub fn traffic(uuid: Uuid, addr: &Address, tx: u64, rx: u64) {
+    if tx != 0 || rx != 0 {
+        record(
+            addr,
+            Stat {
+                connections: 0,
+                tx,
+                rx,
+            },
+        );
+        if totals_enabled() {
+            record_total(uuid, addr, Traffic { tx, rx });
+        }
+    }
+}
+
+fn record_total(uuid: Uuid, addr: &Address, bytes: Traffic) {
+    let Some(dest) = Destination::of(addr) else {
+        return;
+    };
+    let mut totals = TOTALS.lock().unwrap();
+    let len = totals.destinations.len();
+    match totals.destinations.get_mut(&dest) {
+        Some(users) => users.entry(uuid).or_default().add(&bytes),
+        None if len < MAX_TOTALS => {
+            totals
+                .destinations
+                .insert(dest, HashMap::from([(uuid, bytes)]));
+        }
+        None => totals.untracked.add(&bytes),
+    }
+}
+
+fn record(addr: &Address, stat: Stat) {
+    let Some(dest) = Destination::of(addr) else {
+        return;
+    };
+    let index = START.elapsed().as_secs() / SLOT.as_secs();
+    let mut window = WINDOW.lock().unwrap();
+    if window.back().is_none_or(|slot| slot.index != index) {
+        window.push_back(Slot {
+            index,
+            destinations: HashMap::new(),
+            untracked: Stat::default(),
+        });
+    }
+    while window
+        .front()
+        .is_some_and(|slot| slot.index + SLOTS as u64 <= index)
+    {
+        window.pop_front();
+    }
+    let slot = window.back_mut().unwrap();
+    let len = slot.destinations.len();
+    match slot.destinations.get_mut(&dest) {
+        Some(total) => total.add(&stat),
+        None if len < MAX_PER_SLOT => {
+            slot.destinations.insert(dest, stat);
+        }
+        None => slot.untracked.add(&stat),
+    }
+}
+
+/// The `limit` destinations with the most connections or bytes over the last
+/// `window`, rounded up to whole slots
+pub fn top(window: Duration, limit: usize, sort: SortBy) -> Report {
+    let index = START.elapsed().as_secs() / SLOT.as_secs();
+    let slots = window
+        .as_secs()
+        .div_ceil(SLOT.as_secs())
+        .clamp(1, SLOTS as u64);
+    let mut totals: HashMap<String, Stat> = HashMap::new();
+    let mut other = Stat::default();
+    for slot in WINDOW
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|slot| slot.index + slots > index)
+    {
+        for (dest, stat) in &slot.destinations {
+            totals.entry(dest.display()).or_default().add(stat);
+        }
+        other.add(&slot.untracked);
+    }
+
+    let mut destinations: Vec<_> = totals
+        .into_iter()
+        .map(|(destination, stat)| TopDestination { destination, stat })
+        .collect();
+    let key = |dest: &TopDestination| match sort {
+        SortBy::Connections => dest.stat.connections,
+        SortBy::Bytes => dest.stat.tx + dest.stat.rx,
+    };
+    destinations.sort_unstable_by(|a, b| {
+        key(b)
+            .cmp(&key(a))
+            .then_with(|| a.destination.cmp(&b.destination))
+    });
+    for dest in destinations.drain(limit.min(destinations.len())..) {
+        other.add(&dest.stat);
+    }
+    Report {
+        destinations,
+        other,
+    }
+}
+
+/// The `limit` destinations with the most bytes relayed since start or the
+/// last `/reset_traffic`, only counting the traffic of `user` if set
+pub fn totals(limit: usize, user: Option<Uuid>) -> TrafficReport {
+    let mut merged: HashMap<String, HashMap<Uuid, Traffic>> = HashMap::new();
+    let mut other = Traffic::default();
+    {
+        let totals = TOTALS.lock().unwrap();
+        for (dest, users) in &totals.destinations {
+            let merged = merged.entry(dest.display()).or_default();
+            for (uuid, bytes) in users {
+                if user.is_none_or(|user| user == *uuid) {
+                    merged.entry(*uuid).or_default().add(bytes);
+                }
+            }
+        }
+        if user.is_none() {
+            other = totals.untracked;
+        }
+    }
+
+    let mut destinations: Vec<_> = merged
+        .into_iter()
+        .filter(|(_, users)| !users.is_empty())
+        .map(|(destination, users)| {
+            let mut bytes = Traffic::default();
+            let mut users: Vec<_> = users
+                .into_iter()
+                .map(|(uuid, user)| {
+                    bytes.add(&user);
+                    UserTraffic { uuid, bytes: user }
+                })
+                .collect();
+            users.sort_unstable_by(|a, b| {
+                (b.bytes.tx + b.bytes.rx)
+                    .cmp(&(a.bytes.tx + a.bytes.rx))
+                    .then_with(|| a.uuid.cmp(&b.uuid))
+            });
+            DestinationTraffic {
+                destination,
+                bytes,
+                users,
+            }
+        })
+        .collect();
+    destinations.sort_unstable_by(|a, b| {
+        (b.bytes.tx + b.bytes.rx)
+            .cmp(&(a.bytes.tx + a.bytes.rx))
+            .then_with(|| a.destination.cmp(&b.destination))
+    });
+    for dest in destinations.drain(limit.min(destinations.len())..) {
+        other.add(&dest.bytes);
+    }
+    TrafficReport {
+        destinations,
+        other,
+    }
+}
+
+/// Clear the totals of `/traffic/destinations`, along with `/reset_traffic`
+pub fn reset_totals() {
+    *TOTALS.lock().unwrap() = Totals::default();
+}
@@ -0,0 +1,233 @@
+//! Limiting the outbound TCP dials in flight, resolving included, so a burst
+//! of CONNECT requests can't exhaust sockets or the resolver, and a single
+//! user can't spray SYNs at many targets at once
+
+use std::{
+    collections::HashMap,
+    io::{Error as IoError, ErrorKind, Result as IoResult},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        Arc, LazyLock, Mutex, OnceLock,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
+};
+
+use serde_json::{Value, json};
+use tokio::{
+    net::{TcpSocket, TcpStream},
+    sync::{OwnedSemaphorePermit, Semaphore, SemaphorePermit},
+};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{config::OutboundConfig, utils::PortRange};
+
+static PERMITS: OnceLock<Semaphore> = OnceLock::new();
+static MAX: AtomicUsize = AtomicUsize::new(0);
+static MAX_PER_USER: AtomicUsize = AtomicUsize::new(0);
+/// The dial slots of each user who dialed so far, with their number
+static USER_PERMITS: LazyLock<Mutex<HashMap<Uuid, UserPermits>>> = LazyLock::new(Mutex::default);
+static QUEUED: AtomicUsize = AtomicUsize::new(0);
+static PEAK_QUEUED: AtomicUsize = AtomicUsize::new(0);
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+static FAST_OPEN: AtomicBool = AtomicBool::new(false);
+
+pub fn init(cfg: &OutboundConfig) -> eyre::Result<()> {
+    if cfg.bind_device.is_some() && !cfg!(target_os = "linux") {
+        eyre::bail!("`outbound.bind_device` is only supported on Linux");
+    }
+    let max = cfg.max_concurrent_dials;
+    MAX.store(max, Ordering::Relaxed);
+    if max != 0 {
+        _ = PERMITS.set(Semaphore::new(max));
+    }
+    MAX_PER_USER.store(cfg.max_concurrent_dials_per_user, Ordering::Relaxed);
+    if cfg.tcp_fast_open && !cfg!(target_os = "linux") {
+        warn!("`outbound.tcp_fast_open` is only supported on Linux, ignoring it");
+    }
+    FAST_OPEN.store(
+        cfg.tcp_fast_open && cfg!(target_os = "linux"),
+        Ordering::Relaxed,
+    );
+    Ok(())
+}
+
+type UserPermits = (usize, Arc<Semaphore>);
+
+/// Held while resolving and connecting to a target
+pub struct Dial {
+    _permit: Option<SemaphorePermit<'static>>,
+    _user_permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for Dial {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counted in the queue until dropped, even if the stream is dropped while
+/// waiting
+struct Queued;
+
+impl Queued {
+    fn new() -> Self {
+        let queued = QUEUED.fetch_add(1, Ordering::Relaxed) + 1;
+        PEAK_QUEUED.fetch_max(queued, Ordering::Relaxed);
+        Self
+    }
+}
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        QUEUED.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Wait for one of the `max_per_user` dial slots of `user`, then for one
+/// across all users. Streams are handled concurrently, so dials only wait on
+/// each other beyond the limits, which `priority` dials skip
+pub async fn start(user: Option<Uuid>, priority: bool, max_per_user: usize) -> Dial {
+    let user_permit = match user {
+        Some(uuid) if max_per_user != 0 && !priority => {
+            let permits = {
+                let mut users = USER_PERMITS.lock().unwrap();
+                let (max, permits) = users
+                    .entry(uuid)
+                    .or_insert_with(|| (max_per_user, Arc::new(Semaphore::new(max_per_user))));
+                // changed by reloading `user_overrides`, dials in flight keep
+                // their slots of the old limit
+                if *max != max_per_user {
+                    *max = max_per_user;
+                    *permits = Arc::new(Semaphore::new(max_per_user));
+                }
+                permits.clone()
+            };
+            // the semaphore is never closed
+            Some(permits.acquire_owned().await.unwrap())
+        }
+        _ => None,
+    };
+    let permit = match PERMITS.get().filter(|_| !priority) {
+        Some(permits) => Some(match permits.try_acquire() {
+            Ok(permit) => permit,
+            Err(_) => {
+                let _queued = Queued::new();
+                // the semaphore is never closed
+                permits.acquire().await.unwrap()
+            }
+        }),
+        None => None,
+    };
+    IN_FLIGHT.fetch_add(1, Ordering::Relaxed);
+    Dial {
+        _permit: permit,
+        _user_permit: user_permit,
+    }
+}
+
+/// Whether `outbound.tcp_fast_open` is set and supported
+pub fn fast_open() -> bool {
+    FAST_OPEN.load(Ordering::Relaxed)
+}
+
+/// Connect to `addr` from the `outbound` source address and device, with TCP
+/// Fast Open if `fast_open`.
+///
+/// With Fast Open the SYN is deferred to the first write and carries its
+/// data, when the target handed out a cookie before. Connecting then always
+/// succeeds, and failures like a refused connection show up on the first
+/// read or write instead. Nothing is sent before writing, so the caller must
+/// have data to write right away
+pub async fn connect(
+    addr: SocketAddr,
+    cfg: &OutboundConfig,
+    fast_open: bool,
+) -> IoResult<TcpStream> {
+    let (socket, bind) = match addr {
+        SocketAddr::V4(_) => (TcpSocket::new_v4()?, cfg.bind_ipv4.map(IpAddr::V4)),
+        SocketAddr::V6(_) => (TcpSocket::new_v6()?, cfg.bind_ipv6.map(IpAddr::V6)),
+    };
+    if bind.is_some() || cfg.port_range.is_some() {
+        let ip = bind.unwrap_or(match addr {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        });
+        if cfg.port_range.is_some() {
+            // ports left in `TIME_WAIT` by connections closed on our side
+            socket.set_reuseaddr(true)?;
+        }
+        bind_port(ip, cfg.port_range, |addr| socket.bind(addr))?;
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(device) = &cfg.bind_device {
+        socket.bind_device(Some(device.as_bytes()))?;
+    }
+    #[cfg(target_os = "linux")]
+    if fast_open {
+        set_fast_open_connect(&socket)?;
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = fast_open;
+    socket.connect(addr).await
+}
+
+/// Bind with `bind` to `ip` and a port of `range`, trying them in turn from a
+/// random one until one is free, or to any port without a range
+pub fn bind_port(
+    ip: IpAddr,
+    range: Option<PortRange>,
+    mut bind: impl FnMut(SocketAddr) -> IoResult<()>,
+) -> IoResult<()> {
+    let Some(range) = range else {
+        return bind(SocketAddr::new(ip, 0));
+    };
+    let count = range.count();
+    let offset = fastrand::u32(..count);
+    let mut last_err = None;
+    for idx in 0..count {
+        let port = range.start + ((offset + idx) % count) as u16;
+        match bind(SocketAddr::new(ip, port)) {
+            Ok(()) => return Ok(()),
+            Err(err) if err.kind() == ErrorKind::AddrInUse => last_err = Some(err),
+            Err(err) => return Err(err),
+        }
+    }
+    Err(IoError::new(
+        ErrorKind::AddrInUse,
+        format!(
+            "all ports of `outbound.port_range` {range} are in use: {}",
+            last_err.unwrap()
+        ),
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn set_fast_open_connect(socket: &TcpSocket) -> IoResult<()> {
+    use std::os::fd::AsRawFd;
+
+    let enable: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN_CONNECT,
+            (&enable as *const libc::c_int).cast(),
+            size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(IoError::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn snapshot() -> Value {
+    json!({
+        "in_flight": IN_FLIGHT.load(Ordering::Relaxed),
+        "queued": QUEUED.load(Ordering::Relaxed),
+        "peak_queued": PEAK_QUEUED.load(Ordering::Relaxed),
+        "max_concurrent": MAX.load(Ordering::Relaxed),
+        "max_concurrent_per_user": MAX_PER_USER.load(Ordering::Relaxed),
+    })
+}
@@ -0,0 +1,97 @@
+//! Resolving relay destinations with the system resolver, or with an
+//! in-process resolver and cache querying upstreams over UDP, TCP, DNS over
+//! TLS or DNS over HTTPS
+
+use std::{
+    io::{Error as IoError, ErrorKind, Result as IoResult},
+    net::SocketAddr,
+    sync::OnceLock,
+};
+
+use hickory_resolver::{
+    ResolveError, TokioResolver,
+    config::{LookupIpStrategy, NameServerConfig, ResolverConfig, ResolverOpts},
+    name_server::TokioConnectionProvider,
+    proto::xfer::Protocol,
+};
+use rustls::{ClientConfig as RustlsClientConfig, RootCertStore};
+use tokio::net;
+
+use crate::{config::DnsConfig, utils::DnsProtocol};
+
+/// Unset with `dns.protocol = "system"`
+static RESOLVER: OnceLock<TokioResolver> = OnceLock::new();
+
+pub fn init(cfg: &DnsConfig) -> eyre::Result<()> {
+    let protocol = match cfg.protocol {
+        DnsProtocol::System => return Ok(()),
+        DnsProtocol::Udp => Protocol::Udp,
+        DnsProtocol::Tcp => Protocol::Tcp,
+        DnsProtocol::Tls => Protocol::Tls,
+        DnsProtocol::Https => Protocol::Https,
+    };
+    if cfg.servers.is_empty() {
+        eyre::bail!("dns: `servers` is required unless `protocol` is \"system\"");
+    }
+    let encrypted = matches!(cfg.protocol, DnsProtocol::Tls | DnsProtocol::Https);
+    if encrypted && cfg.tls_name.is_none() {
+        eyre::bail!("dns: `tls_name` is required to verify the certificate of the servers");
+    }
+
+    let servers = cfg
+        .servers
+        .iter()
+        .map(|addr| {
+            let mut server = NameServerConfig::new(*addr, protocol);
+            server.tls_dns_name = cfg.tls_name.clone();
+            server
+        })
+        .collect::<Vec<_>>();
+
+    let mut opts = ResolverOpts::default();
+    // both families, for Happy Eyeballs and `udp_relay_ipv6`
+    opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+    opts.timeout = cfg.timeout;
+    opts.cache_size = cfg.cache_size;
+    opts.positive_min_ttl = cfg.min_ttl;
+    opts.positive_max_ttl = cfg.max_ttl;
+    opts.negative_max_ttl = cfg.max_ttl;
+    if encrypted {
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_native_certs::load_native_certs().certs {
+            _ = roots.add(cert);
+        }
+        if roots.is_empty() {
+            eyre::bail!("dns: no trusted root certificates found on the system");
+        }
+        opts.tls_config = RustlsClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+    }
+
+    let resolver = TokioResolver::builder_with_config(
+        ResolverConfig::from_parts(None, Vec::new(), servers),
+        TokioConnectionProvider::default(),
+    )
+    .with_options(opts)
+    .build();
+    _ = RESOLVER.set(resolver);
+    Ok(())
+}
+
+/// The addresses of `domain`, from the cache while their TTL hasn't expired
+pub async fn lookup(domain: &str, port: u16) -> IoResult<Vec<SocketAddr>> {
+    let Some(resolver) = RESOLVER.get() else {
+        return Ok(net::lookup_host((domain, port)).await?.collect());
+    };
+    let lookup = resolver.lookup_ip(domain).await.map_err(io_error)?;
+    Ok(lookup.iter().map(|ip| SocketAddr::new(ip, port)).collect())
+}
+
+fn io_error(err: ResolveError) -> IoError {
+    if err.is_no_records_found() {
+        IoError::new(ErrorKind::NotFound, err)
+    } else {
+        IoError::other(err)
+    }
+}
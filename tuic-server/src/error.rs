@@ -1,17 +1,42 @@
-use std::{io::Error as IoError, net::SocketAddr};
+use std::{
+    io::{Error as IoError, ErrorKind},
+    net::SocketAddr,
+    sync::OnceLock,
+    time::Duration,
+};
 
-use quinn::ConnectionError;
+use h3::{
+    error::{ConnectionError as H3ConnectionError, StreamError},
+    quic::ConnectionErrorIncoming,
+};
+use quinn::{ConnectionError, SendDatagramError};
 use rustls::Error as RustlsError;
 use thiserror::Error;
+use tuic::UnmarshalError;
 use tuic_quinn::Error as ModelError;
 use uuid::Uuid;
 
+use crate::{
+    config::{ErrorLogConfig, LogLevel},
+    privacy,
+};
+
+static ERROR_LOG: OnceLock<ErrorLogConfig> = OnceLock::new();
+
+pub fn init(cfg: &ErrorLogConfig) {
+    _ = ERROR_LOG.set(cfg.clone());
+}
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error(transparent)]
     Io(#[from] IoError),
     #[error(transparent)]
     Rustls(#[from] RustlsError),
+    #[error("TLS setup failed: {0:#}")]
+    Tls(eyre::Report),
+    #[error("failed to bind endpoint UDP socket on {0}: {1}")]
+    Bind(SocketAddr, IoError),
     #[error("invalid max idle time")]
     InvalidMaxIdleTime,
     #[error("connection timed out")]
@@ -19,35 +44,443 @@ pub enum Error {
     #[error("connection locally closed")]
     LocallyClosed,
     #[error(transparent)]
+    Connection(ConnectionError),
+    #[error(transparent)]
     Model(#[from] ModelError),
     #[error("duplicated authentication")]
     DuplicatedAuth,
     #[error("authentication failed: {0}")]
     AuthFailed(Uuid),
+    #[error("authentication failed: {0}, no users registered yet")]
+    NoUsers(Uuid),
+    #[error("authentication refused: {0} is disabled for abuse")]
+    UserDisabled(Uuid),
+    #[error("authentication refused: {0} is over its traffic quota")]
+    QuotaExceeded(Uuid),
     #[error("received packet from unexpected source")]
     UnexpectedPacketSource,
     #[error("{0}: {1}")]
     Socket(&'static str, IoError),
+    #[error("stream idle for {0:?}")]
+    StreamIdle(Duration),
+    #[error("nothing relayed within {0:?} of connecting")]
+    FirstByteTimeout(Duration),
     #[error("task negotiation timed out")]
     TaskNegotiationTimeout,
-    #[error("failed sending packet to {0}: relaying IPv6 UDP packet is disabled")]
+    #[error("more than {0} streams and datagrams waiting for authentication")]
+    TooManyPreAuthTasks(usize),
+    #[error("user already has {0} streams open")]
+    TooManyStreams(usize),
+    #[error("address already has {0} connections open")]
+    TooManyConnections(usize),
+    #[error("connection already has {0} UDP sessions")]
+    TooManyUdpSessions(usize),
+    #[error(
+        "failed sending packet to {}: relaying IPv6 UDP packet is disabled",
+        privacy::socket(.0)
+    )]
     UdpRelayIpv6Disabled(SocketAddr),
+    #[error("UDP relay is disabled for {0}")]
+    UdpRelayDisabled(Uuid),
+    #[error("destination {} is blocklisted", privacy::socket(.0))]
+    Blocklisted(SocketAddr),
+    #[error("destination {} denied by the routing script", privacy::text(.0))]
+    Denied(String),
+    #[error("destination {} denied by the ACL", privacy::text(.0))]
+    AclDenied(String),
+    #[error("MASQUE request without proxy credentials")]
+    ProxyAuthRequired,
+    #[error("malformed MASQUE request: {0}")]
+    MasqueRequest(&'static str),
+    #[error("outbound proxy {0}: {1}")]
+    UpstreamProxy(String, &'static str),
+    #[error(transparent)]
+    Http3(#[from] StreamError),
+    #[error(transparent)]
+    Http3Connection(#[from] H3ConnectionError),
     #[error(transparent)]
     Other(#[from] eyre::Report),
 }
 
-impl Error {
-    pub fn is_trivial(&self) -> bool {
-        matches!(self, Self::TimedOut | Self::LocallyClosed)
+/// What an error says about the connection, deciding the level it's logged
+/// at
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ErrorClass {
+    /// The connection or stream ended the usual way: closed or reset by
+    /// either side, or timed out. Nothing to act on
+    Closed,
+    /// A destination couldn't be reached: connection refused, address not
+    /// available, connecting timed out
+    Network,
+    /// The client misbehaved: failed authentication, protocol violations,
+    /// malformed commands
+    Peer,
+    /// A destination was refused by a policy: ACL, blocklist, routing script,
+    /// IPv6 relaying disabled
+    Policy,
+    /// Anything else, usually a problem of the server itself: TLS setup,
+    /// sockets, resources
+    Local,
+}
+
+impl ErrorClass {
+    /// The level configured in `error_log` for this class
+    pub fn level(self) -> LogLevel {
+        let Some(cfg) = ERROR_LOG.get() else {
+            return ErrorLogConfig::default().level(self);
+        };
+        cfg.level(self)
+    }
+}
+
+impl ErrorLogConfig {
+    fn level(&self, class: ErrorClass) -> LogLevel {
+        match class {
+            ErrorClass::Closed => self.closed,
+            ErrorClass::Network => self.network,
+            ErrorClass::Peer => self.peer,
+            ErrorClass::Policy => self.policy,
+            ErrorClass::Local => self.local,
+        }
+    }
+}
+
+/// Errors that can be classified, including the ones of quinn, rustls and
+/// tuic-quinn the server gets
+pub trait Classify {
+    fn class(&self) -> ErrorClass;
+}
+
+impl Classify for Error {
+    fn class(&self) -> ErrorClass {
+        match self {
+            Self::Io(err) => err.class(),
+            Self::Rustls(err) => err.class(),
+            Self::Connection(err) => err.class(),
+            Self::Model(err) => err.class(),
+            Self::Http3(err) => err.class(),
+            Self::Http3Connection(err) => err.class(),
+            Self::Other(err) => match err.downcast_ref::<IoError>() {
+                Some(err) => err.class(),
+                None => ErrorClass::Local,
+            },
+            Self::TimedOut
+            | Self::LocallyClosed
+            | Self::StreamIdle(_)
+            | Self::FirstByteTimeout(_) => ErrorClass::Closed,
+            Self::DuplicatedAuth
+            | Self::AuthFailed(_)
+            | Self::UnexpectedPacketSource
+            | Self::TaskNegotiationTimeout
+            | Self::TooManyPreAuthTasks(_)
+            | Self::TooManyStreams(_)
+            | Self::TooManyConnections(_)
+            | Self::TooManyUdpSessions(_)
+            | Self::ProxyAuthRequired
+            | Self::MasqueRequest(_) => ErrorClass::Peer,
+            Self::UdpRelayIpv6Disabled(_)
+            | Self::UdpRelayDisabled(_)
+            | Self::Blocklisted(_)
+            | Self::Denied(_)
+            | Self::AclDenied(_)
+            | Self::NoUsers(_)
+            | Self::UserDisabled(_)
+            | Self::QuotaExceeded(_) => ErrorClass::Policy,
+            Self::UpstreamProxy(..) => ErrorClass::Network,
+            Self::Tls(_) | Self::Bind(..) | Self::InvalidMaxIdleTime | Self::Socket(..) => {
+                ErrorClass::Local
+            }
+        }
+    }
+}
+
+impl Classify for IoError {
+    fn class(&self) -> ErrorClass {
+        // quinn's stream and connection errors convert to `io::Error`, see
+        // whether one is wrapped before going by the kind
+        if let Some(err) = self
+            .get_ref()
+            .and_then(|err| err.downcast_ref::<ConnectionError>())
+        {
+            return err.class();
+        }
+        match self.kind() {
+            ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+            | ErrorKind::UnexpectedEof
+            | ErrorKind::NotConnected => ErrorClass::Closed,
+            ErrorKind::ConnectionRefused | ErrorKind::AddrNotAvailable | ErrorKind::TimedOut => {
+                ErrorClass::Network
+            }
+            ErrorKind::InvalidData => ErrorClass::Peer,
+            _ => ErrorClass::Local,
+        }
+    }
+}
+
+impl Classify for ConnectionError {
+    fn class(&self) -> ErrorClass {
+        match self {
+            Self::ApplicationClosed(_)
+            | Self::ConnectionClosed(_)
+            | Self::Reset
+            | Self::TimedOut
+            | Self::LocallyClosed => ErrorClass::Closed,
+            Self::VersionMismatch | Self::TransportError(_) => ErrorClass::Peer,
+            Self::CidsExhausted => ErrorClass::Local,
+        }
+    }
+}
+
+impl Classify for StreamError {
+    fn class(&self) -> ErrorClass {
+        match self {
+            _ if self.is_h3_no_error() => ErrorClass::Closed,
+            Self::RemoteTerminate { .. } | Self::RemoteClosing => ErrorClass::Closed,
+            Self::ConnectionError(err) => err.class(),
+            Self::Undefined(err) => match err.downcast_ref::<ConnectionError>() {
+                Some(err) => err.class(),
+                None => ErrorClass::Local,
+            },
+            _ => ErrorClass::Peer,
+        }
+    }
+}
+
+impl Classify for H3ConnectionError {
+    fn class(&self) -> ErrorClass {
+        match self {
+            _ if self.is_h3_no_error() => ErrorClass::Closed,
+            Self::Timeout => ErrorClass::Closed,
+            Self::Remote(ConnectionErrorIncoming::ApplicationClose { .. })
+            | Self::Remote(ConnectionErrorIncoming::Timeout) => ErrorClass::Closed,
+            Self::Remote(ConnectionErrorIncoming::Undefined(err)) => {
+                match err.downcast_ref::<ConnectionError>() {
+                    Some(err) => err.class(),
+                    None => ErrorClass::Peer,
+                }
+            }
+            Self::Remote(_) => ErrorClass::Local,
+            _ => ErrorClass::Peer,
+        }
     }
 }
 
+impl Classify for RustlsError {
+    fn class(&self) -> ErrorClass {
+        match self {
+            Self::InappropriateMessage { .. }
+            | Self::InappropriateHandshakeMessage { .. }
+            | Self::InvalidMessage(_)
+            | Self::PeerIncompatible(_)
+            | Self::PeerMisbehaved(_)
+            | Self::AlertReceived(_)
+            | Self::InvalidCertificate(_)
+            | Self::NoApplicationProtocol
+            | Self::DecryptError => ErrorClass::Peer,
+            _ => ErrorClass::Local,
+        }
+    }
+}
+
+impl Classify for ModelError {
+    fn class(&self) -> ErrorClass {
+        match self {
+            Self::Io(err) => err.class(),
+            Self::Connection(err) => err.class(),
+            Self::SendDatagram(SendDatagramError::ConnectionLost(err)) => err.class(),
+            Self::SendDatagram(SendDatagramError::UnsupportedByPeer) => ErrorClass::Peer,
+            Self::SendDatagram(_) => ErrorClass::Local,
+            Self::UnmarshalUniStream(UnmarshalError::Io(err), _)
+            | Self::UnmarshalBiStream(UnmarshalError::Io(err), ..)
+            | Self::UnmarshalDatagram(UnmarshalError::Io(err), _) => err.class(),
+            Self::PayloadLength(..)
+            | Self::InvalidUdpSession(..)
+            | Self::Assemble(_)
+            | Self::UnmarshalUniStream(..)
+            | Self::UnmarshalBiStream(..)
+            | Self::UnmarshalDatagram(..)
+            | Self::BadCommandUniStream(..)
+            | Self::BadCommandBiStream(..)
+            | Self::BadCommandDatagram(..) => ErrorClass::Peer,
+        }
+    }
+}
+
+/// Log an error at the level configured for its class
+macro_rules! log_error {
+    ($err:expr, $($arg:tt)+) => {
+        match $crate::error::Classify::class(&$err).level() {
+            $crate::config::LogLevel::Trace => tracing::trace!($($arg)+),
+            $crate::config::LogLevel::Debug => tracing::debug!($($arg)+),
+            $crate::config::LogLevel::Info => tracing::info!($($arg)+),
+            $crate::config::LogLevel::Warn => tracing::warn!($($arg)+),
+            $crate::config::LogLevel::Error => tracing::error!($($arg)+),
+            $crate::config::LogLevel::Off => {}
+        }
+    };
+}
+
+pub(crate) use log_error;
+
 impl From<ConnectionError> for Error {
     fn from(err: ConnectionError) -> Self {
         match err {
             ConnectionError::TimedOut => Self::TimedOut,
             ConnectionError::LocallyClosed => Self::LocallyClosed,
-            _ => Self::Io(IoError::from(err)),
+            err => Self::Connection(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use rustls::AlertDescription;
+
+    use super::*;
+
+    fn io(kind: ErrorKind) -> IoError {
+        IoError::from(kind)
+    }
+
+    #[test]
+    fn closed() {
+        assert_eq!(Error::TimedOut.class(), ErrorClass::Closed);
+        assert_eq!(Error::LocallyClosed.class(), ErrorClass::Closed);
+        assert_eq!(
+            Error::StreamIdle(Duration::from_secs(1)).class(),
+            ErrorClass::Closed
+        );
+        assert_eq!(
+            Error::FirstByteTimeout(Duration::from_secs(1)).class(),
+            ErrorClass::Closed
+        );
+        assert_eq!(
+            Error::from(ConnectionError::Reset).class(),
+            ErrorClass::Closed
+        );
+        assert_eq!(
+            Error::from(io(ErrorKind::ConnectionReset)).class(),
+            ErrorClass::Closed
+        );
+        assert_eq!(
+            Error::from(io(ErrorKind::BrokenPipe)).class(),
+            ErrorClass::Closed
+        );
+        // a stream reset while reading a command header
+        let err = ModelError::UnmarshalDatagram(
+            UnmarshalError::Io(io(ErrorKind::UnexpectedEof)),
+            Bytes::new(),
+        );
+        assert_eq!(Error::from(err).class(), ErrorClass::Closed);
+    }
+
+    #[test]
+    fn wrapped_connection_error() {
+        let err = IoError::from(ConnectionError::Reset);
+        assert_eq!(err.class(), ErrorClass::Closed);
+        let err = IoError::from(ConnectionError::VersionMismatch);
+        assert_eq!(err.class(), ErrorClass::Peer);
+        let err = ModelError::Connection(ConnectionError::TimedOut);
+        assert_eq!(err.class(), ErrorClass::Closed);
+    }
+
+    #[test]
+    fn network() {
+        assert_eq!(
+            Error::from(io(ErrorKind::ConnectionRefused)).class(),
+            ErrorClass::Network
+        );
+        assert_eq!(
+            Error::from(io(ErrorKind::TimedOut)).class(),
+            ErrorClass::Network
+        );
+        let err = eyre::Report::new(io(ErrorKind::AddrNotAvailable));
+        assert_eq!(Error::from(err).class(), ErrorClass::Network);
+        assert_eq!(
+            Error::UpstreamProxy("residential".into(), "connection refused").class(),
+            ErrorClass::Network
+        );
+    }
+
+    #[test]
+    fn peer() {
+        assert_eq!(Error::AuthFailed(Uuid::nil()).class(), ErrorClass::Peer);
+        assert_eq!(Error::TaskNegotiationTimeout.class(), ErrorClass::Peer);
+        assert_eq!(Error::TooManyPreAuthTasks(1).class(), ErrorClass::Peer);
+        assert_eq!(Error::TooManyConnections(1).class(), ErrorClass::Peer);
+        assert_eq!(
+            Error::from(ModelError::PayloadLength(1, 2)).class(),
+            ErrorClass::Peer
+        );
+        assert_eq!(
+            Error::from(ModelError::BadCommandDatagram("connect", Bytes::new())).class(),
+            ErrorClass::Peer
+        );
+        assert_eq!(
+            Error::from(RustlsError::AlertReceived(
+                AlertDescription::HandshakeFailure
+            ))
+            .class(),
+            ErrorClass::Peer
+        );
+    }
+
+    #[test]
+    fn policy() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 80));
+        assert_eq!(Error::Blocklisted(addr).class(), ErrorClass::Policy);
+        assert_eq!(
+            Error::UdpRelayIpv6Disabled(addr).class(),
+            ErrorClass::Policy
+        );
+        assert_eq!(
+            Error::UdpRelayDisabled(Uuid::nil()).class(),
+            ErrorClass::Policy
+        );
+        assert_eq!(
+            Error::QuotaExceeded(Uuid::nil()).class(),
+            ErrorClass::Policy
+        );
+        assert_eq!(
+            Error::AclDenied("localhost:80".into()).class(),
+            ErrorClass::Policy
+        );
+        assert_eq!(
+            Error::Denied("localhost:80".into()).class(),
+            ErrorClass::Policy
+        );
+    }
+
+    #[test]
+    fn local() {
+        assert_eq!(Error::InvalidMaxIdleTime.class(), ErrorClass::Local);
+        assert_eq!(
+            Error::from(eyre::eyre!("UdpSession dropped already")).class(),
+            ErrorClass::Local
+        );
+        assert_eq!(
+            Error::from(RustlsError::General("no key".into())).class(),
+            ErrorClass::Local
+        );
+        assert_eq!(
+            Error::from(io(ErrorKind::PermissionDenied)).class(),
+            ErrorClass::Local
+        );
+    }
+
+    #[test]
+    fn default_levels() {
+        assert!(matches!(ErrorClass::Closed.level(), LogLevel::Debug));
+        for class in [
+            ErrorClass::Network,
+            ErrorClass::Peer,
+            ErrorClass::Policy,
+            ErrorClass::Local,
+        ] {
+            assert!(matches!(class.level(), LogLevel::Warn));
         }
     }
 }
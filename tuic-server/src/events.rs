@@ -0,0 +1,109 @@
+//! Live events streamed by the RESTful `/events` endpoint, for dashboards
+//! that would rather not poll
+
+use std::{collections::HashMap, net::SocketAddr, sync::LazyLock};
+
+use serde::Serialize;
+use tokio::sync::broadcast::{self, Receiver, Sender};
+use uuid::Uuid;
+
+use crate::{connection::CorrelationId, error::Error};
+
+/// Events buffered for each subscriber, slower ones miss the oldest
+const CAPACITY: usize = 1024;
+
+static EVENTS: LazyLock<Sender<Event>> = LazyLock::new(|| broadcast::channel(CAPACITY).0);
+
+#[derive(Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Auth {
+        uuid: Uuid,
+        addr: SocketAddr,
+        correlation_id: String,
+    },
+    /// `uuid` is the one claimed, `None` for unparsable MASQUE credentials
+    AuthFailed {
+        uuid: Option<Uuid>,
+        addr: SocketAddr,
+        correlation_id: String,
+    },
+    Connect {
+        uuid: Uuid,
+        addr: SocketAddr,
+        correlation_id: String,
+    },
+    Disconnect {
+        uuid: Uuid,
+        addr: SocketAddr,
+        correlation_id: String,
+        reason: Option<String>,
+    },
+    /// Bytes relayed by each user since the previous traffic event, users
+    /// without traffic are left out
+    Traffic { users: HashMap<Uuid, Delta> },
+}
+
+#[derive(Clone, Copy, Serialize)]
+pub struct Delta {
+    pub tx: u64,
+    pub rx: u64,
+}
+
+impl Event {
+    /// The SSE event name, same as the `event` field
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Auth { .. } => "auth",
+            Self::AuthFailed { .. } => "auth_failed",
+            Self::Connect { .. } => "connect",
+            Self::Disconnect { .. } => "disconnect",
+            Self::Traffic { .. } => "traffic",
+        }
+    }
+}
+
+/// Whether anyone is listening, to skip building events otherwise
+pub fn enabled() -> bool {
+    EVENTS.receiver_count() != 0
+}
+
+pub fn publish(event: Event) {
+    // fails only without subscribers
+    _ = EVENTS.send(event);
+}
+
+pub fn subscribe() -> Receiver<Event> {
+    EVENTS.subscribe()
+}
+
+pub fn auth(uuid: Uuid, addr: SocketAddr, cid: CorrelationId) {
+    if enabled() {
+        publish(Event::Auth {
+            uuid,
+            addr,
+            correlation_id: cid.to_string(),
+        });
+    }
+}
+
+/// Publish `err` if it's a failed authentication
+pub fn observe(err: &Error, addr: SocketAddr, cid: CorrelationId) {
+    if !enabled() {
+        return;
+    }
+    let uuid = match err {
+        Error::AuthFailed(uuid) if uuid.is_nil() => None,
+        Error::AuthFailed(uuid)
+        | Error::NoUsers(uuid)
+        | Error::UserDisabled(uuid)
+        | Error::QuotaExceeded(uuid) => Some(*uuid),
+        Error::ProxyAuthRequired => None,
+        _ => return,
+    };
+    publish(Event::AuthFailed {
+        uuid,
+        addr,
+        correlation_id: cid.to_string(),
+    });
+}
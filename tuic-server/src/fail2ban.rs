@@ -0,0 +1,103 @@
+//! Banning source IPs that fail authentication `fail2ban.max_failures` times
+//! within `fail2ban.window`, whatever UUID they claim. The bans are the ones
+//! of `abuse`: connections from the IP are refused, and the open ones closed
+
+use std::{
+    collections::{HashMap, VecDeque},
+    net::IpAddr,
+    sync::{LazyLock, Mutex, OnceLock},
+    time::Duration,
+};
+
+use serde_json::json;
+use tokio::time::{self, Instant, MissedTickBehavior};
+use tracing::warn;
+
+use crate::{
+    abuse::{self, Offender},
+    alerts,
+    config::Fail2banConfig,
+    error::Error,
+};
+
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Unset unless `fail2ban.enabled`
+static CFG: OnceLock<Fail2banConfig> = OnceLock::new();
+/// When the recent failures of each IP happened
+static FAILURES: LazyLock<Mutex<HashMap<IpAddr, VecDeque<Instant>>>> =
+    LazyLock::new(Mutex::default);
+
+pub fn init(cfg: &Fail2banConfig) -> eyre::Result<()> {
+    if !cfg.enabled {
+        return Ok(());
+    }
+    if cfg.max_failures == 0 || cfg.window.is_zero() || cfg.ban_duration.is_zero() {
+        eyre::bail!("fail2ban: `max_failures`, `window` and `ban_duration` must be non-zero");
+    }
+    _ = CFG.set(cfg.clone());
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    CFG.get().is_some()
+}
+
+/// Count `err` if it is an authentication failure of a client from `ip`
+pub fn observe(err: &Error, ip: IpAddr) {
+    let (Some(cfg), Error::AuthFailed(_)) = (CFG.get(), err) else {
+        return;
+    };
+    let ip = ip.to_canonical();
+    let now = Instant::now();
+    {
+        let mut failures = FAILURES.lock().unwrap();
+        let times = failures.entry(ip).or_default();
+        while times
+            .front()
+            .is_some_and(|time| now.duration_since(*time) > cfg.window)
+        {
+            times.pop_front();
+        }
+        times.push_back(now);
+        if times.len() < cfg.max_failures {
+            return;
+        }
+        failures.remove(&ip);
+    }
+
+    abuse::impose(Offender::Ip(ip), cfg.ban_duration);
+    warn!(
+        "[fail2ban] [{ip}] {count} authentication failures within {window}, banned for {duration}",
+        count = cfg.max_failures,
+        window = humantime::format_duration(cfg.window),
+        duration = humantime::format_duration(cfg.ban_duration),
+    );
+    alerts::push(
+        "fail2ban",
+        json!({
+            "ip": ip,
+            "failures": cfg.max_failures,
+            "window_secs": cfg.window.as_secs(),
+            "ban_secs": cfg.ban_duration.as_secs(),
+        }),
+    );
+}
+
+/// Forget the failures that fell out of the window every minute
+pub async fn start() {
+    let Some(cfg) = CFG.get() else {
+        return;
+    };
+    let mut interval = time::interval(PRUNE_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+        FAILURES.lock().unwrap().retain(|_, times| {
+            times
+                .back()
+                .is_some_and(|time| now.duration_since(*time) <= cfg.window)
+        });
+    }
+}
@@ -0,0 +1,294 @@
+//! `[fallback]`: a web site over TCP on the ports of `server`, so active
+//! probes of TCP 443 find an ordinary HTTPS site while TUIC runs over UDP. TLS
+//! is terminated with the certificates of `tls`, then the site is either
+//! served from a directory of static files or handed over to a local web
+//! server
+
+use std::{
+    net::{SocketAddr, TcpListener as StdTcpListener},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    Router,
+    body::Body,
+    extract::{Path as UrlPath, State},
+    http::{HeaderValue, StatusCode, header::CONTENT_TYPE},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use eyre::Context;
+use rustls::{ServerConfig as RustlsServerConfig, server::ResolvesServerCert};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::Semaphore,
+    time,
+};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, warn};
+
+use crate::{
+    abuse::{self, Offender},
+    config::FallbackConfig,
+    error::Error,
+    systemd,
+};
+
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const UPSTREAM_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Connections served at once, later ones wait to be accepted
+const MAX_CONNECTIONS: usize = 1024;
+
+pub struct Fallback {
+    listeners: Vec<StdTcpListener>,
+    acceptor: TlsAcceptor,
+    site: Site,
+}
+
+#[derive(Clone)]
+enum Site {
+    Static(Router),
+    Upstream(Arc<str>),
+}
+
+impl Fallback {
+    /// Bind a TCP listener on each of `addrs`
+    pub fn new(
+        cfg: &FallbackConfig,
+        addrs: impl IntoIterator<Item = SocketAddr>,
+        dual_stack: bool,
+        resolver: Arc<dyn ResolvesServerCert>,
+    ) -> Result<Self, Error> {
+        let site = match (&cfg.root, &cfg.upstream) {
+            (Some(root), None) => {
+                if !root.is_dir() {
+                    return Err(eyre::eyre!(
+                        "fallback.root: {} is not a directory",
+                        root.display()
+                    )
+                    .into());
+                }
+                Site::Static(
+                    Router::new()
+                        .route("/", get(index))
+                        .route("/*path", get(file))
+                        .with_state(Arc::<Path>::from(root.as_path())),
+                )
+            }
+            (None, Some(upstream)) => Site::Upstream(upstream.as_str().into()),
+            _ => {
+                return Err(eyre::eyre!("fallback: set either `root` or `upstream`").into());
+            }
+        };
+
+        let mut tls = RustlsServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(resolver);
+        tls.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        let listeners = addrs
+            .into_iter()
+            .map(|addr| bind(addr, dual_stack))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            listeners,
+            acceptor: TlsAcceptor::from(Arc::new(tls)),
+            site,
+        })
+    }
+
+    pub fn start(self) {
+        let permits = Arc::new(Semaphore::new(MAX_CONNECTIONS));
+        for listener in self.listeners {
+            let listener = match TcpListener::from_std(listener) {
+                Ok(listener) => listener,
+                Err(err) => {
+                    warn!("[fallback] failed to register the TCP listener: {err}");
+                    continue;
+                }
+            };
+            tokio::spawn(accept(
+                listener,
+                self.acceptor.clone(),
+                self.site.clone(),
+                permits.clone(),
+            ));
+        }
+    }
+}
+
+fn bind(addr: SocketAddr, dual_stack: bool) -> Result<StdTcpListener, Error> {
+    // already listening
+    if let Some(socket) = systemd::take_socket(addr, Type::STREAM) {
+        socket
+            .set_nonblocking(true)
+            .map_err(|err| Error::Socket("fallback socket setting error", err))?;
+        return Ok(StdTcpListener::from(socket));
+    }
+    let domain = match addr {
+        SocketAddr::V4(_) => Domain::IPV4,
+        SocketAddr::V6(_) => Domain::IPV6,
+    };
+    let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))
+        .context("failed to create fallback TCP socket")?;
+    if dual_stack && addr.is_ipv6() {
+        socket
+            .set_only_v6(false)
+            .map_err(|err| Error::Socket("fallback dual-stack socket setting error", err))?;
+    }
+    socket
+        .set_reuse_address(true)
+        .map_err(|err| Error::Socket("fallback socket setting error", err))?;
+    socket
+        .set_nonblocking(true)
+        .map_err(|err| Error::Socket("fallback socket setting error", err))?;
+    socket
+        .bind(&SockAddr::from(addr))
+        .map_err(|err| Error::Bind(addr, err))?;
+    socket.listen(1024).map_err(|err| Error::Bind(addr, err))?;
+    Ok(StdTcpListener::from(socket))
+}
+
+async fn accept(listener: TcpListener, acceptor: TlsAcceptor, site: Site, permits: Arc<Semaphore>) {
+    loop {
+        let Ok(permit) = permits.clone().acquire_owned().await else {
+            return;
+        };
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                debug!("[fallback] failed to accept connection: {err}");
+                continue;
+            }
+        };
+        if abuse::is_banned(Offender::Ip(peer.ip().to_canonical())) {
+            debug!("[fallback] [{peer}] refused connection from banned address");
+            continue;
+        }
+        let acceptor = acceptor.clone();
+        let site = site.clone();
+        tokio::spawn(async move {
+            serve(stream, peer, acceptor, site).await;
+            drop(permit);
+        });
+    }
+}
+
+async fn serve(stream: TcpStream, peer: SocketAddr, acceptor: TlsAcceptor, site: Site) {
+    let mut stream = match time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(err)) => {
+            debug!("[fallback] [{peer}] TLS handshake failed: {err}");
+            return;
+        }
+        Err(_) => {
+            debug!("[fallback] [{peer}] TLS handshake timed out");
+            return;
+        }
+    };
+    match site {
+        Site::Static(app) => {
+            use hyper::server::conn::http1;
+            use hyper_util::{rt::TokioIo, service::TowerToHyperService};
+
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), TowerToHyperService::new(app))
+                .await
+            {
+                debug!("[fallback] [{peer}] connection error: {err}");
+            }
+        }
+        Site::Upstream(upstream) => {
+            let mut upstream_stream =
+                match time::timeout(UPSTREAM_CONNECT_TIMEOUT, TcpStream::connect(&*upstream)).await
+                {
+                    Ok(Ok(upstream_stream)) => upstream_stream,
+                    Ok(Err(err)) => {
+                        warn!("[fallback] failed to connect to {upstream}: {err}");
+                        return;
+                    }
+                    Err(_) => {
+                        warn!("[fallback] connecting to {upstream} timed out");
+                        return;
+                    }
+                };
+            if let Err(err) = tokio::io::copy_bidirectional(&mut stream, &mut upstream_stream).await
+            {
+                debug!("[fallback] [{peer}] connection error: {err}");
+            }
+        }
+    }
+}
+
+async fn index(State(root): State<Arc<Path>>) -> Response {
+    send(&root, PathBuf::new()).await
+}
+
+async fn file(State(root): State<Arc<Path>>, UrlPath(path): UrlPath<String>) -> Response {
+    let path = PathBuf::from(path);
+    // nothing but plain names, so requests never leave `root`
+    if !path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return not_found(&root).await;
+    }
+    send(&root, path).await
+}
+
+/// `path` under `root`, `index.html` for directories
+async fn send(root: &Path, path: PathBuf) -> Response {
+    let mut path = root.join(path);
+    if path.is_dir() {
+        path.push("index.html");
+    }
+    match tokio::fs::read(&path).await {
+        Ok(body) => with_type(&path, StatusCode::OK, body),
+        Err(_) => not_found(root).await,
+    }
+}
+
+/// `404.html` of `root` if any, like most static site hosts
+async fn not_found(root: &Path) -> Response {
+    let path = root.join("404.html");
+    match tokio::fs::read(&path).await {
+        Ok(body) => with_type(&path, StatusCode::NOT_FOUND, body),
+        Err(_) => (StatusCode::NOT_FOUND, "Not Found").into_response(),
+    }
+}
+
+fn with_type(path: &Path, status: StatusCode, body: Vec<u8>) -> Response {
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_ascii_lowercase);
+    let content_type = match ext.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("pdf") => "application/pdf",
+        Some("wasm") => "application/wasm",
+        _ => "application/octet-stream",
+    };
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    response
+}
@@ -0,0 +1,134 @@
+//! `[acl.geoip]`: clients and destinations allowed or denied by the country
+//! of their address, looked up in a MaxMind database (GeoLite2 or GeoIP2,
+//! Country or City). The database is read again along with the ACL on reload
+
+use std::net::IpAddr;
+
+#[cfg(feature = "geoip")]
+use arc_swap::ArcSwapOption;
+
+use crate::config::GeoIpConfig;
+
+#[cfg(feature = "geoip")]
+static GEOIP: ArcSwapOption<imp::GeoIp> = ArcSwapOption::const_empty();
+
+/// Open the database of `[acl.geoip]`, replacing the current one
+pub fn init(cfg: Option<&GeoIpConfig>) -> eyre::Result<()> {
+    let Some(cfg) = cfg else {
+        #[cfg(feature = "geoip")]
+        GEOIP.store(None);
+        return Ok(());
+    };
+    #[cfg(feature = "geoip")]
+    {
+        GEOIP.store(Some(std::sync::Arc::new(imp::GeoIp::open(cfg)?)));
+        Ok(())
+    }
+    #[cfg(not(feature = "geoip"))]
+    {
+        Err(eyre::eyre!(
+            "acl.geoip {path}: built without the geoip feature",
+            path = cfg.database.display()
+        ))
+    }
+}
+
+/// Whether a client may connect from `ip`
+pub fn allow_client(ip: IpAddr) -> bool {
+    #[cfg(feature = "geoip")]
+    if let Some(geoip) = GEOIP.load().as_ref() {
+        return geoip.allow(&geoip.clients, ip);
+    }
+    _ = ip;
+    true
+}
+
+/// Whether `ip` may be relayed to
+pub fn allow_destination(ip: IpAddr) -> bool {
+    #[cfg(feature = "geoip")]
+    if let Some(geoip) = GEOIP.load().as_ref() {
+        return geoip.allow(&geoip.destinations, ip);
+    }
+    _ = ip;
+    true
+}
+
+#[cfg(feature = "geoip")]
+mod imp {
+    use std::{collections::HashSet, net::IpAddr};
+
+    use eyre::{Context, bail};
+    use maxminddb::{Reader, geoip2};
+
+    use crate::config::GeoIpConfig;
+
+    pub struct GeoIp {
+        reader: Reader<Vec<u8>>,
+        pub clients: Countries,
+        pub destinations: Countries,
+    }
+
+    /// Upper case ISO 3166-1 alpha-2 codes
+    pub struct Countries {
+        /// Everything else is denied, unless empty
+        allow: HashSet<String>,
+        deny: HashSet<String>,
+    }
+
+    impl GeoIp {
+        pub fn open(cfg: &GeoIpConfig) -> eyre::Result<Self> {
+            let clients = Countries::parse("clients", &cfg.allow_clients, &cfg.deny_clients)?;
+            let destinations = Countries::parse(
+                "destinations",
+                &cfg.allow_destinations,
+                &cfg.deny_destinations,
+            )?;
+            let reader = Reader::open_readfile(&cfg.database)
+                .with_context(|| format!("acl.geoip: failed to open {}", cfg.database.display()))?;
+            Ok(Self {
+                reader,
+                clients,
+                destinations,
+            })
+        }
+
+        /// Addresses of no known country, private ones included, are never
+        /// denied
+        pub fn allow(&self, countries: &Countries, ip: IpAddr) -> bool {
+            if countries.allow.is_empty() && countries.deny.is_empty() {
+                return true;
+            }
+            // the country the address is in, or else the one it's registered in
+            let country = self
+                .reader
+                .lookup::<geoip2::Country>(ip.to_canonical())
+                .ok()
+                .and_then(|found| found.country.or(found.registered_country))
+                .and_then(|country| country.iso_code);
+            country.is_none_or(|country| {
+                !countries.deny.contains(country)
+                    && (countries.allow.is_empty() || countries.allow.contains(country))
+            })
+        }
+    }
+
+    impl Countries {
+        fn parse(field: &str, allow: &[String], deny: &[String]) -> eyre::Result<Self> {
+            let parse = |codes: &[String], list: &str| {
+                codes
+                    .iter()
+                    .map(|code| {
+                        if code.len() != 2 || !code.bytes().all(|b| b.is_ascii_alphabetic()) {
+                            bail!("acl.geoip.{list}_{field}: invalid country code {code:?}");
+                        }
+                        Ok(code.to_ascii_uppercase())
+                    })
+                    .collect::<eyre::Result<HashSet<_>>>()
+            };
+            Ok(Self {
+                allow: parse(allow, "allow")?,
+                deny: parse(deny, "deny")?,
+            })
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! Recording what each QUIC handshake negotiated that quinn keeps to itself,
+//! the version and the client's transport parameters, by wrapping the TLS
+//! layer quinn hands them to
+
+use std::{any::Any, sync::Arc};
+
+use quinn::{
+    Connection, ConnectionId, Side,
+    crypto::{
+        self, ExportKeyingMaterialError, HeaderKey, KeyPair, Keys, PacketKey, Session,
+        UnsupportedVersion, rustls::HandshakeData,
+    },
+};
+use quinn_proto::{TransportError, transport_parameters::TransportParameters};
+
+/// The versions quinn implements, QUIC v1 and the drafts 29 to 34, in order
+/// of preference
+pub const SUPPORTED_VERSIONS: &[u32] = &[
+    0x0000_0001,
+    0xff00_001d,
+    0xff00_001e,
+    0xff00_001f,
+    0xff00_0020,
+    0xff00_0021,
+    0xff00_0022,
+];
+
+/// What a connection was opened with, in place of quinn's `HandshakeData`
+pub struct Negotiated {
+    pub version: u32,
+    /// The ALPN protocol agreed on
+    pub protocol: Option<Vec<u8>>,
+    pub server_name: Option<String>,
+    /// Formatted, their fields aren't public
+    pub transport_parameters: Option<String>,
+}
+
+/// What the handshake of `conn` negotiated, `None` until it completed
+pub fn negotiated(conn: &Connection) -> Option<Box<Negotiated>> {
+    conn.handshake_data()?.downcast().ok()
+}
+
+/// `v1`, `draft-29` or the hexadecimal version
+pub fn version_name(version: u32) -> String {
+    match version {
+        0x0000_0001 => "v1".to_owned(),
+        0xff00_0000..=0xff00_00ff => format!("draft-{}", version & 0xff),
+        _ => format!("{version:#010x}"),
+    }
+}
+
+pub struct ServerConfig(pub Arc<dyn crypto::ServerConfig>);
+
+impl crypto::ServerConfig for ServerConfig {
+    fn initial_keys(
+        &self,
+        version: u32,
+        dst_cid: &ConnectionId,
+    ) -> Result<Keys, UnsupportedVersion> {
+        self.0.initial_keys(version, dst_cid)
+    }
+
+    fn retry_tag(&self, version: u32, orig_dst_cid: &ConnectionId, packet: &[u8]) -> [u8; 16] {
+        self.0.retry_tag(version, orig_dst_cid, packet)
+    }
+
+    fn start_session(
+        self: Arc<Self>,
+        version: u32,
+        params: &TransportParameters,
+    ) -> Box<dyn Session> {
+        Box::new(VersionedSession {
+            version,
+            inner: self.0.clone().start_session(version, params),
+        })
+    }
+}
+
+struct VersionedSession {
+    version: u32,
+    inner: Box<dyn Session>,
+}
+
+impl Session for VersionedSession {
+    fn initial_keys(&self, dst_cid: &ConnectionId, side: Side) -> Keys {
+        self.inner.initial_keys(dst_cid, side)
+    }
+
+    fn handshake_data(&self) -> Option<Box<dyn Any>> {
+        let data = self.inner.handshake_data()?;
+        let (protocol, server_name) = match data.downcast::<HandshakeData>() {
+            Ok(data) => (data.protocol, data.server_name),
+            Err(_) => (None, None),
+        };
+        Some(Box::new(Negotiated {
+            version: self.version,
+            protocol,
+            server_name,
+            transport_parameters: self
+                .inner
+                .transport_parameters()
+                .ok()
+                .flatten()
+                .map(|params| format!("{params:?}")),
+        }))
+    }
+
+    fn peer_identity(&self) -> Option<Box<dyn Any>> {
+        self.inner.peer_identity()
+    }
+
+    fn early_crypto(&self) -> Option<(Box<dyn HeaderKey>, Box<dyn PacketKey>)> {
+        self.inner.early_crypto()
+    }
+
+    fn early_data_accepted(&self) -> Option<bool> {
+        self.inner.early_data_accepted()
+    }
+
+    fn is_handshaking(&self) -> bool {
+        self.inner.is_handshaking()
+    }
+
+    fn read_handshake(&mut self, buf: &[u8]) -> Result<bool, TransportError> {
+        self.inner.read_handshake(buf)
+    }
+
+    fn transport_parameters(&self) -> Result<Option<TransportParameters>, TransportError> {
+        self.inner.transport_parameters()
+    }
+
+    fn write_handshake(&mut self, buf: &mut Vec<u8>) -> Option<Keys> {
+        self.inner.write_handshake(buf)
+    }
+
+    fn next_1rtt_keys(&mut self) -> Option<KeyPair<Box<dyn PacketKey>>> {
+        self.inner.next_1rtt_keys()
+    }
+
+    fn is_valid_retry(&self, orig_dst_cid: &ConnectionId, header: &[u8], payload: &[u8]) -> bool {
+        self.inner.is_valid_retry(orig_dst_cid, header, payload)
+    }
+
+    fn export_keying_material(
+        &self,
+        output: &mut [u8],
+        label: &[u8],
+        context: &[u8],
+    ) -> Result<(), ExportKeyingMaterialError> {
+        self.inner.export_keying_material(output, label, context)
+    }
+}
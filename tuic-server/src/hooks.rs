@@ -0,0 +1,192 @@
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    net::SocketAddr,
+    process::Stdio,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{process::Command, time};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::{
+    AppContext,
+    connection::CorrelationId,
+    utils::{AbuseAction, AbuseEvent, ScanAction},
+};
+
+#[derive(Clone, Copy)]
+pub enum HookEvent {
+    Connect,
+    Disconnect,
+}
+
+impl Display for HookEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Connect => write!(f, "connect"),
+            Self::Disconnect => write!(f, "disconnect"),
+        }
+    }
+}
+
+/// Run the configured external command for `event` in the background.
+///
+/// The command receives the client information through `TUIC_*` environment
+/// variables and is killed if it doesn't finish within `exec_timeout`.
+pub fn exec(
+    ctx: &Arc<AppContext>,
+    event: HookEvent,
+    id: u32,
+    cid: CorrelationId,
+    uuid: Uuid,
+    addr: SocketAddr,
+    duration: Duration,
+) {
+    let argv = match event {
+        HookEvent::Connect => &ctx.cfg.on_connect_exec,
+        HookEvent::Disconnect => &ctx.cfg.on_disconnect_exec,
+    };
+    let Some((program, args)) = argv.split_first() else {
+        return;
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let connected_at = now.saturating_sub(duration);
+
+    let mut cmd = Command::new(program);
+    cmd.args(args)
+        .env("TUIC_EVENT", event.to_string())
+        .env("TUIC_CONNECTION_ID", format!("{id:#010x}"))
+        .env("TUIC_CORRELATION_ID", cid.to_string())
+        .env("TUIC_UUID", uuid.to_string())
+        .env("TUIC_IP", addr.ip().to_string())
+        .env("TUIC_PORT", addr.port().to_string())
+        .env("TUIC_TIMESTAMP", now.as_secs().to_string())
+        .env("TUIC_CONNECTED_AT", connected_at.as_secs().to_string())
+        .env("TUIC_DURATION", duration.as_secs().to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    let label = format!("[{id:#010x}] [{cid}] [{addr}] [{uuid}] [{event} hook]");
+    run(cmd, ctx.cfg.exec_timeout, label);
+}
+
+/// Run `abuse.exec` in the background after an `abuse` rule was triggered by
+/// `target`, a user or an IP.
+///
+/// The command receives what happened through `TUIC_*` environment variables
+/// and is killed if it doesn't finish within `exec_timeout`.
+pub fn exec_abuse(
+    ctx: &Arc<AppContext>,
+    event: AbuseEvent,
+    action: AbuseAction,
+    target: &str,
+    duration: Duration,
+) {
+    let Some((program, args)) = ctx.cfg.abuse.exec.split_first() else {
+        return;
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    let mut cmd = Command::new(program);
+    cmd.args(args)
+        .env("TUIC_EVENT", "abuse")
+        .env("TUIC_TRIGGER", event.to_string())
+        .env("TUIC_ACTION", action.to_string())
+        .env("TUIC_TARGET", target)
+        .env("TUIC_TIMESTAMP", now.as_secs().to_string())
+        .env("TUIC_BAN_DURATION", duration.as_secs().to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    run(
+        cmd,
+        ctx.cfg.exec_timeout,
+        format!("[abuse] [{target}] [{action} hook]"),
+    );
+}
+
+/// Run `port_scan.exec` in the background after `user` was detected scanning
+/// ports.
+///
+/// The command receives what happened through `TUIC_*` environment variables
+/// and is killed if it doesn't finish within `exec_timeout`.
+pub fn exec_port_scan(
+    ctx: &Arc<AppContext>,
+    user: Uuid,
+    action: ScanAction,
+    targets: usize,
+    failed: usize,
+    connects: usize,
+) {
+    let Some((program, args)) = ctx.cfg.port_scan.exec.split_first() else {
+        return;
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    let mut cmd = Command::new(program);
+    cmd.args(args)
+        .env("TUIC_EVENT", "port_scan")
+        .env("TUIC_UUID", user.to_string())
+        .env("TUIC_ACTION", action.to_string())
+        .env("TUIC_TARGETS", targets.to_string())
+        .env("TUIC_FAILED", failed.to_string())
+        .env("TUIC_CONNECTS", connects.to_string())
+        .env("TUIC_TIMESTAMP", now.as_secs().to_string())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+
+    run(
+        cmd,
+        ctx.cfg.exec_timeout,
+        format!("[port_scan] [{user}] [{action} hook]"),
+    );
+}
+
+/// Spawn `cmd` and wait for it in the background, logging its failures after
+/// `label`
+fn run(mut cmd: Command, timeout: Duration, label: String) {
+    tokio::spawn(async move {
+        let child = match cmd.spawn() {
+            Ok(child) => child,
+            Err(err) => {
+                warn!("{label} failed to spawn: {err}");
+                return;
+            }
+        };
+
+        match time::timeout(timeout, child.wait_with_output()).await {
+            Ok(Ok(output)) if output.status.success() => {
+                debug!("{label} finished");
+            }
+            Ok(Ok(output)) => warn!(
+                "{label} exited with {status}: {stderr}",
+                status = output.status,
+                stderr = String::from_utf8_lossy(&output.stderr).trim(),
+            ),
+            Ok(Err(err)) => {
+                warn!("{label} failed: {err}");
+            }
+            Err(_) => warn!(
+                "{label} killed after timing out in {timeout}",
+                timeout = humantime::format_duration(timeout),
+            ),
+        }
+    });
+}
@@ -0,0 +1,100 @@
+//! A bare HTTP/1.1 client for the requests the server makes itself:
+//! self-update downloads and webhooks. HTTPS trusts the system's root
+//! certificates
+
+use std::sync::Arc;
+
+use bytes::Bytes;
+use eyre::{OptionExt, bail};
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    Request, Response,
+    header::{HOST, HeaderValue, USER_AGENT},
+};
+use hyper_util::rt::TokioIo;
+use rustls::{ClientConfig as RustlsClientConfig, RootCertStore, pki_types::ServerName};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_rustls::TlsConnector;
+use tracing::debug;
+
+pub struct Client {
+    tls: TlsConnector,
+}
+
+impl Client {
+    pub fn new() -> eyre::Result<Self> {
+        let mut roots = RootCertStore::empty();
+        for cert in rustls_native_certs::load_native_certs().certs {
+            _ = roots.add(cert);
+        }
+        if roots.is_empty() {
+            bail!("no trusted root certificates found on the system");
+        }
+        let mut cfg = RustlsClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        cfg.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(Self {
+            tls: TlsConnector::from(Arc::new(cfg)),
+        })
+    }
+
+    /// Send `req`, whose URI is absolute, on a new connection, returning the
+    /// response with its whole body. Redirects aren't followed
+    pub async fn send(&self, mut req: Request<Full<Bytes>>) -> eyre::Result<Response<Bytes>> {
+        let uri = req.uri().clone();
+        let https = match uri.scheme_str() {
+            Some("https") => true,
+            Some("http") => false,
+            _ => bail!("only HTTP and HTTPS URLs are supported"),
+        };
+        let authority = uri.authority().ok_or_eyre("missing host")?;
+        // IPv6 literals come bracketed
+        let host = authority
+            .host()
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let port = authority.port_u16().unwrap_or(if https { 443 } else { 80 });
+
+        *req.uri_mut() = uri
+            .path_and_query()
+            .map_or("/", |path| path.as_str())
+            .parse()?;
+        req.headers_mut()
+            .insert(HOST, HeaderValue::from_str(authority.as_str())?);
+        req.headers_mut()
+            .entry(USER_AGENT)
+            .or_insert(HeaderValue::from_static(concat!(
+                "tuic-server/",
+                env!("CARGO_PKG_VERSION")
+            )));
+
+        let stream = TcpStream::connect((host, port)).await?;
+        if https {
+            let name = ServerName::try_from(host.to_owned())?;
+            exchange(self.tls.connect(name, stream).await?, req).await
+        } else {
+            exchange(stream, req).await
+        }
+    }
+}
+
+async fn exchange<S>(stream: S, req: Request<Full<Bytes>>) -> eyre::Result<Response<Bytes>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+    tokio::spawn(async move {
+        if let Err(err) = conn.await {
+            debug!("[http] connection error: {err}");
+        }
+    });
+    let (parts, body) = sender.send_request(req).await?.into_parts();
+    Ok(Response::from_parts(
+        parts,
+        body.collect().await?.to_bytes(),
+    ))
+}
@@ -0,0 +1,213 @@
+//! Latency histograms of TCP relaying, telling apart a slow server from a
+//! slow egress path
+
+use std::{
+    io::Result as IoResult,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use serde_json::{Value, json};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Upper bounds of the histogram buckets in milliseconds, followed by an
+/// unbounded one
+const BUCKETS_MS: [u64; 13] = [1, 2, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+static HISTOGRAMS: [[Histogram; PortClass::ALL.len()]; Metric::ALL.len()] =
+    [const { [const { Histogram::new() }; PortClass::ALL.len()] }; Metric::ALL.len()];
+/// Streams reset by `first_byte_timeout`, by destination port class
+static FIRST_BYTE_TIMEOUTS: [AtomicU64; PortClass::ALL.len()] =
+    [const { AtomicU64::new(0) }; PortClass::ALL.len()];
+
+#[derive(Clone, Copy)]
+pub enum Metric {
+    /// Waiting for a slot under `outbound.max_concurrent_dials`
+    DialQueue,
+    /// From resolving the target to the TCP connection being established
+    Connect,
+    /// From the connection being established to the first byte received from
+    /// the target
+    FirstByte,
+}
+
+impl Metric {
+    const ALL: [Self; 3] = [Self::DialQueue, Self::Connect, Self::FirstByte];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::DialQueue => "dial_queue",
+            Self::Connect => "connect",
+            Self::FirstByte => "first_byte",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum PortClass {
+    Http,
+    Https,
+    Dns,
+    Other,
+}
+
+impl PortClass {
+    const ALL: [Self; 4] = [Self::Http, Self::Https, Self::Dns, Self::Other];
+
+    fn of(port: u16) -> Self {
+        match port {
+            80 | 8080 => Self::Http,
+            443 | 8443 => Self::Https,
+            53 | 853 => Self::Dns,
+            _ => Self::Other,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Http => "http",
+            Self::Https => "https",
+            Self::Dns => "dns",
+            Self::Other => "other",
+        }
+    }
+}
+
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS_MS.len() + 1],
+    count: AtomicU64,
+    sum_us: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS_MS.len() + 1],
+            count: AtomicU64::new(0),
+            sum_us: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, elapsed: Duration) {
+        let ms = elapsed.as_millis();
+        let idx = BUCKETS_MS
+            .iter()
+            .position(|&le| ms <= u128::from(le))
+            .unwrap_or(BUCKETS_MS.len());
+        self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_us
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Cumulative buckets, as Prometheus histograms are
+    fn snapshot(&self) -> Value {
+        let mut total = 0;
+        let buckets: Vec<_> = self
+            .buckets
+            .iter()
+            .enumerate()
+            .map(|(idx, count)| {
+                total += count.load(Ordering::Relaxed);
+                json!({ "le_ms": BUCKETS_MS.get(idx), "count": total })
+            })
+            .collect();
+        json!({
+            "count": self.count.load(Ordering::Relaxed),
+            "sum_ms": self.sum_us.load(Ordering::Relaxed) as f64 / 1000.0,
+            "buckets": buckets,
+        })
+    }
+}
+
+pub fn record(metric: Metric, port: u16, elapsed: Duration) {
+    HISTOGRAMS[metric as usize][PortClass::of(port) as usize].observe(elapsed);
+}
+
+/// A stream to a target on `port` reset by `first_byte_timeout`
+pub fn first_byte_timeout(port: u16) {
+    FIRST_BYTE_TIMEOUTS[PortClass::of(port) as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Every histogram, by metric and destination port class, and the streams
+/// reset by `first_byte_timeout`
+pub fn snapshot() -> Value {
+    let mut metrics = serde_json::Map::new();
+    for metric in Metric::ALL {
+        let mut classes = serde_json::Map::new();
+        for class in PortClass::ALL {
+            classes.insert(
+                class.name().to_owned(),
+                HISTOGRAMS[metric as usize][class as usize].snapshot(),
+            );
+        }
+        metrics.insert(metric.name().to_owned(), Value::Object(classes));
+    }
+    let timeouts = PortClass::ALL
+        .iter()
+        .map(|class| {
+            (
+                class.name().to_owned(),
+                FIRST_BYTE_TIMEOUTS[*class as usize]
+                    .load(Ordering::Relaxed)
+                    .into(),
+            )
+        })
+        .collect();
+    metrics.insert("first_byte_timeouts".to_owned(), Value::Object(timeouts));
+    Value::Object(metrics)
+}
+
+/// Wraps the stream to a target, recording the time to its first byte
+pub struct FirstByte<S> {
+    inner: S,
+    port: u16,
+    start: Option<Instant>,
+}
+
+impl<S> FirstByte<S> {
+    pub fn new(inner: S, port: u16) -> Self {
+        Self {
+            inner,
+            port,
+            start: Some(Instant::now()),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for FirstByte<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<IoResult<()>> {
+        let filled = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if matches!(res, Poll::Ready(Ok(()))) && buf.filled().len() > filled {
+            if let Some(start) = self.start.take() {
+                record(Metric::FirstByte, self.port, start.elapsed());
+            }
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for FirstByte<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<IoResult<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
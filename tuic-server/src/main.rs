@@ -5,18 +5,63 @@ use std::{env, process, sync::Arc};
 
 use chrono::{Local, Offset, TimeZone};
 use config::{Config, parse_config};
-use tracing::level_filters::LevelFilter;
+use tracing::warn;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use crate::{old_config::ConfigError, server::Server};
+use crate::{
+    crash::ExitCode, old_config::ConfigError, server::Server, syslog::Syslog, utils::LogOutput,
+};
 
+mod abuse;
+mod acl;
+mod alerts;
+mod auth;
+mod blocklist;
+mod cert;
+mod clock;
+mod cluster;
 mod config;
 mod connection;
+mod crash;
+mod destinations;
+mod dial;
+mod dns;
 mod error;
+mod events;
+mod fail2ban;
+mod fallback;
+mod geoip;
+mod handshake;
+mod hooks;
+mod http_client;
+mod latency;
+mod memory;
 mod old_config;
+mod peaks;
+mod privacy;
+mod rejections;
+mod reload;
 mod restful;
+mod scan;
+mod script;
+mod security_log;
 mod server;
+mod share;
+mod sniff;
+mod state;
+mod syslog;
+mod systemd;
+mod update;
+mod upstream;
+mod users;
+mod users_db;
 mod utils;
+mod validate;
+mod webhook;
+
+#[cfg(feature = "jemallocator")]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 
 struct AppContext {
     pub cfg: Config,
@@ -25,59 +70,127 @@ struct AppContext {
 #[tokio::main]
 async fn main() -> eyre::Result<()> {
     std::env::set_var("RUST_BACKTRACE", "1");
-    let cfg = match parse_config(env::args_os()).await {
-        Ok(cfg) => cfg,
+    let (cfg, warnings) = match parse_config(env::args_os()).await {
+        Ok(res) => res,
         Err(ConfigError::Version(msg) | ConfigError::Help(msg)) => {
             println!("{msg}");
             process::exit(0);
         }
-        Err(err) => {
+        Err(ConfigError::Export(out) | ConfigError::Fixed(out)) => {
+            print!("{out}");
+            process::exit(0);
+        }
+        Err(ConfigError::Updated(msg)) => {
+            println!("{msg}");
+            process::exit(0);
+        }
+        Err(err @ ConfigError::Update(_)) => {
             eprintln!("{err}");
             process::exit(1);
         }
+        Err(err) => crash::exit(ExitCode::Config, &Config::default().crash_report, err),
     };
+    crash::set_panic_hook(cfg.crash_report.clone());
+    let mut table = cfg.users.clone();
+    match users_db::init(cfg.users_db.as_deref()) {
+        Ok(stored) => table.extend(stored),
+        Err(err) => crash::exit(ExitCode::Config, &cfg.crash_report, format!("{err:#}")),
+    }
+    users::init(table);
+    users::set_priority(&cfg.priority_users);
+    users::set_overrides(cfg.user_overrides.clone());
     let ctx = Arc::new(AppContext { cfg });
 
-    let filter = tracing_subscriber::filter::Targets::new()
-        .with_targets(vec![
-            ("tuic", ctx.cfg.log_level),
-            ("tuic_quinn", ctx.cfg.log_level),
-            ("tuic_server", ctx.cfg.log_level),
-        ])
-        .with_default(LevelFilter::INFO);
+    let (filter, filter_handle) =
+        tracing_subscriber::reload::Layer::new(reload::log_filter(ctx.cfg.log_level));
+    reload::init(filter_handle, ctx.cfg.log_level);
+    let syslog = match ctx.cfg.log_output {
+        LogOutput::Stdout => None,
+        LogOutput::Syslog => match Syslog::new(&ctx.cfg.syslog) {
+            Ok(syslog) => Some(syslog),
+            Err(err) => crash::exit(ExitCode::Config, &ctx.cfg.crash_report, err),
+        },
+    };
+    let stdout = syslog.is_none().then(|| {
+        tracing_subscriber::fmt::layer()
+            .with_target(true)
+            .with_timer(tracing_subscriber::fmt::time::OffsetTime::new(
+                time::UtcOffset::from_whole_seconds(
+                    Local
+                        .timestamp_opt(0, 0)
+                        .unwrap()
+                        .offset()
+                        .fix()
+                        .local_minus_utc(),
+                )
+                .unwrap_or(time::UtcOffset::UTC),
+                time::macros::format_description!(
+                    "[year repr:last_two]-[month]-[day] [hour]:[minute]:[second]"
+                ),
+            ))
+    });
+    // syslog timestamps and prioritizes the messages itself
+    let syslog = syslog.map(|syslog| {
+        tracing_subscriber::fmt::layer()
+            .with_target(true)
+            .with_level(false)
+            .without_time()
+            .with_writer(syslog)
+    });
     let registry = tracing_subscriber::registry();
-    registry
-        .with(filter)
-        .with(
-            tracing_subscriber::fmt::layer()
-                .with_target(true)
-                .with_timer(tracing_subscriber::fmt::time::OffsetTime::new(
-                    time::UtcOffset::from_whole_seconds(
-                        Local
-                            .timestamp_opt(0, 0)
-                            .unwrap()
-                            .offset()
-                            .fix()
-                            .local_minus_utc(),
-                    )
-                    .unwrap_or(time::UtcOffset::UTC),
-                    time::macros::format_description!(
-                        "[year repr:last_two]-[month]-[day] [hour]:[minute]:[second]"
-                    ),
-                )),
-        )
-        .try_init()?;
-    tokio::spawn(async move {
-        match Server::init(ctx.clone()) {
-            Ok(server) => server.start().await,
+    registry.with(filter).with(stdout).with(syslog).try_init()?;
+    for warning in warnings {
+        warn!("{warning}");
+    }
+    if let Err(err) = state::load(&ctx.cfg).await {
+        crash::exit(
+            ExitCode::Config,
+            &ctx.cfg.crash_report,
+            format!(
+                "failed to load the runtime state from {path}: {err}",
+                path = ctx.cfg.persistent_data.display()
+            ),
+        );
+    }
+    let mut server = match Server::init(ctx.clone()) {
+        Ok(server) => server,
+        Err(err) => crash::exit(ExitCode::from(&err), &ctx.cfg.crash_report, err),
+    };
+    let server = tokio::spawn(async move { server.start().await });
+    tokio::spawn(reload::start(ctx.clone()));
+    tokio::spawn(clock::start());
+    tokio::spawn(users_db::start());
+    tokio::select! {
+        _ = server => {}
+        () = shutdown_signal() => {
+            systemd::notify("STOPPING=1");
+            state::save(&ctx).await;
+        }
+    }
+    Ok(())
+}
+
+/// Ctrl-C, or `SIGTERM` as sent by service managers when stopping the server
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                res = tokio::signal::ctrl_c() => res.expect("failed to listen for event"),
+                _ = terminate.recv() => {}
+            },
             Err(err) => {
-                eprintln!("{err}");
-                process::exit(1);
+                warn!("failed to listen for SIGTERM: {err}");
+                tokio::signal::ctrl_c()
+                    .await
+                    .expect("failed to listen for event");
             }
         }
-    });
+    }
+    #[cfg(not(unix))]
     tokio::signal::ctrl_c()
         .await
         .expect("failed to listen for event");
-    Ok(())
 }
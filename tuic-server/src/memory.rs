@@ -0,0 +1,79 @@
+use serde::Serialize;
+
+/// Allocator statistics, in bytes
+#[derive(Serialize)]
+pub struct MemoryStats {
+    /// Bytes allocated by the application
+    pub allocated: usize,
+    /// Bytes in pages holding allocations
+    pub active: usize,
+    /// Bytes in physically resident pages mapped by the allocator
+    pub resident: usize,
+    /// Bytes in chunks mapped by the allocator
+    pub mapped: usize,
+    /// Bytes retained by the allocator rather than returned to the OS
+    pub retained: usize,
+    /// Share of the resident memory not backing any allocation
+    pub fragmentation: f64,
+}
+
+#[cfg(feature = "jemallocator")]
+pub fn stats() -> eyre::Result<MemoryStats> {
+    use tikv_jemalloc_ctl::{epoch, stats};
+
+    // `tikv_jemalloc_ctl::Error` doesn't implement `std::error::Error`
+    let ctl = |err: tikv_jemalloc_ctl::Error| eyre::eyre!("mallctl failed: {err}");
+
+    // statistics are cached until the epoch is advanced
+    epoch::advance().map_err(ctl)?;
+    let allocated = stats::allocated::read().map_err(ctl)?;
+    let resident = stats::resident::read().map_err(ctl)?;
+    Ok(MemoryStats {
+        allocated,
+        active: stats::active::read().map_err(ctl)?,
+        resident,
+        mapped: stats::mapped::read().map_err(ctl)?,
+        retained: stats::retained::read().map_err(ctl)?,
+        fragmentation: if resident == 0 {
+            0.0
+        } else {
+            resident.saturating_sub(allocated) as f64 / resident as f64
+        },
+    })
+}
+
+#[cfg(not(feature = "jemallocator"))]
+pub fn stats() -> eyre::Result<MemoryStats> {
+    Err(eyre::eyre!("built without the jemallocator feature"))
+}
+
+/// Return the dirty pages of every arena to the OS
+#[cfg(feature = "jemallocator")]
+pub fn purge() -> eyre::Result<()> {
+    // `MALLCTL_ARENAS_ALL`
+    const NAME: &[u8] = b"arena.4096.purge\0";
+
+    // `tikv_jemalloc_ctl::raw` always passes a value, which write-only
+    // controls taking none reject
+    let ret = unsafe {
+        tikv_jemalloc_sys::mallctl(
+            NAME.as_ptr().cast(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            0,
+        )
+    };
+    if ret != 0 {
+        return Err(eyre::eyre!(
+            "mallctl arena purge failed: {}",
+            std::io::Error::from_raw_os_error(ret)
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "jemallocator"))]
+pub fn purge() -> eyre::Result<()> {
+    Err(eyre::eyre!("built without the jemallocator feature"))
+}
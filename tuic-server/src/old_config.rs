@@ -20,6 +20,21 @@ Arguments:
     -v, --version           Print the version
     -h, --help              Print this help message
     -i, --init              Generate a example configuration (config.toml)
+    --lenient-config        Warn about unknown config keys instead of refusing to start
+    --fix                   Replace weak or reused passwords and the placeholder RESTful
+                            secret in the config file with generated ones, then exit
+
+Commands:
+    export-client --uuid <uuid> --format <v2rayn|clash-meta|sing-box> [--address <host:port>]
+                  [--label <label>]
+                            Print the client configuration of a user, the address
+                            defaults to `subscription.address`, the password to the
+                            first label of a user with several
+    self-update [--check-only] [--public-key <base64>] [--feed <url>]
+                            Replace this binary with the latest release, after
+                            verifying its Ed25519 signature against the public key
+                            built in or given. `--check-only` only reports whether
+                            a newer version is out
 "#;
 
 #[derive(Deserialize)]
@@ -236,8 +251,29 @@ pub enum ConfigError {
     Version(&'static str),
     #[error("{0}")]
     Help(&'static str),
+    #[error("{0}")]
+    Export(String),
+    #[error("{0}")]
+    Fixed(String),
+    #[error("{0}")]
+    Updated(String),
+    #[error("self-update failed: {0:#}")]
+    Update(eyre::Report),
+    #[error(
+        "no users configured: add some under `[users]`, or set up `[auth.http]`, optionally with \
+         `registration_mode = true` to authenticate every user against it"
+    )]
+    NoUsers,
+    #[error("registration_mode needs `[auth.http]`, the only place users are looked up from")]
+    RegistrationWithoutBackend,
     #[error(transparent)]
     Io(#[from] IoError),
     #[error(transparent)]
     Serde(#[from] SerdeError),
+    #[error(transparent)]
+    Toml(#[from] toml::de::Error),
+    #[error(transparent)]
+    TomlEdit(#[from] toml_edit::TomlError),
+    #[error(transparent)]
+    Figment(#[from] figment::Error),
 }
@@ -0,0 +1,139 @@
+//! Per-day high-water marks of concurrent connections and aggregate
+//! throughput, for capacity planning without an external time series database
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        LazyLock, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use chrono::{Local, SecondsFormat};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tokio::time::{self, Instant, MissedTickBehavior};
+
+use crate::{connection::registry, state};
+
+/// How often the throughput is sampled, it is averaged over this long
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+/// How many days are kept, today included
+const HISTORY_DAYS: usize = 30;
+
+static OPEN: AtomicU64 = AtomicU64::new(0);
+/// Bytes per second over the last sample
+static THROUGHPUT: AtomicU64 = AtomicU64::new(0);
+/// By local date, `YYYY-MM-DD`
+static DAYS: LazyLock<Mutex<BTreeMap<String, DayPeaks>>> = LazyLock::new(Mutex::default);
+
+/// The peaks of a day and when they were reached, in RFC 3339
+#[derive(Deserialize, Serialize, Default, Clone)]
+#[serde(default)]
+pub struct DayPeaks {
+    pub date: String,
+    pub max_connections: u64,
+    pub max_connections_at: String,
+    pub max_throughput: u64,
+    pub max_throughput_at: String,
+}
+
+pub fn connection_opened() {
+    let open = OPEN.fetch_add(1, Ordering::Relaxed) + 1;
+    record(|day, at| {
+        if open > day.max_connections {
+            day.max_connections = open;
+            day.max_connections_at = at.to_owned();
+        }
+    });
+}
+
+pub fn connection_closed() {
+    OPEN.fetch_sub(1, Ordering::Relaxed);
+}
+
+pub fn open() -> u64 {
+    OPEN.load(Ordering::Relaxed)
+}
+
+/// Bytes per second over the last sample
+pub fn throughput() -> u64 {
+    THROUGHPUT.load(Ordering::Relaxed)
+}
+
+/// Update the peaks of today, forgetting the days that fell out of the history
+fn record(update: impl FnOnce(&mut DayPeaks, &str)) {
+    let now = Local::now();
+    let date = now.format("%Y-%m-%d").to_string();
+    let mut days = DAYS.lock().unwrap();
+    let day = days.entry(date.clone()).or_insert_with(|| DayPeaks {
+        date,
+        ..Default::default()
+    });
+    update(day, &now.to_rfc3339_opts(SecondsFormat::Secs, false));
+    while days.len() > HISTORY_DAYS {
+        days.pop_first();
+    }
+}
+
+/// Put back the peaks saved by the previous run, then sample the throughput
+/// of every open connection every 5 seconds
+pub async fn start() {
+    {
+        let mut days = DAYS.lock().unwrap();
+        for day in state::peaks() {
+            days.entry(day.date.clone()).or_insert(day);
+        }
+    }
+
+    let mut interval = time::interval(SAMPLE_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last = HashMap::new();
+    let mut last_at = Instant::now();
+    loop {
+        interval.tick().await;
+        let bytes: HashMap<u32, u64> = registry::wire_bytes().await.into_iter().collect();
+        let elapsed = last_at.elapsed();
+        last_at = Instant::now();
+
+        // connections opened since the last sample count from zero, the last
+        // bytes of the ones closed meanwhile are missed
+        let delta = bytes
+            .iter()
+            .map(|(id, bytes)| bytes.saturating_sub(last.get(id).copied().unwrap_or(0)))
+            .sum::<u64>();
+        last = bytes;
+        let throughput = (delta as f64 / elapsed.as_secs_f64()) as u64;
+        THROUGHPUT.store(throughput, Ordering::Relaxed);
+
+        let open = OPEN.load(Ordering::Relaxed);
+        record(|day, at| {
+            // connections still open at midnight count towards the new day
+            if open > day.max_connections {
+                day.max_connections = open;
+                day.max_connections_at = at.to_owned();
+            }
+            if throughput > day.max_throughput {
+                day.max_throughput = throughput;
+                day.max_throughput_at = at.to_owned();
+            }
+        });
+    }
+}
+
+/// The peaks of the days kept, to be saved
+pub fn state() -> Vec<DayPeaks> {
+    DAYS.lock().unwrap().values().cloned().collect()
+}
+
+/// The current connections and throughput, and the peaks of each day, newest
+/// first
+pub fn snapshot() -> Value {
+    let days = DAYS.lock().unwrap();
+    json!({
+        "connections": OPEN.load(Ordering::Relaxed),
+        "throughput": THROUGHPUT.load(Ordering::Relaxed),
+        "peaks": days.values().rev().collect::<Vec<_>>(),
+    })
+}
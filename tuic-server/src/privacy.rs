@@ -0,0 +1,89 @@
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    net::{IpAddr, SocketAddr},
+    sync::OnceLock,
+};
+
+use tuic::Address;
+
+use crate::utils::LogDestinations;
+
+static LOG_DESTINATIONS: OnceLock<LogDestinations> = OnceLock::new();
+
+const REDACTED: &str = "[redacted]";
+
+pub fn init(mode: LogDestinations) {
+    _ = LOG_DESTINATIONS.set(mode);
+}
+
+/// A relay destination, displayed as much as `log_destinations` allows
+pub struct Dest<'a> {
+    full: Full<'a>,
+    domain: Option<&'a str>,
+}
+
+enum Full<'a> {
+    Addr(&'a Address),
+    Socket(&'a SocketAddr),
+    Ip(&'a IpAddr),
+    Text(&'a str),
+}
+
+impl Display for Dest<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match LOG_DESTINATIONS.get().copied().unwrap_or_default() {
+            LogDestinations::Full => match self.full {
+                Full::Addr(addr) => addr.fmt(f),
+                Full::Socket(addr) => addr.fmt(f),
+                Full::Ip(ip) => ip.fmt(f),
+                Full::Text(addr) => f.write_str(addr),
+            },
+            LogDestinations::DomainOnly => f.write_str(self.domain.unwrap_or(REDACTED)),
+            LogDestinations::None => f.write_str(REDACTED),
+        }
+    }
+}
+
+pub fn addr(addr: &Address) -> Dest<'_> {
+    let domain = match addr {
+        Address::DomainAddress(domain, _) => Some(domain.as_str()),
+        _ => None,
+    };
+    Dest {
+        full: Full::Addr(addr),
+        domain,
+    }
+}
+
+pub fn socket(addr: &SocketAddr) -> Dest<'_> {
+    Dest {
+        full: Full::Socket(addr),
+        domain: None,
+    }
+}
+
+/// An IP address without port
+pub fn ip(ip: &IpAddr) -> Dest<'_> {
+    Dest {
+        full: Full::Ip(ip),
+        domain: None,
+    }
+}
+
+/// A destination already formatted as `host:port`
+pub fn text(addr: &str) -> Dest<'_> {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    Dest {
+        full: Full::Text(addr),
+        domain: host.parse::<IpAddr>().is_err().then_some(host),
+    }
+}
+
+/// A domain without port, e.g. a sniffed one
+pub fn domain(domain: &str) -> Dest<'_> {
+    Dest {
+        full: Full::Text(domain),
+        domain: Some(domain),
+    }
+}
@@ -0,0 +1,117 @@
+//! Counting refused attempts per user and per source IP, for the RESTful
+//! `/rejections` endpoint, so that attack pressure and misconfigured clients
+//! show up somewhere other than the logs
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{LazyLock, Mutex},
+};
+
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::error::Error;
+
+/// Source IPs tracked at most, later ones are only counted in the totals
+const MAX_IPS: usize = 65536;
+
+static REJECTIONS: LazyLock<Mutex<Rejections>> = LazyLock::new(Mutex::default);
+
+#[derive(Clone, Copy)]
+pub enum Reason {
+    /// Destinations refused by the ACL, the blocklist, the routing script
+    /// or `user_overrides`, and clients refused by `acl.geoip`
+    Acl,
+    Auth,
+    /// `per_connection_traffic_quota` reached
+    Quota,
+    /// Streams, UDP sessions, pre-authentication tasks or connections from an
+    /// IP over their limits
+    RateLimit,
+}
+
+impl Reason {
+    fn of(err: &Error) -> Option<Self> {
+        match err {
+            Error::AclDenied(_)
+            | Error::Blocklisted(_)
+            | Error::Denied(_)
+            | Error::UdpRelayDisabled(_) => Some(Self::Acl),
+            Error::AuthFailed(_)
+            | Error::NoUsers(_)
+            | Error::UserDisabled(_)
+            | Error::ProxyAuthRequired => Some(Self::Auth),
+            Error::TooManyStreams(_)
+            | Error::TooManyUdpSessions(_)
+            | Error::TooManyPreAuthTasks(_)
+            | Error::TooManyConnections(_) => Some(Self::RateLimit),
+            Error::QuotaExceeded(_) => Some(Self::Quota),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct Counts {
+    acl: u64,
+    auth: u64,
+    quota: u64,
+    rate_limit: u64,
+}
+
+impl Counts {
+    fn add(&mut self, reason: Reason) {
+        match reason {
+            Reason::Acl => self.acl += 1,
+            Reason::Auth => self.auth += 1,
+            Reason::Quota => self.quota += 1,
+            Reason::RateLimit => self.rate_limit += 1,
+        }
+    }
+}
+
+#[derive(Clone, Default, Serialize)]
+pub struct Rejections {
+    total: Counts,
+    users: HashMap<Uuid, Counts>,
+    ips: HashMap<IpAddr, Counts>,
+}
+
+/// Count `err` if it refused an attempt. `user` is the authenticated user of
+/// the connection, authentication failures count towards the user they
+/// claimed
+pub fn observe(err: &Error, user: Option<Uuid>, ip: IpAddr) {
+    let Some(reason) = Reason::of(err) else {
+        return;
+    };
+    let user = match err {
+        // unparsable MASQUE credentials
+        Error::AuthFailed(uuid) if uuid.is_nil() => None,
+        Error::AuthFailed(uuid)
+        | Error::NoUsers(uuid)
+        | Error::UserDisabled(uuid)
+        | Error::QuotaExceeded(uuid) => Some(*uuid),
+        _ => user,
+    };
+    record(reason, user, ip);
+}
+
+pub fn record(reason: Reason, user: Option<Uuid>, ip: IpAddr) {
+    let ip = ip.to_canonical();
+    let mut rejections = REJECTIONS.lock().unwrap();
+    rejections.total.add(reason);
+    if let Some(uuid) = user {
+        rejections.users.entry(uuid).or_default().add(reason);
+    }
+    let tracked = rejections.ips.len();
+    if let Some(counts) = rejections.ips.get_mut(&ip) {
+        counts.add(reason);
+    } else if tracked < MAX_IPS {
+        rejections.ips.entry(ip).or_default().add(reason);
+    }
+}
+
+pub fn snapshot() -> Rejections {
+    REJECTIONS.lock().unwrap().clone()
+}
@@ -0,0 +1,215 @@
+use std::{
+    env,
+    sync::{Arc, Mutex, OnceLock},
+    time::SystemTime,
+};
+
+use serde_json::Value;
+use tokio::time::{self, MissedTickBehavior};
+use tracing::{info, level_filters::LevelFilter, warn};
+use tracing_subscriber::{Registry, filter::Targets, reload::Handle};
+
+use crate::{
+    AppContext, acl,
+    config::{Config, LogLevel, parse_config},
+    restful, users, users_db,
+};
+
+static LOG_FILTER: OnceLock<Handle<Targets, Registry>> = OnceLock::new();
+/// The level currently applied to `LOG_FILTER`
+static LOG_LEVEL: Mutex<LogLevel> = Mutex::new(LogLevel::Info);
+/// The config last applied by reloading, serialized
+static RELOADED: Mutex<Option<Value>> = Mutex::new(None);
+
+/// Top-level sections of the config applied by `apply`, with
+/// `outbound.proxies` and `restful.rate_limit`
+const RELOADABLE: &[&str] = &[
+    "users",
+    "priority_users",
+    "user_overrides",
+    "acl",
+    "egress_mode",
+    "egress_allowlist",
+];
+
+/// The log filter of the TUIC crates at `level`
+pub fn log_filter(level: LogLevel) -> Targets {
+    Targets::new()
+        .with_targets(vec![
+            ("tuic", level),
+            ("tuic_quinn", level),
+            ("tuic_server", level),
+        ])
+        .with_default(LevelFilter::INFO)
+}
+
+/// Keep the handle to swap the log filter when reloading
+pub fn init(handle: Handle<Targets, Registry>, level: LogLevel) {
+    _ = LOG_FILTER.set(handle);
+    *LOG_LEVEL.lock().unwrap() = level;
+}
+
+pub fn log_level() -> LogLevel {
+    *LOG_LEVEL.lock().unwrap()
+}
+
+/// Change the log level of the TUIC crates until the next reload or restart
+pub fn set_log_level(level: LogLevel) -> eyre::Result<()> {
+    let handle = LOG_FILTER
+        .get()
+        .ok_or_else(|| eyre::eyre!("logging isn't initialized"))?;
+    let mut current = LOG_LEVEL.lock().unwrap();
+    handle.reload(log_filter(level))?;
+    *current = level;
+    Ok(())
+}
+
+/// The config in effect: `cfg` as started, with the settings of the last
+/// reload and the current log level
+pub fn effective(cfg: &Config) -> eyre::Result<Value> {
+    let mut value = serde_json::to_value(cfg)?;
+    if let Some(reloaded) = &*RELOADED.lock().unwrap() {
+        for key in RELOADABLE {
+            value[key] = reloaded[key].clone();
+        }
+        value["outbound"]["proxies"] = reloaded["outbound"]["proxies"].clone();
+        if let Some(restful) = value
+            .get_mut("restful")
+            .filter(|restful| restful.is_object())
+        {
+            // back to no limit when reloaded without `[restful]`
+            restful["rate_limit"] = reloaded
+                .pointer("/restful/rate_limit")
+                .cloned()
+                .unwrap_or(0.into());
+        }
+    }
+    value["log_level"] = serde_json::to_value(log_level())?;
+    Ok(value)
+}
+
+/// Reload the config file on `SIGHUP`, and whenever it changes if
+/// `config_watch_interval` is set. `SIGUSR1` cycles the log level
+pub async fn start(ctx: Arc<AppContext>) {
+    #[cfg(unix)]
+    tokio::spawn(on_hangup(ctx.clone()));
+    #[cfg(unix)]
+    tokio::spawn(on_user1());
+    if !ctx.cfg.config_watch_interval.is_zero() {
+        tokio::spawn(watch(ctx));
+    }
+}
+
+#[cfg(unix)]
+async fn on_hangup(ctx: Arc<AppContext>) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(err) => {
+            warn!("failed to listen for SIGHUP: {err}");
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        reload(&ctx).await;
+    }
+}
+
+/// Cycle the log level through info, debug and trace, so that an incident can
+/// be debugged without the RESTful API
+#[cfg(unix)]
+async fn on_user1() {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut user1 = match signal(SignalKind::user_defined1()) {
+        Ok(user1) => user1,
+        Err(err) => {
+            warn!("failed to listen for SIGUSR1: {err}");
+            return;
+        }
+    };
+    while user1.recv().await.is_some() {
+        let level = match log_level() {
+            LogLevel::Info => LogLevel::Debug,
+            LogLevel::Debug => LogLevel::Trace,
+            _ => LogLevel::Info,
+        };
+        match set_log_level(level) {
+            Ok(()) => info!("[reload] log level changed to {level}"),
+            Err(err) => warn!("[reload] failed to change the log level: {err}"),
+        }
+    }
+}
+
+/// Poll the modification time of the config file
+async fn watch(ctx: Arc<AppContext>) {
+    let path = &ctx.cfg.config_path;
+    let modified = || async {
+        tokio::fs::metadata(path)
+            .await
+            .and_then(|meta| meta.modified())
+            .ok()
+    };
+    let mut last: Option<SystemTime> = modified().await;
+    let mut interval = time::interval(ctx.cfg.config_watch_interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let current = modified().await;
+        if current.is_some() && current != last {
+            last = current;
+            reload(&ctx).await;
+        }
+    }
+}
+
+/// Apply the settings that can change without restarting: users, priority
+/// users, user overrides, log level, ACL and RESTful rate limit. Connections
+/// are kept, except the ones of users removed or whose password changed with
+/// `disconnect_on_password_change`
+async fn reload(ctx: &AppContext) {
+    let (cfg, warnings) = match parse_config(env::args_os().collect::<Vec<_>>()).await {
+        Ok(res) => res,
+        Err(err) => {
+            warn!("[reload] failed to reload the config, keeping the current one: {err}");
+            return;
+        }
+    };
+    for warning in warnings {
+        warn!("{warning}");
+    }
+    apply(ctx, cfg).await;
+}
+
+async fn apply(ctx: &AppContext, cfg: Config) {
+    // the only setting that can still be invalid, check it before applying
+    // anything
+    if let Err(err) = acl::init(&cfg) {
+        warn!("[reload] failed to reload the config, keeping the current one: {err}");
+        return;
+    }
+    let reloaded = serde_json::to_value(&cfg);
+    let mut table = cfg.users;
+    match users_db::load().await {
+        Ok(stored) => table.extend(stored),
+        Err(err) => {
+            warn!("[reload] failed to read users_db, keeping the current users: {err:#}");
+            return;
+        }
+    }
+    users::replace(table);
+    users::set_priority(&cfg.priority_users);
+    users::set_overrides(cfg.user_overrides);
+    if let Err(err) = set_log_level(cfg.log_level) {
+        warn!("[reload] failed to change the log level: {err}");
+    }
+    if ctx.cfg.restful.is_some() {
+        restful::set_rate_limit(cfg.restful.map_or(0, |v| v.rate_limit));
+    }
+    match reloaded {
+        Ok(reloaded) => *RELOADED.lock().unwrap() = Some(reloaded),
+        Err(err) => warn!("[reload] failed to record the reloaded config: {err}"),
+    }
+    info!("[reload] config reloaded, other settings take effect after restart");
+}
@@ -1,38 +1,114 @@
 use std::{
     collections::{HashMap, HashSet},
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     ops::Deref,
+    path::Path,
     sync::{
-        Arc, LazyLock,
-        atomic::{AtomicU64, Ordering},
+        Arc, LazyLock, OnceLock, RwLock,
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
     },
+    time::{Duration, Instant},
 };
 
 use axum::{
-    Json, Router,
-    extract::State,
-    http::StatusCode,
-    routing::{get, post},
+    Extension, Json, Router,
+    extract::{ConnectInfo, Path as UrlPath, Query, Request, State},
+    http::{HeaderMap, StatusCode, header::CONTENT_TYPE},
+    middleware::{self, Next},
+    response::{
+        IntoResponse, Response,
+        sse::{Event as SseEvent, KeepAlive, Sse},
+    },
+    routing::{delete, get, patch, post},
 };
 use axum_extra::{
     TypedHeader,
     headers::{Authorization, authorization::Bearer},
 };
 use chashmap::CHashMap;
-use lateinit::LateInit;
+use chrono::{DateTime, Local};
+use futures_util::stream;
 use quinn::{Connection as QuinnConnection, VarInt};
+use rustls::ServerConfig as RustlsServerConfig;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tracing::warn;
+use subtle::ConstantTimeEq;
+use tokio::{
+    fs::OpenOptions, io::AsyncWriteExt, sync::broadcast::error::RecvError, time::MissedTickBehavior,
+};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info, warn};
 use uuid::Uuid;
 
-use crate::AppContext;
+use crate::{
+    AppContext,
+    abuse::{self, Offender},
+    alerts,
+    blocklist::{self, IpRange},
+    cluster::{self, Node, NodeStatus},
+    config::{self, LogLevel, RestfulAddr},
+    connection::{CorrelationId, USER_QUOTA_EXCEEDED, flow_control as flow, registry, streams},
+    crash::{self, ExitCode},
+    destinations::{self, Report, SortBy, TrafficReport},
+    dial,
+    events::{self, Delta, Event},
+    latency, memory, peaks,
+    rejections::{self, Reason, Rejections},
+    reload,
+    share::{Format, Share},
+    state::{self, PersistedBan, PersistedTraffic},
+    users,
+    users_db::{self, StoredUser},
+    utils::{self, ByteSize, UserPasswords},
+    webhook::{self, WebhookEvent},
+};
 
-static ONLINE_COUNTER: LateInit<HashMap<Uuid, AtomicU64>> = LateInit::new();
+// an entry per user, added when the user is added or first seen
+static ONLINE_COUNTER: LazyLock<RwLock<HashMap<Uuid, AtomicU64>>> = LazyLock::new(RwLock::default);
 static ONLINE_CLIENTS: LazyLock<CHashMap<Uuid, HashSet<QuicClient>>> = LazyLock::new(CHashMap::new);
-static TRAFFIC_STATS: LateInit<HashMap<Uuid, (AtomicU64, AtomicU64)>> = LateInit::new(); // (tx, rx)
+static TRAFFIC_STATS: LazyLock<RwLock<HashMap<Uuid, (AtomicU64, AtomicU64)>>> =
+    LazyLock::new(RwLock::default); // (tx, rx)
+static TRAFFIC_TOTALS: LazyLock<RwLock<HashMap<Uuid, (AtomicU64, AtomicU64)>>> =
+    LazyLock::new(RwLock::default); // (tx, rx), never reset
+static TRAFFIC_SNAPSHOTS: LazyLock<CHashMap<String, TrafficSnapshot>> =
+    LazyLock::new(CHashMap::new);
+static DUPLICATE_AUTHS: LazyLock<CHashMap<Uuid, u64>> = LazyLock::new(CHashMap::new);
+static RATE_LIMITER: LazyLock<Arc<RateLimiter>> = LazyLock::new(Arc::default);
+
+static FRAGMENT_PACKETS: AtomicUsize = AtomicUsize::new(0);
+static FRAGMENT_BYTES: AtomicUsize = AtomicUsize::new(0);
+static GC_RUNS: AtomicU64 = AtomicU64::new(0);
+/// `restful.trusted_proxies`
+static TRUSTED_PROXIES: OnceLock<Vec<IpRange>> = OnceLock::new();
 
+/// Set once the traffic stats are loaded from the persistent data file, so
+/// that it's never overwritten with empty stats
+static TRAFFIC_LOADED: AtomicBool = AtomicBool::new(false);
+/// Persisted stats of users no longer in the config, written back untouched
+static TRAFFIC_RETIRED: OnceLock<HashMap<Uuid, PersistedTraffic>> = OnceLock::new();
+
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+const MAX_TRAFFIC_SNAPSHOTS: usize = 64;
+/// Most destinations `/top_destinations` lists
+const MAX_TOP_DESTINATIONS: usize = 1000;
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Pause after failing to accept a connection, as `axum::serve` does, since
+/// running out of file descriptors passes once connections close
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// How often `/events` subscribers get the traffic of each user
+const TRAFFIC_EVENT_INTERVAL: Duration = Duration::from_secs(1);
+/// How often users' traffic is checked against their `quota`
+const QUOTA_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Lifetime traffic totals at the time a snapshot was taken
+struct TrafficSnapshot {
+    taken_at: DateTime<Local>,
+    totals: HashMap<Uuid, (u64, u64)>,
+}
+
+/// An online connection, with its correlation ID for webhook events
 #[derive(Clone)]
-struct QuicClient(QuinnConnection);
+struct QuicClient(QuinnConnection, CorrelationId);
 impl Deref for QuicClient {
     type Target = QuinnConnection;
 
@@ -40,11 +116,6 @@ impl Deref for QuicClient {
         &self.0
     }
 }
-impl From<QuinnConnection> for QuicClient {
-    fn from(value: QuinnConnection) -> Self {
-        Self(value)
-    }
-}
 impl std::hash::Hash for QuicClient {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.0.stable_id().hash(state);
@@ -57,71 +128,932 @@ impl PartialEq for QuicClient {
 }
 impl Eq for QuicClient {}
 
+/// Load the saved traffic stats and hold users to their `quota`, with or
+/// without `[restful]`. Called before accepting connections
+pub fn init(ctx: &Arc<AppContext>) {
+    // `users_db` included
+    let table = users::snapshot();
+
+    let persist = ctx
+        .cfg
+        .restful
+        .as_ref()
+        .is_some_and(|v| !v.persist_interval.is_zero());
+    let mut persisted = if persist {
+        state::traffic()
+    } else {
+        HashMap::new()
+    };
+    {
+        let mut traffic = TRAFFIC_STATS.write().unwrap();
+        let mut totals = TRAFFIC_TOTALS.write().unwrap();
+        for user in table.keys() {
+            let saved = persisted.remove(user).unwrap_or_default();
+            traffic.insert(*user, (saved.tx.into(), saved.rx.into()));
+            totals.insert(*user, (saved.total_tx.into(), saved.total_rx.into()));
+        }
+    }
+    _ = TRAFFIC_RETIRED.set(persisted);
+    TRAFFIC_LOADED.store(persist, Ordering::Release);
+    tokio::spawn(enforce_quotas(ctx.clone()));
+}
+
 pub async fn start(ctx: Arc<AppContext>) {
-    let mut online = HashMap::new();
-    for (user, _) in ctx.cfg.users.iter() {
-        online.insert(user.to_owned(), AtomicU64::new(0));
+    tokio::spawn(flush_traffic(ctx.clone()));
+    tokio::spawn(publish_traffic());
+    tokio::spawn(prune_rate_limits());
+
+    if ctx.cfg.cluster.is_some() {
+        tokio::spawn(cluster::start(ctx.clone()));
     }
 
-    let mut traffic = HashMap::new();
-    for (user, _) in ctx.cfg.users.iter() {
-        // TODO use persist
-        traffic.insert(user.to_owned(), (AtomicU64::new(0), AtomicU64::new(0)));
+    let restful = ctx.cfg.restful.as_ref().unwrap();
+    set_rate_limit(restful.rate_limit);
+    let mut proxies = Vec::new();
+    for proxy in &restful.trusted_proxies {
+        match blocklist::parse_cidr(proxy) {
+            Some(range) => proxies.push(range),
+            None => crash::exit(
+                ExitCode::Config,
+                &ctx.cfg.crash_report,
+                format!("restful.trusted_proxies: invalid IP or CIDR {proxy:?}"),
+            ),
+        }
     }
-    unsafe {
-        ONLINE_COUNTER.init(online);
-        TRAFFIC_STATS.init(traffic);
+    _ = TRUSTED_PROXIES.set(proxies);
+    let addr = restful.addr.clone();
+    let tls = match (&restful.tls_cert, &restful.tls_key) {
+        (Some(cert), Some(key)) => match tls_acceptor(cert, key) {
+            Ok(acceptor) => Some(acceptor),
+            Err(err) => crash::exit(
+                ExitCode::Tls,
+                &ctx.cfg.crash_report,
+                format!("restful: failed to load the TLS certificate: {err:#}"),
+            ),
+        },
+        _ => None,
+    };
+    if restful.secret.is_empty()
+        && matches!(&addr, RestfulAddr::Tcp(addr) if !addr.ip().is_loopback())
+    {
+        warn!(
+            "restful.secret is empty: anyone reaching {addr} can manage the users and the config",
+            addr = restful.addr
+        );
+    }
+    let unix_socket_mode = restful.unix_socket_mode;
+    let crash_report = ctx.cfg.crash_report.clone();
+    let app = router(ctx, RATE_LIMITER.clone());
+    match addr {
+        RestfulAddr::Tcp(addr) => {
+            let listener = match tokio::net::TcpListener::bind(addr).await {
+                Ok(listener) => listener,
+                Err(err) => crash::exit(
+                    ExitCode::Bind,
+                    &crash_report,
+                    format!("failed to bind RESTful server on {addr}: {err}"),
+                ),
+            };
+            if let Some(acceptor) = tls {
+                warn!("RESTful server started, listening on {addr} with TLS");
+                serve_tls(listener, acceptor, app).await;
+            } else {
+                warn!("RESTful server started, listening on {addr}");
+                // accept failures are logged and retried by axum itself
+                if let Err(err) = axum::serve(
+                    listener,
+                    app.into_make_service_with_connect_info::<SocketAddr>(),
+                )
+                .await
+                {
+                    warn!("RESTful server stopped: {err}");
+                }
+            }
+        }
+        RestfulAddr::Unix(path) => serve_unix(&path, unix_socket_mode, app, &crash_report).await,
     }
+}
 
-    let restful = ctx.cfg.restful.as_ref().unwrap();
-    let addr = restful.addr;
-    let app = Router::new()
+/// The RESTful API, every endpoint behind `restful.secret`
+fn router(ctx: Arc<AppContext>, limiter: Arc<RateLimiter>) -> Router {
+    Router::new()
         .route("/kick", post(kick))
+        .route("/kick_connection", post(kick_connection))
+        .route("/users", get(list_users).post(add_user))
+        .route("/users/:uuid", patch(update_user).delete(remove_user))
+        .route("/bans", get(list_bans).post(add_ban))
+        .route("/bans/:target", delete(remove_ban))
         .route("/online", get(list_online))
         .route("/detailed_online", get(list_detailed_online))
         .route("/traffic", get(list_traffic))
         .route("/reset_traffic", get(reset_traffic))
-        .with_state(ctx);
-    let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    warn!("RESTful server started, listening on {addr}");
-    axum::serve(listener, app).await.unwrap();
+        .route("/traffic/destinations", get(destination_traffic))
+        .route(
+            "/traffic/snapshot/:name",
+            get(diff_traffic_snapshot)
+                .post(take_traffic_snapshot)
+                .delete(delete_traffic_snapshot),
+        )
+        .route("/blocklist_hits", get(list_blocklist_hits))
+        .route("/duplicate_auths", get(list_duplicate_auths))
+        .route("/rejections", get(list_rejections))
+        .route("/events", get(stream_events))
+        .route("/memory", get(memory_stats))
+        .route("/memory/purge", post(memory_purge))
+        .route("/log_level", get(get_log_level).put(set_log_level))
+        .route("/config", get(get_config))
+        .route("/connections", get(list_connections))
+        .route("/connections/:id/streams", get(list_streams))
+        .route("/latency", get(list_latency))
+        .route("/dials", get(list_dials))
+        .route("/top_destinations", get(top_destinations))
+        .route("/status", get(status))
+        .route("/metrics", get(metrics))
+        .route("/alerts", get(list_alerts))
+        .route("/flow_control", get(flow_control))
+        .route("/fragment_cache", get(fragment_cache))
+        .route("/subscription/:uuid", get(subscription))
+        .route("/cluster/node", get(cluster_node))
+        .route("/cluster/nodes", get(cluster_nodes))
+        .route("/cluster/recommended", get(cluster_recommended))
+        .route_layer(middleware::from_fn_with_state(ctx.clone(), authorize))
+        .layer(middleware::from_fn_with_state(limiter, rate_limit))
+        .layer(middleware::from_fn(forwarded_source))
+        .with_state(ctx)
 }
 
-async fn kick(
+/// Save the runtime state periodically, so the traffic stats survive
+/// restarts
+async fn flush_traffic(ctx: Arc<AppContext>) {
+    let interval = ctx
+        .cfg
+        .restful
+        .as_ref()
+        .map_or(Duration::ZERO, |v| v.persist_interval);
+    if interval.is_zero() {
+        return;
+    }
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        state::save(&ctx).await;
+    }
+}
+
+/// Publish the traffic of each user every `TRAFFIC_EVENT_INTERVAL` while
+/// `/events` has subscribers
+async fn publish_traffic() {
+    let mut ticker = tokio::time::interval(TRAFFIC_EVENT_INTERVAL);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last: HashMap<Uuid, (u64, u64)> = HashMap::new();
+    loop {
+        ticker.tick().await;
+        if !events::enabled() {
+            last.clear();
+            continue;
+        }
+        let mut users = HashMap::new();
+        for (uuid, (tx, rx)) in TRAFFIC_TOTALS.read().unwrap().iter() {
+            let now = (tx.load(Ordering::Relaxed), rx.load(Ordering::Relaxed));
+            // the first tick after subscribing only records the baseline
+            if let Some(then) = last.insert(*uuid, now)
+                && now != then
+            {
+                users.insert(
+                    *uuid,
+                    Delta {
+                        tx: now.0 - then.0,
+                        rx: now.1 - then.1,
+                    },
+                );
+            }
+        }
+        if !users.is_empty() {
+            events::publish(Event::Traffic { users });
+        }
+    }
+}
+
+/// Whether `uuid` relayed its `quota` since the last `/reset_traffic`
+pub fn over_quota(ctx: &AppContext, uuid: &Uuid) -> bool {
+    let quota = users::policy(&ctx.cfg, Some(*uuid)).quota;
+    quota != 0
+        && TRAFFIC_STATS
+            .read()
+            .unwrap()
+            .get(uuid)
+            .is_some_and(|(tx, rx)| {
+                tx.load(Ordering::Relaxed) + rx.load(Ordering::Relaxed) >= quota
+            })
+}
+
+/// Close the connections of users reaching their `quota`, once: the ones
+/// already closed are left for `client_disconnect` to remove
+async fn enforce_quotas(ctx: Arc<AppContext>) {
+    let mut ticker = tokio::time::interval(QUOTA_CHECK_INTERVAL);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let users: Vec<_> = TRAFFIC_STATS.read().unwrap().keys().copied().collect();
+        for uuid in &users {
+            if !over_quota(&ctx, uuid) {
+                continue;
+            }
+            let Some(list) = ONLINE_CLIENTS.get(uuid).await else {
+                continue;
+            };
+            for client in list.iter().filter(|client| client.close_reason().is_none()) {
+                info!(
+                    "[{cid}] [{addr}] [{uuid}] user over its traffic quota, closing connection",
+                    cid = client.1,
+                    addr = client.remote_address(),
+                );
+                rejections::record(Reason::Quota, Some(*uuid), client.remote_address().ip());
+                client.close(USER_QUOTA_EXCEEDED, b"User traffic quota exceeded");
+                webhook::notify(
+                    &ctx,
+                    WebhookEvent::Limit,
+                    *uuid,
+                    client.remote_address(),
+                    client.1,
+                    Some("quota".into()),
+                );
+            }
+        }
+    }
+}
+
+/// The traffic stats to save, `None` unless they were loaded with
+/// `persist_interval`
+pub fn traffic_state() -> Option<HashMap<Uuid, PersistedTraffic>> {
+    if !TRAFFIC_LOADED.load(Ordering::Acquire) {
+        return None;
+    }
+    let mut traffic = TRAFFIC_RETIRED.get().cloned().unwrap_or_default();
+    let (stats, totals) = (
+        TRAFFIC_STATS.read().unwrap(),
+        TRAFFIC_TOTALS.read().unwrap(),
+    );
+    for (uuid, (tx, rx)) in stats.iter() {
+        let (total_tx, total_rx) = totals
+            .get(uuid)
+            .map(|(tx, rx)| (tx.load(Ordering::Relaxed), rx.load(Ordering::Relaxed)))
+            .unwrap_or_default();
+        traffic.insert(*uuid, PersistedTraffic {
+            tx: tx.load(Ordering::Relaxed),
+            rx: rx.load(Ordering::Relaxed),
+            total_tx,
+            total_rx,
+        });
+    }
+    Some(traffic)
+}
+
+fn tls_acceptor(cert: &Path, key: &Path) -> eyre::Result<TlsAcceptor> {
+    let certs = utils::load_cert_chain(cert)?;
+    let key = utils::load_priv_key(key)?;
+    let mut cfg = RustlsServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    cfg.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(TlsAcceptor::from(Arc::new(cfg)))
+}
+
+/// The accepted connection, or `None` after logging the failure and pausing
+async fn accepted<T>(res: std::io::Result<T>) -> Option<T> {
+    match res {
+        Ok(accepted) => Some(accepted),
+        Err(err) => {
+            warn!("[RESTful] failed to accept a connection: {err}");
+            tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+            None
+        }
+    }
+}
+
+async fn serve_tls(listener: tokio::net::TcpListener, acceptor: TlsAcceptor, app: Router) {
+    use hyper::server::conn::http1;
+    use hyper_util::{rt::TokioIo, service::TowerToHyperService};
+
+    loop {
+        let Some((stream, peer)) = accepted(listener.accept().await).await else {
+            continue;
+        };
+        let acceptor = acceptor.clone();
+        // what `into_make_service_with_connect_info` provides on plain TCP
+        let service = TowerToHyperService::new(app.clone().layer(Extension(ConnectInfo(peer))));
+        tokio::spawn(async move {
+            let stream =
+                match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(err)) => {
+                        debug!("[RESTful] [{peer}] TLS handshake failed: {err}");
+                        return;
+                    }
+                    Err(_) => {
+                        debug!("[RESTful] [{peer}] TLS handshake timed out");
+                        return;
+                    }
+                };
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                debug!("[RESTful] [{peer}] connection error: {err}");
+            }
+        });
+    }
+}
+
+#[cfg(unix)]
+async fn bind_unix(path: &Path, mode: u32) -> std::io::Result<tokio::net::UnixListener> {
+    use std::{
+        fs::Permissions,
+        os::unix::fs::{FileTypeExt, PermissionsExt},
+    };
+
+    // Remove the socket left behind by a previous run, but never anything else
+    if let Ok(meta) = tokio::fs::symlink_metadata(path).await
+        && meta.file_type().is_socket()
+    {
+        tokio::fs::remove_file(path).await?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    tokio::fs::set_permissions(path, Permissions::from_mode(mode)).await?;
+    Ok(listener)
+}
+
+#[cfg(unix)]
+async fn serve_unix(path: &Path, mode: u32, app: Router, crash_report: &Path) {
+    use hyper::server::conn::http1;
+    use hyper_util::{rt::TokioIo, service::TowerToHyperService};
+
+    let listener = match bind_unix(path, mode).await {
+        Ok(listener) => listener,
+        Err(err) => crash::exit(
+            ExitCode::Bind,
+            crash_report,
+            format!(
+                "failed to bind RESTful server on unix:{path}: {err}",
+                path = path.display()
+            ),
+        ),
+    };
+    warn!(
+        "RESTful server started, listening on unix:{path}",
+        path = path.display()
+    );
+
+    loop {
+        let Some((stream, _)) = accepted(listener.accept().await).await else {
+            continue;
+        };
+        let service = TowerToHyperService::new(app.clone());
+        tokio::spawn(async move {
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                debug!("[RESTful] [unix] connection error: {err}");
+            }
+        });
+    }
+}
+
+#[cfg(not(unix))]
+async fn serve_unix(path: &Path, _mode: u32, _app: Router, crash_report: &Path) {
+    crash::exit(
+        ExitCode::Bind,
+        crash_report,
+        format!(
+            "failed to bind RESTful server on unix:{path}: Unix domain sockets are not supported \
+             on this platform",
+            path = path.display()
+        ),
+    )
+}
+
+/// Refuse the requests without the bearer token `restful.secret`, unless it's
+/// empty
+async fn authorize(
     State(ctx): State<Arc<AppContext>>,
     token: Option<TypedHeader<Authorization<Bearer>>>,
-    Json(users): Json<Vec<Uuid>>,
-) -> StatusCode {
-    if let Some(restful) = &ctx.cfg.restful
-        && !restful.secret.is_empty()
-        && let Some(TypedHeader(token)) = token
-        && restful.secret != token.token()
+    req: Request,
+    next: Next,
+) -> Response {
+    let secret = ctx
+        .cfg
+        .restful
+        .as_ref()
+        .map_or("", |restful| restful.secret.as_str());
+    // constant time, so the secret can't be guessed byte by byte from the timing
+    if !secret.is_empty()
+        && token.is_none_or(|TypedHeader(token)| {
+            !bool::from(token.token().as_bytes().ct_eq(secret.as_bytes()))
+        })
     {
-        return StatusCode::UNAUTHORIZED;
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    next.run(req).await
+}
+
+/// Requests counted by source IP, in fixed windows of `RATE_LIMIT_WINDOW`
+#[derive(Default)]
+struct RateLimiter {
+    /// `restful.rate_limit`, changed by reloading the config
+    limit: AtomicU32,
+    sources: CHashMap<String, (Instant, u32)>, // (window start, requests)
+}
+
+/// Fixed-window rate limiting, keyed by the source IP, as the bearer token
+/// isn't checked yet
+async fn rate_limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    addr: Option<ConnectInfo<SocketAddr>>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let limit = limiter.limit.load(Ordering::Relaxed);
+    if limit == 0 {
+        return next.run(req).await;
+    }
+
+    let mut limited = false;
+    limiter
+        .sources
+        .upsert(
+            source(addr),
+            || (Instant::now(), 1),
+            |(start, count)| {
+                if start.elapsed() >= RATE_LIMIT_WINDOW {
+                    *start = Instant::now();
+                    *count = 1;
+                } else {
+                    *count = count.saturating_add(1);
+                    limited = *count > limit;
+                }
+            },
+        )
+        .await;
+
+    if limited {
+        warn!(
+            "[RESTful] [{source}] rate limited on {path}",
+            source = source(addr),
+            path = req.uri().path()
+        );
+        return StatusCode::TOO_MANY_REQUESTS.into_response();
+    }
+    next.run(req).await
+}
+
+/// Forget the sources whose window ended, which would count from zero again
+async fn prune_rate_limits() {
+    let mut ticker = tokio::time::interval(RATE_LIMIT_WINDOW);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        RATE_LIMITER
+            .sources
+            .retain(|_, (start, _)| start.elapsed() < RATE_LIMIT_WINDOW)
+            .await;
+    }
+}
+
+/// Take the client IP from the `Forwarded` or `X-Forwarded-For` headers of
+/// requests relayed by a trusted proxy, so the rate limit and the audit log
+/// see it rather than the proxy's. Unix domain socket peers are local
+/// processes, trusted as soon as any proxy is
+async fn forwarded_source(mut req: Request, next: Next) -> Response {
+    let proxies = TRUSTED_PROXIES.get().map_or(&[][..], Vec::as_slice);
+    if proxies.is_empty() {
+        return next.run(req).await;
     }
+    let trusted = |ip: IpAddr| proxies.iter().any(|range| range.contains(ip));
+    let peer_trusted = match req.extensions().get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(addr)) => trusted(addr.ip()),
+        None => true,
+    };
+    if peer_trusted && let Some(ip) = forwarded_for(req.headers(), trusted) {
+        req.extensions_mut()
+            .insert(ConnectInfo(SocketAddr::new(ip, 0)));
+    }
+    next.run(req).await
+}
+
+/// The client at the end of the forwarding chain: the last address that isn't
+/// a trusted proxy, as the ones before it could be forged by the client
+fn forwarded_for(headers: &HeaderMap, trusted: impl Fn(IpAddr) -> bool) -> Option<IpAddr> {
+    let values = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect::<Vec<_>>()
+    };
+    let forwarded = values("forwarded");
+    let chain = if forwarded.is_empty() {
+        values("x-forwarded-for")
+    } else {
+        forwarded
+            .into_iter()
+            .filter_map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.trim().split_once('=')?;
+                    key.eq_ignore_ascii_case("for").then_some(value)
+                })
+            })
+            .collect()
+    };
+
+    let mut client = None;
+    for node in chain.into_iter().rev() {
+        // `unknown` or an obfuscated identifier, nothing to trust beyond it
+        let ip = parse_node(node)?;
+        client = Some(ip);
+        if !trusted(ip) {
+            break;
+        }
+    }
+    client
+}
+
+/// An IP from a forwarding header, optionally quoted and with a port:
+/// `192.0.2.1`, `192.0.2.1:80`, `2001:db8::1` or `"[2001:db8::1]:80"`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    node.parse()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+/// The client IP of a RESTful request, or `unix` for Unix domain socket peers
+fn source(addr: Option<ConnectInfo<SocketAddr>>) -> String {
+    addr.map_or_else(
+        || "unix".to_owned(),
+        |ConnectInfo(addr)| addr.ip().to_string(),
+    )
+}
+
+/// Record an admin action, appending it to `restful.audit_log` when configured
+async fn audit(
+    ctx: &AppContext,
+    addr: Option<ConnectInfo<SocketAddr>>,
+    action: &str,
+    detail: serde_json::Value,
+) {
+    let source = source(addr);
+    info!("[RESTful] [{source}] [audit] {action} {detail}");
+
+    let Some(path) = ctx.cfg.restful.as_ref().and_then(|v| v.audit_log.as_ref()) else {
+        return;
+    };
+    let mut line = json!({
+        "time": Local::now().to_rfc3339(),
+        "source": source,
+        "action": action,
+        "detail": detail,
+    })
+    .to_string();
+    line.push('\n');
+
+    let res = async {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?
+            .write_all(line.as_bytes())
+            .await
+    };
+    if let Err(err) = res.await {
+        warn!(
+            "[RESTful] failed to write audit log {path}: {err}",
+            path = path.display()
+        );
+    }
+}
+
+async fn kick(
+    State(ctx): State<Arc<AppContext>>,
+    addr: Option<ConnectInfo<SocketAddr>>,
+    Json(users): Json<Vec<Uuid>>,
+) -> StatusCode {
+    audit(&ctx, addr, "kick", json!(users)).await;
     for user in users {
         if let Some(list) = ONLINE_CLIENTS.get(&user).await {
             for client in list.iter() {
                 client.close(VarInt::from_u32(6002), "Client got kicked".as_bytes());
+                webhook::notify(
+                    &ctx,
+                    WebhookEvent::Kick,
+                    user,
+                    client.remote_address(),
+                    client.1,
+                    None,
+                );
             }
         }
     }
     StatusCode::OK
 }
 
-async fn list_online(
+/// A connection ID as in the server logs and `/connections`, in hex
+/// (`"0x1a2b3c4d"`) or decimal, as a string or a number
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum ConnectionId {
+    Number(u32),
+    Text(String),
+}
+
+impl ConnectionId {
+    fn parse(&self) -> Option<u32> {
+        match self {
+            Self::Number(id) => Some(*id),
+            Self::Text(id) => parse_connection_id(id),
+        }
+    }
+}
+
+fn parse_connection_id(id: &str) -> Option<u32> {
+    match id.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => id.parse().ok(),
+    }
+}
+
+/// Close single connections, leaving the other ones of their users open
+async fn kick_connection(
     State(ctx): State<Arc<AppContext>>,
-    token: Option<TypedHeader<Authorization<Bearer>>>,
-) -> (StatusCode, Json<HashMap<Uuid, u64>>) {
-    if let Some(restful) = &ctx.cfg.restful
-        && !restful.secret.is_empty()
-        && let Some(TypedHeader(token)) = token
-        && restful.secret != token.token()
+    addr: Option<ConnectInfo<SocketAddr>>,
+    Json(ids): Json<Vec<ConnectionId>>,
+) -> StatusCode {
+    let Some(parsed) = ids
+        .iter()
+        .map(ConnectionId::parse)
+        .collect::<Option<Vec<_>>>()
+    else {
+        return StatusCode::BAD_REQUEST;
+    };
+    audit(&ctx, addr, "kick_connection", json!(ids)).await;
+    for id in parsed {
+        if let Some((Some(uuid), remote, cid)) =
+            registry::close(id, VarInt::from_u32(6002), b"Client got kicked").await
+        {
+            webhook::notify(&ctx, WebhookEvent::Kick, uuid, remote, cid, None);
+        }
+    }
+    StatusCode::OK
+}
+
+#[derive(Deserialize)]
+struct NewUser {
+    /// Generated if missing
+    uuid: Option<Uuid>,
+    password: UserPasswords,
+    /// Seconds since the Unix epoch, `users_db` only
+    expires_at: Option<u64>,
+    /// `users_db` only
+    quota: Option<ByteSize>,
+}
+
+#[derive(Deserialize)]
+struct UserUpdate {
+    password: Option<UserPasswords>,
+    /// Seconds since the Unix epoch, `0` to never expire, `users_db` only
+    expires_at: Option<u64>,
+    /// `0` for no quota, `users_db` only
+    quota: Option<ByteSize>,
+}
+
+/// The users authenticating with the quota they are held to, plus the expired
+/// ones kept in `users_db`
+async fn list_users(
+    State(ctx): State<Arc<AppContext>>,
+) -> Result<Json<Vec<StoredUser>>, StatusCode> {
+    let mut stored: HashMap<Uuid, StoredUser> = match users_db::list().await {
+        Ok(stored) => stored.into_iter().map(|user| (user.uuid, user)).collect(),
+        Err(err) => {
+            warn!("[restful] failed to read users_db: {err:#}");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    let mut list: Vec<_> = users::snapshot()
+        .iter()
+        .map(|(uuid, password)| StoredUser {
+            uuid: *uuid,
+            password: password.clone(),
+            expires_at: stored.remove(uuid).and_then(|user| user.expires_at),
+            quota: Some(users::policy(&ctx.cfg, Some(*uuid)).quota).filter(|quota| *quota != 0),
+        })
+        .collect();
+    list.extend(stored.into_values());
+    list.sort_unstable_by_key(|user| user.uuid);
+    Ok(Json(list))
+}
+
+/// Add a user at runtime, counted in `/online` and `/traffic` right away
+async fn add_user(
+    State(ctx): State<Arc<AppContext>>,
+    addr: Option<ConnectInfo<SocketAddr>>,
+    Json(user): Json<NewUser>,
+) -> Result<(StatusCode, Json<serde_json::Value>), StatusCode> {
+    if user.password.has_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if user.expires_at.is_some()
+        && (!users_db::enabled() || user.expires_at <= Some(users_db::unix_now()))
+        || user.quota.is_some() && !users_db::enabled()
+    {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let uuid = user.uuid.unwrap_or_else(Uuid::new_v4);
+    let stored = StoredUser {
+        uuid,
+        password: user.password,
+        expires_at: user.expires_at,
+        quota: user.quota.map(|quota| quota.0).filter(|quota| *quota != 0),
+    };
+    if !users::add(uuid, stored.password.clone()) {
+        return Err(StatusCode::CONFLICT);
+    }
+    track(&uuid);
+    if users_db::enabled()
+        && let Err(err) = users_db::put(&stored).await
+    {
+        warn!("[restful] failed to store {uuid} in users_db: {err:#}");
+        users::remove(&uuid);
+        return Err(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    audit(
+        &ctx,
+        addr,
+        "add_user",
+        json!({ "uuid": uuid, "expires_at": stored.expires_at, "quota": stored.quota }),
+    )
+    .await;
+    Ok((StatusCode::CREATED, Json(json!({ "uuid": uuid }))))
+}
+
+/// Change the passwords of a user, closing the connections authenticated with a
+/// changed or removed one if `disconnect_on_password_change` is set, or renew a
+/// user of `users_db` and change its quota
+async fn update_user(
+    State(ctx): State<Arc<AppContext>>,
+    addr: Option<ConnectInfo<SocketAddr>>,
+    UrlPath(uuid): UrlPath<Uuid>,
+    Json(user): Json<UserUpdate>,
+) -> StatusCode {
+    if user.password.as_ref().is_some_and(UserPasswords::has_empty)
+        || user.password.is_none() && user.expires_at.is_none() && user.quota.is_none()
     {
-        return (StatusCode::UNAUTHORIZED, Json(HashMap::new()));
+        return StatusCode::BAD_REQUEST;
+    }
+    let stored = match users_db::get(&uuid).await {
+        Ok(stored) => stored,
+        Err(err) => {
+            warn!("[restful] failed to read {uuid} from users_db: {err:#}");
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    };
+    if let Some(mut stored) = stored {
+        if let Some(password) = &user.password {
+            stored.password = password.clone();
+        }
+        if let Some(expires_at) = user.expires_at {
+            stored.expires_at = (expires_at != 0).then_some(expires_at);
+        }
+        if let Some(quota) = user.quota {
+            stored.quota = (quota.0 != 0).then_some(quota.0);
+        }
+        if let Err(err) = users_db::put(&stored).await {
+            warn!("[restful] failed to store {uuid} in users_db: {err:#}");
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+        if stored.expired(users_db::unix_now()) {
+            users::remove(&uuid);
+        } else if !users::set_passwords(&uuid, stored.password.clone()) {
+            // renewed after expiring
+            users::add(uuid, stored.password.clone());
+        }
+    } else {
+        // only users of `users_db` expire or have their quota changed here
+        let (Some(password), None, None) = (user.password, user.expires_at, user.quota) else {
+            return if users::passwords(&uuid).is_some() {
+                StatusCode::BAD_REQUEST
+            } else {
+                StatusCode::NOT_FOUND
+            };
+        };
+        if !users::set_passwords(&uuid, password) {
+            return StatusCode::NOT_FOUND;
+        }
+    }
+    audit(
+        &ctx,
+        addr,
+        "update_user",
+        json!({ "uuid": uuid, "expires_at": user.expires_at, "quota": user.quota }),
+    )
+    .await;
+    StatusCode::NO_CONTENT
+}
+
+/// Remove a user, closing its connections if `disconnect_on_password_change`
+/// is set
+async fn remove_user(
+    State(ctx): State<Arc<AppContext>>,
+    addr: Option<ConnectInfo<SocketAddr>>,
+    UrlPath(uuid): UrlPath<Uuid>,
+) -> StatusCode {
+    let stored = match users_db::remove(&uuid).await {
+        Ok(stored) => stored,
+        Err(err) => {
+            warn!("[restful] failed to remove {uuid} from users_db: {err:#}");
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    };
+    if !users::remove(&uuid) && !stored {
+        return StatusCode::NOT_FOUND;
+    }
+    audit(&ctx, addr, "remove_user", json!({ "uuid": uuid })).await;
+    StatusCode::NO_CONTENT
+}
+
+/// The users and IPs banned, by `abuse` rules, `fail2ban` or through the API
+async fn list_bans() -> Json<Vec<PersistedBan>> {
+    Json(abuse::bans_state())
+}
+
+#[derive(Deserialize)]
+struct NewBan {
+    ip: Option<IpAddr>,
+    user: Option<Uuid>,
+    #[serde(default = "default_ban_duration", with = "humantime_serde")]
+    duration: Duration,
+}
+
+fn default_ban_duration() -> Duration {
+    Duration::from_secs(3600)
+}
+
+/// Ban an IP or disable a user, closing their connections like `abuse` rules
+/// do. Kept longer if already banned for longer
+async fn add_ban(
+    State(ctx): State<Arc<AppContext>>,
+    addr: Option<ConnectInfo<SocketAddr>>,
+    Json(ban): Json<NewBan>,
+) -> StatusCode {
+    let offender = match (ban.ip, ban.user) {
+        (Some(ip), None) => Offender::Ip(ip.to_canonical()),
+        (None, Some(uuid)) => Offender::User(uuid),
+        _ => return StatusCode::BAD_REQUEST,
+    };
+    if ban.duration.is_zero() {
+        return StatusCode::BAD_REQUEST;
     }
+    abuse::impose(offender, ban.duration);
+    audit(
+        &ctx,
+        addr,
+        "add_ban",
+        json!({ "target": offender.to_string(), "duration_secs": ban.duration.as_secs() }),
+    )
+    .await;
+    StatusCode::NO_CONTENT
+}
+
+/// Lift the ban of an IP or user
+async fn remove_ban(
+    State(ctx): State<Arc<AppContext>>,
+    addr: Option<ConnectInfo<SocketAddr>>,
+    UrlPath(target): UrlPath<String>,
+) -> StatusCode {
+    let offender = if let Ok(ip) = target.parse::<IpAddr>() {
+        Offender::Ip(ip.to_canonical())
+    } else if let Ok(uuid) = target.parse::<Uuid>() {
+        Offender::User(uuid)
+    } else {
+        return StatusCode::BAD_REQUEST;
+    };
+    if !abuse::lift(offender) {
+        return StatusCode::NOT_FOUND;
+    }
+    audit(&ctx, addr, "remove_ban", json!({ "target": target })).await;
+    StatusCode::NO_CONTENT
+}
+
+async fn list_online() -> (StatusCode, Json<HashMap<Uuid, u64>>) {
     let mut result = HashMap::new();
-    for (user, count) in ONLINE_COUNTER.iter() {
+    for (user, count) in ONLINE_COUNTER.read().unwrap().iter() {
         let count = count.load(Ordering::Relaxed);
         if count != 0 {
             result.insert(user.to_owned(), count);
@@ -131,17 +1063,7 @@ async fn list_online(
     (StatusCode::OK, Json(result))
 }
 
-async fn list_detailed_online(
-    State(ctx): State<Arc<AppContext>>,
-    token: Option<TypedHeader<Authorization<Bearer>>>,
-) -> (StatusCode, Json<HashMap<Uuid, Vec<SocketAddr>>>) {
-    if let Some(restful) = &ctx.cfg.restful
-        && !restful.secret.is_empty()
-        && let Some(TypedHeader(token)) = token
-        && restful.secret != token.token()
-    {
-        return (StatusCode::UNAUTHORIZED, Json(HashMap::new()));
-    }
+async fn list_detailed_online() -> (StatusCode, Json<HashMap<Uuid, Vec<SocketAddr>>>) {
     let mut result = HashMap::new();
     for (user, list) in ONLINE_CLIENTS.clone_locking().await.into_iter() {
         if list.is_empty() {
@@ -153,19 +1075,9 @@ async fn list_detailed_online(
     (StatusCode::OK, Json(result))
 }
 
-async fn list_traffic(
-    State(ctx): State<Arc<AppContext>>,
-    token: Option<TypedHeader<Authorization<Bearer>>>,
-) -> (StatusCode, Json<HashMap<Uuid, serde_json::Value>>) {
-    if let Some(restful) = &ctx.cfg.restful
-        && !restful.secret.is_empty()
-        && let Some(TypedHeader(token)) = token
-        && restful.secret != token.token()
-    {
-        return (StatusCode::UNAUTHORIZED, Json(HashMap::new()));
-    }
+async fn list_traffic() -> (StatusCode, Json<HashMap<Uuid, serde_json::Value>>) {
     let mut result = HashMap::new();
-    for (uuid, (tx, rx)) in TRAFFIC_STATS.iter() {
+    for (uuid, (tx, rx)) in TRAFFIC_STATS.read().unwrap().iter() {
         let tx = tx.load(Ordering::Relaxed);
         let rx = rx.load(Ordering::Relaxed);
         if tx != 0 || rx != 0 {
@@ -178,17 +1090,12 @@ async fn list_traffic(
 
 async fn reset_traffic(
     State(ctx): State<Arc<AppContext>>,
-    token: Option<TypedHeader<Authorization<Bearer>>>,
+    addr: Option<ConnectInfo<SocketAddr>>,
 ) -> (StatusCode, Json<HashMap<Uuid, serde_json::Value>>) {
-    if let Some(restful) = &ctx.cfg.restful
-        && !restful.secret.is_empty()
-        && let Some(TypedHeader(token)) = token
-        && restful.secret != token.token()
-    {
-        return (StatusCode::UNAUTHORIZED, Json(HashMap::new()));
-    }
+    audit(&ctx, addr, "reset_traffic", serde_json::Value::Null).await;
+    destinations::reset_totals();
     let mut result = HashMap::new();
-    for (uuid, (tx, rx)) in TRAFFIC_STATS.iter() {
+    for (uuid, (tx, rx)) in TRAFFIC_STATS.read().unwrap().iter() {
         let tx = tx.swap(0, Ordering::Relaxed);
         let rx = rx.swap(0, Ordering::Relaxed);
         if tx != 0 || rx != 0 {
@@ -199,55 +1106,811 @@ async fn reset_traffic(
     (StatusCode::OK, Json(result))
 }
 
-pub async fn client_connect(ctx: &AppContext, uuid: &Uuid, conn: QuinnConnection) {
-    if ctx.cfg.restful.is_none() {
-        return;
+#[derive(Deserialize)]
+struct DestinationTrafficQuery {
+    #[serde(default = "default_destination_traffic_top")]
+    top: usize,
+    user: Option<Uuid>,
+}
+
+fn default_destination_traffic_top() -> usize {
+    50
+}
+
+/// The destinations relayed the most since start or the last
+/// `/reset_traffic`, with the users behind them. `404` unless
+/// `traffic_by_destination` is set
+async fn destination_traffic(
+    Query(query): Query<DestinationTrafficQuery>,
+) -> Result<Json<TrafficReport>, StatusCode> {
+    if !destinations::totals_enabled() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok(Json(destinations::totals(
+        query.top.min(MAX_TOP_DESTINATIONS),
+        query.user,
+    )))
+}
+
+async fn take_traffic_snapshot(
+    State(ctx): State<Arc<AppContext>>,
+    addr: Option<ConnectInfo<SocketAddr>>,
+    UrlPath(name): UrlPath<String>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    if TRAFFIC_SNAPSHOTS.len() >= MAX_TRAFFIC_SNAPSHOTS
+        && !TRAFFIC_SNAPSHOTS.contains_key(&name).await
+    {
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
+    audit(&ctx, addr, "traffic_snapshot", json!({ "name": name })).await;
+    let snapshot = TrafficSnapshot {
+        taken_at: Local::now(),
+        totals: TRAFFIC_TOTALS
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(uuid, (tx, rx))| {
+                (
+                    *uuid,
+                    (tx.load(Ordering::SeqCst), rx.load(Ordering::SeqCst)),
+                )
+            })
+            .collect(),
+    };
+    let taken_at = snapshot.taken_at.to_rfc3339();
+    TRAFFIC_SNAPSHOTS.insert(name.clone(), snapshot).await;
+
+    Ok(Json(json!({ "name": name, "taken_at": taken_at })))
+}
+
+/// Traffic since the snapshot was taken, unaffected by `/reset_traffic`
+async fn diff_traffic_snapshot(
+    UrlPath(name): UrlPath<String>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let snapshot = TRAFFIC_SNAPSHOTS
+        .get(&name)
+        .await
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let mut users = HashMap::new();
+    for (uuid, (tx, rx)) in TRAFFIC_TOTALS.read().unwrap().iter() {
+        let (tx_then, rx_then) = snapshot.totals.get(uuid).copied().unwrap_or_default();
+        let tx = tx.load(Ordering::SeqCst) - tx_then;
+        let rx = rx.load(Ordering::SeqCst) - rx_then;
+        if tx != 0 || rx != 0 {
+            users.insert(*uuid, json!({"tx": tx, "rx": rx}));
+        }
+    }
+
+    Ok(Json(json!({
+        "name": name,
+        "taken_at": snapshot.taken_at.to_rfc3339(),
+        "users": users,
+    })))
+}
+
+async fn delete_traffic_snapshot(
+    State(ctx): State<Arc<AppContext>>,
+    addr: Option<ConnectInfo<SocketAddr>>,
+    UrlPath(name): UrlPath<String>,
+) -> StatusCode {
+    audit(
+        &ctx,
+        addr,
+        "delete_traffic_snapshot",
+        json!({ "name": name }),
+    )
+    .await;
+    match TRAFFIC_SNAPSHOTS.remove(&name).await {
+        Some(_) => StatusCode::NO_CONTENT,
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+async fn list_blocklist_hits() -> (StatusCode, Json<HashMap<Uuid, u64>>) {
+    (
+        StatusCode::OK,
+        Json(blocklist::hits().await.into_iter().collect()),
+    )
+}
+
+async fn list_connections() -> (StatusCode, Json<Vec<serde_json::Value>>) {
+    (StatusCode::OK, Json(registry::list().await))
+}
+
+async fn list_streams(UrlPath(id): UrlPath<String>) -> (StatusCode, Json<Vec<serde_json::Value>>) {
+    let Some(id) = parse_connection_id(&id) else {
+        return (StatusCode::BAD_REQUEST, Json(Vec::new()));
+    };
+
+    match streams::list(id).await {
+        Some(list) => (StatusCode::OK, Json(list)),
+        None => (StatusCode::NOT_FOUND, Json(Vec::new())),
+    }
+}
+
+async fn fragment_cache() -> (StatusCode, Json<serde_json::Value>) {
+    (
+        StatusCode::OK,
+        Json(json!({
+            "packets": FRAGMENT_PACKETS.load(Ordering::Relaxed),
+            "bytes": FRAGMENT_BYTES.load(Ordering::Relaxed),
+            "gc_runs": GC_RUNS.load(Ordering::Relaxed),
+        })),
+    )
+}
+
+async fn list_latency() -> (StatusCode, Json<serde_json::Value>) {
+    (StatusCode::OK, Json(latency::snapshot()))
+}
+
+#[derive(Deserialize)]
+struct TopDestinationsQuery {
+    #[serde(default = "default_top_window", with = "humantime_serde")]
+    window: Duration,
+    #[serde(default = "default_top_limit")]
+    limit: usize,
+    #[serde(default)]
+    sort: SortBy,
+}
+
+fn default_top_window() -> Duration {
+    Duration::from_secs(15 * 60)
+}
+
+fn default_top_limit() -> usize {
+    20
+}
+
+/// The destinations relayed the most over the last `window`, at most an hour
+async fn top_destinations(
+    Query(query): Query<TopDestinationsQuery>,
+) -> Result<Json<Report>, StatusCode> {
+    if query.window.is_zero() || query.window > destinations::SLOT * destinations::SLOTS as u32 {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok(Json(destinations::top(
+        query.window,
+        query.limit.min(MAX_TOP_DESTINATIONS),
+        query.sort,
+    )))
+}
+
+async fn list_dials() -> (StatusCode, Json<serde_json::Value>) {
+    (StatusCode::OK, Json(dial::snapshot()))
+}
+
+async fn status() -> (StatusCode, Json<serde_json::Value>) {
+    let mut status = peaks::snapshot();
+    // jemalloc builds only
+    if let Ok(stats) = memory::stats() {
+        status["memory"] = json!(stats);
+    }
+    (StatusCode::OK, Json(status))
+}
+
+/// `/status` in the Prometheus text exposition format, without the peaks
+async fn metrics() -> Response {
+    let mut out = String::new();
+    gauge(
+        &mut out,
+        "tuic_connections",
+        "Open connections",
+        peaks::open(),
+    );
+    gauge(
+        &mut out,
+        "tuic_throughput_bytes_per_second",
+        "Bytes sent and received per second by all connections",
+        peaks::throughput(),
+    );
+    if let Ok(stats) = memory::stats() {
+        for (name, help, value) in [
+            (
+                "allocated",
+                "Bytes allocated by the application",
+                stats.allocated,
+            ),
+            ("active", "Bytes in pages holding allocations", stats.active),
+            (
+                "resident",
+                "Bytes in physically resident pages",
+                stats.resident,
+            ),
+            (
+                "mapped",
+                "Bytes in chunks mapped by the allocator",
+                stats.mapped,
+            ),
+            (
+                "retained",
+                "Bytes retained rather than returned to the OS",
+                stats.retained,
+            ),
+        ] {
+            gauge(&mut out, &format!("tuic_memory_{name}_bytes"), help, value);
+        }
+        gauge(
+            &mut out,
+            "tuic_memory_fragmentation_ratio",
+            "Share of the resident memory not backing any allocation",
+            stats.fragmentation,
+        );
+    }
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4")], out).into_response()
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: impl std::fmt::Display) {
+    use std::fmt::Write;
+
+    _ = writeln!(
+        out,
+        "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}"
+    );
+}
+
+async fn list_alerts() -> (StatusCode, Json<serde_json::Value>) {
+    (StatusCode::OK, Json(alerts::list()))
+}
+
+async fn flow_control() -> (StatusCode, Json<serde_json::Value>) {
+    (StatusCode::OK, Json(flow::snapshot()))
+}
+
+#[derive(Deserialize)]
+struct SubscriptionQuery {
+    format: Format,
+    /// Which password of a user with several, the first label if missing
+    label: Option<String>,
+}
+
+async fn subscription(
+    State(ctx): State<Arc<AppContext>>,
+    UrlPath(uuid): UrlPath<Uuid>,
+    Query(query): Query<SubscriptionQuery>,
+) -> Response {
+    let (Some(sub), Some(passwords)) = (&ctx.cfg.subscription, users::passwords(&uuid)) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let password = match &query.label {
+        Some(label) => passwords.get(Some(label)),
+        None => passwords.primary(),
+    };
+    let Some(password) = password else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let share = Share::new(&ctx.cfg, sub, uuid, password);
+
+    (
+        [(CONTENT_TYPE, query.format.content_type())],
+        share.render(query.format),
+    )
+        .into_response()
+}
+
+async fn cluster_node(State(ctx): State<Arc<AppContext>>) -> Result<Json<NodeStatus>, StatusCode> {
+    let cfg = ctx.cfg.cluster.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json(cluster::local(cfg)))
+}
+
+async fn cluster_nodes(State(ctx): State<Arc<AppContext>>) -> Result<Json<Vec<Node>>, StatusCode> {
+    let cfg = ctx.cfg.cluster.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json(cluster::nodes(cfg).await))
+}
+
+async fn cluster_recommended(
+    State(ctx): State<Arc<AppContext>>,
+) -> Result<Json<NodeStatus>, StatusCode> {
+    let cfg = ctx.cfg.cluster.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json(cluster::recommended(cfg).await))
+}
+
+async fn list_duplicate_auths() -> (StatusCode, Json<HashMap<Uuid, u64>>) {
+    (
+        StatusCode::OK,
+        Json(DUPLICATE_AUTHS.clone_locking().await.into_iter().collect()),
+    )
+}
+
+async fn list_rejections() -> Json<Rejections> {
+    Json(rejections::snapshot())
+}
+
+/// The status for allocator control failures, telling apart builds without
+/// jemalloc
+fn memory_error(err: eyre::Report) -> Response {
+    let status = if cfg!(feature = "jemallocator") {
+        StatusCode::INTERNAL_SERVER_ERROR
+    } else {
+        StatusCode::NOT_IMPLEMENTED
+    };
+    (status, err.to_string()).into_response()
+}
+
+async fn memory_stats() -> Response {
+    match memory::stats() {
+        Ok(stats) => Json(stats).into_response(),
+        Err(err) => memory_error(err),
+    }
+}
+
+async fn memory_purge(
+    State(ctx): State<Arc<AppContext>>,
+    addr: Option<ConnectInfo<SocketAddr>>,
+) -> Response {
+    audit(&ctx, addr, "memory_purge", json!(null)).await;
+
+    match memory::purge() {
+        Ok(()) => StatusCode::OK.into_response(),
+        Err(err) => memory_error(err),
     }
-    let cfg = ctx.cfg.restful.as_ref().unwrap();
-    let current = ONLINE_COUNTER
-        .get(uuid)
-        .expect("Authorized UUID not present in users table")
-        .fetch_add(1, Ordering::Release);
-    if cfg.maximum_clients_per_user != 0 && current > cfg.maximum_clients_per_user {
+}
+
+#[derive(Serialize, Deserialize)]
+struct LogLevelBody {
+    level: LogLevel,
+}
+
+async fn get_log_level() -> Json<LogLevelBody> {
+    Json(LogLevelBody {
+        level: reload::log_level(),
+    })
+}
+
+/// Change the log level until the config is reloaded or the server restarts
+async fn set_log_level(
+    State(ctx): State<Arc<AppContext>>,
+    addr: Option<ConnectInfo<SocketAddr>>,
+    Json(body): Json<LogLevelBody>,
+) -> StatusCode {
+    audit(&ctx, addr, "log_level", json!(body.level)).await;
+    match reload::set_log_level(body.level) {
+        Ok(()) => {
+            info!("[restful] log level changed to {}", body.level);
+            StatusCode::NO_CONTENT
+        }
+        Err(err) => {
+            warn!("[restful] failed to change the log level: {err}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
+/// The config in effect as JSON, credentials redacted, for fleet tooling to
+/// compare with the intended one
+async fn get_config(State(ctx): State<Arc<AppContext>>) -> Response {
+    match reload::effective(&ctx.cfg) {
+        Ok(mut value) => {
+            config::redact(&mut value);
+            Json(value).into_response()
+        }
+        Err(err) => {
+            warn!("[restful] failed to serialize the config: {err}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Stream live events as server-sent events, each named after its `event`
+/// field. Subscribers too slow to keep up miss the oldest events
+async fn stream_events() -> Response {
+    let stream = stream::unfold(events::subscribe(), |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(event) => {
+                    let sse = SseEvent::default().event(event.name()).json_data(&event);
+                    return Some((sse, rx));
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    debug!("[restful] /events subscriber lagging, {missed} events missed");
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    });
+    Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+/// Replace a connection's share of the fragment cache, as (packets, bytes)
+pub fn record_fragment_cache(old: (usize, usize), new: (usize, usize)) {
+    FRAGMENT_PACKETS.fetch_add(new.0, Ordering::Relaxed);
+    FRAGMENT_PACKETS.fetch_sub(old.0, Ordering::Relaxed);
+    FRAGMENT_BYTES.fetch_add(new.1, Ordering::Relaxed);
+    FRAGMENT_BYTES.fetch_sub(old.1, Ordering::Relaxed);
+}
+
+/// Requests per minute allowed for each source IP, 0 for no limit
+pub fn set_rate_limit(limit: u32) {
+    RATE_LIMITER.limit.store(limit, Ordering::Relaxed);
+}
+
+pub fn record_gc_run() {
+    GC_RUNS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The number of authenticated connections across all users
+pub fn online_connections() -> u64 {
+    ONLINE_COUNTER
+        .read()
+        .unwrap()
+        .values()
+        .map(|count| count.load(Ordering::Relaxed))
+        .sum()
+}
+
+pub async fn record_duplicate_auth(uuid: Uuid) {
+    DUPLICATE_AUTHS.upsert(uuid, || 1, |cnt| *cnt += 1).await;
+}
+
+/// Run `f` on the entry of `uuid`, added by `init` if missing
+fn with_entry<T, R>(
+    map: &RwLock<HashMap<Uuid, T>>,
+    uuid: &Uuid,
+    init: impl FnOnce() -> T,
+    f: impl FnOnce(&T) -> R,
+) -> R {
+    if let Some(entry) = map.read().unwrap().get(uuid) {
+        return f(entry);
+    }
+    f(map.write().unwrap().entry(*uuid).or_insert_with(init))
+}
+
+/// Count `tx` and `rx` bytes relayed for `uuid`, resuming from its saved
+/// traffic if first seen
+fn add_traffic(uuid: &Uuid, tx: u64, rx: u64) {
+    let saved = || {
+        TRAFFIC_RETIRED
+            .get()
+            .and_then(|retired| retired.get(uuid))
+            .copied()
+            .unwrap_or_default()
+    };
+    let add = |(total_tx, total_rx): &(AtomicU64, AtomicU64)| {
+        total_tx.fetch_add(tx, Ordering::SeqCst);
+        total_rx.fetch_add(rx, Ordering::SeqCst);
+    };
+    with_entry(
+        &TRAFFIC_STATS,
+        uuid,
+        || (saved().tx.into(), saved().rx.into()),
+        add,
+    );
+    with_entry(
+        &TRAFFIC_TOTALS,
+        uuid,
+        || (saved().total_tx.into(), saved().total_rx.into()),
+        add,
+    );
+}
+
+/// Count the connections and traffic of `uuid` from now on, for the users
+/// added at runtime
+pub fn track(uuid: &Uuid) {
+    with_entry(&ONLINE_COUNTER, uuid, AtomicU64::default, |_| ());
+    add_traffic(uuid, 0, 0);
+}
+
+pub async fn client_connect(
+    ctx: &AppContext,
+    uuid: &Uuid,
+    conn: QuinnConnection,
+    cid: CorrelationId,
+) {
+    let maximum = ctx
+        .cfg
+        .restful
+        .as_ref()
+        .map_or(0, |cfg| cfg.maximum_clients_per_user);
+    let addr = conn.remote_address();
+    let current = with_entry(&ONLINE_COUNTER, uuid, AtomicU64::default, |counter| {
+        counter.fetch_add(1, Ordering::Release)
+    });
+    if maximum != 0 && current > maximum && !users::is_priority(uuid) {
         conn.close(
             VarInt::from_u32(6001),
             "Reached maximum clients limitation".as_bytes(),
         );
+        webhook::notify(
+            ctx,
+            WebhookEvent::Limit,
+            *uuid,
+            addr,
+            cid,
+            Some("maximum_clients_per_user".into()),
+        );
         return;
     }
+    let client = QuicClient(conn, cid);
+    // `insert` is called instead of `update` for the first connection
     ONLINE_CLIENTS
-        .upsert(*uuid, HashSet::new, |v| {
-            v.insert(conn.into());
-        })
+        .upsert(
+            *uuid,
+            || HashSet::from([client.clone()]),
+            |v| {
+                v.insert(client.clone());
+            },
+        )
         .await;
+    publish_connect(*uuid, addr, cid);
+    webhook::notify(ctx, WebhookEvent::Connect, *uuid, addr, cid, None);
 }
-pub async fn client_disconnect(ctx: &AppContext, uuid: &Uuid, conn: QuinnConnection) {
-    if ctx.cfg.restful.is_none() {
-        return;
+fn publish_connect(uuid: Uuid, addr: SocketAddr, cid: CorrelationId) {
+    if events::enabled() {
+        events::publish(Event::Connect {
+            uuid,
+            addr,
+            correlation_id: cid.to_string(),
+        });
+    }
+}
+
+pub async fn client_disconnect(
+    ctx: &AppContext,
+    uuid: &Uuid,
+    conn: QuinnConnection,
+    cid: CorrelationId,
+) {
+    let reason = conn.close_reason().map(|err| err.to_string());
+    if events::enabled() {
+        events::publish(Event::Disconnect {
+            uuid: *uuid,
+            addr: conn.remote_address(),
+            correlation_id: cid.to_string(),
+            reason: reason.clone(),
+        });
+    }
+    webhook::notify(
+        ctx,
+        WebhookEvent::Disconnect,
+        *uuid,
+        conn.remote_address(),
+        cid,
+        reason,
+    );
+    if let Some(counter) = ONLINE_COUNTER.read().unwrap().get(uuid) {
+        counter.fetch_sub(1, Ordering::SeqCst);
     }
-    ONLINE_COUNTER
-        .get(uuid)
-        .expect("Authorized UUID not present in users table")
-        .fetch_sub(1, Ordering::SeqCst);
     if let Some(mut pair) = ONLINE_CLIENTS.get_mut(uuid).await {
-        pair.remove(&conn.into());
+        pair.remove(&QuicClient(conn, cid));
     }
 }
 
-pub fn traffic_tx(ctx: &AppContext, uuid: &Uuid, size: u64) {
-    if ctx.cfg.restful.is_none() {
-        return;
+pub fn traffic_tx(uuid: &Uuid, size: u64) {
+    add_traffic(uuid, size, 0);
+}
+
+pub fn traffic_rx(uuid: &Uuid, size: u64) {
+    add_traffic(uuid, 0, size);
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::header::AUTHORIZATION};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::config::{Config, RestfulConfig, SubscriptionConfig, UserOverrides};
+
+    fn app(secret: &str) -> Router {
+        app_limited(secret, Arc::default())
     }
-    if let Some((tx, _)) = TRAFFIC_STATS.get(uuid) {
-        tx.fetch_add(size, Ordering::SeqCst);
+
+    fn app_limited(secret: &str, limiter: Arc<RateLimiter>) -> Router {
+        router(
+            Arc::new(AppContext {
+                cfg: Config {
+                    restful: Some(RestfulConfig {
+                        secret: secret.to_owned(),
+                        ..Default::default()
+                    }),
+                    subscription: Some(SubscriptionConfig {
+                        address: "example.com:443".to_owned(),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            }),
+            limiter,
+        )
     }
-}
 
-pub fn traffic_rx(ctx: &AppContext, uuid: &Uuid, size: u64) {
-    if ctx.cfg.restful.is_none() {
-        return;
+    async fn call(app: Router, req: Request, token: Option<&str>) -> StatusCode {
+        let (mut parts, body) = req.into_parts();
+        if let Some(token) = token {
+            parts
+                .headers
+                .insert(AUTHORIZATION, format!("Bearer {token}").parse().unwrap());
+        }
+        let res = app.oneshot(Request::from_parts(parts, body)).await.unwrap();
+        res.status()
+    }
+
+    async fn list_users(app: Router, token: Option<&str>) -> StatusCode {
+        let req = Request::get("/users").body(Body::empty()).unwrap();
+        call(app, req, token).await
+    }
+
+    fn json(method: &str, uri: &str, body: serde_json::Value) -> Request {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn missing_token_is_refused() {
+        assert_eq!(
+            list_users(app("secret"), None).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn wrong_token_is_refused() {
+        assert_eq!(
+            list_users(app("secret"), Some("guess")).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[tokio::test]
+    async fn right_token_is_accepted() {
+        assert_eq!(
+            list_users(app("secret"), Some("secret")).await,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn empty_secret_needs_no_token() {
+        assert_eq!(list_users(app(""), None).await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn user_changes_need_token() {
+        let uuid = Uuid::from_u128(0x755);
+        let user = json!({ "uuid": uuid, "password": "password" });
+        for token in [None, Some("guess")] {
+            let requests = [
+                json("POST", "/users", user.clone()),
+                json(
+                    "PATCH",
+                    &format!("/users/{uuid}"),
+                    json!({ "password": "changed" }),
+                ),
+                Request::delete(format!("/users/{uuid}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            ];
+            for req in requests {
+                assert_eq!(
+                    call(app("secret"), req, token).await,
+                    StatusCode::UNAUTHORIZED
+                );
+            }
+        }
+        assert!(users::passwords(&uuid).is_none());
+    }
+
+    #[tokio::test]
+    async fn subscription_needs_token() {
+        let uuid = Uuid::from_u128(0x740);
+        users::add(uuid, serde_json::from_value(json!("password")).unwrap());
+        let uri = format!("/subscription/{uuid}?format=v2rayn");
+        for (token, status) in [
+            (None, StatusCode::UNAUTHORIZED),
+            (Some("guess"), StatusCode::UNAUTHORIZED),
+            (Some("secret"), StatusCode::OK),
+        ] {
+            let req = Request::get(&uri).body(Body::empty()).unwrap();
+            assert_eq!(call(app("secret"), req, token).await, status);
+        }
+    }
+
+    #[tokio::test]
+    async fn log_level_change_needs_token() {
+        for token in [None, Some("guess")] {
+            let req = json("PUT", "/log_level", json!({ "level": "trace" }));
+            assert_eq!(
+                call(app("secret"), req, token).await,
+                StatusCode::UNAUTHORIZED
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn ban_changes_need_token() {
+        let ip = IpAddr::from([192, 0, 2, 71]);
+        for token in [None, Some("guess")] {
+            let requests = [
+                json("POST", "/bans", json!({ "ip": ip })),
+                Request::delete(format!("/bans/{ip}"))
+                    .body(Body::empty())
+                    .unwrap(),
+            ];
+            for req in requests {
+                assert_eq!(
+                    call(app("secret"), req, token).await,
+                    StatusCode::UNAUTHORIZED
+                );
+            }
+        }
+        assert!(abuse::bans_state().iter().all(|ban| ban.ip != Some(ip)));
+    }
+
+    #[tokio::test]
+    async fn users_first_seen_are_counted() {
+        let uuid = Uuid::from_u128(0x738);
+        add_traffic(&uuid, 5, 7);
+        let req = Request::get("/traffic").body(Body::empty()).unwrap();
+        let res = app("").oneshot(req).await.unwrap();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let traffic: HashMap<Uuid, serde_json::Value> = serde_json::from_slice(&body).unwrap();
+        assert_eq!(traffic[&uuid], json!({ "tx": 5, "rx": 7 }));
+    }
+
+    #[tokio::test]
+    async fn metrics_are_exposed() {
+        let req = Request::get("/metrics").body(Body::empty()).unwrap();
+        let res = app("").oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.contains("# TYPE tuic_connections gauge\ntuic_connections "));
+        assert!(body.contains("\ntuic_throughput_bytes_per_second "));
+        // only in jemalloc builds
+        assert_eq!(
+            body.contains("tuic_memory_"),
+            cfg!(feature = "jemallocator")
+        );
+    }
+
+    #[test]
+    fn quota_holds_without_restful() {
+        let uuid = Uuid::from_u128(0x782);
+        users::set_overrides(HashMap::from([(
+            uuid,
+            UserOverrides {
+                quota: Some(ByteSize(100)),
+                ..Default::default()
+            },
+        )]));
+        let ctx = AppContext {
+            cfg: Config::default(),
+        };
+        traffic_tx(&uuid, 60);
+        assert!(!over_quota(&ctx, &uuid));
+        traffic_rx(&uuid, 40);
+        assert!(over_quota(&ctx, &uuid));
     }
-    if let Some((__, rx)) = TRAFFIC_STATS.get(uuid) {
-        rx.fetch_add(size, Ordering::SeqCst);
+
+    #[tokio::test]
+    async fn rate_limit_ignores_tokens() {
+        // a limiter of its own, the other tests aren't limited
+        let limiter = Arc::new(RateLimiter::default());
+        limiter.limit.store(50, Ordering::Relaxed);
+        let addr = SocketAddr::from(([192, 0, 2, 21], 0));
+        for n in 0..=50 {
+            let mut req = Request::get("/users").body(Body::empty()).unwrap();
+            req.extensions_mut().insert(ConnectInfo(addr));
+            let app = app_limited("secret", limiter.clone());
+            let status = call(app, req, Some(&format!("guess{n}"))).await;
+            let expected = if n < 50 {
+                StatusCode::UNAUTHORIZED
+            } else {
+                StatusCode::TOO_MANY_REQUESTS
+            };
+            assert_eq!(status, expected);
+        }
     }
 }
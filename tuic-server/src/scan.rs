@@ -0,0 +1,144 @@
+//! Detecting users scanning ports through the server, dialing many distinct
+//! targets that mostly fail within `port_scan.window`, and throttling their
+//! dials before the server's IP gets reported for it
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, LazyLock, Mutex, OnceLock},
+};
+
+use serde_json::json;
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::{self, Instant},
+};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{AppContext, alerts, config::PortScanConfig, hooks, users, utils::ScanAction};
+
+type Throttled = HashMap<Uuid, (Instant, Arc<Semaphore>)>;
+
+/// Unset unless `port_scan.enabled`
+static CFG: OnceLock<PortScanConfig> = OnceLock::new();
+/// The recent connects of each user
+static CONNECTS: LazyLock<Mutex<HashMap<Uuid, VecDeque<Connect>>>> = LazyLock::new(Mutex::default);
+/// The throttled users, until when, and the single dial slot they share
+static THROTTLED: LazyLock<Mutex<Throttled>> = LazyLock::new(Mutex::default);
+
+struct Connect {
+    at: Instant,
+    target: String,
+    failed: bool,
+}
+
+pub fn init(cfg: &PortScanConfig) -> eyre::Result<()> {
+    if !cfg.enabled {
+        return Ok(());
+    }
+    if cfg.min_targets == 0 || cfg.window.is_zero() || cfg.throttle_duration.is_zero() {
+        eyre::bail!("port_scan: `min_targets`, `window` and `throttle_duration` must be non-zero");
+    }
+    if !(0.0..=1.0).contains(&cfg.min_failure_ratio) {
+        eyre::bail!("port_scan: `min_failure_ratio` must be between 0 and 1");
+    }
+    _ = CFG.set(cfg.clone());
+    Ok(())
+}
+
+/// Count a connect of `user` to `target`, resolving included, and act once
+/// the recent ones look like a scan
+pub fn observe(ctx: &Arc<AppContext>, user: Uuid, target: &str, failed: bool) {
+    let Some(cfg) = CFG.get() else {
+        return;
+    };
+    if users::is_priority(&user) {
+        return;
+    }
+
+    let now = Instant::now();
+    let (targets, failures, total) = {
+        let mut connects = CONNECTS.lock().unwrap();
+        let recent = connects.entry(user).or_default();
+        while recent
+            .front()
+            .is_some_and(|connect| now.duration_since(connect.at) > cfg.window)
+        {
+            recent.pop_front();
+        }
+        recent.push_back(Connect {
+            at: now,
+            target: target.to_owned(),
+            failed,
+        });
+        // keeps the check cheap under a flood of connects
+        if recent.len() > cfg.min_targets * 4 {
+            recent.pop_front();
+        }
+
+        let targets = recent
+            .iter()
+            .map(|connect| connect.target.as_str())
+            .collect::<HashSet<_>>()
+            .len();
+        let failures = recent.iter().filter(|connect| connect.failed).count();
+        let total = recent.len();
+        if targets < cfg.min_targets || (failures as f64) < cfg.min_failure_ratio * total as f64 {
+            return;
+        }
+        // start over, so the scan is reported once rather than on every
+        // further connect
+        connects.remove(&user);
+        (targets, failures, total)
+    };
+
+    if cfg.action == ScanAction::Throttle {
+        let until = now + cfg.throttle_duration;
+        THROTTLED
+            .lock()
+            .unwrap()
+            .entry(user)
+            .and_modify(|(expiry, _)| *expiry = until)
+            .or_insert_with(|| (until, Arc::new(Semaphore::new(1))));
+    }
+    warn!(
+        "[port_scan] [{user}] {targets} distinct targets, {failures} of {total} connects failed \
+         within {window}, {action}",
+        window = humantime::format_duration(cfg.window),
+        action = cfg.action,
+    );
+    alerts::push(
+        "port_scan",
+        json!({
+            "user": user,
+            "targets": targets,
+            "failed": failures,
+            "connects": total,
+            "window_secs": cfg.window.as_secs(),
+            "action": cfg.action,
+        }),
+    );
+    hooks::exec_port_scan(ctx, user, cfg.action, targets, failures, total);
+}
+
+/// Wait for the single dial slot of `user` if throttled, then for
+/// `port_scan.throttle_delay`. The slot is released when the returned permit
+/// is dropped
+pub async fn throttle(user: Uuid) -> Option<OwnedSemaphorePermit> {
+    let cfg = CFG.get()?;
+    let permits = {
+        let mut throttled = THROTTLED.lock().unwrap();
+        match throttled.get(&user) {
+            Some((until, permits)) if *until > Instant::now() => permits.clone(),
+            Some(_) => {
+                throttled.remove(&user);
+                return None;
+            }
+            None => return None,
+        }
+    };
+    // the semaphore is never closed
+    let permit = permits.acquire_owned().await.unwrap();
+    time::sleep(cfg.throttle_delay).await;
+    Some(permit)
+}
@@ -0,0 +1,142 @@
+use std::sync::OnceLock;
+
+use uuid::Uuid;
+
+use crate::config::ScriptConfig;
+
+#[cfg(feature = "script")]
+static SCRIPT: OnceLock<imp::RoutingScript> = OnceLock::new();
+#[cfg(not(feature = "script"))]
+static SCRIPT: OnceLock<()> = OnceLock::new();
+
+/// Compile the routing script, if one is configured
+pub fn init(cfg: Option<&ScriptConfig>) -> eyre::Result<()> {
+    let Some(cfg) = cfg else {
+        return Ok(());
+    };
+    #[cfg(feature = "script")]
+    {
+        _ = SCRIPT.set(imp::RoutingScript::load(cfg)?);
+        Ok(())
+    }
+    #[cfg(not(feature = "script"))]
+    {
+        Err(eyre::eyre!(
+            "routing_script {path}: built without the script feature",
+            path = cfg.path.display()
+        ))
+    }
+}
+
+/// Ask the routing script whether `uuid` may relay `protocol` traffic to
+/// `dest`. Everything is allowed when no script is configured, and nothing
+/// when the script fails.
+pub fn allow(uuid: Uuid, dest: &str, protocol: &str) -> bool {
+    #[cfg(feature = "script")]
+    {
+        SCRIPT
+            .get()
+            .map_or(true, |script| script.allow(uuid, dest, protocol))
+    }
+    #[cfg(not(feature = "script"))]
+    {
+        _ = (uuid, dest, protocol);
+        SCRIPT.get().is_none()
+    }
+}
+
+#[cfg(feature = "script")]
+mod imp {
+    use std::{
+        cell::Cell,
+        time::{Duration, Instant},
+    };
+
+    use eyre::Context;
+    use rhai::{AST, Dynamic, Engine, Scope};
+    use tracing::warn;
+    use uuid::Uuid;
+
+    use crate::{config::ScriptConfig, privacy};
+
+    thread_local! {
+        static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+    }
+
+    pub struct RoutingScript {
+        engine: Engine,
+        ast: AST,
+        timeout: Duration,
+    }
+
+    impl RoutingScript {
+        pub fn load(cfg: &ScriptConfig) -> eyre::Result<Self> {
+            let mut engine = Engine::new();
+            engine
+                .set_max_operations(cfg.max_operations)
+                .set_max_call_levels(32)
+                .set_max_expr_depths(64, 32)
+                .set_max_string_size(cfg.max_data_size)
+                .set_max_array_size(cfg.max_data_size)
+                .set_max_map_size(cfg.max_data_size)
+                .on_progress(|_| {
+                    DEADLINE
+                        .get()
+                        .is_some_and(|deadline| Instant::now() > deadline)
+                        .then_some(Dynamic::UNIT)
+                });
+            let ast = engine
+                .compile_file(cfg.path.clone())
+                .with_context(|| format!("failed to compile {}", cfg.path.display()))?;
+            Ok(Self {
+                engine,
+                ast,
+                timeout: cfg.timeout,
+            })
+        }
+
+        pub fn allow(&self, uuid: Uuid, dest: &str, protocol: &str) -> bool {
+            DEADLINE.set(Some(Instant::now() + self.timeout));
+            let res = self.engine.call_fn::<Dynamic>(
+                &mut Scope::new(),
+                &self.ast,
+                "route",
+                (uuid.to_string(), dest.to_owned(), protocol.to_owned()),
+            );
+            DEADLINE.set(None);
+
+            let decision = match res {
+                Ok(decision) => decision,
+                Err(err) => {
+                    warn!(
+                        "[script] route({uuid}, {dest}, {protocol}) failed: {err}",
+                        dest = privacy::text(dest)
+                    );
+                    return false;
+                }
+            };
+            if let Ok(allow) = decision.as_bool() {
+                return allow;
+            }
+            match decision.into_string().as_deref() {
+                Ok("allow") => true,
+                Ok("deny") => false,
+                Ok(other) => {
+                    warn!(
+                        "[script] route({uuid}, {dest}, {protocol}) returned unsupported decision \
+                         {other:?}",
+                        dest = privacy::text(dest)
+                    );
+                    false
+                }
+                Err(ty) => {
+                    warn!(
+                        "[script] route({uuid}, {dest}, {protocol}) returned a {ty}",
+                        dest = privacy::text(dest)
+                    );
+                    false
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,112 @@
+//! `[security_log]`: authentication failures and IP bans, one event per line
+//! with the source IP first, for fail2ban or crowdsec to firewall
+//! brute-forcers at the kernel level. The format is kept stable:
+//!
+//! ```text
+//! 203.0.113.7 2025-01-01T00:00:00Z auth_failure port=51234 uuid=...
+//! 203.0.113.7 2025-01-01T00:00:00Z ban duration=3600
+//! ```
+
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    net::{IpAddr, SocketAddr},
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+use chrono::{SecondsFormat, Utc};
+use eyre::Context;
+use tracing::debug;
+
+use crate::{config::SecurityLogConfig, error::Error};
+
+static SINKS: OnceLock<Sinks> = OnceLock::new();
+
+struct Sinks {
+    file: Option<Mutex<File>>,
+    #[cfg(unix)]
+    socket: Option<std::os::unix::net::UnixDatagram>,
+}
+
+pub fn init(cfg: Option<&SecurityLogConfig>) -> eyre::Result<()> {
+    let Some(cfg) = cfg else {
+        return Ok(());
+    };
+    if cfg.path.is_none() && cfg.socket.is_none() {
+        eyre::bail!("security_log: set `path`, `socket` or both");
+    }
+    let file = cfg
+        .path
+        .as_ref()
+        .map(|path| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("security_log: failed to open {}", path.display()))
+        })
+        .transpose()?;
+    #[cfg(unix)]
+    let socket = cfg
+        .socket
+        .as_ref()
+        .map(|path| {
+            let socket = std::os::unix::net::UnixDatagram::unbound()
+                .context("security_log: failed to create Unix socket")?;
+            socket.set_nonblocking(true)?;
+            socket.connect(path).with_context(|| {
+                format!("security_log: failed to connect to {}", path.display())
+            })?;
+            Ok::<_, eyre::Report>(socket)
+        })
+        .transpose()?;
+    #[cfg(not(unix))]
+    if cfg.socket.is_some() {
+        eyre::bail!("security_log: `socket` is only supported on Unix");
+    }
+    _ = SINKS.set(Sinks {
+        file: file.map(Mutex::new),
+        #[cfg(unix)]
+        socket,
+    });
+    Ok(())
+}
+
+/// Log `err` if it is an authentication failure of a client from `addr`
+pub fn observe(err: &Error, addr: SocketAddr) {
+    if let Error::AuthFailed(uuid) = err {
+        write(
+            addr.ip(),
+            format_args!("auth_failure port={port} uuid={uuid}", port = addr.port()),
+        );
+    }
+}
+
+/// Log a ban of `ip`, by `[abuse]` rules, `[fail2ban]` or the RESTful API
+pub fn ban(ip: IpAddr, duration: Duration) {
+    write(ip, format_args!("ban duration={}", duration.as_secs()));
+}
+
+fn write(ip: IpAddr, event: std::fmt::Arguments<'_>) {
+    let Some(sinks) = SINKS.get() else {
+        return;
+    };
+    let line = format!(
+        "{ip} {time} {event}\n",
+        ip = ip.to_canonical(),
+        time = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+    );
+    if let Some(file) = &sinks.file
+        && let Err(err) = file.lock().unwrap().write_all(line.as_bytes())
+    {
+        debug!("[security_log] failed to write: {err}");
+    }
+    #[cfg(unix)]
+    if let Some(socket) = &sinks.socket
+        && let Err(err) = socket.send(line.as_bytes())
+    {
+        // nobody listening, or a full buffer
+        debug!("[security_log] failed to send: {err}");
+    }
+}
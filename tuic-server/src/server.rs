@@ -1,51 +1,129 @@
 use std::{
-    net::{SocketAddr, UdpSocket as StdUdpSocket},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket as StdUdpSocket},
     sync::Arc,
 };
 
 use eyre::Context;
 use quinn::{
-    Endpoint, EndpointConfig, IdleTimeout, ServerConfig, TokioRuntime, TransportConfig, VarInt,
+    AckFrequencyConfig, Endpoint, EndpointConfig, IdleTimeout, ServerConfig, TokioRuntime,
+    TransportConfig, VarInt,
     congestion::{BbrConfig, CubicConfig, NewRenoConfig},
     crypto::rustls::QuicServerConfig,
 };
-use rustls::{
-    ServerConfig as RustlsServerConfig,
-    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
-};
+use rustls::ServerConfig as RustlsServerConfig;
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use tokio::task::JoinSet;
 use tracing::{debug, warn};
 
 use crate::{
     AppContext,
-    connection::{Connection, INIT_CONCURRENT_STREAMS},
-    error::Error,
-    utils::{self, CongestionController},
+    abuse::{self, Offender},
+    acl, auth,
+    cert::CertResolver,
+    config::RestfulAddr,
+    connection::{Connection, INIT_CONCURRENT_STREAMS, masque},
+    destinations, dial, dns,
+    error::{self, Error},
+    fail2ban,
+    fallback::Fallback,
+    handshake, privacy, scan, script, security_log, systemd,
+    utils::{CongestionController, SessionTicketer},
+    webhook,
 };
 
 pub struct Server {
-    ep: Endpoint,
+    /// One per address of `server`, sharing the same config
+    eps: Vec<Endpoint>,
+    /// `[fallback]`, on the same addresses over TCP
+    fallback: Option<Fallback>,
     ctx: Arc<AppContext>,
 }
 
 impl Server {
     pub fn init(ctx: Arc<AppContext>) -> Result<Self, Error> {
-        let mut crypto: RustlsServerConfig;
-        if ctx.cfg.tls.self_sign {
-            let cert = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
-            let cert_der = CertificateDer::from(cert.cert);
-            let priv_key = PrivatePkcs8KeyDer::from(cert.key_pair.serialize_der());
-            crypto = RustlsServerConfig::builder_with_protocol_versions(&[&rustls::version::TLS13])
-                .with_no_client_auth()
-                .with_single_cert(vec![cert_der], PrivateKeyDer::Pkcs8(priv_key))?;
-        } else {
-            let certs = utils::load_cert_chain(&ctx.cfg.tls.certificate)?;
-            let priv_key = utils::load_priv_key(&ctx.cfg.tls.private_key)?;
-            crypto = RustlsServerConfig::builder_with_protocol_versions(&[&rustls::version::TLS13])
-                .with_no_client_auth()
-                .with_single_cert(certs, priv_key)?;
+        script::init(ctx.cfg.routing_script.as_ref())?;
+        acl::init(&ctx.cfg)?;
+        privacy::init(ctx.cfg.log_destinations);
+        destinations::init(
+            ctx.cfg
+                .restful
+                .as_ref()
+                .is_some_and(|restful| restful.traffic_by_destination),
+        );
+        error::init(&ctx.cfg.error_log);
+        dial::init(&ctx.cfg.outbound)?;
+        dns::init(&ctx.cfg.dns)?;
+        abuse::init(&ctx.cfg)?;
+        scan::init(&ctx.cfg.port_scan)?;
+        fail2ban::init(&ctx.cfg.fail2ban)?;
+        security_log::init(ctx.cfg.security_log.as_ref())?;
+        auth::init(ctx.cfg.auth.http.as_ref())?;
+        if ctx.cfg.udp_relay_dual_stack
+            && ctx.cfg.udp_relay_bind() != (Ipv4Addr::UNSPECIFIED, Ipv6Addr::UNSPECIFIED)
+        {
+            return Err(eyre::eyre!(
+                "udp_relay_dual_stack: a single socket can't be bound to both an IPv4 and an IPv6 \
+                 address, unset `udp_relay_bind_ipv4`, `udp_relay_bind_ipv6`, \
+                 `outbound.bind_ipv4` and `outbound.bind_ipv6` or the option"
+            )
+            .into());
+        }
+        if let Some(restful) = &ctx.cfg.restful {
+            if restful.tls_cert.is_some() != restful.tls_key.is_some() {
+                return Err(
+                    eyre::eyre!("restful: set both `tls_cert` and `tls_key`, or neither").into(),
+                );
+            }
+            if restful.tls_cert.is_some() && matches!(restful.addr, RestfulAddr::Unix(_)) {
+                return Err(eyre::eyre!(
+                    "restful.tls_cert: TLS is only served on TCP addresses, not Unix domain \
+                     sockets"
+                )
+                .into());
+            }
+            webhook::init(restful.webhook.as_ref())?;
+            if restful.tls_cert.is_some() && ctx.cfg.cluster.is_some() {
+                return Err(eyre::eyre!(
+                    "restful.tls_cert: cluster peers poll each other over plain HTTP, disable TLS \
+                     or the cluster"
+                )
+                .into());
+            }
+        }
+        if ctx.cfg.cluster.is_some() && ctx.cfg.restful.is_none() {
+            return Err(eyre::eyre!(
+                "cluster: nodes exchange their status over RESTful, enable it"
+            )
+            .into());
+        }
+
+        if ctx.cfg.tcp_relay_buffer_size == 0 {
+            return Err(eyre::eyre!("tcp_relay_buffer_size: must be at least 1 byte").into());
+        }
+
+        if ctx.cfg.quic.versions.is_empty() {
+            return Err(eyre::eyre!("quic.versions: list at least one version").into());
+        }
+        if let Some(version) = ctx
+            .cfg
+            .quic
+            .versions
+            .iter()
+            .find(|version| !handshake::SUPPORTED_VERSIONS.contains(version))
+        {
+            return Err(eyre::eyre!(
+                "quic.versions: {version:#010x} is not implemented, use QUIC v1 (0x00000001) or \
+                 the drafts 29 to 34 (0xff00001d to 0xff000022)"
+            )
+            .into());
         }
 
+        let builder =
+            RustlsServerConfig::builder_with_protocol_versions(&[&rustls::version::TLS13])
+                .with_no_client_auth();
+        let resolver = Arc::new(CertResolver::new(&ctx.cfg.tls, builder.crypto_provider())?);
+        let mut crypto = builder.with_cert_resolver(resolver.clone());
+
         crypto.alpn_protocols = ctx
             .cfg
             .tls
@@ -54,13 +132,44 @@ impl Server {
             .cloned()
             .map(|alpn| alpn.into_bytes())
             .collect();
+        if ctx.cfg.masque.enabled || ctx.cfg.fallback_h3_upstream.is_some() {
+            if crypto.alpn_protocols.is_empty()
+                || crypto
+                    .alpn_protocols
+                    .iter()
+                    .any(|alpn| alpn == masque::ALPN)
+            {
+                return Err(eyre::eyre!(
+                    "masque, fallback_h3_upstream: HTTP/3 clients are told apart by the `h3` \
+                     ALPN, list the ALPN of the TUIC clients in `tls.alpn`, other than `h3`"
+                )
+                .into());
+            }
+            crypto.alpn_protocols.push(masque::ALPN.to_vec());
+        }
+        crypto.send_tls13_tickets = ctx.cfg.tls.session_tickets;
+        if let Some(lifetime) = ctx.cfg.tls.ticket_lifetime {
+            if lifetime > SessionTicketer::MAX_LIFETIME {
+                warn!(
+                    "tls.ticket_lifetime is capped at {max} by the ticket key rotation",
+                    max = humantime::format_duration(SessionTicketer::MAX_LIFETIME),
+                );
+            }
+            crypto.ticketer = SessionTicketer::new(lifetime)?;
+        }
+
         // TODO only set when 0-RTT enabled
         crypto.max_early_data_size = u32::MAX;
         crypto.send_half_rtt_data = ctx.cfg.zero_rtt_handshake;
 
-        let mut config = ServerConfig::with_crypto(Arc::new(
-            QuicServerConfig::try_from(crypto).context("no initial cipher suite found")?,
-        ));
+        let mut config = ServerConfig::with_crypto(Arc::new(handshake::ServerConfig(Arc::new(
+            QuicServerConfig::try_from(crypto)
+                .context("no initial cipher suite found")
+                .map_err(Error::Tls)?,
+        ))));
+        // No `pacing` option: quinn (0.11) paces from the congestion window and RTT,
+        // and disabling it would mean reporting a window that disables congestion
+        // control as well. `congestion_control.initial_window` is the only knob
         let mut tp_cfg = TransportConfig::default();
 
         tp_cfg
@@ -81,6 +190,30 @@ impl Server {
                 Some(Default::default())
             });
 
+        // quinn can't change the per-stream window of a live connection, so
+        // auto-tuning opens it to `max_receive_window` and bounds the streams with
+        // the connection-level window instead, which `tune_windows` moves. quinn
+        // leaves that one unbounded, it starts at the per-stream one
+        if ctx.cfg.quic.auto_tune_window {
+            let max = ctx
+                .cfg
+                .quic
+                .max_receive_window
+                .max(u64::from(ctx.cfg.quic.receive_window));
+            tp_cfg
+                .receive_window(VarInt::from_u32(ctx.cfg.quic.receive_window))
+                .stream_receive_window(VarInt::from_u64(max).unwrap_or(VarInt::MAX));
+        }
+
+        if let Some(ack_frequency) = &ctx.cfg.quic.ack_frequency {
+            let mut ack_cfg = AckFrequencyConfig::default();
+            ack_cfg
+                .ack_eliciting_threshold(VarInt::from_u32(ack_frequency.ack_eliciting_threshold))
+                .max_ack_delay(ack_frequency.max_ack_delay)
+                .reordering_threshold(VarInt::from_u32(ack_frequency.reordering_threshold));
+            tp_cfg.ack_frequency_config(Some(ack_cfg));
+        }
+
         match ctx.cfg.quic.congestion_control.controller {
             CongestionController::Bbr => {
                 let mut bbr_config = BbrConfig::default();
@@ -101,61 +234,132 @@ impl Server {
 
         config.transport_config(Arc::new(tp_cfg));
 
-        let socket = {
-            let domain = match ctx.cfg.server {
-                SocketAddr::V4(_) => Domain::IPV4,
-                SocketAddr::V6(_) => Domain::IPV6,
-            };
-
-            let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))
-                .context("failed to create endpoint UDP socket")?;
-
-            if ctx.cfg.dual_stack {
-                socket.set_only_v6(!ctx.cfg.dual_stack).map_err(|err| {
-                    Error::Socket("endpoint dual-stack socket setting error", err)
-                })?;
-            }
+        let mut ep_cfg = EndpointConfig::default();
+        ep_cfg
+            .supported_versions(ctx.cfg.quic.versions.clone())
+            .grease_quic_bit(ctx.cfg.quic.grease_quic_bit);
 
-            socket
-                .bind(&SockAddr::from(ctx.cfg.server))
-                .context("failed to bind endpoint UDP socket")?;
-
-            StdUdpSocket::from(socket)
-        };
+        let eps = ctx
+            .cfg
+            .server
+            .iter()
+            .map(|addr| bind(&ctx, addr, ep_cfg.clone(), config.clone()))
+            .collect::<Result<_, _>>()?;
 
-        let ep = Endpoint::new(
-            EndpointConfig::default(),
-            Some(config),
-            socket,
-            Arc::new(TokioRuntime),
-        )?;
+        let fallback = ctx
+            .cfg
+            .fallback
+            .as_ref()
+            .map(|cfg| Fallback::new(cfg, ctx.cfg.server.iter(), ctx.cfg.dual_stack, resolver))
+            .transpose()?;
+        systemd::close_unused();
 
-        Ok(Self { ep, ctx })
+        Ok(Self { eps, fallback, ctx })
     }
 
-    pub async fn start(&self) {
+    pub async fn start(&mut self) {
         warn!(
             "server started, listening on {}",
-            self.ep.local_addr().unwrap()
+            self.ctx
+                .cfg
+                .server
+                .0
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
         );
+        systemd::notify("READY=1");
+        tokio::spawn(systemd::watchdog());
+        crate::restful::init(&self.ctx);
         if self.ctx.cfg.restful.is_some() {
             tokio::spawn(crate::restful::start(self.ctx.clone()));
         }
+        if self.ctx.cfg.blocklist.is_some() {
+            tokio::spawn(crate::blocklist::start(self.ctx.clone()));
+        }
+        if abuse::enabled() {
+            tokio::spawn(abuse::start());
+        }
+        if fail2ban::enabled() {
+            tokio::spawn(fail2ban::start());
+        }
+        tokio::spawn(crate::peaks::start());
+        if let Some(fallback) = self.fallback.take() {
+            fallback.start();
+        }
+
+        let mut accepting = JoinSet::new();
+        for ep in &self.eps {
+            accepting.spawn(accept(self.ctx.clone(), ep.clone()));
+        }
+        while accepting.join_next().await.is_some() {}
+    }
+}
 
-        loop {
-            match self.ep.accept().await {
-                Some(conn) => match conn.accept() {
-                    Ok(conn) => {
-                        tokio::spawn(Connection::handle(self.ctx.clone(), conn));
-                    }
-                    Err(e) => {
-                        debug!("[Incoming] Failed to accept connection: {e}");
-                    }
-                },
-                None => {
-                    debug!("[Incoming] the endpoint is closed");
-                    return;
+fn bind(
+    ctx: &AppContext,
+    addr: SocketAddr,
+    ep_cfg: EndpointConfig,
+    config: ServerConfig,
+) -> Result<Endpoint, Error> {
+    let domain = match addr {
+        SocketAddr::V4(_) => Domain::IPV4,
+        SocketAddr::V6(_) => Domain::IPV6,
+    };
+
+    let socket = match systemd::take_socket(addr, Type::DGRAM) {
+        Some(socket) => socket,
+        None => {
+            let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))
+                .context("failed to create endpoint UDP socket")?;
+
+            if ctx.cfg.dual_stack && addr.is_ipv6() {
+                socket.set_only_v6(!ctx.cfg.dual_stack).map_err(|err| {
+                    Error::Socket("endpoint dual-stack socket setting error", err)
+                })?;
+            }
+
+            socket
+                .bind(&SockAddr::from(addr))
+                .map_err(|err| Error::Bind(addr, err))?;
+            socket
+        }
+    };
+
+    Endpoint::new(
+        ep_cfg,
+        Some(config),
+        StdUdpSocket::from(socket),
+        Arc::new(TokioRuntime),
+    )
+    .map_err(|err| Error::Bind(addr, err))
+}
+
+/// Accept the connections of `ep` until it is closed
+async fn accept(ctx: Arc<AppContext>, ep: Endpoint) {
+    loop {
+        match ep.accept().await {
+            Some(conn)
+                if abuse::is_banned(Offender::Ip(conn.remote_address().ip().to_canonical())) =>
+            {
+                debug!(
+                    "[Incoming] refused connection from banned {addr}",
+                    addr = conn.remote_address()
+                );
+                conn.refuse();
+            }
+            Some(conn) => match conn.accept() {
+                Ok(conn) => {
+                    tokio::spawn(Connection::handle(ctx.clone(), conn));
+                }
+                Err(e) => {
+                    debug!("[Incoming] Failed to accept connection: {e}");
                 }
+            },
+            None => {
+                debug!("[Incoming] the endpoint is closed");
+                return;
             }
         }
     }
@@ -0,0 +1,196 @@
+//! Client configurations for the formats of popular TUIC clients, so panels
+//! and users don't have to assemble them by hand
+
+use std::str::FromStr;
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde::Deserialize;
+use serde_json::json;
+use uuid::Uuid;
+
+use crate::{
+    config::{Config, SubscriptionConfig},
+    utils::CongestionController,
+};
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Format {
+    /// A base64 encoded `tuic://` share link, as v2rayN subscriptions are
+    #[serde(rename = "v2rayn")]
+    V2rayN,
+    ClashMeta,
+    SingBox,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "v2rayn" => Ok(Self::V2rayN),
+            "clash-meta" => Ok(Self::ClashMeta),
+            "sing-box" => Ok(Self::SingBox),
+            _ => Err(format!(
+                "unknown format {s:?}, expected v2rayn, clash-meta or sing-box"
+            )),
+        }
+    }
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::V2rayN => "text/plain; charset=utf-8",
+            Self::ClashMeta => "text/yaml; charset=utf-8",
+            Self::SingBox => "application/json",
+        }
+    }
+}
+
+/// Everything a client needs to connect to this server as one user
+pub struct Share<'a> {
+    name: &'a str,
+    host: &'a str,
+    port: u16,
+    sni: &'a str,
+    uuid: Uuid,
+    password: &'a str,
+    alpn: &'a [String],
+    congestion_control: &'static str,
+    zero_rtt_handshake: bool,
+    insecure: bool,
+}
+
+impl<'a> Share<'a> {
+    pub fn new(
+        cfg: &'a Config,
+        sub: &'a SubscriptionConfig,
+        uuid: Uuid,
+        password: &'a str,
+    ) -> Self {
+        // the port may be omitted, defaulting to the one the server listens on
+        let (host, port) = match sub.address.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') && port.parse::<u16>().is_ok() => {
+                (host, port.parse().unwrap())
+            }
+            _ => (sub.address.as_str(), cfg.server.port()),
+        };
+        let host = host.trim_matches(['[', ']']);
+        Self {
+            name: &sub.name,
+            host,
+            port,
+            sni: if sub.sni.is_empty() { host } else { &sub.sni },
+            uuid,
+            password,
+            alpn: &cfg.tls.alpn,
+            congestion_control: match cfg.quic.congestion_control.controller {
+                CongestionController::Bbr => "bbr",
+                CongestionController::Cubic => "cubic",
+                CongestionController::NewReno => "new_reno",
+            },
+            zero_rtt_handshake: cfg.zero_rtt_handshake,
+            insecure: cfg.tls.self_sign,
+        }
+    }
+
+    pub fn render(&self, format: Format) -> String {
+        match format {
+            Format::V2rayN => STANDARD.encode(self.link()),
+            Format::ClashMeta => self.clash_meta(),
+            Format::SingBox => {
+                serde_json::to_string_pretty(&self.sing_box()).expect("JSON must serialize")
+            }
+        }
+    }
+
+    /// The `tuic://` share link understood by v2rayN, NekoBox and others
+    pub fn link(&self) -> String {
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.to_owned()
+        };
+        let mut link = format!(
+            "tuic://{uuid}:{password}@{host}:{port}?sni={sni}&congestion_control={cc}&\
+             udp_relay_mode=native",
+            uuid = self.uuid,
+            password = percent_encode(self.password),
+            port = self.port,
+            sni = percent_encode(self.sni),
+            cc = self.congestion_control,
+        );
+        if !self.alpn.is_empty() {
+            link.push_str("&alpn=");
+            link.push_str(&percent_encode(&self.alpn.join(",")));
+        }
+        if self.insecure {
+            link.push_str("&allow_insecure=1");
+        }
+        link.push('#');
+        link.push_str(&percent_encode(self.name));
+        link
+    }
+
+    /// A clash-meta (mihomo) proxy list with a single entry
+    pub fn clash_meta(&self) -> String {
+        // JSON strings are valid YAML scalars and spare escaping rules
+        let s = |v: &str| serde_json::Value::from(v).to_string();
+        let mut fields = vec![
+            ("type", "tuic".to_owned()),
+            ("server", s(self.host)),
+            ("port", self.port.to_string()),
+            ("uuid", self.uuid.to_string()),
+            ("password", s(self.password)),
+            ("sni", s(self.sni)),
+            ("congestion-controller", self.congestion_control.to_owned()),
+            ("udp-relay-mode", "native".to_owned()),
+            ("reduce-rtt", self.zero_rtt_handshake.to_string()),
+            ("skip-cert-verify", self.insecure.to_string()),
+        ];
+        if !self.alpn.is_empty() {
+            let alpn: Vec<_> = self.alpn.iter().map(|v| s(v)).collect();
+            fields.push(("alpn", format!("[{}]", alpn.join(", "))));
+        }
+
+        let mut yaml = format!("proxies:\n  - name: {}\n", s(self.name));
+        for (key, value) in fields {
+            yaml.push_str(&format!("    {key}: {value}\n"));
+        }
+        yaml
+    }
+
+    /// A sing-box outbound
+    pub fn sing_box(&self) -> serde_json::Value {
+        json!({
+            "type": "tuic",
+            "tag": self.name,
+            "server": self.host,
+            "server_port": self.port,
+            "uuid": self.uuid,
+            "password": self.password,
+            "congestion_control": self.congestion_control,
+            "udp_relay_mode": "native",
+            "zero_rtt_handshake": self.zero_rtt_handshake,
+            "tls": {
+                "enabled": true,
+                "server_name": self.sni,
+                "alpn": self.alpn,
+                "insecure": self.insecure,
+            },
+        })
+    }
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~') {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{b:02X}"));
+        }
+    }
+    out
+}
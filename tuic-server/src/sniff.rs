@@ -0,0 +1,395 @@
+//! Extract the domain a client is actually connecting to from the first bytes
+//! of a relayed stream or from a QUIC Initial packet, as clients often request
+//! IP targets
+
+#[cfg(feature = "aws-lc-rs")]
+use rustls::crypto::aws_lc_rs::cipher_suite::TLS13_AES_128_GCM_SHA256;
+#[cfg(all(feature = "ring", not(feature = "aws-lc-rs")))]
+use rustls::crypto::ring::cipher_suite::TLS13_AES_128_GCM_SHA256;
+use rustls::{
+    Side,
+    quic::{Keys, Version},
+};
+
+/// How much of a stream is buffered while sniffing
+pub const MAX_SNIFF_LEN: usize = 4096;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Sniffed {
+    Domain(String),
+    /// The buffered bytes look like a known protocol, but are cut short
+    Incomplete,
+    Unknown,
+}
+
+/// Sniff the TLS SNI or the HTTP `Host` header from the start of a stream
+pub fn sniff_stream(buf: &[u8]) -> Sniffed {
+    match tls_sni(buf) {
+        Sniffed::Unknown => http_host(buf),
+        res => res,
+    }
+}
+
+/// Parse the server name from a TLS record carrying a ClientHello
+fn tls_sni(buf: &[u8]) -> Sniffed {
+    // content type handshake, legacy record version 3.x
+    if buf.len() < 5 {
+        return if buf.first().is_none_or(|&ty| ty == 0x16) {
+            Sniffed::Incomplete
+        } else {
+            Sniffed::Unknown
+        };
+    }
+    if buf[0] != 0x16 || buf[1] != 0x03 {
+        return Sniffed::Unknown;
+    }
+    let len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+    match buf.get(5..5 + len) {
+        Some(record) => client_hello_sni(record),
+        None if buf.len() < MAX_SNIFF_LEN => Sniffed::Incomplete,
+        // a ClientHello larger than the buffer, try with what we have
+        None => client_hello_sni(&buf[5..]),
+    }
+}
+
+/// Parse the server name from a handshake message carrying a ClientHello,
+/// shared with the QUIC sniffer where it arrives in CRYPTO frames
+pub fn client_hello_sni(msg: &[u8]) -> Sniffed {
+    let mut r = Reader(msg);
+    let mut parse = || -> Option<Option<String>> {
+        // handshake type client_hello
+        if r.u8()? != 0x01 {
+            return Some(None);
+        }
+        r.skip(3)?; // length
+        r.skip(2 + 32)?; // legacy version, random
+        let len = r.u8()? as usize;
+        r.skip(len)?; // legacy session id
+        let len = r.u16()? as usize;
+        r.skip(len)?; // cipher suites
+        let len = r.u8()? as usize;
+        r.skip(len)?; // legacy compression methods
+        let len = r.u16()? as usize;
+        let mut exts = Reader(r.take(len)?);
+        while !exts.0.is_empty() {
+            let ty = exts.u16()?;
+            let len = exts.u16()? as usize;
+            let data = exts.take(len)?;
+            if ty != 0x0000 {
+                continue;
+            }
+            let mut list = Reader(data);
+            let len = list.u16()? as usize;
+            let mut list = Reader(list.take(len)?);
+            while !list.0.is_empty() {
+                let name_ty = list.u8()?;
+                let len = list.u16()? as usize;
+                let name = list.take(len)?;
+                if name_ty == 0x00 {
+                    return Some(std::str::from_utf8(name).ok().map(str::to_owned));
+                }
+            }
+        }
+        Some(None)
+    };
+    match parse() {
+        Some(Some(domain)) => Sniffed::Domain(domain),
+        Some(None) => Sniffed::Unknown,
+        None => Sniffed::Incomplete,
+    }
+}
+
+/// Parse the `Host` header of an HTTP/1 request
+fn http_host(buf: &[u8]) -> Sniffed {
+    const METHODS: &[&[u8]] = &[
+        b"GET ",
+        b"POST ",
+        b"PUT ",
+        b"HEAD ",
+        b"DELETE ",
+        b"OPTIONS ",
+        b"PATCH ",
+        b"CONNECT ",
+        b"TRACE ",
+    ];
+    let is_method = METHODS.iter().any(|method| {
+        let len = method.len().min(buf.len());
+        buf[..len] == method[..len]
+    });
+    if !is_method {
+        return Sniffed::Unknown;
+    }
+
+    let mut complete = false;
+    for line in buf.split(|&b| b == b'\n').skip(1) {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            complete = true;
+            break;
+        }
+        if let Some((name, value)) = line.split_at_checked(5)
+            && name.eq_ignore_ascii_case(b"host:")
+            && let Ok(value) = std::str::from_utf8(value)
+        {
+            let host = value.trim();
+            // strip the port, keeping bracketed IPv6 literals intact
+            let host = match host.rsplit_once(':') {
+                Some((host, port)) if !port.contains(']') => host,
+                _ => host,
+            };
+            return Sniffed::Domain(host.trim_matches(['[', ']']).to_owned());
+        }
+    }
+    if complete || buf.len() >= MAX_SNIFF_LEN {
+        Sniffed::Unknown
+    } else {
+        Sniffed::Incomplete
+    }
+}
+
+/// Sniff the TLS SNI from the ClientHello carried by a client's QUIC Initial
+/// packet. Only the first packet of a datagram is looked at, so a ClientHello
+/// split across datagrams is recognized only if the server name comes first.
+pub fn sniff_quic(pkt: &[u8]) -> Option<String> {
+    // long header with the fixed bit set
+    if pkt.first()? & 0xc0 != 0xc0 {
+        return None;
+    }
+    let mut r = Reader(&pkt[1..]);
+    let (version, initial_ty) = match r.u32()? {
+        0x0000_0001 => (Version::V1, 0b00),
+        0x6b33_43cf => (Version::V2, 0b01),
+        _ => return None,
+    };
+    if (pkt[0] >> 4) & 0b11 != initial_ty {
+        return None;
+    }
+    let len = r.u8()? as usize;
+    let dcid = r.take(len)?;
+    let len = r.u8()? as usize;
+    r.skip(len)?; // source connection id
+    let len = r.varint()? as usize;
+    r.skip(len)?; // token
+    let len = r.varint()? as usize;
+    let pn_offset = pkt.len() - r.0.len();
+    let end = pn_offset.checked_add(len).filter(|end| *end <= pkt.len())?;
+
+    let suite = TLS13_AES_128_GCM_SHA256.tls13()?;
+    let keys = Keys::initial(version, suite, suite.quic?, dcid, Side::Server).remote;
+
+    // remove the header protection
+    let mut pkt = pkt[..end].to_vec();
+    let sample_offset = pn_offset + 4;
+    let sample = pkt
+        .get(sample_offset..sample_offset + keys.header.sample_len())?
+        .to_vec();
+    let (first, rest) = pkt.split_first_mut()?;
+    keys.header
+        .decrypt_in_place(&sample, first, &mut rest[pn_offset - 1..pn_offset + 3])
+        .ok()?;
+    let pn_len = (pkt[0] & 0b11) as usize + 1;
+    let pn = pkt[pn_offset..pn_offset + pn_len]
+        .iter()
+        .fold(0, |pn, &b| (pn << 8) | u64::from(b));
+
+    let (header, payload) = pkt.split_at_mut(pn_offset + pn_len);
+    let payload = keys.packet.decrypt_in_place(pn, header, payload).ok()?;
+
+    // reassemble the start of the CRYPTO stream, whose frames may be reordered
+    let mut frames = Reader(payload);
+    let mut crypto = Vec::new();
+    while !frames.0.is_empty() {
+        match frames.varint()? {
+            // PADDING, PING
+            0x00 | 0x01 => {}
+            // ACK
+            ty @ (0x02 | 0x03) => {
+                frames.varint()?; // largest acknowledged
+                frames.varint()?; // delay
+                let ranges = frames.varint()?;
+                frames.varint()?; // first range
+                for _ in 0..ranges * 2 {
+                    frames.varint()?;
+                }
+                if ty == 0x03 {
+                    for _ in 0..3 {
+                        frames.varint()?;
+                    }
+                }
+            }
+            // CRYPTO
+            0x06 => {
+                let offset = frames.varint()? as usize;
+                let len = frames.varint()? as usize;
+                crypto.push((offset, frames.take(len)?));
+            }
+            _ => break,
+        }
+    }
+    crypto.sort_unstable_by_key(|(offset, _)| *offset);
+    let mut hello = Vec::new();
+    for (offset, data) in crypto {
+        if offset > hello.len() {
+            break;
+        }
+        hello.extend_from_slice(data.get(hello.len() - offset..).unwrap_or_default());
+    }
+
+    match client_hello_sni(&hello) {
+        Sniffed::Domain(domain) => Some(domain),
+        Sniffed::Incomplete | Sniffed::Unknown => None,
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let (head, tail) = self.0.split_at_checked(len)?;
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// QUIC variable-length integer
+    fn varint(&mut self) -> Option<u64> {
+        let first = self.u8()?;
+        let len = 1 << (first >> 6);
+        let rest = self.take(len - 1)?;
+        Some(
+            rest.iter()
+                .fold(u64::from(first & 0x3f), |v, &b| (v << 8) | u64::from(b)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The ClientHello of RFC 9001 Appendix A.2, for `example.com`
+    const CLIENT_HELLO: &str = "\
+        010000ed0303ebf8fa56f12939b9584a3896472ec40bb863cfd3e86804fe3a47f06a2b69484c000004\
+        13011302010000c000000010000e00000b6578616d706c652e636f6dff01000100000a00080006001d\
+        0017001800100007000504616c706e000500050100000000003300260024001d00209370b2c9caa47f\
+        babaf4559fedba753de171fa71f50f1ce15d43e994ec74d748002b0003020304000d0010000e040305\
+        0306030203080408050806002d00020101001c00024001003900320408ffffffffffffffff05048000\
+        ffff07048000ffff0801100104800075300901100f088394c8f03e51570806048000ffff";
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn domain(domain: &str) -> Sniffed {
+        Sniffed::Domain(domain.to_owned())
+    }
+
+    #[test]
+    fn streams() {
+        let hello = hex(CLIENT_HELLO);
+        assert_eq!(hello.len(), 0xf1);
+        let mut record = vec![0x16, 0x03, 0x01, 0x00, 0xf1];
+        record.extend_from_slice(&hello);
+
+        let cases: [(&[u8], Sniffed); 11] = [
+            (&record, domain("example.com")),
+            (&record[..100], Sniffed::Incomplete),
+            (&record[..3], Sniffed::Incomplete),
+            (b"", Sniffed::Incomplete),
+            (b"SSH-2.0-OpenSSH_9.6\r\n", Sniffed::Unknown),
+            (&[0x17, 0x03, 0x03, 0x00, 0x10], Sniffed::Unknown),
+            (
+                b"GET / HTTP/1.1\r\nHost: example.com:8080\r\n\r\n",
+                domain("example.com"),
+            ),
+            (
+                b"POST /upload HTTP/1.1\r\nhost: [2001:db8::1]:8443\r\n\r\n",
+                domain("2001:db8::1"),
+            ),
+            (b"GET / HTTP/1.1\r\nHost: [::1]\r\n\r\n", domain("::1")),
+            (
+                b"GET / HTTP/1.1\r\nUser-Agent: curl/8.0\r\n\r\n",
+                Sniffed::Unknown,
+            ),
+            (b"GET / HTTP/1.1\r\nUser-Agent: cu", Sniffed::Incomplete),
+        ];
+        for (i, (buf, sniffed)) in cases.into_iter().enumerate() {
+            assert_eq!(sniff_stream(buf), sniffed, "case {i}");
+        }
+    }
+
+    /// The client Initial of RFC 9001 Appendix A.2, protected with the keys
+    /// derived from its destination connection ID
+    fn client_initial() -> Vec<u8> {
+        let dcid = hex("8394c8f03e515708");
+        let suite = TLS13_AES_128_GCM_SHA256.tls13().unwrap();
+        let keys =
+            Keys::initial(Version::V1, suite, suite.quic.unwrap(), &dcid, Side::Client).local;
+
+        // packet number 2 on 4 bytes, 1182 bytes of packet number, payload and tag
+        let mut pkt = hex("c300000001088394c8f03e5157080000449e00000002");
+        let pn_offset = pkt.len() - 4;
+        let mut payload = hex("060040f1");
+        payload.extend_from_slice(&hex(CLIENT_HELLO));
+        payload.resize(1182 - 4 - 16, 0);
+        let tag = keys.packet.encrypt_in_place(2, &pkt, &mut payload).unwrap();
+        pkt.extend_from_slice(&payload);
+        pkt.extend_from_slice(tag.as_ref());
+
+        let sample = pkt[pn_offset + 4..pn_offset + 4 + 16].to_vec();
+        let (first, rest) = pkt.split_first_mut().unwrap();
+        keys.header
+            .encrypt_in_place(&sample, first, &mut rest[pn_offset - 1..pn_offset + 3])
+            .unwrap();
+        pkt
+    }
+
+    #[test]
+    fn quic_initial() {
+        let pkt = client_initial();
+        assert_eq!(pkt.len(), 1200);
+        assert_eq!(
+            pkt[..22],
+            hex("c000000001088394c8f03e5157080000449e7b9aec34")
+        );
+        assert_eq!(sniff_quic(&pkt).as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn quic_not_initial() {
+        let pkt = client_initial();
+        // cut short
+        assert_eq!(sniff_quic(&pkt[..600]), None);
+        // short header
+        let mut short = pkt.clone();
+        short[0] &= 0x7f;
+        assert_eq!(sniff_quic(&short), None);
+        // unknown version
+        let mut unknown = pkt.clone();
+        unknown[1..5].copy_from_slice(&[0xff, 0x00, 0x00, 0x1d]);
+        assert_eq!(sniff_quic(&unknown), None);
+        // another destination connection ID derives other keys
+        let mut other = pkt;
+        other[6] ^= 0xff;
+        assert_eq!(sniff_quic(&other), None);
+    }
+}
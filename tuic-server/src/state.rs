@@ -0,0 +1,145 @@
+//! The runtime state kept across restarts in `persistent_data`: the RESTful
+//! traffic stats, the bans and the daily peaks
+
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    net::IpAddr,
+    sync::OnceLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    AppContext, abuse,
+    config::Config,
+    peaks::{self, DayPeaks},
+    restful,
+};
+
+/// What the previous run saved. Sections this run doesn't keep up to date are
+/// written back untouched
+static LOADED: OnceLock<PersistentData> = OnceLock::new();
+
+/// The content of the persistent data file
+#[derive(Deserialize, Serialize, Default, Clone)]
+#[serde(default)]
+struct PersistentData {
+    traffic: HashMap<Uuid, PersistedTraffic>,
+    bans: Vec<PersistedBan>,
+    peaks: Vec<DayPeaks>,
+}
+
+#[derive(Deserialize, Serialize, Default, Clone, Copy)]
+#[serde(default)]
+pub struct PersistedTraffic {
+    pub tx: u64,
+    pub rx: u64,
+    pub total_tx: u64,
+    pub total_rx: u64,
+}
+
+/// A user or IP banned until `expires_at`, in seconds since the Unix epoch
+#[derive(Deserialize, Serialize, Clone, Copy)]
+pub struct PersistedBan {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+    pub expires_at: u64,
+}
+
+/// Whether anything is kept: the traffic stats with
+/// `restful.persist_interval`, the bans with `abuse` rules or `fail2ban`, the
+/// daily peaks with any of them
+pub fn enabled(cfg: &Config) -> bool {
+    cfg.restful
+        .as_ref()
+        .is_some_and(|restful| !restful.persist_interval.is_zero())
+        || !cfg.abuse.rules.is_empty()
+        || cfg.fail2ban.enabled
+}
+
+/// Read what a previous run saved, a missing file is nothing saved
+pub async fn load(cfg: &Config) -> eyre::Result<()> {
+    if !enabled(cfg) {
+        return Ok(());
+    }
+    let data = match tokio::fs::read_to_string(&cfg.persistent_data).await {
+        Ok(data) => toml::from_str(&data)?,
+        Err(err) if err.kind() == ErrorKind::NotFound => PersistentData::default(),
+        Err(err) => return Err(err.into()),
+    };
+    _ = LOADED.set(data);
+    Ok(())
+}
+
+/// The traffic stats saved by the previous run
+pub fn traffic() -> HashMap<Uuid, PersistedTraffic> {
+    LOADED
+        .get()
+        .map(|data| data.traffic.clone())
+        .unwrap_or_default()
+}
+
+/// The bans saved by the previous run, expired or not
+pub fn bans() -> Vec<PersistedBan> {
+    LOADED
+        .get()
+        .map(|data| data.bans.clone())
+        .unwrap_or_default()
+}
+
+/// The daily peaks saved by the previous run
+pub fn peaks() -> Vec<DayPeaks> {
+    LOADED
+        .get()
+        .map(|data| data.peaks.clone())
+        .unwrap_or_default()
+}
+
+/// Seconds since the Unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Write the state to the persistent data file, replacing it atomically
+pub async fn save(ctx: &AppContext) {
+    // nothing was loaded, so the file may hold the state of a previous run
+    // that would be lost
+    let Some(loaded) = LOADED.get() else {
+        return;
+    };
+    let data = PersistentData {
+        traffic: restful::traffic_state().unwrap_or_else(|| loaded.traffic.clone()),
+        bans: if abuse::enabled() {
+            abuse::bans_state()
+        } else {
+            loaded.bans.clone()
+        },
+        peaks: peaks::state(),
+    };
+
+    let path = &ctx.cfg.persistent_data;
+    let res = async {
+        let data = toml::to_string(&data)?;
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        tokio::fs::write(&tmp, data).await?;
+        tokio::fs::rename(&tmp, path).await?;
+        eyre::Ok(())
+    }
+    .await;
+    if let Err(err) = res {
+        warn!(
+            "failed to save the runtime state to {path}: {err}",
+            path = path.display()
+        );
+    }
+}
@@ -0,0 +1,159 @@
+use std::{
+    fs,
+    io::{Result as IoResult, Write},
+    net::{SocketAddr, UdpSocket},
+    sync::Arc,
+};
+
+use chrono::{SecondsFormat, Utc};
+use eyre::Context;
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::config::SyslogConfig;
+
+/// Longest message sent, longer ones are truncated
+const MAX_MESSAGE_LEN: usize = 8192;
+
+const SEVERITY_INFO: u8 = 6;
+
+enum Socket {
+    Udp(UdpSocket),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixDatagram),
+}
+
+struct Inner {
+    socket: Socket,
+    facility: u8,
+    hostname: String,
+    tag: String,
+    pid: u32,
+}
+
+/// Writes each log line as an RFC 5424 message
+#[derive(Clone)]
+pub struct Syslog(Arc<Inner>);
+
+impl Syslog {
+    pub fn new(cfg: &SyslogConfig) -> eyre::Result<Self> {
+        let socket = match cfg.server {
+            Some(server) => {
+                let bind = match server {
+                    SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+                    SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+                };
+                let socket = UdpSocket::bind(bind).context("syslog: failed to bind UDP socket")?;
+                socket
+                    .connect(server)
+                    .with_context(|| format!("syslog: failed to connect to {server}"))?;
+                Socket::Udp(socket)
+            }
+            #[cfg(unix)]
+            None => {
+                let socket = std::os::unix::net::UnixDatagram::unbound()
+                    .context("syslog: failed to create Unix socket")?;
+                socket.connect(&cfg.path).with_context(|| {
+                    format!("syslog: failed to connect to {}", cfg.path.display())
+                })?;
+                Socket::Unix(socket)
+            }
+            #[cfg(not(unix))]
+            None => eyre::bail!("syslog: set `syslog.server`, there is no local syslog socket"),
+        };
+        let hostname = fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|name| name.trim().to_owned())
+            .ok()
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| "-".into());
+        Ok(Self(Arc::new(Inner {
+            socket,
+            facility: cfg.facility.code(),
+            hostname,
+            tag: if cfg.tag.is_empty() {
+                "-".into()
+            } else {
+                cfg.tag.clone()
+            },
+            pid: std::process::id(),
+        })))
+    }
+}
+
+/// A single log line, sent when dropped
+pub struct Line {
+    syslog: Arc<Inner>,
+    severity: u8,
+    buf: Vec<u8>,
+}
+
+impl Write for Line {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Line {
+    fn drop(&mut self) {
+        let msg = String::from_utf8_lossy(&self.buf);
+        let mut msg = msg.trim_end();
+        if msg.is_empty() {
+            return;
+        }
+        let inner = &self.syslog;
+        let mut packet = format!(
+            "<{pri}>1 {time} {host} {tag} {pid} - - ",
+            pri = inner.facility * 8 + self.severity,
+            time = Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+            host = inner.hostname,
+            tag = inner.tag,
+            pid = inner.pid,
+        )
+        .into_bytes();
+        if msg.len() > MAX_MESSAGE_LEN {
+            let end = (0..=MAX_MESSAGE_LEN)
+                .rev()
+                .find(|idx| msg.is_char_boundary(*idx))
+                .unwrap_or(0);
+            msg = &msg[..end];
+        }
+        packet.extend_from_slice(msg.as_bytes());
+        // nowhere to report a failure to, the logs are what failed
+        _ = match &inner.socket {
+            Socket::Udp(socket) => socket.send(&packet),
+            #[cfg(unix)]
+            Socket::Unix(socket) => socket.send(&packet),
+        };
+    }
+}
+
+impl<'a> MakeWriter<'a> for Syslog {
+    type Writer = Line;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        Line {
+            syslog: self.0.clone(),
+            severity: SEVERITY_INFO,
+            buf: Vec::new(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        let severity = match *meta.level() {
+            Level::ERROR => 3,
+            Level::WARN => 4,
+            Level::INFO => SEVERITY_INFO,
+            Level::DEBUG | Level::TRACE => 7,
+        };
+        Line {
+            syslog: self.0.clone(),
+            severity,
+            buf: Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,146 @@
+//! systemd integration, idle unless started by systemd: the sockets passed by
+//! socket activation (`LISTEN_FDS`) are used rather than binding `server`, so
+//! that port 443 needs no privilege; `READY=1` is sent once listening, for
+//! `Type=notify` units; and the watchdog of `WatchdogSec=` is pet from the
+//! async runtime, so that a stuck server gets restarted
+
+use std::{
+    env,
+    net::SocketAddr,
+    process,
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+
+use socket2::{Socket, Type};
+use tokio::time;
+use tracing::{debug, warn};
+
+/// The sockets passed by socket activation and not taken yet
+static LISTEN_FDS: LazyLock<Mutex<Vec<Socket>>> = LazyLock::new(|| Mutex::new(listen_fds()));
+
+/// The first file descriptor passed, after stdin, stdout and stderr
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Whether the variable `name`, if set, names this process. The variables are
+/// inherited by the processes spawned, which must not act on them
+fn for_this_process(name: &str) -> bool {
+    env::var(name)
+        .ok()
+        .is_none_or(|pid| pid.parse() == Ok(process::id()))
+}
+
+#[cfg(unix)]
+fn listen_fds() -> Vec<Socket> {
+    use std::os::fd::FromRawFd;
+
+    if env::var_os("LISTEN_PID").is_none() || !for_this_process("LISTEN_PID") {
+        return Vec::new();
+    }
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<i32>().ok())
+        .unwrap_or(0);
+    (LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(count))
+        .filter_map(|fd| {
+            // SAFETY: systemd passes the descriptors from `LISTEN_FDS_START`
+            // on to this process only, nothing else owns them
+            let socket = unsafe { Socket::from_raw_fd(fd) };
+            // passed without close-on-exec, the commands run by `hooks` must
+            // not inherit them
+            match socket.set_cloexec(true) {
+                Ok(()) => Some(socket),
+                Err(err) => {
+                    warn!("[systemd] ignoring passed file descriptor {fd}: {err}");
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+fn listen_fds() -> Vec<Socket> {
+    Vec::new()
+}
+
+/// The passed socket of type `ty` bound to `addr`, if any
+pub fn take_socket(addr: SocketAddr, ty: Type) -> Option<Socket> {
+    let mut fds = LISTEN_FDS.lock().unwrap();
+    let idx = fds.iter().position(|socket| {
+        socket.r#type().is_ok_and(|socket_ty| socket_ty == ty)
+            && socket
+                .local_addr()
+                .is_ok_and(|local| local.as_socket() == Some(addr))
+    })?;
+    debug!("[systemd] using the passed socket bound to {addr}");
+    Some(fds.swap_remove(idx))
+}
+
+/// Close the passed sockets that no address of `server` took
+pub fn close_unused() {
+    for socket in LISTEN_FDS.lock().unwrap().drain(..) {
+        warn!(
+            "[systemd] closing the passed socket bound to {addr}, which isn't an address of \
+             `server`",
+            addr = match socket.local_addr().map(|addr| addr.as_socket()) {
+                Ok(Some(addr)) => addr.to_string(),
+                _ => "an unknown address".to_owned(),
+            }
+        );
+    }
+}
+
+/// Send `state` to the service manager, if it asked for notifications
+pub fn notify(state: &str) {
+    #[cfg(unix)]
+    if let Some(path) = env::var_os("NOTIFY_SOCKET")
+        && let Err(err) = send(&path, state)
+    {
+        debug!("[systemd] failed to send {state:?}: {err}");
+    }
+    #[cfg(not(unix))]
+    let _ = state;
+}
+
+#[cfg(unix)]
+fn send(path: &std::ffi::OsStr, state: &str) -> std::io::Result<usize> {
+    use std::os::unix::{ffi::OsStrExt, net::UnixDatagram};
+
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr as UnixAddr};
+
+            socket.send_to_addr(state.as_bytes(), &UnixAddr::from_abstract_name(name)?)
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "abstract socket addresses are only supported on Linux",
+        )),
+        None => socket.send_to(state.as_bytes(), path),
+    }
+}
+
+/// Pet the watchdog at half its timeout, if `WatchdogSec=` is set
+pub async fn watchdog() {
+    if !for_this_process("WATCHDOG_PID") {
+        return;
+    }
+    let Some(timeout) = env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse().ok())
+        .filter(|usec| *usec != 0)
+        .map(Duration::from_micros)
+    else {
+        return;
+    };
+    let mut interval = time::interval(timeout / 2);
+    loop {
+        interval.tick().await;
+        notify("WATCHDOG=1");
+    }
+}
@@ -0,0 +1,255 @@
+//! `tuic-server self-update`: replace the binary with the one of the latest
+//! release, once its detached Ed25519 signature checks out
+
+use std::{
+    env,
+    ffi::OsString,
+    fs::{self, File},
+    io::Write,
+    path::PathBuf,
+    time::Duration,
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use bytes::Bytes;
+use eyre::{Context, OptionExt, bail};
+use http_body_util::Full;
+use hyper::{
+    Request, Uri,
+    header::LOCATION,
+    http::uri::{Parts, Scheme},
+};
+use rustls::{ClientConfig as RustlsClientConfig, SignatureScheme};
+use serde::Deserialize;
+use tokio::time;
+use tracing::debug;
+
+use crate::http_client::Client;
+
+/// GitHub's API for the latest stable release of the repository
+const DEFAULT_FEED: &str = "https://api.github.com/repos/Itsusinn/tuic/releases/latest";
+const MAX_REDIRECTS: usize = 5;
+/// For each request, downloads included
+const TIMEOUT: Duration = Duration::from_secs(300);
+
+#[derive(Default)]
+pub struct UpdateArgs {
+    pub check_only: bool,
+    /// Base64 Ed25519 public key, defaults to the one built in
+    pub public_key: Option<String>,
+    pub feed: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Release {
+    tag_name: String,
+    assets: Vec<Asset>,
+}
+
+#[derive(Deserialize)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+/// Returns what was done, to be printed
+pub async fn run(args: UpdateArgs) -> eyre::Result<String> {
+    let client = Client::new()?;
+    let feed = args.feed.as_deref().unwrap_or(DEFAULT_FEED);
+    let release: Release =
+        serde_json::from_slice(&get(&client, feed).await?).context("malformed release feed")?;
+
+    let current = env!("CARGO_PKG_VERSION");
+    let latest = release
+        .tag_name
+        .trim_start_matches(|c: char| !c.is_ascii_digit());
+    if parse_version(latest)? <= parse_version(current)? {
+        return Ok(format!("tuic-server {current} is up to date"));
+    }
+    if args.check_only {
+        return Ok(format!(
+            "tuic-server {latest} is available, running {current}"
+        ));
+    }
+
+    let name = asset_name().ok_or_eyre("no release binaries are published for this platform")?;
+    let find = |name: &str| {
+        release
+            .assets
+            .iter()
+            .find(|asset| asset.name == name)
+            .ok_or_else(|| eyre::eyre!("release {} has no asset {name}", release.tag_name))
+    };
+    let binary = find(&name)?;
+    let signature = find(&format!("{name}.sig"))?;
+    let key = public_key(args.public_key.as_deref())?;
+
+    let binary = get(&client, &binary.browser_download_url).await?;
+    let signature = get(&client, &signature.browser_download_url).await?;
+    verify(&key, &binary, &signature)?;
+    let exe = install(&binary)?;
+    Ok(format!(
+        "updated {exe} from {current} to {latest}, restart the server to run it",
+        exe = exe.display()
+    ))
+}
+
+/// The numeric components of a `major.minor.patch` version, ignoring any
+/// pre-release or build suffix
+fn parse_version(version: &str) -> eyre::Result<Vec<u64>> {
+    version
+        .split(['-', '+'])
+        .next()
+        .unwrap_or_default()
+        .split('.')
+        .map(|part| part.parse())
+        .collect::<Result<_, _>>()
+        .with_context(|| format!("unrecognized version {version:?}"))
+}
+
+/// The name of this platform's binary in releases, as published by the CI
+fn asset_name() -> Option<String> {
+    let arch = match env::consts::ARCH {
+        "x86_64" | "aarch64" => env::consts::ARCH,
+        "x86" => "i686",
+        "arm" => "armv7",
+        "riscv64" => "riscv64gc",
+        _ => return None,
+    };
+    let os = match env::consts::OS {
+        "linux" => "linux",
+        "windows" => "windows",
+        "macos" => "darwin",
+        _ => return None,
+    };
+    let abi = match (cfg!(target_env = "musl"), cfg!(target_abi = "eabihf")) {
+        (true, true) => "-muslhf",
+        (true, false) => "-musl",
+        (false, true) => "-hf",
+        (false, false) => "",
+    };
+    Some(format!(
+        "tuic-server-{arch}-{os}{abi}{suffix}",
+        suffix = env::consts::EXE_SUFFIX
+    ))
+}
+
+fn public_key(arg: Option<&str>) -> eyre::Result<Vec<u8>> {
+    let key = arg.or(option_env!("TUIC_UPDATE_PUBLIC_KEY")).ok_or_eyre(
+        "no public key to verify releases with, pass --public-key or build with \
+         TUIC_UPDATE_PUBLIC_KEY set",
+    )?;
+    let key = STANDARD
+        .decode(key.trim())
+        .context("public key: invalid base64")?;
+    if key.len() != 32 {
+        bail!(
+            "public key: expected 32 bytes of Ed25519 key, got {}",
+            key.len()
+        );
+    }
+    Ok(key)
+}
+
+/// Checks `signature`, either raw or base64, of `msg` with the TLS backend's
+/// Ed25519 implementation
+fn verify(key: &[u8], msg: &[u8], signature: &[u8]) -> eyre::Result<()> {
+    let signature = if signature.len() == 64 {
+        signature.to_vec()
+    } else {
+        STANDARD
+            .decode(signature.trim_ascii())
+            .context("signature: invalid base64")?
+    };
+
+    let provider = RustlsClientConfig::builder().crypto_provider().clone();
+    let (_, algs) = provider
+        .signature_verification_algorithms
+        .mapping
+        .iter()
+        .find(|(scheme, _)| *scheme == SignatureScheme::ED25519)
+        .ok_or_eyre("the TLS backend can't verify Ed25519 signatures")?;
+    if !algs
+        .iter()
+        .any(|alg| alg.verify_signature(key, msg, &signature).is_ok())
+    {
+        bail!("signature mismatch, the download is corrupted or wasn't signed by the release key");
+    }
+    Ok(())
+}
+
+/// Writes `binary` next to the running executable and renames it over it, so
+/// that nothing is left half written if interrupted
+fn install(binary: &[u8]) -> eyre::Result<PathBuf> {
+    let exe = env::current_exe().context("failed to locate the running executable")?;
+    let with_suffix = |suffix: &str| {
+        let mut path = OsString::from(exe.as_os_str());
+        path.push(suffix);
+        PathBuf::from(path)
+    };
+    let new = with_suffix(".new");
+
+    let res = (|| {
+        let mut file = File::create(&new)?;
+        file.write_all(binary)?;
+        file.set_permissions(fs::metadata(&exe)?.permissions())?;
+        file.sync_all()?;
+        // a running executable can be renamed but not replaced on Windows
+        #[cfg(windows)]
+        {
+            let old = with_suffix(".old");
+            _ = fs::remove_file(&old);
+            fs::rename(&exe, &old)?;
+        }
+        fs::rename(&new, &exe)
+    })();
+    if let Err(err) = res {
+        _ = fs::remove_file(&new);
+        return Err(err).with_context(|| format!("failed to replace {}", exe.display()));
+    }
+    Ok(exe)
+}
+
+/// The body of `url`, following redirects
+async fn get(client: &Client, url: &str) -> eyre::Result<Bytes> {
+    let mut uri: Uri = url.parse().with_context(|| format!("invalid URL {url}"))?;
+    for _ in 0..=MAX_REDIRECTS {
+        if uri.scheme() != Some(&Scheme::HTTPS) {
+            bail!("{uri}: only HTTPS is supported");
+        }
+        let req = Request::get(uri.clone()).body(Full::default())?;
+        let res = time::timeout(TIMEOUT, client.send(req))
+            .await
+            .map_err(|_| eyre::eyre!("{uri}: timed out"))?
+            .with_context(|| format!("{uri}"))?;
+        if res.status().is_redirection() {
+            let location = res
+                .headers()
+                .get(LOCATION)
+                .ok_or_eyre("redirect without a location")?
+                .to_str()?;
+            debug!("[update] {uri} redirected to {location}");
+            uri = resolve(&uri, location)?;
+            continue;
+        }
+        if !res.status().is_success() {
+            bail!("{uri}: unexpected status {}", res.status());
+        }
+        return Ok(res.into_body());
+    }
+    bail!("{url}: too many redirects")
+}
+
+/// `location` relative to `base`, when it's only a path
+fn resolve(base: &Uri, location: &str) -> eyre::Result<Uri> {
+    if !location.starts_with('/') {
+        return location
+            .parse()
+            .with_context(|| format!("invalid redirect {location}"));
+    }
+    let mut parts = Parts::default();
+    parts.scheme = base.scheme().cloned();
+    parts.authority = base.authority().cloned();
+    parts.path_and_query = Some(location.parse()?);
+    Ok(Uri::from_parts(parts)?)
+}
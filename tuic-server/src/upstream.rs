@@ -0,0 +1,366 @@
+//! `[[outbound.proxies]]`: upstream SOCKS5 (RFC 1928) and HTTP proxies the
+//! destinations of `[[acl.rules]]` naming them are dialed through, instead of
+//! directly. Destinations are handed over unresolved, the proxy resolves them.
+//!
+//! TCP goes through SOCKS5 `CONNECT` or HTTP `CONNECT`, UDP through SOCKS5
+//! `UDP ASSOCIATE`, one association per UDP session and proxy
+
+use std::{
+    io::{Error as IoError, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use eyre::bail;
+use socket2::SockRef;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    time,
+};
+use tuic::Address;
+
+use crate::{
+    config::{OutboundConfig, OutboundProxy},
+    dial, dns,
+    error::Error,
+    utils::{self, ProxyProtocol},
+};
+
+const SOCKS5_VERSION: u8 = 5;
+const CMD_CONNECT: u8 = 1;
+const CMD_UDP_ASSOCIATE: u8 = 3;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+const METHOD_NONE: u8 = 0;
+const METHOD_PASSWORD: u8 = 2;
+const METHOD_UNACCEPTABLE: u8 = 0xff;
+/// HTTP proxies answering `CONNECT` with longer headers are given up on
+const MAX_HTTP_RESPONSE: usize = 8192;
+
+#[derive(Debug)]
+pub struct Proxy {
+    pub name: String,
+    protocol: ProxyProtocol,
+    host: String,
+    port: u16,
+    credentials: Option<(String, String)>,
+}
+
+impl Proxy {
+    pub fn parse(cfg: &OutboundProxy) -> eyre::Result<Self> {
+        let name = &cfg.name;
+        if name.is_empty() {
+            bail!("outbound.proxies: every proxy needs a `name`");
+        }
+        let (host, port) = cfg
+            .addr
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+            .ok_or_else(|| eyre::eyre!("outbound.proxies.{name}: `addr` must be host:port"))?;
+        let credentials = match (&cfg.username, &cfg.password) {
+            (Some(username), Some(password)) => {
+                if cfg.protocol == ProxyProtocol::Socks5
+                    && (username.len() > 255 || password.len() > 255)
+                {
+                    bail!(
+                        "outbound.proxies.{name}: SOCKS5 user names and passwords are at most 255 \
+                         bytes"
+                    );
+                }
+                Some((username.clone(), password.clone()))
+            }
+            (None, None) => None,
+            _ => bail!("outbound.proxies.{name}: set both `username` and `password`, or neither"),
+        };
+        Ok(Self {
+            name: name.clone(),
+            protocol: cfg.protocol,
+            host: host
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .to_owned(),
+            port,
+            credentials,
+        })
+    }
+
+    /// A TCP connection to `target` through the proxy
+    pub async fn connect(
+        &self,
+        target: &Address,
+        cfg: &OutboundConfig,
+    ) -> Result<TcpStream, Error> {
+        let mut stream = self.dial(cfg).await?;
+        let handshake = async {
+            match self.protocol {
+                ProxyProtocol::Socks5 => {
+                    self.socks5(&mut stream, CMD_CONNECT, target).await?;
+                }
+                ProxyProtocol::Http => self.http_connect(&mut stream, target).await?,
+            }
+            Ok::<_, Error>(())
+        };
+        with_timeout(cfg, handshake).await?;
+        Ok(stream)
+    }
+
+    /// A UDP association with the proxy: the connection keeping it open and
+    /// a socket connected to the relay, sending and receiving datagrams
+    /// wrapped by [`encapsulate`]
+    pub async fn associate(&self, cfg: &OutboundConfig) -> Result<(TcpStream, UdpSocket), Error> {
+        if self.protocol != ProxyProtocol::Socks5 {
+            return Err(Error::UpstreamProxy(
+                self.name.clone(),
+                "HTTP proxies don't relay UDP",
+            ));
+        }
+        let mut stream = self.dial(cfg).await?;
+        let proxy_addr = stream.peer_addr()?;
+        let unspecified = match proxy_addr {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let relay = with_timeout(
+            cfg,
+            self.socks5(
+                &mut stream,
+                CMD_UDP_ASSOCIATE,
+                &Address::SocketAddress(SocketAddr::new(unspecified, 0)),
+            ),
+        )
+        .await?;
+        let relay = match relay {
+            // at the address the proxy was reached on
+            Address::SocketAddress(addr) if addr.ip().is_unspecified() => {
+                SocketAddr::new(proxy_addr.ip(), addr.port())
+            }
+            Address::SocketAddress(addr) => addr,
+            _ => {
+                return Err(Error::UpstreamProxy(
+                    self.name.clone(),
+                    "UDP relay address isn't an IP address",
+                ));
+            }
+        };
+        let socket = UdpSocket::bind(SocketAddr::new(unspecified, 0)).await?;
+        utils::ignore_udp_resets(&SockRef::from(&socket))?;
+        socket.connect(relay).await?;
+        Ok((stream, socket))
+    }
+
+    /// Connect to the proxy itself, its addresses tried in turn
+    async fn dial(&self, cfg: &OutboundConfig) -> Result<TcpStream, Error> {
+        let addrs = match self.host.parse::<IpAddr>() {
+            Ok(ip) => vec![SocketAddr::new(ip, self.port)],
+            Err(_) => dns::lookup(&self.host, self.port).await?,
+        };
+        let mut last_err = None;
+        for addr in addrs {
+            match with_timeout(cfg, async { Ok(dial::connect(addr, cfg, false).await?) }).await {
+                Ok(stream) => {
+                    stream.set_nodelay(true)?;
+                    return Ok(stream);
+                }
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err
+            .unwrap_or_else(|| IoError::new(ErrorKind::NotFound, "no address resolved").into()))
+    }
+
+    /// Negotiate `cmd` for `target`, returning the address the proxy bound
+    async fn socks5(
+        &self,
+        stream: &mut TcpStream,
+        cmd: u8,
+        target: &Address,
+    ) -> Result<Address, Error> {
+        let method = if self.credentials.is_some() {
+            METHOD_PASSWORD
+        } else {
+            METHOD_NONE
+        };
+        stream.write_all(&[SOCKS5_VERSION, 1, method]).await?;
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply).await?;
+        if reply[0] != SOCKS5_VERSION {
+            return Err(self.error("not a SOCKS5 proxy"));
+        }
+        if reply[1] == METHOD_UNACCEPTABLE || reply[1] != method {
+            return Err(self.error("no acceptable authentication method"));
+        }
+
+        if let Some((username, password)) = &self.credentials {
+            // RFC 1929
+            let mut req = vec![1, username.len() as u8];
+            req.extend_from_slice(username.as_bytes());
+            req.push(password.len() as u8);
+            req.extend_from_slice(password.as_bytes());
+            stream.write_all(&req).await?;
+            stream.read_exact(&mut reply).await?;
+            if reply[1] != 0 {
+                return Err(self.error("authentication failed"));
+            }
+        }
+
+        let mut req = BytesMut::from(&[SOCKS5_VERSION, cmd, 0][..]);
+        write_addr(&mut req, target);
+        stream.write_all(&req).await?;
+        let mut head = [0; 4];
+        stream.read_exact(&mut head).await?;
+        if head[1] != 0 {
+            return Err(self.error(match head[1] {
+                2 => "connection not allowed by ruleset",
+                3 => "network unreachable",
+                4 => "host unreachable",
+                5 => "connection refused",
+                6 => "TTL expired",
+                7 => "command not supported",
+                8 => "address type not supported",
+                _ => "general failure",
+            }));
+        }
+        let len = match head[3] {
+            ATYP_IPV4 => 4,
+            ATYP_IPV6 => 16,
+            ATYP_DOMAIN => stream.read_u8().await? as usize,
+            _ => return Err(self.error("invalid bound address")),
+        };
+        let mut bound = vec![0; len + 2];
+        stream.read_exact(&mut bound).await?;
+        let mut buf = BytesMut::from(&[head[3]][..]);
+        if head[3] == ATYP_DOMAIN {
+            buf.put_u8(len as u8);
+        }
+        buf.extend_from_slice(&bound);
+        read_addr(&mut buf.freeze()).ok_or_else(|| self.error("invalid bound address"))
+    }
+
+    async fn http_connect(&self, stream: &mut TcpStream, target: &Address) -> Result<(), Error> {
+        let mut req = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+        if let Some((username, password)) = &self.credentials {
+            let credentials = STANDARD.encode(format!("{username}:{password}"));
+            req.push_str(&format!("Proxy-Authorization: Basic {credentials}\r\n"));
+        }
+        req.push_str("\r\n");
+        stream.write_all(req.as_bytes()).await?;
+
+        // byte by byte, as what follows the headers belongs to the target
+        let mut resp = Vec::new();
+        while !resp.ends_with(b"\r\n\r\n") {
+            if resp.len() == MAX_HTTP_RESPONSE {
+                return Err(self.error("response headers too long"));
+            }
+            resp.push(stream.read_u8().await?);
+        }
+        let status = resp
+            .split(|&b| b == b' ')
+            .nth(1)
+            .and_then(|status| std::str::from_utf8(status).ok())
+            .and_then(|status| status.parse::<u16>().ok())
+            .ok_or_else(|| self.error("malformed response"))?;
+        match status {
+            200..=299 => Ok(()),
+            407 => Err(self.error("authentication failed")),
+            403 => Err(self.error("connection not allowed")),
+            _ => Err(self.error("CONNECT refused")),
+        }
+    }
+
+    fn error(&self, reason: &'static str) -> Error {
+        Error::UpstreamProxy(self.name.clone(), reason)
+    }
+}
+
+async fn with_timeout<T>(
+    cfg: &OutboundConfig,
+    fut: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    match cfg.connect_timeout {
+        Some(timeout) => time::timeout(timeout, fut)
+            .await
+            .unwrap_or_else(|_| Err(IoError::new(ErrorKind::TimedOut, "connect timed out").into())),
+        None => fut.await,
+    }
+}
+
+/// Prefix `pkt` with the SOCKS5 UDP request header for `target`
+pub fn encapsulate(pkt: &[u8], target: &Address) -> Bytes {
+    let mut buf = BytesMut::with_capacity(pkt.len() + 262);
+    // reserved and fragment number, fragments aren't used
+    buf.put_slice(&[0, 0, 0]);
+    write_addr(&mut buf, target);
+    buf.put_slice(pkt);
+    buf.freeze()
+}
+
+/// The payload of a datagram from the relay and where it came from, `None`
+/// for a malformed or fragmented one
+pub fn decapsulate(mut datagram: Bytes) -> Option<(Bytes, Address)> {
+    if datagram.len() < 3 || datagram[2] != 0 {
+        return None;
+    }
+    datagram.advance(3);
+    let addr = read_addr(&mut datagram)?;
+    Some((datagram, addr))
+}
+
+fn write_addr(buf: &mut BytesMut, addr: &Address) {
+    match addr {
+        Address::SocketAddress(SocketAddr::V4(addr)) => {
+            buf.put_u8(ATYP_IPV4);
+            buf.put_slice(&addr.ip().octets());
+            buf.put_u16(addr.port());
+        }
+        Address::SocketAddress(SocketAddr::V6(addr)) => {
+            buf.put_u8(ATYP_IPV6);
+            buf.put_slice(&addr.ip().octets());
+            buf.put_u16(addr.port());
+        }
+        Address::DomainAddress(domain, port) => {
+            buf.put_u8(ATYP_DOMAIN);
+            buf.put_u8(domain.len() as u8);
+            buf.put_slice(domain.as_bytes());
+            buf.put_u16(*port);
+        }
+        Address::None => {
+            buf.put_u8(ATYP_IPV4);
+            buf.put_slice(&[0; 6]);
+        }
+    }
+}
+
+fn read_addr(buf: &mut Bytes) -> Option<Address> {
+    let atyp = *buf.first()?;
+    let len = match atyp {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN => *buf.get(1)? as usize + 1,
+        _ => return None,
+    };
+    if buf.len() < 1 + len + 2 {
+        return None;
+    }
+    buf.advance(1);
+    let addr = match atyp {
+        ATYP_IPV4 => {
+            let mut ip = [0; 4];
+            buf.copy_to_slice(&mut ip);
+            Address::SocketAddress(SocketAddr::new(ip.into(), buf.get_u16()))
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0; 16];
+            buf.copy_to_slice(&mut ip);
+            Address::SocketAddress(SocketAddr::new(ip.into(), buf.get_u16()))
+        }
+        _ => {
+            let len = buf.get_u8() as usize;
+            let domain = String::from_utf8(buf.split_to(len).to_vec()).ok()?;
+            Address::DomainAddress(domain, buf.get_u16())
+        }
+    };
+    Some(addr)
+}
@@ -0,0 +1,185 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+
+use arc_swap::ArcSwap;
+use tokio::sync::watch;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+    config::{Config, UserOverrides},
+    users_db,
+    utils::UserPasswords,
+};
+
+/// The user table, replaced as a whole when reloaded
+static USERS: LazyLock<watch::Sender<Arc<HashMap<Uuid, UserPasswords>>>> =
+    LazyLock::new(|| watch::Sender::new(Arc::default()));
+/// `priority_users`
+static PRIORITY: LazyLock<ArcSwap<HashSet<Uuid>>> = LazyLock::new(ArcSwap::default);
+/// `user_overrides`
+static OVERRIDES: LazyLock<ArcSwap<HashMap<Uuid, UserOverrides>>> = LazyLock::new(ArcSwap::default);
+
+/// The limits a user is held to, its `user_overrides` applied over the global
+/// settings
+#[derive(Clone, Copy)]
+pub struct UserPolicy {
+    pub priority: bool,
+    pub max_connection_lifetime: Duration,
+    pub per_connection_traffic_quota: u64,
+    pub max_udp_sessions_per_connection: usize,
+    pub max_concurrent_streams: usize,
+    pub max_concurrent_dials: usize,
+    pub udp_relay: bool,
+    /// Bytes relayed across connections until `/reset_traffic`, `0` for no
+    /// quota
+    pub quota: u64,
+}
+
+pub fn init(users: HashMap<Uuid, UserPasswords>) {
+    USERS.send_replace(Arc::new(users));
+}
+
+/// The whole user table
+pub fn snapshot() -> Arc<HashMap<Uuid, UserPasswords>> {
+    USERS.borrow().clone()
+}
+
+pub fn passwords(uuid: &Uuid) -> Option<UserPasswords> {
+    USERS.borrow().get(uuid).cloned()
+}
+
+/// The label of the first password of the user `validate` accepts, `None`
+/// inside if it is the single unlabelled one. `None` if none is accepted
+pub fn verify(uuid: &Uuid, validate: impl Fn(&str) -> bool) -> Option<Option<String>> {
+    let users = USERS.borrow();
+    let (label, _) = users
+        .get(uuid)?
+        .iter()
+        .find(|(_, password)| validate(password))?;
+    Some(label.map(str::to_owned))
+}
+
+/// Replace the users whose connections skip the limits meant to protect the
+/// server from the others
+pub fn set_priority(users: &[Uuid]) {
+    PRIORITY.store(Arc::new(users.iter().copied().collect()));
+}
+
+pub fn is_priority(uuid: &Uuid) -> bool {
+    OVERRIDES
+        .load()
+        .get(uuid)
+        .and_then(|overrides| overrides.priority)
+        .unwrap_or_else(|| PRIORITY.load().contains(uuid))
+}
+
+/// Replace `user_overrides`, affecting the connections already open
+pub fn set_overrides(overrides: HashMap<Uuid, UserOverrides>) {
+    OVERRIDES.store(Arc::new(overrides));
+}
+
+pub fn has_overrides() -> bool {
+    !OVERRIDES.load().is_empty()
+}
+
+/// The policy of `user`, the global settings of `cfg` for unauthenticated
+/// connections. The quota stored in `users_db` wins over `user_overrides`
+pub fn policy(cfg: &Config, user: Option<Uuid>) -> UserPolicy {
+    let stored_quota = user.and_then(|uuid| users_db::quota(&uuid));
+    let global = UserPolicy {
+        priority: user.is_some_and(|uuid| is_priority(&uuid)),
+        max_connection_lifetime: cfg.max_connection_lifetime,
+        per_connection_traffic_quota: cfg.per_connection_traffic_quota,
+        max_udp_sessions_per_connection: cfg.max_udp_sessions_per_connection,
+        max_concurrent_streams: cfg.max_concurrent_streams_per_user,
+        max_concurrent_dials: cfg.outbound.max_concurrent_dials_per_user,
+        udp_relay: true,
+        quota: stored_quota.unwrap_or(0),
+    };
+    let overrides = OVERRIDES.load();
+    let Some(overrides) = user.and_then(|uuid| overrides.get(&uuid)) else {
+        return global;
+    };
+    UserPolicy {
+        max_connection_lifetime: overrides
+            .max_connection_lifetime
+            .unwrap_or(global.max_connection_lifetime),
+        per_connection_traffic_quota: overrides
+            .per_connection_traffic_quota
+            .unwrap_or(global.per_connection_traffic_quota),
+        max_udp_sessions_per_connection: overrides
+            .max_udp_sessions_per_connection
+            .unwrap_or(global.max_udp_sessions_per_connection),
+        max_concurrent_streams: overrides
+            .max_concurrent_streams
+            .unwrap_or(global.max_concurrent_streams),
+        max_concurrent_dials: overrides
+            .max_concurrent_dials
+            .unwrap_or(global.max_concurrent_dials),
+        udp_relay: overrides.udp_relay.unwrap_or(global.udp_relay),
+        quota: stored_quota
+            .or(overrides.quota.map(|quota| quota.0))
+            .unwrap_or(0),
+        ..global
+    }
+}
+
+pub fn is_empty() -> bool {
+    USERS.borrow().is_empty()
+}
+
+/// Receive every new version of the user table
+pub fn subscribe() -> watch::Receiver<Arc<HashMap<Uuid, UserPasswords>>> {
+    USERS.subscribe()
+}
+
+/// Add a user, `false` if the UUID is taken
+pub fn add(uuid: Uuid, passwords: UserPasswords) -> bool {
+    USERS.send_if_modified(|users| {
+        if users.contains_key(&uuid) {
+            return false;
+        }
+        Arc::make_mut(users).insert(uuid, passwords);
+        true
+    })
+}
+
+/// Remove a user, `false` if there is no such user
+pub fn remove(uuid: &Uuid) -> bool {
+    USERS.send_if_modified(|users| {
+        if !users.contains_key(uuid) {
+            return false;
+        }
+        Arc::make_mut(users).remove(uuid);
+        true
+    })
+}
+
+/// Change the passwords of a user, `false` if there is no such user
+pub fn set_passwords(uuid: &Uuid, passwords: UserPasswords) -> bool {
+    USERS.send_if_modified(|users| match Arc::make_mut(users).get_mut(uuid) {
+        Some(old) => {
+            *old = passwords;
+            true
+        }
+        None => false,
+    })
+}
+
+/// Swap in a reloaded user table. Connections authenticated with a password
+/// that changed or got removed learn about it through [`subscribe`].
+pub fn replace(users: HashMap<Uuid, UserPasswords>) {
+    let old = USERS.send_replace(Arc::new(users));
+    let new = USERS.borrow();
+    let added = new.keys().filter(|uuid| !old.contains_key(uuid)).count();
+    let removed = old.keys().filter(|uuid| !new.contains_key(uuid)).count();
+    let changed = old
+        .iter()
+        .filter(|(uuid, passwords)| new.get(uuid).is_some_and(|new| new != *passwords))
+        .count();
+    info!("[users] reloaded: {added} added, {removed} removed, {changed} password changed");
+}
@@ -0,0 +1,293 @@
+//! `users_db`: users kept in an SQLite file managed by the server, for
+//! deployments with too many users to keep in the config file. They are
+//! loaded over `users` on start and reload, and changed through the RESTful
+//! `/users` endpoints, along with their expiry dates and traffic quotas
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, LazyLock, OnceLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use arc_swap::ArcSwap;
+use serde::Serialize;
+use tracing::{info, warn};
+use uuid::Uuid;
+
+use crate::{users, utils::UserPasswords};
+
+/// How often users past their expiry date are looked for
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[cfg(feature = "sqlite")]
+static DB: OnceLock<imp::Db> = OnceLock::new();
+#[cfg(not(feature = "sqlite"))]
+static DB: OnceLock<()> = OnceLock::new();
+/// The quotas stored, kept in memory as they are checked every second
+static QUOTAS: LazyLock<ArcSwap<HashMap<Uuid, u64>>> = LazyLock::new(ArcSwap::default);
+
+#[derive(Clone, Serialize)]
+pub struct StoredUser {
+    pub uuid: Uuid,
+    pub password: UserPasswords,
+    /// Seconds since the Unix epoch
+    pub expires_at: Option<u64>,
+    /// Bytes, replaces the `quota` of `user_overrides`
+    pub quota: Option<u64>,
+}
+
+impl StoredUser {
+    pub fn expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// Seconds since the Unix epoch, the unit of `expires_at`
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Open `users_db`, creating it if needed, and return the users to load over
+/// `users`
+pub fn init(path: Option<&Path>) -> eyre::Result<HashMap<Uuid, UserPasswords>> {
+    let Some(path) = path else {
+        return Ok(HashMap::new());
+    };
+    // read before serving anything, blocking is fine
+    #[cfg(feature = "sqlite")]
+    {
+        let db = imp::Db::open(path)?;
+        let stored = db.list()?;
+        _ = DB.set(db);
+        Ok(active(stored))
+    }
+    #[cfg(not(feature = "sqlite"))]
+    {
+        Err(eyre::eyre!(
+            "users_db {path}: built without the sqlite feature",
+            path = path.display()
+        ))
+    }
+}
+
+pub fn enabled() -> bool {
+    DB.get().is_some()
+}
+
+/// The users stored that haven't expired
+pub async fn load() -> eyre::Result<HashMap<Uuid, UserPasswords>> {
+    Ok(active(list().await?))
+}
+
+/// Remember the quotas of `stored` and keep the users that haven't expired
+fn active(stored: Vec<StoredUser>) -> HashMap<Uuid, UserPasswords> {
+    let now = unix_now();
+    QUOTAS.store(Arc::new(
+        stored
+            .iter()
+            .filter_map(|user| Some((user.uuid, user.quota?)))
+            .collect(),
+    ));
+    stored
+        .into_iter()
+        .filter(|user| !user.expired(now))
+        .map(|user| (user.uuid, user.password))
+        .collect()
+}
+
+/// Run `f` on a blocking thread, as SQLite waits on the disk. `None` without
+/// `users_db`
+#[cfg(feature = "sqlite")]
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce(&'static imp::Db) -> eyre::Result<T> + Send + 'static,
+) -> Option<eyre::Result<T>> {
+    let db = DB.get()?;
+    Some(
+        tokio::task::spawn_blocking(move || f(db))
+            .await
+            .unwrap_or_else(|err| Err(eyre::eyre!("users_db: {err}"))),
+    )
+}
+
+/// Every user stored, expired ones included
+pub async fn list() -> eyre::Result<Vec<StoredUser>> {
+    #[cfg(feature = "sqlite")]
+    if let Some(res) = blocking(|db| db.list()).await {
+        return res;
+    }
+    Ok(Vec::new())
+}
+
+pub async fn get(uuid: &Uuid) -> eyre::Result<Option<StoredUser>> {
+    #[cfg(feature = "sqlite")]
+    {
+        let uuid = *uuid;
+        if let Some(res) = blocking(move |db| db.get(&uuid)).await {
+            return res;
+        }
+    }
+    _ = uuid;
+    Ok(None)
+}
+
+pub fn quota(uuid: &Uuid) -> Option<u64> {
+    QUOTAS.load().get(uuid).copied()
+}
+
+/// Insert or replace a user
+pub async fn put(user: &StoredUser) -> eyre::Result<()> {
+    #[cfg(feature = "sqlite")]
+    {
+        let stored = user.clone();
+        if let Some(res) = blocking(move |db| db.put(&stored)).await {
+            res?;
+            QUOTAS.rcu(|quotas| {
+                let mut quotas = HashMap::clone(quotas);
+                match user.quota {
+                    Some(quota) => quotas.insert(user.uuid, quota),
+                    None => quotas.remove(&user.uuid),
+                };
+                quotas
+            });
+            return Ok(());
+        }
+    }
+    _ = user;
+    Err(eyre::eyre!("users_db isn't configured"))
+}
+
+/// Delete a user, `false` if there is no such user
+pub async fn remove(uuid: &Uuid) -> eyre::Result<bool> {
+    #[cfg(feature = "sqlite")]
+    {
+        let key = *uuid;
+        if let Some(res) = blocking(move |db| db.remove(&key)).await {
+            let deleted = res?;
+            QUOTAS.rcu(|quotas| {
+                let mut quotas = HashMap::clone(quotas);
+                quotas.remove(uuid);
+                quotas
+            });
+            return Ok(deleted);
+        }
+    }
+    _ = uuid;
+    Ok(false)
+}
+
+/// Remove the users reaching their expiry date from the user table. They stay
+/// in the database, to be renewed through the RESTful `/users` endpoint
+pub async fn start() {
+    if !enabled() {
+        return;
+    }
+    let mut interval = tokio::time::interval(EXPIRY_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let stored = match list().await {
+            Ok(stored) => stored,
+            Err(err) => {
+                warn!("[users_db] failed to read the users: {err:#}");
+                continue;
+            }
+        };
+        let now = unix_now();
+        for user in stored.iter().filter(|user| user.expired(now)) {
+            if users::remove(&user.uuid) {
+                info!("[users_db] {uuid} expired", uuid = user.uuid);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "sqlite")]
+mod imp {
+    use std::{path::Path, sync::Mutex};
+
+    use eyre::Context;
+    use rusqlite::{Connection, Row, params};
+    use uuid::Uuid;
+
+    use super::StoredUser;
+
+    pub struct Db(Mutex<Connection>);
+
+    impl Db {
+        pub fn open(path: &Path) -> eyre::Result<Self> {
+            let conn = Connection::open(path)
+                .with_context(|| format!("users_db: failed to open {}", path.display()))?;
+            conn.execute_batch(
+                "PRAGMA journal_mode = WAL;
+                 CREATE TABLE IF NOT EXISTS users (
+                     uuid TEXT PRIMARY KEY NOT NULL,
+                     password TEXT NOT NULL,
+                     expires_at INTEGER,
+                     quota INTEGER
+                 );",
+            )
+            .context("users_db: failed to create the users table")?;
+            Ok(Self(Mutex::new(conn)))
+        }
+
+        pub fn list(&self) -> eyre::Result<Vec<StoredUser>> {
+            let conn = self.0.lock().unwrap();
+            let mut stmt =
+                conn.prepare("SELECT uuid, password, expires_at, quota FROM users ORDER BY uuid")?;
+            let rows = stmt.query_and_then([], user)?;
+            rows.collect()
+        }
+
+        pub fn get(&self, uuid: &Uuid) -> eyre::Result<Option<StoredUser>> {
+            let conn = self.0.lock().unwrap();
+            let mut stmt = conn
+                .prepare("SELECT uuid, password, expires_at, quota FROM users WHERE uuid = ?1")?;
+            let mut rows = stmt.query_and_then([uuid.to_string()], user)?;
+            rows.next().transpose()
+        }
+
+        pub fn put(&self, user: &StoredUser) -> eyre::Result<()> {
+            self.0.lock().unwrap().execute(
+                "INSERT INTO users (uuid, password, expires_at, quota) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (uuid) DO UPDATE
+                 SET password = excluded.password, expires_at = excluded.expires_at,
+                     quota = excluded.quota",
+                params![
+                    user.uuid.to_string(),
+                    serde_json::to_string(&user.password)?,
+                    user.expires_at.map(|secs| secs as i64),
+                    user.quota.map(|bytes| bytes as i64),
+                ],
+            )?;
+            Ok(())
+        }
+
+        pub fn remove(&self, uuid: &Uuid) -> eyre::Result<bool> {
+            let deleted = self
+                .0
+                .lock()
+                .unwrap()
+                .execute("DELETE FROM users WHERE uuid = ?1", [uuid.to_string()])?;
+            Ok(deleted != 0)
+        }
+    }
+
+    fn user(row: &Row<'_>) -> eyre::Result<StoredUser> {
+        let uuid: String = row.get(0)?;
+        let password: String = row.get(1)?;
+        let expires_at: Option<i64> = row.get(2)?;
+        let quota: Option<i64> = row.get(3)?;
+        Ok(StoredUser {
+            uuid: uuid
+                .parse()
+                .with_context(|| format!("users_db: invalid UUID {uuid:?}"))?,
+            password: serde_json::from_str(&password)
+                .with_context(|| format!("users_db: invalid password of {uuid}"))?,
+            expires_at: expires_at.map(|secs| secs as u64),
+            quota: quota.map(|bytes| bytes as u64),
+        })
+    }
+}
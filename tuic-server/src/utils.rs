@@ -1,14 +1,27 @@
 use std::{
+    collections::BTreeMap,
     fmt::{Display, Formatter, Result as FmtResult},
     fs,
+    io::Result as IoResult,
+    net::{IpAddr, SocketAddr},
     path::Path,
     str::FromStr,
+    sync::Arc,
+    time::Duration,
 };
 
 use educe::Educe;
 use eyre::Context;
-use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
-use serde::{Deserialize, Serialize};
+#[cfg(feature = "aws-lc-rs")]
+use rustls::crypto::aws_lc_rs::Ticketer;
+#[cfg(all(feature = "ring", not(feature = "aws-lc-rs")))]
+use rustls::crypto::ring::Ticketer;
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+    server::ProducesTickets,
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error as DeError};
+use socket2::Socket;
 
 pub fn load_cert_chain(cert_path: &Path) -> eyre::Result<Vec<CertificateDer<'static>>> {
     let cert_chain = fs::read(cert_path).context("failed to read certificate chain")?;
@@ -34,6 +47,44 @@ pub fn load_priv_key(key_path: &Path) -> eyre::Result<PrivateKeyDer<'static>> {
     Ok(key)
 }
 
+/// Stateless session tickets whose keys rotate every 6 hours, advertising a
+/// custom lifetime to clients
+#[derive(Debug)]
+pub struct SessionTicketer {
+    inner: Arc<dyn ProducesTickets>,
+    lifetime: u32,
+}
+
+impl SessionTicketer {
+    /// Tickets stay decryptable for at least one key rotation period
+    pub const MAX_LIFETIME: Duration = Duration::from_secs(6 * 60 * 60);
+
+    pub fn new(lifetime: Duration) -> Result<Arc<Self>, rustls::Error> {
+        Ok(Arc::new(Self {
+            inner: Ticketer::new()?,
+            lifetime: lifetime.min(Self::MAX_LIFETIME).as_secs() as u32,
+        }))
+    }
+}
+
+impl ProducesTickets for SessionTicketer {
+    fn enabled(&self) -> bool {
+        self.inner.enabled()
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.lifetime
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.inner.encrypt(plain)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        self.inner.decrypt(cipher)
+    }
+}
+
 #[derive(Clone, Copy)]
 pub enum UdpRelayMode {
     Native,
@@ -60,6 +111,506 @@ pub enum CongestionController {
     NewReno,
 }
 
+/// How much of the relay destinations is written to the logs
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+#[derive(Educe)]
+#[educe(Default)]
+pub enum LogDestinations {
+    #[educe(Default)]
+    Full,
+    /// Domains without port, IP addresses redacted
+    DomainOnly,
+    /// Everything redacted
+    None,
+}
+
+/// Where the logs are written
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[derive(Educe)]
+#[educe(Default)]
+pub enum LogOutput {
+    #[educe(Default)]
+    Stdout,
+    /// RFC 5424 messages to the local syslog socket, or a remote UDP server
+    Syslog,
+}
+
+/// The syslog facility the logs are sent with
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[derive(Educe)]
+#[educe(Default)]
+pub enum SyslogFacility {
+    User,
+    #[educe(Default)]
+    Daemon,
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+impl SyslogFacility {
+    pub fn code(self) -> u8 {
+        match self {
+            Self::User => 1,
+            Self::Daemon => 3,
+            Self::Local0 => 16,
+            Self::Local1 => 17,
+            Self::Local2 => 18,
+            Self::Local3 => 19,
+            Self::Local4 => 20,
+            Self::Local5 => 21,
+            Self::Local6 => 22,
+            Self::Local7 => 23,
+        }
+    }
+}
+
+/// Whether an ACL rule lets a destination through
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+#[derive(Educe)]
+#[educe(Default)]
+pub enum AclAction {
+    #[educe(Default)]
+    Allow,
+    Deny,
+}
+
+/// Whether destinations are open unless the ACL denies them, or denied unless
+/// listed in `egress_allowlist`
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[derive(Educe)]
+#[educe(Default)]
+pub enum EgressMode {
+    #[educe(Default)]
+    Open,
+    Allowlist,
+}
+
+/// What to do when an already authenticated connection sends `Authenticate`
+/// again, which some clients do after their 0-RTT data got rejected
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[derive(Educe)]
+#[educe(Default)]
+pub enum DuplicateAuthPolicy {
+    /// Close the connection
+    #[educe(Default)]
+    Close,
+    /// Accept the command silently if it carries the same, valid credentials
+    Ignore,
+}
+
+/// What counts towards an `abuse` rule
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "snake_case")]
+#[derive(Educe)]
+#[educe(Default)]
+pub enum AbuseEvent {
+    /// A destination denied by the ACL
+    #[educe(Default)]
+    AclDeny,
+    /// A destination on the blocklist
+    BlocklistHit,
+    /// Credentials that didn't match
+    AuthFailure,
+}
+
+impl Display for AbuseEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::AclDeny => write!(f, "acl_deny"),
+            Self::BlocklistHit => write!(f, "blocklist_hit"),
+            Self::AuthFailure => write!(f, "auth_failure"),
+        }
+    }
+}
+
+/// What an `abuse` rule does once triggered
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+#[derive(Educe)]
+#[educe(Default)]
+pub enum AbuseAction {
+    /// Refuse the user's authentication and close their connections
+    #[educe(Default)]
+    DisableUser,
+    /// Refuse connections from the source IP and close the ones open
+    BanIp,
+}
+
+impl Display for AbuseAction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::DisableUser => write!(f, "disable_user"),
+            Self::BanIp => write!(f, "ban_ip"),
+        }
+    }
+}
+
+/// What is done about a user detected scanning ports
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+#[derive(Educe)]
+#[educe(Default)]
+pub enum ScanAction {
+    /// Dial the user's targets one at a time, each after
+    /// `port_scan.throttle_delay`
+    #[educe(Default)]
+    Throttle,
+    /// Only report it
+    Flag,
+}
+
+impl Display for ScanAction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Throttle => write!(f, "throttle"),
+            Self::Flag => write!(f, "flag"),
+        }
+    }
+}
+
+/// How relay destinations are resolved
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[derive(Educe)]
+#[educe(Default)]
+pub enum DnsProtocol {
+    /// The system resolver, `getaddrinfo` on Unix
+    #[educe(Default)]
+    System,
+    Udp,
+    Tcp,
+    /// DNS over TLS
+    Tls,
+    /// DNS over HTTPS
+    Https,
+}
+
+/// The protocol of an `[[outbound.proxies]]` entry
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+#[derive(Educe)]
+#[educe(Default)]
+pub enum ProxyProtocol {
+    /// Relays TCP and UDP
+    #[educe(Default)]
+    Socks5,
+    /// `CONNECT`, TCP only
+    Http,
+}
+
+/// An inclusive range of ports, written as `"40000-50000"`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    /// How many ports there are
+    pub fn count(&self) -> u32 {
+        u32::from(self.end - self.start) + 1
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once('-').unwrap_or((s, s));
+        let parse = |port: &str| {
+            port.trim()
+                .parse::<u16>()
+                .map_err(|_| format!("invalid port range `{s}`"))
+        };
+        let (start, end) = (parse(start)?, parse(end)?);
+        if start == 0 || start > end {
+            return Err(format!(
+                "invalid port range `{s}`, expected `START-END` with 0 < START <= END"
+            ));
+        }
+        Ok(Self { start, end })
+    }
+}
+
+impl Display for PortRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+impl Serialize for PortRange {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for PortRange {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(DeError::custom)
+    }
+}
+
+/// A number of bytes, written as an integer or with a unit, `"100GB"` or
+/// `"512 MiB"`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ByteSize(pub u64);
+
+impl ByteSize {
+    /// Largest first, so that sizes are displayed in the largest unit dividing
+    /// them
+    const UNITS: &[(&str, u64)] = &[
+        ("TiB", 1 << 40),
+        ("TB", 1_000_000_000_000),
+        ("GiB", 1 << 30),
+        ("GB", 1_000_000_000),
+        ("MiB", 1 << 20),
+        ("MB", 1_000_000),
+        ("KiB", 1 << 10),
+        ("KB", 1_000),
+        ("B", 1),
+    ];
+}
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let split = s
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let unit = unit.trim();
+        let factor = if unit.is_empty() {
+            1
+        } else {
+            Self::UNITS
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(unit))
+                .map(|(_, factor)| *factor)
+                .ok_or_else(|| format!("invalid size `{s}`, unknown unit `{unit}`"))?
+        };
+        let number: f64 = number.parse().map_err(|_| {
+            format!("invalid size `{s}`, expected a number with a unit like `100GB`")
+        })?;
+        let bytes = number * factor as f64;
+        if bytes >= u64::MAX as f64 {
+            return Err(format!("invalid size `{s}`, too large"));
+        }
+        Ok(Self(bytes as u64))
+    }
+}
+
+impl Display for ByteSize {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let (name, factor) = Self::UNITS
+            .iter()
+            .find(|(_, factor)| self.0 != 0 && self.0 % factor == 0)
+            .unwrap_or(&("B", 1));
+        write!(f, "{}{name}", self.0 / factor)
+    }
+}
+
+impl Serialize for ByteSize {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteSize {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Bytes(u64),
+            Text(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Bytes(bytes) => Ok(Self(bytes)),
+            Raw::Text(text) => text.parse().map_err(DeError::custom),
+        }
+    }
+}
+
+/// An address the server listens on, `"[::]:443"`, or on every port of a
+/// range for port hopping, `"[::]:20000-20100"`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ListenAddr {
+    pub ip: IpAddr,
+    pub ports: PortRange,
+}
+
+impl ListenAddr {
+    /// One socket address per port
+    pub fn iter(&self) -> impl Iterator<Item = SocketAddr> {
+        let ip = self.ip;
+        (self.ports.start..=self.ports.end).map(move |port| SocketAddr::new(ip, port))
+    }
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ip, ports) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("invalid listen address `{s}`, expected `IP:PORT`"))?;
+        let ip = ip
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse()
+            .map_err(|_| format!("invalid IP address in listen address `{s}`"))?;
+        Ok(Self {
+            ip,
+            ports: ports.parse()?,
+        })
+    }
+}
+
+impl Display for ListenAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self.ip {
+            IpAddr::V4(ip) => write!(f, "{ip}:")?,
+            IpAddr::V6(ip) => write!(f, "[{ip}]:")?,
+        }
+        if self.ports.start == self.ports.end {
+            write!(f, "{}", self.ports.start)
+        } else {
+            write!(f, "{}", self.ports)
+        }
+    }
+}
+
+impl From<SocketAddr> for ListenAddr {
+    fn from(addr: SocketAddr) -> Self {
+        Self {
+            ip: addr.ip(),
+            ports: PortRange {
+                start: addr.port(),
+                end: addr.port(),
+            },
+        }
+    }
+}
+
+/// What `server` is set to, a single address or a list of them
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ListenAddrs(pub Vec<ListenAddr>);
+
+impl ListenAddrs {
+    /// Every socket address to listen on, port ranges expanded
+    pub fn iter(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.0.iter().flat_map(ListenAddr::iter)
+    }
+
+    /// The port of the first address, the one clients are given by default
+    pub fn port(&self) -> u16 {
+        self.0.first().map_or(443, |addr| addr.ports.start)
+    }
+}
+
+impl From<SocketAddr> for ListenAddrs {
+    fn from(addr: SocketAddr) -> Self {
+        Self(vec![addr.into()])
+    }
+}
+
+impl Serialize for ListenAddrs {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0.as_slice() {
+            [addr] => serializer.collect_str(addr),
+            addrs => serializer.collect_seq(addrs.iter().map(ToString::to_string)),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ListenAddrs {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum OneOrMany {
+            One(String),
+            Many(Vec<String>),
+        }
+
+        let addrs = match OneOrMany::deserialize(deserializer)? {
+            OneOrMany::One(addr) => vec![addr],
+            OneOrMany::Many(addrs) => addrs,
+        };
+        if addrs.is_empty() {
+            return Err(DeError::custom("list at least one address to listen on"));
+        }
+        addrs
+            .iter()
+            .map(|addr| addr.parse().map_err(DeError::custom))
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+/// The password of a user, or several labelled ones, e.g. one per device or
+/// the old and new one during a rotation:
+/// `{ phone = "PASSWORD_1", laptop = "PASSWORD_2" }`
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+#[serde(untagged)]
+pub enum UserPasswords {
+    Single(String),
+    Labelled(BTreeMap<String, String>),
+}
+
+impl UserPasswords {
+    /// Every password and its label, labels in order
+    pub fn iter(&self) -> impl Iterator<Item = (Option<&str>, &str)> {
+        let (single, labelled) = match self {
+            Self::Single(password) => (Some((None, password.as_str())), None),
+            Self::Labelled(passwords) => (None, Some(passwords)),
+        };
+        single.into_iter().chain(
+            labelled
+                .into_iter()
+                .flatten()
+                .map(|(label, password)| (Some(label.as_str()), password.as_str())),
+        )
+    }
+
+    /// The password with `label`, the single password has none
+    pub fn get(&self, label: Option<&str>) -> Option<&str> {
+        self.iter()
+            .find(|(this, _)| *this == label)
+            .map(|(_, password)| password)
+    }
+
+    /// The password shared with clients unless a label is asked for: the
+    /// single one, or the first label in order
+    pub fn primary(&self) -> Option<&str> {
+        self.iter().next().map(|(_, password)| password)
+    }
+
+    /// Whether there is no password at all, or an empty one
+    pub fn has_empty(&self) -> bool {
+        self.primary().is_none() || self.iter().any(|(_, password)| password.is_empty())
+    }
+}
+
+impl From<String> for UserPasswords {
+    fn from(password: String) -> Self {
+        Self::Single(password)
+    }
+}
+
 // TODO remove in 2.0.0
 impl FromStr for CongestionController {
     type Err = &'static str;
@@ -111,3 +662,43 @@ where
         }
     }
 }
+
+/// Keep the ICMP errors caused by earlier sends on a UDP socket from failing
+/// its next read, as Windows does even for unconnected sockets, with
+/// `WSAECONNRESET` for port unreachable and `WSAENETRESET` for TTL expired.
+/// Elsewhere only connected sockets get them
+#[cfg(windows)]
+pub fn ignore_udp_resets(socket: &Socket) -> IoResult<()> {
+    use std::{ffi::c_void, io::Error as IoError, mem, os::windows::io::AsRawSocket, ptr};
+
+    use windows_sys::Win32::Networking::WinSock::{
+        SIO_UDP_CONNRESET, SIO_UDP_NETRESET, SOCKET, SOCKET_ERROR, WSAIoctl,
+    };
+
+    for code in [SIO_UDP_CONNRESET, SIO_UDP_NETRESET] {
+        let enable: i32 = 0;
+        let mut returned = 0;
+        let res = unsafe {
+            WSAIoctl(
+                socket.as_raw_socket() as SOCKET,
+                code,
+                ptr::from_ref(&enable).cast::<c_void>(),
+                mem::size_of_val(&enable) as u32,
+                ptr::null_mut(),
+                0,
+                &mut returned,
+                ptr::null_mut(),
+                None,
+            )
+        };
+        if res == SOCKET_ERROR {
+            return Err(IoError::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+pub fn ignore_udp_resets(_socket: &Socket) -> IoResult<()> {
+    Ok(())
+}
@@ -0,0 +1,122 @@
+use std::{collections::HashMap, path::Path};
+
+use uuid::Uuid;
+
+use crate::{config::Config, old_config::ConfigError};
+
+/// Passwords and secrets shorter than this are easy to brute-force
+const MIN_SECRET_LEN: usize = 8;
+
+/// Placeholders written by `--init`
+const PLACEHOLDER_PASSWORD: &str = "YOUR_USER_PASSWD_HERE";
+const PLACEHOLDER_SECRET: &str = "YOUR_SECRET_HERE";
+
+enum Issue {
+    /// The user, the label of the password if it has several, the issue
+    Password(Uuid, Option<String>, String),
+    Secret(String),
+}
+
+fn issues(cfg: &Config) -> Vec<Issue> {
+    let mut issues = Vec::new();
+
+    let mut users = cfg.users.iter().collect::<Vec<_>>();
+    users.sort_unstable_by_key(|(uuid, _)| **uuid);
+    let mut seen = HashMap::new();
+    let passwords = users.into_iter().flat_map(|(uuid, passwords)| {
+        passwords
+            .iter()
+            .map(move |(label, password)| (uuid, label, password))
+    });
+    for (uuid, label, password) in passwords {
+        let issue = if password.is_empty() {
+            "has an empty password".into()
+        } else if password == PLACEHOLDER_PASSWORD {
+            "has the placeholder password".into()
+        } else if password.chars().count() < MIN_SECRET_LEN {
+            format!("has a password shorter than {MIN_SECRET_LEN} characters")
+        } else if let Some(first) = seen.insert(password, *uuid) {
+            // keep the first owner, so only the reusing users get new passwords
+            seen.insert(password, first);
+            format!("reuses the password of user {first}")
+        } else {
+            continue;
+        };
+        issues.push(Issue::Password(*uuid, label.map(str::to_owned), issue));
+    }
+
+    if let Some(restful) = &cfg.restful {
+        if restful.secret == PLACEHOLDER_SECRET {
+            issues.push(Issue::Secret("is the placeholder".into()));
+        } else if !restful.secret.is_empty() && restful.secret.chars().count() < MIN_SECRET_LEN {
+            issues.push(Issue::Secret(format!(
+                "is shorter than {MIN_SECRET_LEN} characters"
+            )));
+        }
+    }
+
+    issues
+}
+
+/// Warnings about weak or reused passwords and secrets
+pub fn check(cfg: &Config) -> Vec<String> {
+    issues(cfg)
+        .into_iter()
+        .map(|issue| match issue {
+            Issue::Password(uuid, label, issue) => {
+                format!(
+                    "user {uuid}{label} {issue}, run with `--fix` to replace it",
+                    label = label_suffix(label.as_deref())
+                )
+            }
+            Issue::Secret(issue) => {
+                format!("`restful.secret` {issue}, run with `--fix` to replace it")
+            }
+        })
+        .collect()
+}
+
+/// Names the password of a user with several
+fn label_suffix(label: Option<&str>) -> String {
+    label.map(|label| format!(" ({label})")).unwrap_or_default()
+}
+
+/// 122 random bits
+fn generate() -> String {
+    Uuid::new_v4().simple().to_string()
+}
+
+/// Replace the weak passwords and secrets in the config file with generated
+/// ones, keeping its formatting. Returns what was replaced, for the operator
+/// to update the clients
+pub async fn fix(path: &Path, cfg: &Config) -> Result<String, ConfigError> {
+    let issues = issues(cfg);
+    if issues.is_empty() {
+        return Ok("nothing to fix".into());
+    }
+    let mut doc: toml_edit::DocumentMut = tokio::fs::read_to_string(path).await?.parse()?;
+    let mut out = String::new();
+    for issue in issues {
+        let replacement = generate();
+        match issue {
+            Issue::Password(uuid, label, issue) => {
+                let user = &mut doc["users"][uuid.to_string().as_str()];
+                match &label {
+                    Some(label) => user[label.as_str()] = toml_edit::value(&replacement),
+                    None => *user = toml_edit::value(&replacement),
+                }
+                out += &format!(
+                    "user {uuid}{label} {issue}, new password: {replacement}\n",
+                    label = label_suffix(label.as_deref())
+                );
+            }
+            Issue::Secret(issue) => {
+                doc["restful"]["secret"] = toml_edit::value(&replacement);
+                out += &format!("`restful.secret` {issue}, new secret: {replacement}\n");
+            }
+        }
+    }
+    tokio::fs::write(path, doc.to_string()).await?;
+    out += &format!("updated {path}\n", path = path.display());
+    Ok(out)
+}
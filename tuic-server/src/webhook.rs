@@ -0,0 +1,139 @@
+//! Pushing client events to `restful.webhook`, for billing systems and panels
+//! that would rather not poll
+
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    net::SocketAddr,
+    sync::OnceLock,
+    time::Duration,
+};
+
+use bytes::Bytes;
+use chrono::{Local, SecondsFormat};
+use eyre::{Context, bail};
+use http_body_util::Full;
+use hyper::{
+    Request, Uri,
+    header::{AUTHORIZATION, CONTENT_TYPE},
+};
+use serde::Serialize;
+use tokio::time;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::{AppContext, config::WebhookConfig, connection::CorrelationId, http_client::Client};
+
+/// Delay before the first retry, doubled on every following one
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+static CLIENT: OnceLock<Client> = OnceLock::new();
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookEvent {
+    Connect,
+    Disconnect,
+    /// Closed through the RESTful `/kick` or `/kick_connection`
+    Kick,
+    /// Closed for reaching a limit, named by the reason
+    Limit,
+}
+
+impl Display for WebhookEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::Connect => write!(f, "connect"),
+            Self::Disconnect => write!(f, "disconnect"),
+            Self::Kick => write!(f, "kick"),
+            Self::Limit => write!(f, "limit"),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Payload {
+    event: WebhookEvent,
+    uuid: Uuid,
+    addr: SocketAddr,
+    correlation_id: String,
+    reason: Option<String>,
+    /// RFC 3339, local time
+    timestamp: String,
+}
+
+pub fn init(cfg: Option<&WebhookConfig>) -> eyre::Result<()> {
+    let Some(cfg) = cfg else {
+        return Ok(());
+    };
+    let uri: Uri = cfg
+        .url
+        .parse()
+        .with_context(|| format!("restful.webhook.url: invalid URL {:?}", cfg.url))?;
+    if !matches!(uri.scheme_str(), Some("http" | "https")) || uri.host().is_none() {
+        bail!("restful.webhook.url: expected an http:// or https:// URL");
+    }
+    let client = Client::new().context("restful.webhook")?;
+    _ = CLIENT.set(client);
+    Ok(())
+}
+
+/// POST `event` to the webhook in the background, retrying failed deliveries
+/// up to `retries` times
+pub fn notify(
+    ctx: &AppContext,
+    event: WebhookEvent,
+    uuid: Uuid,
+    addr: SocketAddr,
+    cid: CorrelationId,
+    reason: Option<String>,
+) {
+    let Some(cfg) = ctx.cfg.restful.as_ref().and_then(|v| v.webhook.clone()) else {
+        return;
+    };
+    let Some(client) = CLIENT.get() else {
+        return;
+    };
+    let payload = Payload {
+        event,
+        uuid,
+        addr,
+        correlation_id: cid.to_string(),
+        reason,
+        timestamp: Local::now().to_rfc3339_opts(SecondsFormat::Secs, false),
+    };
+    let body = Bytes::from(serde_json::to_vec(&payload).unwrap());
+
+    tokio::spawn(async move {
+        let mut backoff = RETRY_BACKOFF;
+        for attempt in 0..=cfg.retries {
+            if attempt != 0 {
+                time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            match time::timeout(cfg.timeout, deliver(client, &cfg, body.clone())).await {
+                Ok(Ok(())) => {
+                    debug!("[webhook] [{cid}] delivered {uuid} {event}");
+                    return;
+                }
+                Ok(Err(err)) => debug!("[webhook] [{cid}] delivery attempt failed: {err:#}"),
+                Err(_) => debug!("[webhook] [{cid}] delivery attempt timed out"),
+            }
+        }
+        warn!(
+            "[webhook] [{cid}] failed to deliver {uuid} {event} after {attempts} attempts",
+            attempts = cfg.retries + 1,
+        );
+    });
+}
+
+async fn deliver(client: &Client, cfg: &WebhookConfig, body: Bytes) -> eyre::Result<()> {
+    let mut req = Request::post(cfg.url.as_str()).header(CONTENT_TYPE, "application/json");
+    if !cfg.secret.is_empty() {
+        req = req.header(AUTHORIZATION, format!("Bearer {}", cfg.secret));
+    }
+    let res = client.send(req.body(Full::new(body))?).await?;
+    if !res.status().is_success() {
+        bail!("unexpected status {}", res.status());
+    }
+    Ok(())
+}
@@ -3,7 +3,7 @@
 mod protocol;
 
 pub use self::protocol::{
-    Address, Authenticate, Connect, Dissociate, Header, Heartbeat, Packet, VERSION,
+    Address, Authenticate, Connect, Dissociate, ExternalAddress, Header, Heartbeat, Packet, VERSION,
 };
 
 #[cfg(any(feature = "async_marshal", feature = "marshal"))]
@@ -6,7 +6,9 @@ use std::{
 use bytes::{BufMut, BytesMut};
 use futures_util::{AsyncWrite, AsyncWriteExt};
 
-use crate::{Address, Authenticate, Connect, Dissociate, Header, Heartbeat, Packet, VERSION};
+use crate::{
+    Address, Authenticate, Connect, Dissociate, ExternalAddress, Header, Heartbeat, Packet, VERSION,
+};
 
 impl Header {
     /// Marshals the header into an `AsyncWrite` stream
@@ -36,6 +38,7 @@ impl Header {
             Self::Packet(packet) => packet.write(buf),
             Self::Dissociate(dissociate) => dissociate.write(buf),
             Self::Heartbeat(heartbeat) => heartbeat.write(buf),
+            Self::ExternalAddress(ext) => ext.write(buf),
         }
     }
 }
@@ -98,3 +101,10 @@ impl Dissociate {
 impl Heartbeat {
     fn write(&self, _buf: &mut impl BufMut) {}
 }
+
+impl ExternalAddress {
+    fn write(&self, buf: &mut impl BufMut) {
+        buf.put_u16(self.assoc_id());
+        self.addr().write(buf);
+    }
+}
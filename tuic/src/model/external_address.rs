@@ -0,0 +1,86 @@
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+
+use super::side::{self, Side};
+use crate::{Address, ExternalAddress as ExternalAddressHeader, Header};
+
+/// The model of the `ExternalAddress` command
+pub struct ExternalAddress<M> {
+    inner: Side<Tx, Rx>,
+    _marker: M,
+}
+
+struct Tx {
+    header: Header,
+}
+
+impl ExternalAddress<side::Tx> {
+    pub(super) fn new(assoc_id: u16, addr: Address) -> Self {
+        Self {
+            inner: Side::Tx(Tx {
+                header: Header::ExternalAddress(ExternalAddressHeader::new(assoc_id, addr)),
+            }),
+            _marker: side::Tx,
+        }
+    }
+
+    /// Returns the header of the `ExternalAddress` command
+    pub fn header(&self) -> &Header {
+        let Side::Tx(tx) = &self.inner else {
+            unreachable!()
+        };
+        &tx.header
+    }
+}
+
+impl Debug for ExternalAddress<side::Tx> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let Side::Tx(tx) = &self.inner else {
+            unreachable!()
+        };
+        f.debug_struct("ExternalAddress")
+            .field("header", &tx.header)
+            .finish()
+    }
+}
+
+struct Rx {
+    assoc_id: u16,
+    addr: Address,
+}
+
+impl ExternalAddress<side::Rx> {
+    pub(super) fn new(assoc_id: u16, addr: Address) -> Self {
+        Self {
+            inner: Side::Rx(Rx { assoc_id, addr }),
+            _marker: side::Rx,
+        }
+    }
+
+    /// Returns the UDP session ID
+    pub fn assoc_id(&self) -> u16 {
+        let Side::Rx(rx) = &self.inner else {
+            unreachable!()
+        };
+        rx.assoc_id
+    }
+
+    /// Returns the external address of the UDP session
+    pub fn addr(&self) -> &Address {
+        let Side::Rx(rx) = &self.inner else {
+            unreachable!()
+        };
+        &rx.addr
+    }
+}
+
+impl Debug for ExternalAddress<side::Rx> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        let Side::Rx(rx) = &self.inner else {
+            unreachable!()
+        };
+        f.debug_struct("ExternalAddress")
+            .field("assoc_id", &rx.assoc_id)
+            .field("addr", &rx.addr)
+            .finish()
+    }
+}
@@ -19,12 +19,14 @@ use uuid::Uuid;
 
 use crate::{
     Address, Authenticate as AuthenticateHeader, Connect as ConnectHeader,
-    Dissociate as DissociateHeader, Heartbeat as HeartbeatHeader, Packet as PacketHeader,
+    Dissociate as DissociateHeader, ExternalAddress as ExternalAddressHeader,
+    Heartbeat as HeartbeatHeader, Packet as PacketHeader,
 };
 
 mod authenticate;
 mod connect;
 mod dissociate;
+mod external_address;
 mod heartbeat;
 mod packet;
 
@@ -32,6 +34,7 @@ pub use self::{
     authenticate::{Authenticate, KeyingMaterialExporter},
     connect::Connect,
     dissociate::Dissociate,
+    external_address::ExternalAddress,
     heartbeat::Heartbeat,
     packet::{Fragments, Packet},
 };
@@ -150,6 +153,20 @@ where
         Heartbeat::<side::Rx>::new()
     }
 
+    /// Sends an `ExternalAddress`
+    pub fn send_external_address(&self, assoc_id: u16, addr: Address) -> ExternalAddress<side::Tx> {
+        ExternalAddress::<side::Tx>::new(assoc_id, addr)
+    }
+
+    /// Receives an `ExternalAddress`
+    pub fn recv_external_address(
+        &self,
+        header: ExternalAddressHeader,
+    ) -> ExternalAddress<side::Rx> {
+        let (assoc_id, addr) = header.into();
+        ExternalAddress::<side::Rx>::new(assoc_id, addr)
+    }
+
     /// Returns the number of `Connect` tasks
     pub fn task_connect_count(&self) -> usize {
         self.task_connect_count.count()
@@ -165,6 +182,12 @@ where
     pub fn collect_garbage(&self, timeout: Duration) {
         self.udp_sessions.lock().collect_garbage(timeout);
     }
+
+    /// Returns the number of packets waiting for fragments and the bytes of
+    /// the fragments they hold
+    pub fn fragment_cache_size(&self) -> (usize, usize) {
+        self.udp_sessions.lock().fragment_cache_size()
+    }
 }
 
 impl<B> Debug for Connection<B>
@@ -286,6 +309,13 @@ where
             session.collect_garbage(timeout);
         }
     }
+
+    fn fragment_cache_size(&self) -> (usize, usize) {
+        self.sessions
+            .values()
+            .map(UdpSession::fragment_cache_size)
+            .fold((0, 0), |(pkts, bytes), (p, b)| (pkts + p, bytes + b))
+    }
 }
 
 impl<B> Debug for UdpSessions<B>
@@ -372,6 +402,16 @@ where
     fn collect_garbage(&mut self, timeout: Duration) {
         self.pkt_buf.retain(|_, buf| buf.c_time.elapsed() < timeout);
     }
+
+    fn fragment_cache_size(&self) -> (usize, usize) {
+        let bytes = self
+            .pkt_buf
+            .values()
+            .flat_map(|buf| buf.buf.iter().flatten())
+            .map(|frag| frag.as_ref().len())
+            .sum();
+        (self.pkt_buf.len(), bytes)
+    }
 }
 
 impl<B> Debug for UdpSession<B>
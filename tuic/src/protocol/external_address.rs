@@ -0,0 +1,57 @@
+use super::Address;
+
+/// Command `ExternalAddress`
+///
+/// ```plain
+/// +----------+------+
+/// | ASSOC_ID | ADDR |
+/// +----------+------+
+/// |    2     | VAR  |
+/// +----------+------+
+/// ```
+///
+/// where:
+///
+/// - `ASSOC_ID` - UDP relay session ID
+/// - `ADDR` - the address the server relays the UDP session's packets from
+#[derive(Clone, Debug)]
+pub struct ExternalAddress {
+    assoc_id: u16,
+    addr: Address,
+}
+
+impl ExternalAddress {
+    const TYPE_CODE: u8 = 0x05;
+
+    /// Creates a new `ExternalAddress` command
+    pub const fn new(assoc_id: u16, addr: Address) -> Self {
+        Self { assoc_id, addr }
+    }
+
+    /// Returns the UDP relay session ID
+    pub fn assoc_id(&self) -> u16 {
+        self.assoc_id
+    }
+
+    /// Returns the external address of the UDP relay session
+    pub fn addr(&self) -> &Address {
+        &self.addr
+    }
+
+    /// Returns the command type code
+    pub const fn type_code() -> u8 {
+        Self::TYPE_CODE
+    }
+
+    /// Returns the serialized length of the command
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        2 + self.addr.len()
+    }
+}
+
+impl From<ExternalAddress> for (u16, Address) {
+    fn from(ext: ExternalAddress) -> Self {
+        (ext.assoc_id, ext.addr)
+    }
+}
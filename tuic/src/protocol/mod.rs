@@ -7,12 +7,13 @@ use std::{
 mod authenticate;
 mod connect;
 mod dissociate;
+mod external_address;
 mod heartbeat;
 mod packet;
 
 pub use self::{
-    authenticate::Authenticate, connect::Connect, dissociate::Dissociate, heartbeat::Heartbeat,
-    packet::Packet,
+    authenticate::Authenticate, connect::Connect, dissociate::Dissociate,
+    external_address::ExternalAddress, heartbeat::Heartbeat, packet::Packet,
 };
 
 /// The TUIC protocol version
@@ -35,13 +36,15 @@ pub const VERSION: u8 = 0x05;
 ///
 /// ## Command Types
 ///
-/// There are five types of command:
+/// There are six types of command:
 ///
 /// - `0x00` - `Authenticate` - for authenticating the multiplexed stream
 /// - `0x01` - `Connect` - for establishing a TCP relay
 /// - `0x02` - `Packet` - for relaying (fragmented part of) a UDP packet
 /// - `0x03` - `Dissociate` - for terminating a UDP relaying session
 /// - `0x04` - `Heartbeat` - for keeping the QUIC connection alive
+/// - `0x05` - `ExternalAddress` - for reporting the address a UDP relaying
+///   session is relayed from (extension, only sent when enabled on the server)
 ///
 /// Command `Connect` and `Packet` carry payload (stream / packet fragment)
 #[non_exhaustive]
@@ -52,12 +55,14 @@ pub enum Header {
     Packet(Packet),
     Dissociate(Dissociate),
     Heartbeat(Heartbeat),
+    ExternalAddress(ExternalAddress),
 }
 
 impl Header {
     pub const TYPE_CODE_AUTHENTICATE: u8 = Authenticate::type_code();
     pub const TYPE_CODE_CONNECT: u8 = Connect::type_code();
     pub const TYPE_CODE_DISSOCIATE: u8 = Dissociate::type_code();
+    pub const TYPE_CODE_EXTERNAL_ADDRESS: u8 = ExternalAddress::type_code();
     pub const TYPE_CODE_HEARTBEAT: u8 = Heartbeat::type_code();
     pub const TYPE_CODE_PACKET: u8 = Packet::type_code();
 
@@ -69,6 +74,7 @@ impl Header {
             Self::Packet(_) => Packet::type_code(),
             Self::Dissociate(_) => Dissociate::type_code(),
             Self::Heartbeat(_) => Heartbeat::type_code(),
+            Self::ExternalAddress(_) => ExternalAddress::type_code(),
         }
     }
 
@@ -81,6 +87,7 @@ impl Header {
             Self::Packet(packet) => packet.len(),
             Self::Dissociate(dissociate) => dissociate.len(),
             Self::Heartbeat(heartbeat) => heartbeat.len(),
+            Self::ExternalAddress(ext) => ext.len(),
         }
     }
 }
@@ -8,7 +8,9 @@ use futures_util::{AsyncRead, AsyncReadExt};
 use thiserror::Error;
 use uuid::{Error as UuidError, Uuid};
 
-use crate::{Address, Authenticate, Connect, Dissociate, Header, Heartbeat, Packet, VERSION};
+use crate::{
+    Address, Authenticate, Connect, Dissociate, ExternalAddress, Header, Heartbeat, Packet, VERSION,
+};
 
 impl Header {
     /// Unmarshals a header from an `AsyncRead` stream
@@ -34,6 +36,9 @@ impl Header {
             Header::TYPE_CODE_PACKET => Packet::async_read(s).await.map(Self::Packet),
             Header::TYPE_CODE_DISSOCIATE => Dissociate::async_read(s).await.map(Self::Dissociate),
             Header::TYPE_CODE_HEARTBEAT => Heartbeat::async_read(s).await.map(Self::Heartbeat),
+            Header::TYPE_CODE_EXTERNAL_ADDRESS => ExternalAddress::async_read(s)
+                .await
+                .map(Self::ExternalAddress),
             _ => Err(UnmarshalError::InvalidCommand(cmd)),
         }
     }
@@ -59,6 +64,9 @@ impl Header {
             Header::TYPE_CODE_PACKET => Packet::read(s).map(Self::Packet),
             Header::TYPE_CODE_DISSOCIATE => Dissociate::read(s).map(Self::Dissociate),
             Header::TYPE_CODE_HEARTBEAT => Heartbeat::read(s).map(Self::Heartbeat),
+            Header::TYPE_CODE_EXTERNAL_ADDRESS => {
+                ExternalAddress::read(s).map(Self::ExternalAddress)
+            }
             _ => Err(UnmarshalError::InvalidCommand(cmd)),
         }
     }
@@ -258,6 +266,26 @@ impl Heartbeat {
     }
 }
 
+impl ExternalAddress {
+    #[cfg(feature = "async_marshal")]
+    async fn async_read(s: &mut (impl AsyncRead + Unpin)) -> Result<Self, UnmarshalError> {
+        let mut buf = [0; 2];
+        s.read_exact(&mut buf).await?;
+        let assoc_id = u16::from_be_bytes(buf);
+        let addr = Address::async_read(s).await?;
+        Ok(Self::new(assoc_id, addr))
+    }
+
+    #[cfg(feature = "marshal")]
+    fn read(s: &mut impl Read) -> Result<Self, UnmarshalError> {
+        let mut buf = [0; 2];
+        s.read_exact(&mut buf)?;
+        let assoc_id = u16::from_be_bytes(buf);
+        let addr = Address::read(s)?;
+        Ok(Self::new(assoc_id, addr))
+    }
+}
+
 /// Errors that can occur when unmarshalling a packet
 #[derive(Debug, Error)]
 pub enum UnmarshalError {